@@ -0,0 +1,73 @@
+//! Packs the folder named by `INITFS_FOLDER`, or `initfs` next to this file, into the kernel.
+//! The generated `initfs.rs` lists every directory and file, with each file in page aligned
+//! static memory so the `initfs:` scheme can map it into processes without copying.
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn mode(metadata: &fs::Metadata) -> u16 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        (metadata.permissions().mode() & 0o777) as u16
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.is_dir() { 0o555 } else { 0o444 }
+    }
+}
+
+fn scan(folder: &Path, prefix: &str, entries: &mut Vec<(String, PathBuf, bool, u16)>) {
+    let mut children: Vec<_> = fs::read_dir(folder)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", folder.display(), err))
+        .map(|entry| entry.expect("failed to read initfs entry").path())
+        .collect();
+    children.sort();
+
+    for path in children {
+        let name = path.file_name().unwrap().to_str()
+            .filter(|name| name.is_ascii())
+            .expect("initfs names must be ASCII")
+            .to_string();
+        let name = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let metadata = fs::metadata(&path).unwrap();
+
+        println!("cargo:rerun-if-changed={}", path.display());
+        entries.push((name.clone(), path.clone(), metadata.is_dir(), mode(&metadata)));
+        if metadata.is_dir() {
+            scan(&path, &name, entries);
+        }
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=INITFS_FOLDER");
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let folder = env::var("INITFS_FOLDER")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| manifest_dir.join("initfs"));
+
+    let mut entries = Vec::new();
+    if folder.is_dir() {
+        println!("cargo:rerun-if-changed={}", folder.display());
+        scan(&folder, "", &mut entries);
+    }
+
+    let mut statics = String::new();
+    let mut nodes = String::from("    Node { path: b\"\", mode: MODE_DIR | 0o555, data: &[] },\n");
+    for (i, &(ref name, ref path, is_dir, mode)) in entries.iter().enumerate() {
+        if is_dir {
+            writeln!(nodes, "    Node {{ path: b{:?}, mode: MODE_DIR | 0o{:o}, data: &[] }},", name, mode).unwrap();
+        } else {
+            let len = fs::metadata(path).unwrap().len();
+            writeln!(statics, "static FILE_{}: Aligned<[u8; {}]> = Aligned(*include_bytes!({:?}));", i, len, path.canonicalize().unwrap()).unwrap();
+            writeln!(nodes, "    Node {{ path: b{:?}, mode: MODE_FILE | 0o{:o}, data: &FILE_{}.0 }},", name, mode, i).unwrap();
+        }
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initfs.rs");
+    let source = format!("{}\npub static NODES: &[Node] = &[\n{}];\n", statics, nodes);
+    fs::write(&out, source).unwrap_or_else(|err| panic!("failed to write {}: {}", out.display(), err));
+}
@@ -0,0 +1,112 @@
+use core::{mem, ptr};
+
+use super::gas::GenericAddressStructure;
+use super::sdt::Sdt;
+
+/// Fixed ACPI Description Table, see ACPI 6.2 section 5.2.9.
+/// Older firmware provides a shorter table, the missing fields read as zero.
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct Fadt {
+    pub header: Sdt,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+
+    // field used in ACPI 1.0; no longer in use, for compatibility only
+    reserved: u8,
+
+    pub preferred_power_managament: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4_bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub c_state_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+
+    // reserved in ACPI 1.0; used since ACPI 2.0+
+    pub boot_architecture_flags: u16,
+
+    reserved2: u8,
+    pub flags: u32,
+
+    // ACPI 2.0+
+    pub reset_reg: GenericAddressStructure,
+    pub reset_value: u8,
+    reserved3: [u8; 3],
+
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+
+    pub x_pm1a_event_block: GenericAddressStructure,
+    pub x_pm1b_event_block: GenericAddressStructure,
+    pub x_pm1a_control_block: GenericAddressStructure,
+    pub x_pm1b_control_block: GenericAddressStructure,
+    pub x_pm2_control_block: GenericAddressStructure,
+    pub x_pm_timer_block: GenericAddressStructure,
+    pub x_gpe0_block: GenericAddressStructure,
+    pub x_gpe1_block: GenericAddressStructure,
+}
+
+/// `boot_architecture_flags`: the 8042 keyboard controller is present
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// `flags`: the reset register is supported
+pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+impl Fadt {
+    pub fn new(sdt: &'static Sdt) -> Option<Fadt> {
+        if &sdt.signature != b"FACP" {
+            return None;
+        }
+
+        let len = (sdt.length as usize).min(mem::size_of::<Fadt>());
+        let mut fadt: Fadt = unsafe { mem::zeroed() };
+        unsafe {
+            ptr::copy_nonoverlapping(sdt as *const Sdt as *const u8, &mut fadt as *mut Fadt as *mut u8, len);
+        }
+        Some(fadt)
+    }
+
+    /// Physical address of the DSDT, preferring the 64-bit field
+    pub fn dsdt_address(&self) -> u64 {
+        if self.x_dsdt != 0 {
+            self.x_dsdt
+        } else {
+            self.dsdt as u64
+        }
+    }
+
+    /// The CMOS register holding the century, if any
+    pub fn century_register(&self) -> Option<u8> {
+        if self.century != 0 {
+            Some(self.century)
+        } else {
+            None
+        }
+    }
+}
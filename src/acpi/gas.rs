@@ -0,0 +1,21 @@
+/// Generic Address Structure, see ACPI 6.2 section 5.2.3.2
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(packed)]
+pub struct GenericAddressStructure {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+pub const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+
+impl GenericAddressStructure {
+    /// An all zero structure means the register is not implemented
+    pub fn is_valid(&self) -> bool {
+        self.address != 0
+    }
+}
@@ -0,0 +1,36 @@
+use core::{mem, ptr};
+
+use super::gas::GenericAddressStructure;
+use super::sdt::Sdt;
+
+/// High Precision Event Timer description table
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct Hpet {
+    pub header: Sdt,
+
+    pub hw_rev_id: u8,
+    pub comparator_descriptor: u8,
+    pub pci_vendor_id: u16,
+
+    pub base_address: GenericAddressStructure,
+
+    pub hpet_number: u8,
+    pub min_periodic_clk_tick: u16,
+    pub oem_attribute: u8,
+}
+
+impl Hpet {
+    pub fn new(sdt: &'static Sdt) -> Option<Hpet> {
+        if &sdt.signature == b"HPET" && sdt.length as usize >= mem::size_of::<Hpet>() {
+            Some(unsafe { ptr::read_unaligned(sdt as *const Sdt as *const Hpet) })
+        } else {
+            None
+        }
+    }
+
+    /// Number of comparators, from the hardware block ID
+    pub fn comparator_count(&self) -> u8 {
+        (self.comparator_descriptor & 0x1F) + 1
+    }
+}
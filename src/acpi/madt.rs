@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+use core::mem;
+
+use super::sdt::Sdt;
+
+/// Multiple APIC Description Table, decoded into the entries the kernel cares about
+#[derive(Clone, Debug)]
+pub struct Madt {
+    /// Physical address of the local APICs
+    pub local_address: u32,
+    pub flags: u32,
+    /// Processors, each with their local APIC
+    pub cpus: Vec<MadtLocalApic>,
+    pub io_apics: Vec<MadtIoApic>,
+    /// ISA IRQs that are not identity mapped to global system interrupts
+    pub overrides: Vec<MadtIntSrcOverride>,
+}
+
+/// The system also has dual 8259 PICs
+pub const FLAG_PCAT_COMPAT: u32 = 1;
+
+/// The processor is ready to use
+pub const CPU_ENABLED: u32 = 1;
+
+/// MADT Local APIC
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtLocalApic {
+    /// Processor ID
+    pub processor: u8,
+    /// Local APIC ID
+    pub id: u8,
+    /// Flags. 1 means that the processor is enabled
+    pub flags: u32,
+}
+
+/// MADT I/O APIC
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtIoApic {
+    /// I/O APIC ID
+    pub id: u8,
+    reserved: u8,
+    /// I/O APIC address
+    pub address: u32,
+    /// Global system interrupt base
+    pub gsi_base: u32,
+}
+
+/// MADT Interrupt Source Override
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtIntSrcOverride {
+    /// Bus Source
+    pub bus_source: u8,
+    /// IRQ Source
+    pub irq_source: u8,
+    /// Global system interrupt base
+    pub gsi_base: u32,
+    /// Flags
+    pub flags: u16,
+}
+
+impl Madt {
+    pub fn new(sdt: &'static Sdt) -> Option<Madt> {
+        if &sdt.signature != b"APIC" || sdt.data_len() < 8 {
+            return None;
+        }
+
+        let data = sdt.data();
+        let mut madt = Madt {
+            local_address: read(data, 0),
+            flags: read(data, 4),
+            cpus: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut i = 8;
+        while i + 2 <= data.len() {
+            let entry_type = data[i];
+            let entry_len = data[i + 1] as usize;
+            if entry_len < 2 || i + entry_len > data.len() {
+                break;
+            }
+
+            let entry = &data[i + 2..i + entry_len];
+            match entry_type {
+                0 if entry.len() >= mem::size_of::<MadtLocalApic>() => madt.cpus.push(read(entry, 0)),
+                1 if entry.len() >= mem::size_of::<MadtIoApic>() => madt.io_apics.push(read(entry, 0)),
+                2 if entry.len() >= mem::size_of::<MadtIntSrcOverride>() => madt.overrides.push(read(entry, 0)),
+                // Local APIC address override
+                5 if entry.len() >= 10 => madt.local_address = read::<u64>(entry, 2) as u32,
+                _ => (),
+            }
+
+            i += entry_len;
+        }
+
+        Some(madt)
+    }
+
+    /// Processors that can be started
+    pub fn enabled_cpus<'a>(&'a self) -> impl Iterator<Item=&'a MadtLocalApic> + 'a {
+        self.cpus.iter().filter(|cpu| cpu.flags & CPU_ENABLED == CPU_ENABLED)
+    }
+
+    /// The global system interrupt an ISA IRQ is connected to
+    pub fn irq_to_gsi(&self, irq: u8) -> u32 {
+        self.overrides.iter()
+            .find(|over| over.bus_source == 0 && over.irq_source == irq)
+            .map(|over| over.gsi_base)
+            .unwrap_or(irq as u32)
+    }
+}
+
+/// Read an unaligned `T` at `offset` of `data`, which must be long enough
+fn read<T: Copy>(data: &[u8], offset: usize) -> T {
+    assert!(offset + mem::size_of::<T>() <= data.len());
+    unsafe { (data.as_ptr().offset(offset as isize) as *const T).read_unaligned() }
+}
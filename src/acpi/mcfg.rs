@@ -0,0 +1,45 @@
+use alloc::vec::Vec;
+use core::mem;
+
+use super::sdt::Sdt;
+
+/// PCI Express memory mapped configuration table, listing where the configuration space of each
+/// range of buses is mapped
+#[derive(Clone, Debug)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+/// The configuration space of buses `start_bus` to `end_bus` of a segment, 4 KiB per function
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    reserved: u32,
+}
+
+impl Mcfg {
+    pub fn new(sdt: &'static Sdt) -> Option<Mcfg> {
+        // Entries follow 8 reserved bytes
+        if &sdt.signature != b"MCFG" || sdt.data_len() < 8 {
+            return None;
+        }
+
+        let data = &sdt.data()[8..];
+        let entries = data.chunks(mem::size_of::<McfgEntry>())
+            .filter(|entry| entry.len() == mem::size_of::<McfgEntry>())
+            .map(|entry| unsafe { (entry.as_ptr() as *const McfgEntry).read_unaligned() })
+            .collect();
+        Some(Mcfg { entries })
+    }
+
+    /// The entry covering `bus` of segment 0, the only one the kernel uses
+    pub fn find(&self, bus: u8) -> Option<McfgEntry> {
+        self.entries.iter()
+            .find(|entry| entry.segment == 0 && entry.start_bus <= bus && bus <= entry.end_bus)
+            .cloned()
+    }
+}
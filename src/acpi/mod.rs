@@ -0,0 +1,124 @@
+//! # ACPI
+//!
+//! Finds the RSDP, walks the RSDT or XSDT and decodes the tables used by the device layer.
+//! See the [ACPI specification](https://uefi.org/specifications) and [osdev](https://wiki.osdev.org/ACPI)
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::memory::{self, ActivePageTable};
+use crate::println;
+
+use self::fadt::Fadt;
+use self::hpet::Hpet;
+use self::madt::Madt;
+use self::mcfg::Mcfg;
+use self::rsdp::Rsdp;
+use self::rsdt::Rsdt;
+use self::sdt::Sdt;
+use self::xsdt::Xsdt;
+
+pub mod fadt;
+pub mod gas;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+pub mod rsdp;
+pub mod rsdt;
+pub mod sdt;
+pub mod xsdt;
+
+/// The decoded ACPI tables
+pub struct Acpi {
+    /// Every table with a valid checksum, as listed by the RSDT or XSDT
+    pub sdts: Vec<&'static Sdt>,
+    pub fadt: Option<Fadt>,
+    pub dsdt: Option<&'static Sdt>,
+    pub madt: Option<Madt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+pub static ACPI_TABLE: Mutex<Acpi> = Mutex::new(Acpi {
+    sdts: Vec::new(),
+    fadt: None,
+    dsdt: None,
+    madt: None,
+    hpet: None,
+    mcfg: None,
+});
+
+impl Acpi {
+    /// Find a table by its signature
+    pub fn find_sdt(&self, signature: &[u8; 4]) -> Option<&'static Sdt> {
+        self.sdts.iter().find(|sdt| &sdt.signature == signature).map(|&sdt| sdt)
+    }
+}
+
+/// Map a table and return a reference to it, checking its checksum
+unsafe fn get_sdt(active_table: &mut ActivePageTable, address: u64) -> Option<&'static Sdt> {
+    // Map the header first to learn the length, then the whole table
+    let header = memory::map_device(active_table, PhysAddr::new(address), core::mem::size_of::<Sdt>());
+    let length = (*header.as_ptr::<Sdt>()).length as usize;
+    if length < core::mem::size_of::<Sdt>() {
+        return None;
+    }
+
+    let sdt = &*memory::map_device(active_table, PhysAddr::new(address), length).as_ptr::<Sdt>();
+    if sdt.checksum_valid() {
+        Some(sdt)
+    } else {
+        println!("ACPI: {} at {:#X} has an invalid checksum", signature(sdt), address);
+        None
+    }
+}
+
+fn signature(sdt: &Sdt) -> &str {
+    core::str::from_utf8(&sdt.signature).unwrap_or("????")
+}
+
+/// Parse the ACPI tables to gather information about the CPUs, interrupt routing and timers
+pub unsafe fn init(active_table: &mut ActivePageTable) {
+    let rsdp = match Rsdp::search() {
+        Some(rsdp) => rsdp,
+        None => {
+            println!("ACPI: no RSDP found");
+            return;
+        }
+    };
+
+    let root = match get_sdt(active_table, rsdp.sdt_address()) {
+        Some(root) => root,
+        None => return,
+    };
+
+    let mut addresses = Vec::new();
+    if rsdp.is_xsdt() {
+        if let Some(xsdt) = Xsdt::new(root) {
+            addresses.extend(xsdt.iter());
+        }
+    } else if let Some(rsdt) = Rsdt::new(root) {
+        addresses.extend(rsdt.iter());
+    }
+
+    let mut acpi = ACPI_TABLE.lock();
+    for address in addresses {
+        if let Some(sdt) = get_sdt(active_table, address) {
+            acpi.sdts.push(sdt);
+        }
+    }
+
+    acpi.fadt = acpi.find_sdt(b"FACP").and_then(Fadt::new);
+    acpi.dsdt = acpi.fadt.and_then(|fadt| get_sdt(active_table, fadt.dsdt_address()))
+        .filter(|sdt| &sdt.signature == b"DSDT");
+    acpi.madt = acpi.find_sdt(b"APIC").and_then(Madt::new);
+    acpi.hpet = acpi.find_sdt(b"HPET").and_then(Hpet::new);
+    acpi.mcfg = acpi.find_sdt(b"MCFG").and_then(Mcfg::new);
+
+    for sdt in acpi.sdts.iter() {
+        println!("ACPI: {} {:#X} {}", signature(sdt), *sdt as *const Sdt as usize, { sdt.length });
+    }
+    if let Some(ref madt) = acpi.madt {
+        println!("ACPI: {} CPUs, {} I/O APICs", madt.enabled_cpus().count(), madt.io_apics.len());
+    }
+}
@@ -0,0 +1,82 @@
+use core::{mem, slice};
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt_addr;
+
+/// Root System Description Pointer, see ACPI 6.2 section 5.2.5.3
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oemid: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP, which is covered by `checksum`
+const RSDP_V1_SIZE: usize = 20;
+
+impl Rsdp {
+    /// Search for the RSDP in the first KiB of the EBDA, then in the BIOS area below 1 MiB
+    pub fn search() -> Option<Rsdp> {
+        let ebda_segment = unsafe { *phys_to_virt_addr(PhysAddr::new(0x40E)).as_ptr::<u16>() };
+        let ebda_start = (ebda_segment as u64) << 4;
+
+        if ebda_start != 0 {
+            if let Some(rsdp) = Self::search_range(ebda_start, ebda_start + 1024) {
+                return Some(rsdp);
+            }
+        }
+
+        Self::search_range(0xE_0000, 0x10_0000)
+    }
+
+    /// The signature is aligned on a 16 byte boundary
+    fn search_range(start: u64, end: u64) -> Option<Rsdp> {
+        let mut address = start;
+        while address + mem::size_of::<Rsdp>() as u64 <= end {
+            let virt = phys_to_virt_addr(PhysAddr::new(address));
+            let signature = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), 8) };
+            if signature == b"RSD PTR " {
+                let rsdp = unsafe { *virt.as_ptr::<Rsdp>() };
+                if rsdp.checksum_valid() {
+                    return Some(rsdp);
+                }
+            }
+            address += 16;
+        }
+
+        None
+    }
+
+    fn checksum_valid(&self) -> bool {
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<Rsdp>()) };
+        let sum = |bytes: &[u8]| bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+
+        if sum(&bytes[..RSDP_V1_SIZE]) != 0 {
+            return false;
+        }
+
+        // ACPI 2.0 and later extend the structure and add a checksum for the whole of it
+        self.revision < 2 || sum(&bytes[..(self.length as usize).min(bytes.len())]) == 0
+    }
+
+    /// Get the RSDT or XSDT address
+    pub fn sdt_address(&self) -> u64 {
+        if self.revision >= 2 {
+            self.xsdt_address
+        } else {
+            self.rsdt_address as u64
+        }
+    }
+
+    /// Whether `sdt_address` points at an XSDT, with 64-bit entries
+    pub fn is_xsdt(&self) -> bool {
+        self.revision >= 2
+    }
+}
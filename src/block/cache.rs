@@ -0,0 +1,176 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::cmp;
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::syscall::error::*;
+
+struct Block {
+    data: Box<[u8; SECTOR_SIZE]>,
+    /// Changed since it was read from the device
+    dirty: bool,
+    /// Value of the cache clock when the block was last used
+    used: u64,
+}
+
+/// Sectors of `device` kept in memory, up to `capacity` of them. Writes stay in the cache until
+/// `sync`, or until their sector is the least recently used one and makes room for another.
+pub struct BlockCache<D> {
+    device: D,
+    blocks: BTreeMap<u64, Block>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    pub fn new(device: D, capacity: usize) -> BlockCache<D> {
+        BlockCache {
+            device,
+            blocks: BTreeMap::new(),
+            capacity: cmp::max(capacity, 1),
+            clock: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Size of the device in bytes
+    pub fn size(&self) -> u64 {
+        self.device.sectors() * SECTOR_SIZE as u64
+    }
+
+    /// Write back all sectors and return the device
+    pub fn into_inner(mut self) -> Result<D> {
+        self.sync()?;
+        Ok(self.device)
+    }
+
+    /// The cached block of `sector`, read from the device unless `overwrite` is set because the
+    /// caller replaces all of it
+    fn block(&mut self, sector: u64, overwrite: bool) -> Result<&mut Block> {
+        self.clock += 1;
+
+        if !self.blocks.contains_key(&sector) {
+            if sector >= self.device.sectors() {
+                return Err(Error::new(EIO));
+            }
+
+            if self.blocks.len() >= self.capacity {
+                self.evict()?;
+            }
+
+            let mut data = Box::new([0; SECTOR_SIZE]);
+            if !overwrite {
+                self.device.read(sector, &mut data[..])?;
+            }
+            self.blocks.insert(sector, Block {
+                data,
+                dirty: false,
+                used: 0,
+            });
+        }
+
+        let block = self.blocks.get_mut(&sector).unwrap();
+        block.used = self.clock;
+        Ok(block)
+    }
+
+    /// Remove the least recently used block, writing it back if needed
+    fn evict(&mut self) -> Result<()> {
+        let sector = match self.blocks.iter().min_by_key(|&(_, block)| block.used) {
+            Some((&sector, _)) => sector,
+            None => return Ok(()),
+        };
+
+        {
+            let block = &self.blocks[&sector];
+            if block.dirty {
+                self.device.write(sector, &block.data[..])?;
+            }
+        }
+        self.blocks.remove(&sector);
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes from byte `offset` of the device
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let sector = position / SECTOR_SIZE as u64;
+            let start = (position % SECTOR_SIZE as u64) as usize;
+            let count = cmp::min(SECTOR_SIZE - start, buf.len() - done);
+
+            let block = self.block(sector, false)?;
+            buf[done..done + count].copy_from_slice(&block.data[start..start + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to byte `offset` of the device
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let sector = position / SECTOR_SIZE as u64;
+            let start = (position % SECTOR_SIZE as u64) as usize;
+            let count = cmp::min(SECTOR_SIZE - start, buf.len() - done);
+
+            let block = self.block(sector, count == SECTOR_SIZE)?;
+            block.data[start..start + count].copy_from_slice(&buf[done..done + count]);
+            block.dirty = true;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Set `len` bytes from byte `offset` of the device to zero
+    pub fn zero_at(&mut self, offset: u64, len: u64) -> Result<()> {
+        let zeros = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let count = cmp::min(SECTOR_SIZE as u64 - (offset + done) % SECTOR_SIZE as u64, len - done);
+            self.write_at(offset + done, &zeros[..count as usize])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Write all changed sectors to the device, in order, and flush it
+    pub fn sync(&mut self) -> Result<()> {
+        for (&sector, block) in self.blocks.iter_mut() {
+            if block.dirty {
+                self.device.write(sector, &block.data[..])?;
+                block.dirty = false;
+            }
+        }
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    #[test]
+    fn write_back() {
+        let mut cache = BlockCache::new(RamDisk::new(vec![0; 4 * SECTOR_SIZE]), 2);
+
+        // Spans two sectors, neither of which reaches the disk before the third is used
+        cache.write_at(500, b"across sectors").unwrap();
+        let mut buf = [0; 14];
+        cache.read_at(500, &mut buf).unwrap();
+        assert_eq!(&buf, b"across sectors");
+
+        cache.read_at(3 * SECTOR_SIZE as u64, &mut buf).unwrap();
+        let disk = cache.into_inner().unwrap().into_inner();
+        assert_eq!(&disk[500..514], b"across sectors");
+
+        let mut cache = BlockCache::new(RamDisk::new(disk), 2);
+        assert_eq!(cache.read_at(4 * SECTOR_SIZE as u64, &mut buf), Err(Error::new(EIO)));
+    }
+}
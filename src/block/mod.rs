@@ -0,0 +1,55 @@
+//! # Block devices
+//!
+//! Storage that is read and written in whole sectors. Filesystems reach a device through a
+//! `BlockCache`, which keeps recently used sectors in memory and turns byte ranges into sector
+//! reads and writes.
+use alloc::boxed::Box;
+
+use crate::syscall::error::Result;
+
+pub use self::cache::BlockCache;
+pub use self::ramdisk::RamDisk;
+
+/// Sectors kept in memory
+pub mod cache;
+
+/// A device backed by memory
+pub mod ramdisk;
+
+/// Size of a sector in bytes
+pub const SECTOR_SIZE: usize = 512;
+
+/// A device made of `SECTOR_SIZE` byte sectors
+pub trait BlockDevice {
+    /// Number of sectors on the device
+    fn sectors(&self) -> u64;
+
+    /// Read the sectors from `sector` into `buf`, a whole number of sectors long
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Write `buf`, a whole number of sectors long, to the sectors from `sector`
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()>;
+
+    /// Wait until written sectors are stored, for devices with a write cache of their own
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for Box<T> {
+    fn sectors(&self) -> u64 {
+        (**self).sectors()
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        (**self).read(sector, buf)
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        (**self).write(sector, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
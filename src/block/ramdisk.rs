@@ -0,0 +1,54 @@
+use alloc::vec::Vec;
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::syscall::error::*;
+
+/// A block device kept in memory, such as an image loaded with the kernel
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// A disk holding `data`, padded with zeros to a whole number of sectors
+    pub fn new(mut data: Vec<u8>) -> RamDisk {
+        let len = (data.len() + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        data.resize(len, 0);
+        RamDisk { data }
+    }
+
+    /// The contents of the disk
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The bytes of `len` bytes of sectors from `sector`
+    fn range(&self, sector: u64, len: usize) -> Result<(usize, usize)> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let start = (sector as usize).checked_mul(SECTOR_SIZE).ok_or(Error::new(EIO))?;
+        let end = start.checked_add(len).ok_or(Error::new(EIO))?;
+        if end > self.data.len() {
+            return Err(Error::new(EIO));
+        }
+        Ok((start, end))
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sectors(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let (start, end) = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.data[start..end]);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        let (start, end) = self.range(sector, buf.len())?;
+        self.data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+}
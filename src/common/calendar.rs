@@ -0,0 +1,251 @@
+//! # Calendar
+//!
+//! Conversions between Unix time and the proleptic Gregorian calendar, which extends the
+//! Gregorian leap year rules to dates before its introduction. Day counts are converted with the
+//! [algorithms of Howard Hinnant](http://howardhinnant.github.io/date_algorithms.html), which work
+//! in 400-year eras starting on March 1st so that the leap day is the last day of a year.
+use core::fmt;
+
+pub const SECONDS_PER_MINUTE: i64 = 60;
+pub const SECONDS_PER_HOUR: i64 = 60 * SECONDS_PER_MINUTE;
+pub const SECONDS_PER_DAY: i64 = 24 * SECONDS_PER_HOUR;
+
+/// Days in a 400-year era
+const DAYS_PER_ERA: i64 = 146_097;
+/// Days from 0000-03-01 to 1970-01-01
+const DAYS_TO_UNIX_EPOCH: i64 = 719_468;
+
+/// Days of the week, numbered as in ISO 8601
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Weekday {
+    Monday = 1,
+    Tuesday = 2,
+    Wednesday = 3,
+    Thursday = 4,
+    Friday = 5,
+    Saturday = 6,
+    Sunday = 7,
+}
+
+impl Weekday {
+    /// The weekday of a day counted from the Unix epoch, which was a Thursday
+    pub fn from_days(days: i64) -> Weekday {
+        match mod_floor(days + 3, 7) {
+            0 => Weekday::Monday,
+            1 => Weekday::Tuesday,
+            2 => Weekday::Wednesday,
+            3 => Weekday::Thursday,
+            4 => Weekday::Friday,
+            5 => Weekday::Saturday,
+            _ => Weekday::Sunday,
+        }
+    }
+
+    /// Days since Sunday, as used by POSIX
+    pub fn days_from_sunday(self) -> u8 {
+        self as u8 % 7
+    }
+
+    pub fn short_name(self) -> &'static str {
+        match self {
+            Weekday::Monday => "Mon",
+            Weekday::Tuesday => "Tue",
+            Weekday::Wednesday => "Wed",
+            Weekday::Thursday => "Thu",
+            Weekday::Friday => "Fri",
+            Weekday::Saturday => "Sat",
+            Weekday::Sunday => "Sun",
+        }
+    }
+}
+
+/// Division rounding towards negative infinity
+fn div_floor(a: i64, b: i64) -> i64 {
+    let q = a / b;
+    if a % b < 0 { q - 1 } else { q }
+}
+
+/// Remainder of `div_floor`, always positive
+fn mod_floor(a: i64, b: i64) -> i64 {
+    let r = a % b;
+    if r < 0 { r + b } else { r }
+}
+
+pub fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// Days in `month` (1 to 12) of `year`
+pub fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to the given date, negative before it
+pub fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let (month, day) = (month as i64, day as i64);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = div_floor(year, 400);
+    let year_of_era = mod_floor(year, 400);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era - DAYS_TO_UNIX_EPOCH
+}
+
+/// The (year, month, day) of a day counted from 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + DAYS_TO_UNIX_EPOCH;
+    let era = div_floor(days, DAYS_PER_ERA);
+    let day_of_era = mod_floor(days, DAYS_PER_ERA);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A broken-down date and time, at a fixed offset from UTC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    /// Seconds east of UTC
+    pub utc_offset: i32,
+}
+
+impl DateTime {
+    /// A UTC date and time, or `None` if a field is out of range
+    pub fn new(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<DateTime> {
+        if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        Some(DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            nanosecond: 0,
+            utc_offset: 0,
+        })
+    }
+
+    /// The UTC date and time of a Unix timestamp
+    pub fn from_unix(secs: i64, nanosecond: u32) -> DateTime {
+        DateTime::from_unix_with_offset(secs, nanosecond, 0)
+    }
+
+    /// The date and time of a Unix timestamp, `utc_offset` seconds east of UTC
+    pub fn from_unix_with_offset(secs: i64, nanosecond: u32, utc_offset: i32) -> DateTime {
+        let local = secs + utc_offset as i64;
+        let (year, month, day) = civil_from_days(div_floor(local, SECONDS_PER_DAY));
+        let secs_of_day = mod_floor(local, SECONDS_PER_DAY);
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / SECONDS_PER_HOUR) as u8,
+            minute: (secs_of_day % SECONDS_PER_HOUR / SECONDS_PER_MINUTE) as u8,
+            second: (secs_of_day % SECONDS_PER_MINUTE) as u8,
+            nanosecond,
+            utc_offset,
+        }
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn to_unix(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + self.hour as i64 * SECONDS_PER_HOUR
+            + self.minute as i64 * SECONDS_PER_MINUTE
+            + self.second as i64
+            - self.utc_offset as i64
+    }
+
+    pub fn weekday(&self) -> Weekday {
+        Weekday::from_days(days_from_civil(self.year, self.month, self.day))
+    }
+
+    /// Day of the year, from 1 to 366
+    pub fn ordinal(&self) -> u16 {
+        (days_from_civil(self.year, self.month, self.day) - days_from_civil(self.year, 1, 1) + 1) as u16
+    }
+}
+
+/// ISO 8601 extended format, such as `2019-08-04T13:05:00Z` or `2019-08-04T21:05:00+08:00`
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.year < 0 || self.year > 9999 {
+            write!(f, "{:+05}", self.year)?;
+        } else {
+            write!(f, "{:04}", self.year)?;
+        }
+        write!(f, "-{:02}-{:02}T{:02}:{:02}:{:02}", self.month, self.day, self.hour, self.minute, self.second)?;
+
+        if self.utc_offset == 0 {
+            write!(f, "Z")
+        } else {
+            let sign = if self.utc_offset < 0 { '-' } else { '+' };
+            let offset = self.utc_offset.abs();
+            write!(f, "{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unix_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(Weekday::from_days(0), Weekday::Thursday);
+    }
+
+    #[test]
+    fn century_years() {
+        assert!(is_leap_year(2000));
+        assert!(!is_leap_year(2100));
+        assert!(!is_leap_year(1900));
+        assert_eq!(DateTime::new(2100, 3, 1, 0, 0, 0).unwrap().to_unix(), 4_107_542_400);
+        assert_eq!(DateTime::from_unix(4_107_542_400 - 1, 0), DateTime::new(2100, 2, 28, 23, 59, 59).unwrap());
+        assert_eq!(DateTime::new(2100, 2, 29, 0, 0, 0), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut days = -800_000;
+        while days < 800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert!(day >= 1 && day <= days_in_month(year, month));
+            assert_eq!(days_from_civil(year, month, day), days);
+            days += 97;
+        }
+    }
+
+    #[test]
+    fn format() {
+        let date_time = DateTime::from_unix_with_offset(1_564_923_900, 0, 8 * 3600);
+        assert_eq!(format!("{}", date_time), "2019-08-04T21:05:00+08:00");
+        assert_eq!(date_time.weekday(), Weekday::Sunday);
+        assert_eq!(date_time.ordinal(), 216);
+        assert_eq!(format!("{}", DateTime::from_unix(-1, 0)), "1969-12-31T23:59:59Z");
+    }
+}
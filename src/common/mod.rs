@@ -1,2 +1,4 @@
 #[macro_use]
 pub mod int_like;
+pub mod calendar;
+pub mod tzif;
@@ -0,0 +1,512 @@
+//! # Time zone information files
+//!
+//! A parser for the TZif format of the tz database, version 1 to 4, with the POSIX TZ string of
+//! the footer for times after the last transition. See [RFC 8536](https://tools.ietf.org/html/rfc8536)
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+
+use super::calendar::{self, DateTime, SECONDS_PER_DAY, SECONDS_PER_HOUR, SECONDS_PER_MINUTE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TzifError {
+    /// The data does not start with `TZif`
+    Magic,
+    /// The data ends before the counts in the header say
+    Truncated,
+    /// A field or the footer holds a value out of range
+    Invalid,
+}
+
+/// An offset from UTC and its name, such as `CEST`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalTimeType {
+    /// Seconds east of UTC
+    pub utc_offset: i32,
+    pub is_dst: bool,
+    pub abbreviation: String,
+}
+
+impl LocalTimeType {
+    pub fn utc() -> LocalTimeType {
+        LocalTimeType {
+            utc_offset: 0,
+            is_dst: false,
+            abbreviation: String::from("UTC"),
+        }
+    }
+}
+
+/// Day of the year a POSIX rule switches on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RuleDay {
+    /// `Jn`, 1 to 365, February 29th is never counted
+    Julian1(u16),
+    /// `n`, 0 to 365, February 29th is counted in leap years
+    Julian0(u16),
+    /// `Mm.w.d`, day `d` (0 is Sunday) of week `w` (5 is the last) of month `m`
+    MonthWeekDay(u8, u8, u8),
+}
+
+impl RuleDay {
+    /// Days from 1970-01-01 to this day of `year`
+    fn days(self, year: i64) -> i64 {
+        let january_1st = calendar::days_from_civil(year, 1, 1);
+        match self {
+            RuleDay::Julian1(day) => {
+                let day = day as i64;
+                let leap = if calendar::is_leap_year(year) && day >= 60 { 1 } else { 0 };
+                january_1st + day - 1 + leap
+            }
+            RuleDay::Julian0(day) => january_1st + day as i64,
+            RuleDay::MonthWeekDay(month, week, weekday) => {
+                let first = calendar::days_from_civil(year, month, 1);
+                let first_weekday = calendar::Weekday::from_days(first).days_from_sunday() as i64;
+                let mut day = (weekday as i64 - first_weekday + 7) % 7 + (week as i64 - 1) * 7;
+                while day >= calendar::days_in_month(year, month) as i64 {
+                    day -= 7;
+                }
+                first + day
+            }
+        }
+    }
+}
+
+/// A switch between standard and daylight saving time, at a local time of day
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rule {
+    day: RuleDay,
+    /// Seconds after local midnight, may be negative or above a day
+    time: i64,
+}
+
+/// The footer of a TZif file, such as `CET-1CEST,M3.5.0,M10.5.0/3`
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PosixTz {
+    std: LocalTimeType,
+    dst: Option<(LocalTimeType, Rule, Rule)>,
+}
+
+impl PosixTz {
+    fn parse(s: &[u8]) -> Result<PosixTz, TzifError> {
+        let mut parser = Parser { s, i: 0 };
+
+        let std_name = parser.name()?;
+        let std_offset = -parser.offset()?;
+        let std = LocalTimeType {
+            utc_offset: std_offset as i32,
+            is_dst: false,
+            abbreviation: std_name,
+        };
+
+        if parser.done() {
+            return Ok(PosixTz { std, dst: None });
+        }
+
+        let dst_name = parser.name()?;
+        let dst_offset = match parser.peek() {
+            Some(b',') | None => std_offset + SECONDS_PER_HOUR,
+            _ => -parser.offset()?,
+        };
+        let dst = LocalTimeType {
+            utc_offset: dst_offset as i32,
+            is_dst: true,
+            abbreviation: dst_name,
+        };
+
+        let (start, end) = if parser.done() {
+            // The rules of the United States, as POSIX leaves the default to the implementation
+            (Rule { day: RuleDay::MonthWeekDay(3, 2, 0), time: 2 * SECONDS_PER_HOUR },
+             Rule { day: RuleDay::MonthWeekDay(11, 1, 0), time: 2 * SECONDS_PER_HOUR })
+        } else {
+            parser.expect(b',')?;
+            let start = parser.rule()?;
+            parser.expect(b',')?;
+            let end = parser.rule()?;
+            (start, end)
+        };
+
+        if !parser.done() {
+            return Err(TzifError::Invalid);
+        }
+
+        Ok(PosixTz { std, dst: Some((dst, start, end)) })
+    }
+
+    fn find(&self, time: i64) -> &LocalTimeType {
+        let (dst, start, end) = match self.dst {
+            Some((ref dst, start, end)) => (dst, start, end),
+            None => return &self.std,
+        };
+
+        let year = DateTime::from_unix_with_offset(time, 0, self.std.utc_offset).year;
+        // The start is given in standard time, the end in daylight saving time
+        let start = start.day.days(year) * SECONDS_PER_DAY + start.time - self.std.utc_offset as i64;
+        let end = end.day.days(year) * SECONDS_PER_DAY + end.time - dst.utc_offset as i64;
+
+        let in_dst = if start < end {
+            time >= start && time < end
+        } else {
+            // Southern hemisphere, daylight saving time spans the new year
+            !(time >= end && time < start)
+        };
+
+        if in_dst { dst } else { &self.std }
+    }
+}
+
+/// Cursor over a POSIX TZ string
+struct Parser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn done(&self) -> bool {
+        self.i >= self.s.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.i).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), TzifError> {
+        if self.peek() == Some(c) {
+            self.i += 1;
+            Ok(())
+        } else {
+            Err(TzifError::Invalid)
+        }
+    }
+
+    /// An abbreviation, alphabetic or quoted in angle brackets like `<+03>`
+    fn name(&mut self) -> Result<String, TzifError> {
+        let (start, end) = if self.peek() == Some(b'<') {
+            let start = self.i + 1;
+            while self.peek().map_or(false, |c| c != b'>') {
+                self.i += 1;
+            }
+            let end = self.i;
+            self.expect(b'>')?;
+            (start, end)
+        } else {
+            let start = self.i;
+            while self.peek().map_or(false, |c| c.is_ascii_alphabetic()) {
+                self.i += 1;
+            }
+            (start, self.i)
+        };
+
+        if end - start < 3 {
+            return Err(TzifError::Invalid);
+        }
+        str::from_utf8(&self.s[start..end]).map(String::from).map_err(|_| TzifError::Invalid)
+    }
+
+    fn number(&mut self) -> Result<i64, TzifError> {
+        let start = self.i;
+        let mut value = 0;
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+            value = value * 10 + (c - b'0') as i64;
+            self.i += 1;
+            if value > 1000 {
+                return Err(TzifError::Invalid);
+            }
+        }
+        if self.i == start {
+            return Err(TzifError::Invalid);
+        }
+        Ok(value)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds, west of UTC as POSIX counts offsets
+    fn offset(&mut self) -> Result<i64, TzifError> {
+        let sign = match self.peek() {
+            Some(b'-') => { self.i += 1; -1 }
+            Some(b'+') => { self.i += 1; 1 }
+            _ => 1,
+        };
+
+        let mut seconds = self.number()? * SECONDS_PER_HOUR;
+        if self.peek() == Some(b':') {
+            self.i += 1;
+            seconds += self.number()? * SECONDS_PER_MINUTE;
+            if self.peek() == Some(b':') {
+                self.i += 1;
+                seconds += self.number()?;
+            }
+        }
+        Ok(sign * seconds)
+    }
+
+    /// `date[/time]`
+    fn rule(&mut self) -> Result<Rule, TzifError> {
+        let day = match self.peek() {
+            Some(b'J') => {
+                self.i += 1;
+                let day = self.number()?;
+                if day < 1 || day > 365 {
+                    return Err(TzifError::Invalid);
+                }
+                RuleDay::Julian1(day as u16)
+            }
+            Some(b'M') => {
+                self.i += 1;
+                let month = self.number()?;
+                self.expect(b'.')?;
+                let week = self.number()?;
+                self.expect(b'.')?;
+                let weekday = self.number()?;
+                if month < 1 || month > 12 || week < 1 || week > 5 || weekday > 6 {
+                    return Err(TzifError::Invalid);
+                }
+                RuleDay::MonthWeekDay(month as u8, week as u8, weekday as u8)
+            }
+            _ => {
+                let day = self.number()?;
+                if day > 365 {
+                    return Err(TzifError::Invalid);
+                }
+                RuleDay::Julian0(day as u16)
+            }
+        };
+
+        let time = if self.peek() == Some(b'/') {
+            self.i += 1;
+            self.offset()?
+        } else {
+            2 * SECONDS_PER_HOUR
+        };
+
+        Ok(Rule { day, time })
+    }
+}
+
+/// Counts of the header, in file order
+struct Header {
+    version: u8,
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
+
+const HEADER_SIZE: usize = 44;
+
+impl Header {
+    fn parse(data: &[u8]) -> Result<Header, TzifError> {
+        if data.len() < HEADER_SIZE {
+            return Err(TzifError::Truncated);
+        }
+        if &data[..4] != b"TZif" {
+            return Err(TzifError::Magic);
+        }
+
+        let count = |i: usize| read_be(&data[20 + i * 4..24 + i * 4]) as usize;
+        let header = Header {
+            version: data[4],
+            isutcnt: count(0),
+            isstdcnt: count(1),
+            leapcnt: count(2),
+            timecnt: count(3),
+            typecnt: count(4),
+            charcnt: count(5),
+        };
+
+        if header.typecnt == 0 || (header.isutcnt != 0 && header.isutcnt != header.typecnt)
+            || (header.isstdcnt != 0 && header.isstdcnt != header.typecnt) {
+            return Err(TzifError::Invalid);
+        }
+        Ok(header)
+    }
+
+    /// Size of the data block following the header, with `time_size` byte times
+    fn data_size(&self, time_size: usize) -> usize {
+        self.timecnt * time_size + self.timecnt + self.typecnt * 6 + self.charcnt
+            + self.leapcnt * (time_size + 4) + self.isstdcnt + self.isutcnt
+    }
+}
+
+/// Big endian integer of up to 8 bytes, sign extended
+fn read_be(bytes: &[u8]) -> i64 {
+    let mut value = if bytes[0] & 0x80 == 0x80 { -1 } else { 0 };
+    for &byte in bytes {
+        value = value << 8 | byte as i64;
+    }
+    value
+}
+
+/// The rules of a time zone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Transition times in seconds since the Unix epoch, with the index of the type they switch to
+    transitions: Vec<(i64, usize)>,
+    types: Vec<LocalTimeType>,
+    rule: Option<PosixTz>,
+}
+
+impl TimeZone {
+    pub fn utc() -> TimeZone {
+        TimeZone {
+            transitions: Vec::new(),
+            types: vec![LocalTimeType::utc()],
+            rule: None,
+        }
+    }
+
+    /// Parse the contents of a TZif file
+    pub fn parse(data: &[u8]) -> Result<TimeZone, TzifError> {
+        let mut header = Header::parse(data)?;
+        let mut block = &data[HEADER_SIZE..];
+        let mut time_size = 4;
+
+        // Version 2 and up repeat the data with 64-bit times after the version 1 block
+        if header.version >= b'2' {
+            let v1_size = header.data_size(4);
+            if block.len() < v1_size {
+                return Err(TzifError::Truncated);
+            }
+            header = Header::parse(&block[v1_size..])?;
+            block = &block[v1_size + HEADER_SIZE..];
+            time_size = 8;
+        }
+
+        let data_size = header.data_size(time_size);
+        if block.len() < data_size {
+            return Err(TzifError::Truncated);
+        }
+        let (block, footer) = block.split_at(data_size);
+
+        let (times, block) = block.split_at(header.timecnt * time_size);
+        let (indices, block) = block.split_at(header.timecnt);
+        let (infos, block) = block.split_at(header.typecnt * 6);
+        let chars = &block[..header.charcnt];
+
+        let mut transitions = Vec::with_capacity(header.timecnt);
+        for (time, &index) in times.chunks(time_size).zip(indices) {
+            if index as usize >= header.typecnt {
+                return Err(TzifError::Invalid);
+            }
+            transitions.push((read_be(time), index as usize));
+        }
+
+        let mut types = Vec::with_capacity(header.typecnt);
+        for info in infos.chunks(6) {
+            let utc_offset = read_be(&info[..4]);
+            let abbreviation_index = info[5] as usize;
+            if utc_offset <= -SECONDS_PER_DAY || utc_offset >= SECONDS_PER_DAY || abbreviation_index >= chars.len().max(1) {
+                return Err(TzifError::Invalid);
+            }
+
+            let abbreviation = chars.get(abbreviation_index..).unwrap_or(&[]);
+            let end = abbreviation.iter().position(|&c| c == 0).unwrap_or(abbreviation.len());
+            types.push(LocalTimeType {
+                utc_offset: utc_offset as i32,
+                is_dst: info[4] != 0,
+                abbreviation: String::from(str::from_utf8(&abbreviation[..end]).map_err(|_| TzifError::Invalid)?),
+            });
+        }
+
+        // The footer is a POSIX TZ string between newlines, possibly empty
+        let mut rule = None;
+        if time_size == 8 && footer.len() >= 2 && footer[0] == b'\n' {
+            let end = footer[1..].iter().position(|&c| c == b'\n').ok_or(TzifError::Invalid)? + 1;
+            if end > 1 {
+                rule = Some(PosixTz::parse(&footer[1..end])?);
+            }
+        }
+
+        Ok(TimeZone { transitions, types, rule })
+    }
+
+    /// The local time type in effect at `time`, in seconds since the Unix epoch
+    pub fn find(&self, time: i64) -> &LocalTimeType {
+        match self.transitions.iter().rposition(|&(transition, _)| transition <= time) {
+            Some(i) if i + 1 < self.transitions.len() || self.rule.is_none() => {
+                &self.types[self.transitions[i].1]
+            }
+            Some(_) => self.rule.as_ref().map(|rule| rule.find(time)).unwrap(),
+            None if self.transitions.is_empty() && self.rule.is_some() => {
+                self.rule.as_ref().map(|rule| rule.find(time)).unwrap()
+            }
+            // Before the first transition, the first type applies
+            None => &self.types[0],
+        }
+    }
+
+    /// The local date and time at `time`, in seconds since the Unix epoch
+    pub fn local(&self, time: i64, nanosecond: u32) -> DateTime {
+        DateTime::from_unix_with_offset(time, nanosecond, self.find(time).utc_offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A version 2 file with one transition and a footer, as `zic -b slim` writes them
+    fn slim_file(footer: &str) -> Vec<u8> {
+        fn header(data: &mut Vec<u8>, timecnt: u32, typecnt: u32, charcnt: u32) {
+            data.extend_from_slice(b"TZif2");
+            data.extend_from_slice(&[0; 15]);
+            for &count in &[0, 0, 0, timecnt, typecnt, charcnt] {
+                data.extend_from_slice(&(count as u32).to_be_bytes());
+            }
+        }
+
+        let mut data = Vec::new();
+        header(&mut data, 0, 1, 1);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+
+        header(&mut data, 1, 2, 8);
+        data.extend_from_slice(&(-1_000_000_000i64).to_be_bytes());
+        data.push(1);
+        data.extend_from_slice(&(-17_762i32).to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&(3600i32).to_be_bytes());
+        data.extend_from_slice(&[0, 4]);
+        data.extend_from_slice(b"LMT\0CET\0");
+        data.push(b'\n');
+        data.extend_from_slice(footer.as_bytes());
+        data.push(b'\n');
+        data
+    }
+
+    #[test]
+    fn posix_rule() {
+        let zone = TimeZone::parse(&slim_file("CET-1CEST,M3.5.0,M10.5.0/3")).unwrap();
+
+        assert_eq!(zone.find(-2_000_000_000).abbreviation, "LMT");
+        // 2019-03-31T00:59:59Z and 01:00:00Z
+        assert_eq!(zone.find(1_553_993_999).abbreviation, "CET");
+        assert_eq!(zone.find(1_553_994_000).abbreviation, "CEST");
+        // 2019-10-27T00:59:59Z and 01:00:00Z
+        assert_eq!(zone.find(1_572_137_999).utc_offset, 7200);
+        assert_eq!(zone.find(1_572_138_000).utc_offset, 3600);
+
+        assert_eq!(format!("{}", zone.local(1_564_923_900, 0)), "2019-08-04T15:05:00+02:00");
+    }
+
+    #[test]
+    fn southern_hemisphere() {
+        let zone = TimeZone::parse(&slim_file("<+10>-10<+11>,M10.1.0,M4.1.0/3")).unwrap();
+
+        assert_eq!(zone.find(1_564_923_900).abbreviation, "+10");
+        assert!(!zone.find(1_564_923_900).is_dst);
+        assert!(zone.find(1_546_300_800).is_dst);
+        assert_eq!(zone.find(1_546_300_800).utc_offset, 11 * 3600);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(TimeZone::parse(b"TZjf"), Err(TzifError::Truncated));
+        assert_eq!(TimeZone::parse(&[b'X'; 64]), Err(TzifError::Magic));
+        assert_eq!(TimeZone::parse(&slim_file("CET-1CEST,M13.5.0,M10.5.0")), Err(TzifError::Invalid));
+
+        let mut data = slim_file("UTC0");
+        data.truncate(90);
+        assert_eq!(TimeZone::parse(&data), Err(TzifError::Truncated));
+    }
+}
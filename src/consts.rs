@@ -1,5 +1,6 @@
 // Because the memory map is so important to not be aliased, it is defined here, in one place
-// The lower 256 PML4 entries are reserved for userspace
+// The lower 256 PML4 entries are reserved for userspace, but for those the bootloader uses for the
+// kernel image, its stack and the physical memory window, which are kept out of the user range
 // Each PML4 entry references up to 512 GB of memory
 // The top (511) PML4 is reserved for recursive mapping
 // The second from the top (510) PML4 is reserved for the kernel
@@ -18,21 +19,21 @@ pub const KERNEL_PML4: usize = (KERNEL_OFFSET & PML4_MASK) / PML4_SIZE;
 /// Offset to kernel heap
 pub const KERNEL_HEAP_OFFSET: usize = KERNEL_OFFSET - PML4_SIZE;
 pub const KERNEL_HEAP_PML4: usize = (KERNEL_HEAP_OFFSET & PML4_MASK) / PML4_SIZE;
-/// Size of kernel heap
+/// Initial size of kernel heap, and the least it grows by
 pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
 
-/// Offset to kernel percpu variables
-//TODO: Use 64-bit fs offset to enable this pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
-pub const KERNEL_PERCPU_OFFSET: usize = 0xC000_0000;
+/// Offset to kernel percpu variables, addressed through GS
+pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
+pub const KERNEL_PERCPU_PML4: usize = (KERNEL_PERCPU_OFFSET & PML4_MASK) / PML4_SIZE;
 /// Size of kernel percpu variables
 pub const KERNEL_PERCPU_SIZE: usize = 64 * 1024; // 64 KB
 
-/// Offset to user image
-pub const USER_OFFSET: usize = 0;
+/// Offset to user image, past the first PML4 entries where the bootloader puts the kernel image
+pub const USER_OFFSET: usize = 64 * PML4_SIZE;
 pub const USER_PML4: usize = (USER_OFFSET & PML4_MASK) / PML4_SIZE;
 
 /// Offset to user TCB
-pub const USER_TCB_OFFSET: usize = 0xB000_0000;
+pub const USER_TCB_OFFSET: usize = USER_OFFSET + 0xB000_0000;
 
 /// Offset to user arguments
 pub const USER_ARG_OFFSET: usize = USER_OFFSET + PML4_SIZE / 2;
@@ -9,11 +9,14 @@ use spin::Mutex;
 
 use crate::memory::PAGE_SIZE;
 use crate::context::arch;
-use crate::context::memory::{Memory, SharedMemory, Tls};
+use crate::context::file::FileDescriptor;
+use crate::context::memory::{Grant, Memory, SharedMemory, Tls};
 use crate::sync::WaitMap;
 use crate::syscall::data::SigAction;
-use crate::syscall::flag::SIG_DFL;
+use crate::syscall::flag::{CLONE_FILES, SIG_DFL};
 use crate::int_like;
+use crate::ipi::{ipi_single, IpiKind};
+use crate::scheme::FileHandle;
 /// Unique identifier for a context (i.e. `pid`).
 use core::sync::atomic::AtomicUsize;
 int_like!(ContextId, AtomicContextId, usize, AtomicUsize);
@@ -87,6 +90,51 @@ impl PartialEq for WaitpidKey {
 
 impl Eq for WaitpidKey {}
 
+/// Make `path` absolute against the working directory `cwd`. Paths without a scheme are relative
+/// to `cwd`, or with a leading slash to the root of its scheme. The reference after the scheme
+/// is normalized: `.` and empty components are dropped and `..` removes the one before, so it
+/// never starts or ends with a slash.
+pub fn canonicalize(cwd: &[u8], path: &[u8]) -> Vec<u8> {
+    let joined = if path.contains(&b':') {
+        path.to_vec()
+    } else if path.starts_with(b"/") {
+        let scheme_end = cwd.iter().position(|&b| b == b':').map_or(0, |i| i + 1);
+        let mut joined = cwd[..scheme_end].to_vec();
+        joined.extend_from_slice(path);
+        joined
+    } else {
+        let mut joined = cwd.to_vec();
+        if !joined.is_empty() && !joined.ends_with(b"/") && !joined.ends_with(b":") {
+            joined.push(b'/');
+        }
+        joined.extend_from_slice(path);
+        joined
+    };
+
+    let scheme_end = joined.iter().position(|&b| b == b':').map_or(0, |i| i + 1);
+    let (scheme, reference) = joined.split_at(scheme_end);
+
+    let mut parts: Vec<&[u8]> = Vec::new();
+    for part in reference.split(|&b| b == b'/') {
+        match part {
+            b"" | b"." => (),
+            b".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+
+    let mut canon = scheme.to_vec();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            canon.push(b'/');
+        }
+        canon.extend_from_slice(part);
+    }
+    canon
+}
+
 /// A context, which identifies either a process or a thread
 #[derive(Debug)]
 pub struct Context {
@@ -96,6 +144,14 @@ pub struct Context {
     pub pgid: ContextId,
     /// The ID of the parent context
     pub ppid: ContextId,
+    /// The real user id
+    pub ruid: u32,
+    /// The real group id
+    pub rgid: u32,
+    /// The effective user id
+    pub euid: u32,
+    /// The effective group id
+    pub egid: u32,
     /// Process umask
     pub umask: usize,
     /// Status of context
@@ -138,14 +194,14 @@ pub struct Context {
     pub sigstack: Option<Memory>,
     /// User Thread local storage
     pub tls: Option<Tls>,
-    /// User grants
-//    pub grants: Arc<Mutex<Vec<Grant>>>,
+    /// User grants, sorted by address
+    pub grants: Arc<Mutex<Vec<Grant>>>,
     /// The name of the context
     pub name: Arc<Mutex<Box<[u8]>>>,
     /// The current working directory
     pub cwd: Arc<Mutex<Vec<u8>>>,
     /// The open files in the scheme
-//    pub files: Arc<Mutex<Vec<Option<FileDescriptor>>>>,
+    pub files: Arc<Mutex<Vec<Option<FileDescriptor>>>>,
     /// Singal actions
     pub actions: Arc<Mutex<Vec<(SigAction, usize)>>>,
 }
@@ -159,6 +215,10 @@ impl Context {
             id,
             pgid: id,
             ppid: ContextId::from(0),
+            ruid: 0,
+            rgid: 0,
+            euid: 0,
+            egid: 0,
             umask: 0o022,
             status: Status::Blocked,
             running: false,
@@ -180,10 +240,10 @@ impl Context {
             stack: None,
             sigstack: None,
             tls: None,
-//            grants: Arc::new(Mutex::new(Vec::new())),
+            grants: Arc::new(Mutex::new(Vec::new())),
             name: Arc::new(Mutex::new(Vec::new().into_boxed_slice())),
             cwd: Arc::new(Mutex::new(Vec::new())),
-//            files: Arc::new(Mutex::new(Vec::new())),
+            files: Arc::new(Mutex::new(Vec::new())),
             actions: Arc::new(Mutex::new(
                 vec![(
                          SigAction {
@@ -196,6 +256,105 @@ impl Context {
         }
     }
 
+    /// Make a path absolute, see `canonicalize`
+    pub fn canonicalize(&self, path: &[u8]) -> Vec<u8> {
+        canonicalize(&self.cwd.lock(), path)
+    }
+
+    /// Add a file to the lowest available slot.
+    /// Return the file descriptor number or None if no slot was found
+    pub fn add_file(&self, file: FileDescriptor) -> Option<FileHandle> {
+        self.add_file_min(file, 0)
+    }
+
+    /// Add a file to the lowest available slot greater than or equal to min.
+    /// Return the file descriptor number or None if no slot was found
+    pub fn add_file_min(&self, file: FileDescriptor, min: usize) -> Option<FileHandle> {
+        let mut files = self.files.lock();
+        for (i, file_option) in files.iter_mut().enumerate().skip(min) {
+            if file_option.is_none() {
+                *file_option = Some(file);
+                return Some(FileHandle::from(i));
+            }
+        }
+        let len = files.len().max(min);
+        if len < super::CONTEXT_MAX_FILES {
+            while files.len() < len {
+                files.push(None);
+            }
+            files.push(Some(file));
+            Some(FileHandle::from(len))
+        } else {
+            None
+        }
+    }
+
+    /// The file table for a context cloned from this one: the same table with `CLONE_FILES`,
+    /// otherwise a copy whose descriptors share their descriptions with these
+    pub fn clone_files(&self, flags: usize) -> Arc<Mutex<Vec<Option<FileDescriptor>>>> {
+        if flags & CLONE_FILES == CLONE_FILES {
+            Arc::clone(&self.files)
+        } else {
+            Arc::new(Mutex::new(self.files.lock().clone()))
+        }
+    }
+
+    /// Close the descriptors marked close-on-exec, as exec does
+    pub fn close_cloexec_files(&self) {
+        let files: Vec<FileDescriptor> = {
+            let mut files = self.files.lock();
+            files.iter_mut()
+                .filter(|file_option| file_option.as_ref().map_or(false, |file| file.cloexec))
+                .filter_map(|file_option| file_option.take())
+                .collect()
+        };
+
+        // The files lock is not held while schemes close the files
+        for file in files {
+            let _ = file.close();
+        }
+    }
+
+    /// Get a file
+    pub fn get_file(&self, i: FileHandle) -> Option<FileDescriptor> {
+        let files = self.files.lock();
+        if i.into() < files.len() {
+            files[i.into()].clone()
+        } else {
+            None
+        }
+    }
+
+    /// Insert a file with a specific handle number. This is used by dup2
+    /// Return the file descriptor number or None if the slot was not empty, or i was invalid
+    pub fn insert_file(&self, i: FileHandle, file: FileDescriptor) -> Option<FileHandle> {
+        let mut files = self.files.lock();
+        if i.into() < super::CONTEXT_MAX_FILES {
+            while i.into() >= files.len() {
+                files.push(None);
+            }
+            if files[i.into()].is_none() {
+                files[i.into()] = Some(file);
+                Some(i)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Remove a file
+    // TODO: adjust files vector to smaller size if possible
+    pub fn remove_file(&self, i: FileHandle) -> Option<FileDescriptor> {
+        let mut files = self.files.lock();
+        if i.into() < files.len() {
+            files[i.into()].take()
+        } else {
+            None
+        }
+    }
+
     /// Block the context, and return true if it was runnable before being blocked
     pub fn block(&mut self) -> bool {
         if self.status == Status::Runnable {
@@ -206,17 +365,27 @@ impl Context {
         }
     }
 
+    /// The RFLAGS restored when the context returns to user mode. Entries from user mode push
+    /// their interrupt frame at the top of the kernel stack, where RFLAGS is the third word from
+    /// the end.
+    pub fn user_rflags(&mut self) -> Option<&mut usize> {
+        self.kstack.as_mut().map(|kstack| {
+            let offset = kstack.len() - 3 * mem::size_of::<usize>();
+            unsafe { &mut *(kstack.as_mut_ptr().add(offset) as *mut usize) }
+        })
+    }
+
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.status == Status::Blocked {
             self.status = Status::Runnable;
 
-//            if let Some(cpu_id) = self.cpu_id {
-//                if cpu_id != ::cpu_id() {
-//                    // Send IPI if not on current CPU
-//                    ipi(IpiKind::Wakeup, IpiTarget::Other);
-//                }
-//            }
+            if let Some(cpu_id) = self.cpu_id {
+                if cpu_id != crate::cpu_id() {
+                    // Send IPI if not on current CPU
+                    ipi_single(IpiKind::Switch, cpu_id);
+                }
+            }
 
             true
         } else {
@@ -224,3 +393,59 @@ impl Context {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use spin::RwLock;
+
+    use super::{canonicalize, Context, ContextId};
+    use crate::context::file::{FileDescription, FileDescriptor};
+    use crate::scheme::SchemeId;
+    use crate::syscall::flag::CLONE_FILES;
+
+    fn file(number: usize, cloexec: bool) -> FileDescriptor {
+        FileDescriptor {
+            description: Arc::new(RwLock::new(FileDescription { scheme: SchemeId::from(0), number, flags: 0 })),
+            cloexec,
+        }
+    }
+
+    #[test]
+    fn cloned_files() {
+        let context = Context::new(ContextId::from(1));
+        let fd = context.add_file(file(7, false)).unwrap();
+
+        // A shared table sees the descriptor go, a copy keeps it and its description
+        let shared = context.clone_files(CLONE_FILES);
+        let copied = context.clone_files(0);
+        let removed = context.remove_file(fd).unwrap();
+        assert!(shared.lock()[fd.into()].is_none());
+        let copy = copied.lock()[fd.into()].clone().unwrap();
+        assert!(Arc::ptr_eq(&copy.description, &removed.description));
+    }
+
+    #[test]
+    fn cloexec_files_closed() {
+        let context = Context::new(ContextId::from(1));
+        let (kept, closed) = (file(1, false), file(2, true));
+        // Holding the descriptions keeps the descriptors from closing the files in their scheme
+        let closed_description = Arc::clone(&closed.description);
+        let kept_fd = context.add_file(kept).unwrap();
+        let closed_fd = context.add_file(closed).unwrap();
+
+        context.close_cloexec_files();
+        assert!(context.get_file(kept_fd).is_some());
+        assert!(context.get_file(closed_fd).is_none());
+        assert_eq!(Arc::strong_count(&closed_description), 1);
+    }
+
+    #[test]
+    fn canonical_paths() {
+        assert_eq!(canonicalize(b"file:/home/user", b"notes"), b"file:home/user/notes".to_vec());
+        assert_eq!(canonicalize(b"file:/home/user", b"/etc/./passwd"), b"file:etc/passwd".to_vec());
+        assert_eq!(canonicalize(b"file:/home/user", b"../../../tmp/"), b"file:tmp".to_vec());
+        assert_eq!(canonicalize(b"file:", b"a//b"), b"file:a/b".to_vec());
+        assert_eq!(canonicalize(b"file:/home", b"debug:"), b"debug:".to_vec());
+    }
+}
@@ -0,0 +1,46 @@
+//! File structs
+
+use alloc::sync::Arc;
+use spin::RwLock;
+
+use crate::scheme::{self, SchemeId};
+use crate::syscall::error::{Result, Error, EBADF};
+
+/// A file description
+#[derive(Debug)]
+pub struct FileDescription {
+    /// The scheme that this file refers to
+    pub scheme: SchemeId,
+    /// The number the scheme uses to refer to this file
+    pub number: usize,
+    /// The flags passed to open or fcntl(SETFL)
+    pub flags: usize,
+}
+
+/// A file descriptor
+#[derive(Clone, Debug)]
+pub struct FileDescriptor {
+    /// Corresponding file description, shared by the descriptors copied from this one
+    pub description: Arc<RwLock<FileDescription>>,
+    /// Whether exec closes this descriptor
+    pub cloexec: bool,
+}
+
+impl FileDescriptor {
+    /// Close the descriptor, and the file in its scheme if this was the last descriptor of it
+    pub fn close(self) -> Result<usize> {
+        if let Ok(file) = Arc::try_unwrap(self.description) {
+            let file = file.into_inner();
+
+            let scheme = {
+                let schemes = scheme::schemes();
+                let scheme = schemes.get(file.scheme).ok_or(Error::new(EBADF))?;
+                scheme.clone()
+            };
+
+            scheme.close(file.number)
+        } else {
+            Ok(0)
+        }
+    }
+}
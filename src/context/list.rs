@@ -3,7 +3,6 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem;
-use core::sync::atomic::Ordering;
 use spin::RwLock;
 
 use crate::memory::ActivePageTable;
@@ -37,7 +36,7 @@ impl ContextList {
 
     /// Get the current context.
     pub fn current(&self) -> Option<&Arc<RwLock<Context>>> {
-        self.map.get(&super::CONTEXT_ID.load(Ordering::SeqCst))
+        self.map.get(&super::context_id())
     }
 
     pub fn iter(&self) -> alloc::collections::btree_map::Iter<ContextId, Arc<RwLock<Context>>> {
@@ -1,19 +1,170 @@
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use spin::Mutex;
 use x86_64::{
+    PhysAddr,
     VirtAddr,
     structures::{
         paging::{
             page::PageRangeInclusive,
-            Page,
             Mapper,
+            Page,
+            PageSize,
             PageTableFlags as EntryFlags,
+            PhysFrame,
+            Size1GiB,
+            Size2MiB,
+            Size4KiB,
         }
     },
 };
 use core::intrinsics;
 
-use crate::memory::{ActivePageTable, InactivePageTable, mapper::MapperFlushAll, FRAME_ALLOCATOR};
+use crate::ipi;
+
+use crate::memory::{self, ActivePageTable, InactivePageTable, mapper::MapperFlushAll, table::map_to_sized, PAGE_SIZE};
+
+/// Memory of a context mapped to frames it does not own, such as device memory. Unmapping it
+/// leaves the frames alone.
+#[derive(Debug)]
+pub struct Grant {
+    start: VirtAddr,
+    size: usize,
+    flags: EntryFlags,
+    /// Whether the grant is in the active table, which dropping it unmaps it from. Grants in
+    /// the table of another context are unmapped with `unmap_inactive`.
+    active: bool,
+}
+
+impl Grant {
+    /// Map `size` bytes of physical memory at `from` to `to`, both page aligned
+    pub fn physmap(from: PhysAddr, to: VirtAddr, size: usize, flags: EntryFlags) -> Grant {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let mut flush_all = MapperFlushAll::new();
+
+        for i in 0..size / PAGE_SIZE {
+            let offset = (i * PAGE_SIZE) as u64;
+            let page = Page::<Size4KiB>::containing_address(to + offset);
+            let frame = PhysFrame::containing_address(from + offset);
+            let result = unsafe {
+                active_table.map_to(page, frame, flags, memory::FRAME_ALLOCATOR.lock().as_mut().unwrap())
+                    .expect("Grant::physmap: page already mapped")
+            };
+            flush_all.consume(result);
+        }
+
+        flush_all.flush(&mut active_table);
+
+        Grant {
+            start: to,
+            size,
+            flags,
+            active: true,
+        }
+    }
+
+    /// Map each of `frames` to consecutive pages from `to`, for memory that is not physically
+    /// contiguous, such as the kernel image
+    pub fn map_frames(frames: &[PhysFrame], to: VirtAddr, flags: EntryFlags) -> Grant {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let mut flush_all = MapperFlushAll::new();
+
+        for (i, &frame) in frames.iter().enumerate() {
+            let page = Page::<Size4KiB>::containing_address(to + (i * PAGE_SIZE) as u64);
+            let result = unsafe {
+                active_table.map_to(page, frame, flags, memory::FRAME_ALLOCATOR.lock().as_mut().unwrap())
+                    .expect("Grant::map_frames: page already mapped")
+            };
+            flush_all.consume(result);
+        }
+
+        flush_all.flush(&mut active_table);
+
+        Grant {
+            start: to,
+            size: frames.len() * PAGE_SIZE,
+            flags,
+            active: true,
+        }
+    }
+
+    /// Map `size` bytes of physical memory at `from` to `to` in the table of another context
+    pub fn map_inactive(from: PhysAddr, to: VirtAddr, size: usize, flags: EntryFlags, new_table: &mut InactivePageTable) -> Grant {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        active_table.with(new_table, |mapper| {
+            for i in 0..size / PAGE_SIZE {
+                let offset = (i * PAGE_SIZE) as u64;
+                let frame = PhysFrame::containing_address(from + offset);
+                unsafe { map_to_sized(mapper, to + offset, frame, Size4KiB::SIZE, flags); }
+            }
+        });
+
+        Grant {
+            start: to,
+            size,
+            flags,
+            active: false,
+        }
+    }
+
+    /// Unmap a grant made with `map_inactive` from the table it was mapped in
+    pub fn unmap_inactive(self, new_table: &mut InactivePageTable) {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        active_table.with(new_table, |mapper| {
+            for i in 0..self.size / PAGE_SIZE {
+                let page = Page::<Size4KiB>::containing_address(self.start + (i * PAGE_SIZE) as u64);
+                let (_frame, result) = mapper.unmap(page).expect("Grant::unmap_inactive: page not mapped");
+                // The TLBs of the CPUs running the table are flushed below
+                result.ignore();
+            }
+        });
+
+        let table = unsafe { new_table.address() };
+        if table == unsafe { ActivePageTable::address().as_u64() } {
+            active_table.flush_all();
+        } else {
+            ipi::tlb_shootdown(table as usize);
+        }
+    }
+
+    pub fn start_address(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    pub fn end_address(&self) -> VirtAddr {
+        VirtAddr::new(self.start.as_u64() + self.size as u64)
+    }
+}
+
+impl Drop for Grant {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let mut flush_all = MapperFlushAll::new();
+
+        for (addr, size) in active_table.pages_in(self.start, self.end_address(), Size4KiB::SIZE) {
+            active_table.unmap_sized(addr, size, &mut flush_all);
+        }
+
+        flush_all.flush(&mut active_table);
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum SharedMemory {
@@ -49,14 +200,23 @@ pub struct Memory {
     start: VirtAddr,
     size: usize,
     flags: EntryFlags,
+    /// Whether aligned parts may be mapped with 2 MiB and 1 GiB pages
+    huge: bool,
 }
 
 impl Memory {
     pub fn new(start: VirtAddr, size: usize, flags: EntryFlags, clear: bool) -> Self {
+        Memory::with_huge_pages(start, size, flags, clear, false)
+    }
+
+    /// Like `new`, but with `huge` the memory is mapped with the largest pages that fit. They are
+    /// split into smaller ones when only a part of them is remapped or unmapped.
+    pub fn with_huge_pages(start: VirtAddr, size: usize, flags: EntryFlags, clear: bool, huge: bool) -> Self {
         let mut memory = Memory {
             start,
             size,
             flags,
+            huge,
         };
 
         memory.map(clear);
@@ -80,6 +240,14 @@ impl Memory {
         self.flags
     }
 
+    pub fn is_huge(&self) -> bool {
+        self.huge
+    }
+
+    pub fn end_address(&self) -> VirtAddr {
+        VirtAddr::new(self.start.as_u64() + self.size as u64)
+    }
+
     pub fn pages(&self) -> PageRangeInclusive {
         let start_page = Page::containing_address(self.start);
         let end_page = Page::containing_address(VirtAddr::new(self.start.as_u64() + self.size as u64 - 1));
@@ -91,10 +259,10 @@ impl Memory {
 
         let mut flush_all = MapperFlushAll::new();
 
-        for page in self.pages() {
-            let result = active_table.map(page, self.flags);
-            flush_all.consume(result);
-        }
+        active_table.map_range(self.start, self.size, self.flags, self.huge, &mut flush_all);
+
+        flush_all.flush(&mut active_table);
+
         if clear {
             assert!(self.flags.contains(EntryFlags::WRITABLE));
             unsafe {
@@ -108,11 +276,17 @@ impl Memory {
 
         let mut flush_all = MapperFlushAll::new();
 
-        for page in self.pages() {
-            let result = active_table.unmap(page);
-            flush_all.consume(result.unwrap().1);
+        let mut frames = Vec::new();
+        for (addr, size) in active_table.pages_in(self.start, self.end_address(), Size1GiB::SIZE) {
+            let frame = active_table.unmap_sized(addr, size, &mut flush_all);
+            frames.push((frame, size));
         }
         flush_all.flush(&mut active_table);
+
+        // Only reuse the frames once no TLB can reach them anymore
+        for (frame, size) in frames {
+            memory::deallocate_frames(frame, size as usize / PAGE_SIZE);
+        }
     }
 
     /// A complicated operation to move a piece of memory to a new page table
@@ -122,20 +296,23 @@ impl Memory {
 
         let mut flush_all = MapperFlushAll::new();
 
-        for page in self.pages() {
-            let (frame, result) = active_table.unmap(page).unwrap();
-            flush_all.consume(result);
+        // Huge pages only stay whole if the move keeps them aligned
+        let delta = new_start.as_u64().wrapping_sub(self.start.as_u64());
+        let max_size = [Size1GiB::SIZE, Size2MiB::SIZE].iter().cloned()
+            .find(|size| delta & (size - 1) == 0)
+            .unwrap_or(Size4KiB::SIZE);
+
+        for (addr, size) in active_table.pages_in(self.start, self.end_address(), max_size) {
+            let frame = active_table.unmap_sized(addr, size, &mut flush_all);
 
             active_table.with(new_table, |mapper| {
-                let new_page = Page::containing_address(VirtAddr::new(page.start_address().as_u64() - self.start.as_u64() + new_start.as_u64()));
-                let result = unsafe {
-                    mapper.map_to(new_page, frame, self.flags, FRAME_ALLOCATOR.lock().as_mut().unwrap())
-                };
-                // This is not the active table, so the flush can be ignored
-                result.unwrap().ignore();
+                let new_addr = VirtAddr::new(addr.as_u64().wrapping_add(delta));
+                unsafe { map_to_sized(mapper, new_addr, frame, size, self.flags); }
             });
         }
 
+        flush_all.flush(&mut active_table);
+
         self.start = new_start;
     }
 
@@ -144,9 +321,8 @@ impl Memory {
 
         let mut flush_all = MapperFlushAll::new();
 
-        for page in self.pages() {
-            let result = active_table.update_flags(page, new_flags);
-            flush_all.consume(result.unwrap());
+        for (addr, size) in active_table.pages_in(self.start, self.end_address(), Size1GiB::SIZE) {
+            active_table.update_flags_sized(addr, size, new_flags, &mut flush_all);
         }
 
         flush_all.flush(&mut active_table);
@@ -161,14 +337,7 @@ impl Memory {
         if new_size > self.size {
             let mut flush_all = MapperFlushAll::new();
 
-            let start_page = Page::containing_address(VirtAddr::new(self.start.as_u64() + self.size as u64));
-            let end_page = Page::containing_address(VirtAddr::new(self.start.as_u64() + new_size as u64 - 1));
-            for page in Page::range_inclusive(start_page, end_page) {
-                if active_table.translate_page(page).is_err() {
-                    let result = active_table.map(page, self.flags);
-                    flush_all.consume(result);
-                }
-            }
+            active_table.map_range(self.end_address(), new_size - self.size, self.flags, self.huge, &mut flush_all);
 
             flush_all.flush(&mut active_table);
 
@@ -178,19 +347,22 @@ impl Memory {
                 }
             }
         } else if new_size < self.size {
-            let start_page = Page::containing_address(VirtAddr::new(self.start.as_u64() + (new_size as u64)));
-            let end_page = Page::containing_address(VirtAddr::new(self.start.as_u64() + ((self.size - 1) as u64)));
+            // Pages are kept up to the page containing the new end, like `pages` does
+            let new_end = memory::align_up(self.start.as_u64() + new_size as u64, Size4KiB::SIZE);
 
             let mut flush_all = MapperFlushAll::new();
 
-            for page in Page::range_inclusive(start_page, end_page) {
-                if active_table.translate_page(page).is_ok() {
-                    let result = active_table.unmap(page).unwrap();
-                    flush_all.consume(result.1);
-                }
+            let mut frames = Vec::new();
+            for (addr, size) in active_table.pages_in(VirtAddr::new(new_end), self.end_address(), Size1GiB::SIZE) {
+                let frame = active_table.unmap_sized(addr, size, &mut flush_all);
+                frames.push((frame, size));
             }
 
             flush_all.flush(&mut active_table);
+
+            for (frame, size) in frames {
+                memory::deallocate_frames(frame, size as usize / PAGE_SIZE);
+            }
         }
 
         self.size = new_size;
@@ -6,15 +6,20 @@ use core::alloc::{GlobalAlloc, Layout};
 use core::sync::atomic::Ordering;
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-pub use self::context::{Context, ContextId, Status, WaitpidKey};
+use crate::memory::ActivePageTable;
+use crate::percpu;
+
+pub use self::context::{AtomicContextId, Context, ContextId, Status, WaitpidKey};
 pub use self::list::ContextList;
 pub use self::switch::switch;
 
 mod context;
 mod list;
 mod switch;
+pub mod file;
 pub mod memory;
 pub mod signal;
+pub mod timeout;
 #[path = "arch/x86_64.rs"]
 mod arch;
 
@@ -27,9 +32,6 @@ pub const CONTEXT_MAX_FILES: usize = 65_536;
 /// Contexts list
 static CONTEXTS: Once<RwLock<ContextList>> = Once::new();
 
-
-static CONTEXT_ID: context::AtomicContextId = context::AtomicContextId::default();
-
 pub fn init() {
     let mut contexts = contexts_mut();
     let context_lock = contexts.new_context().expect("could not initialize first context");
@@ -44,7 +46,12 @@ pub fn init() {
     context.status = Status::Runnable;
     context.running = true;
     context.cpu_id = Some(crate::cpu_id());
-    CONTEXT_ID.store(context.id, Ordering::SeqCst);
+    context.arch.set_page_table(unsafe { ActivePageTable::address().as_u64() as usize });
+
+    let percpu = percpu::current();
+    percpu.set_page_table(context.arch.get_page_table());
+    percpu.context_id.store(context.id, Ordering::SeqCst);
+    percpu.run_queue.lock().push_back(context.id);
 }
 
 /// Initialize contexts, called if needed
@@ -64,6 +71,7 @@ pub fn contexts_mut() -> RwLockWriteGuard<'static, ContextList> {
     CONTEXTS.call_once(init_contexts).write()
 }
 
+/// The ID of the context running on the current CPU
 pub fn context_id() -> ContextId {
-    CONTEXT_ID.load(Ordering::SeqCst)
+    percpu::current().context_id.load(Ordering::SeqCst)
 }
\ No newline at end of file
@@ -1,16 +1,18 @@
 use core::sync::atomic::Ordering;
 
-use crate::context::{arch, contexts, Context, Status, CONTEXT_ID};
+use crate::context::{arch, contexts, Context, Status};
 use crate::gdt;
 use crate::interrupt;
+use crate::percpu::{self, PercpuBlock};
 use crate::interrupt::irq::PIT_TICKS;
 use crate::time;
 use super::signal::signal_handler;
 
-unsafe fn update(context: &mut Context, cpu_id: usize) {
+unsafe fn update(context: &mut Context, percpu: &PercpuBlock) {
     // Take ownership if not already owned
     if context.cpu_id == None {
-        context.cpu_id = Some(cpu_id);
+        context.cpu_id = Some(percpu.cpu_id);
+        percpu.run_queue.lock().push_back(context.id);
         // println!("{}: take {} {}", cpu_id, context.id, ::core::str::from_utf8_unchecked(&context.name.lock()));
     }
 
@@ -74,7 +76,8 @@ pub unsafe fn switch() -> bool {
         interrupt::pause();
     }
 
-    let cpu_id = crate::cpu_id();
+    let percpu = percpu::current();
+    let cpu_id = percpu.cpu_id;
 
     let from_ptr;
     let mut to_ptr = 0 as *mut Context;
@@ -91,12 +94,26 @@ pub unsafe fn switch() -> bool {
 
         for (_pid, context_lock) in contexts.iter() {
             let mut context = context_lock.write();
-            update(&mut context, cpu_id);
+            update(&mut context, percpu);
         }
 
-        for (pid, context_lock) in contexts.iter() {
-            if *pid > (*from_ptr).id {
+        // Round-robin over the contexts owned by this CPU, the chosen one goes to the back
+        let mut run_queue = percpu.run_queue.lock();
+        for _ in 0..run_queue.len() {
+            let id = match run_queue.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+
+            // Contexts that exited or moved to another CPU leave the queue
+            if let Some(context_lock) = contexts.get(id) {
                 let mut context = context_lock.write();
+                if context.cpu_id != Some(cpu_id) {
+                    continue;
+                }
+
+                run_queue.push_back(id);
+
                 if runnable(&mut context, cpu_id) {
                     to_ptr = context.deref_mut() as *mut Context;
                     if (&mut *to_ptr).ksig.is_none() {
@@ -106,21 +123,6 @@ pub unsafe fn switch() -> bool {
                 }
             }
         }
-
-        if to_ptr as usize == 0 {
-            for (pid, context_lock) in contexts.iter() {
-                if *pid < (*from_ptr).id {
-                    let mut context = context_lock.write();
-                    if runnable(&mut context, cpu_id) {
-                        to_ptr = context.deref_mut() as *mut Context;
-                        if (&mut *to_ptr).ksig.is_none() {
-                            to_sig = context.pending.pop_front();
-                        }
-                        break;
-                    }
-                }
-            }
-        }
     };
 
     // Switch process states, TSS stack pointer, and store new context ID
@@ -130,7 +132,8 @@ pub unsafe fn switch() -> bool {
         if let Some(ref stack) = (*to_ptr).kstack {
             gdt::set_tss_stack(stack.as_ptr() as usize + stack.len());
         }
-        CONTEXT_ID.store((&mut *to_ptr).id, Ordering::SeqCst);
+        percpu.context_id.store((&mut *to_ptr).id, Ordering::SeqCst);
+        percpu.set_page_table((&mut *to_ptr).arch.get_page_table());
     }
 
     // Unset global lock before switch, as arch is only usable by the current CPU at this time
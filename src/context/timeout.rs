@@ -0,0 +1,68 @@
+//! # Timeouts
+//!
+//! Callbacks to run once the monotonic clock reaches a deadline. They are checked on every timer
+//! interrupt, and when an idle CPU wakes up.
+use alloc::collections::VecDeque;
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::interrupt;
+use crate::time;
+
+#[derive(Debug)]
+struct Timeout {
+    /// Monotonic time in nanoseconds
+    deadline: u64,
+    callback: fn(usize),
+    data: usize,
+}
+
+type Registry = VecDeque<Timeout>;
+
+static REGISTRY: Once<Mutex<Registry>> = Once::new();
+
+/// Initialize registry, called if needed
+fn init_registry() -> Mutex<Registry> {
+    Mutex::new(Registry::new())
+}
+
+/// Get the timeout list, sorted by deadline. Interrupts must be disabled while it is held,
+/// as the timer interrupt takes it too.
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.call_once(init_registry).lock()
+}
+
+/// Call `callback(data)` once the monotonic clock reaches `deadline`, as (seconds, nanoseconds).
+/// The callback runs in interrupt context and must not block.
+pub fn register(deadline: (u64, u64), callback: fn(usize), data: usize) {
+    let deadline = time::to_nanos(deadline);
+    interrupt::without_interrupts(|| {
+        let mut registry = registry();
+        let index = registry.iter().position(|timeout| timeout.deadline > deadline).unwrap_or(registry.len());
+        registry.insert(index, Timeout { deadline, callback, data });
+    });
+}
+
+/// The earliest deadline, in nanoseconds of monotonic time
+pub fn next_deadline() -> Option<u64> {
+    interrupt::without_interrupts(|| registry().front().map(|timeout| timeout.deadline))
+}
+
+/// Run the callbacks of all expired timeouts
+pub fn trigger() {
+    let now = time::to_nanos(time::monotonic());
+    loop {
+        let timeout = interrupt::without_interrupts(|| {
+            let mut registry = registry();
+            if registry.front().map_or(false, |timeout| timeout.deadline <= now) {
+                registry.pop_front()
+            } else {
+                None
+            }
+        });
+
+        match timeout {
+            Some(timeout) => (timeout.callback)(timeout.data),
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! # ATA disks
+//!
+//! Disks on the two IDE channels at the legacy ports, where QEMU attaches `-hda` to `-hdd`.
+//! Sectors move through the data port (PIO), with LBA28 commands, or LBA48 ones when the disk
+//! supports them. A channel raises its IRQ, 14 or 15, when a sector is ready or written; the
+//! driver yields until then while interrupts are enabled, and polls the status register when they
+//! are not, as during boot.
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::context;
+use crate::device::pic;
+use crate::interrupt;
+use crate::println;
+use crate::syscall::error::*;
+use crate::syscall::io::{Io, Pio, ReadOnly};
+
+const STATUS_BSY: u8 = 0x80;
+const STATUS_DF: u8 = 0x20;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_ERR: u8 = 0x01;
+
+const CMD_READ_PIO: u8 = 0x20;
+const CMD_READ_PIO_EXT: u8 = 0x24;
+const CMD_WRITE_PIO: u8 = 0x30;
+const CMD_WRITE_PIO_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Sectors moved by one command, the most an LBA28 sector count can hold
+const MAX_SECTORS: usize = 256;
+
+/// Polls of the status register before a busy drive is given up on
+const POLL_LIMIT: usize = 1_000_000;
+
+/// Yields while waiting for an interrupt, after which the status register is polled instead
+const IRQ_LIMIT: usize = 10_000;
+
+/// Names of the disks, in the order of the channels and drives
+const NAMES: [&str; 4] = ["hda", "hdb", "hdc", "hdd"];
+
+/// Set by the interrupt handler of each channel
+static IRQS: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// The registers of an IDE channel
+struct Channel {
+    index: usize,
+    data: Pio<u16>,
+    error: ReadOnly<Pio<u8>>,
+    count: Pio<u8>,
+    lba: [Pio<u8>; 3],
+    device: Pio<u8>,
+    /// The status register when read
+    command: Pio<u8>,
+    /// The alternate status register when read, which does not acknowledge interrupts
+    control: Pio<u8>,
+}
+
+impl Channel {
+    fn new(index: usize, base: u16, control: u16) -> Channel {
+        let mut channel = Channel {
+            index,
+            data: Pio::new(base),
+            error: ReadOnly::new(Pio::new(base + 1)),
+            count: Pio::new(base + 2),
+            lba: [Pio::new(base + 3), Pio::new(base + 4), Pio::new(base + 5)],
+            device: Pio::new(base + 6),
+            command: Pio::new(base + 7),
+            control: Pio::new(control),
+        };
+        // Clear nIEN, so drives interrupt
+        channel.control.write(0);
+        channel
+    }
+
+    /// Let the drive update its status, each read of the alternate status taking 100ns
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.control.read();
+        }
+    }
+
+    fn select(&mut self, device: u8) {
+        self.device.write(device);
+        self.delay();
+    }
+
+    fn start(&mut self, command: u8) {
+        IRQS[self.index].store(false, Ordering::SeqCst);
+        self.command.write(command);
+    }
+
+    /// Start `command` on `count` sectors from `lba`
+    fn transfer(&mut self, slave: bool, lba48: bool, lba: u64, count: usize, command: u8) {
+        let slave = (slave as u8) << 4;
+        if lba48 {
+            // The high bytes go first, the registers keep both
+            self.select(0x40 | slave);
+            self.count.write((count >> 8) as u8);
+            self.lba[0].write((lba >> 24) as u8);
+            self.lba[1].write((lba >> 32) as u8);
+            self.lba[2].write((lba >> 40) as u8);
+        } else {
+            self.select(0xE0 | slave | (lba >> 24) as u8 & 0xF);
+        }
+        self.count.write(count as u8);
+        self.lba[0].write(lba as u8);
+        self.lba[1].write((lba >> 8) as u8);
+        self.lba[2].write((lba >> 16) as u8);
+        self.start(command);
+    }
+
+    /// Wait until the drive is no longer busy, returning its status
+    fn poll(&self) -> Result<u8> {
+        self.delay();
+        for _ in 0..POLL_LIMIT {
+            let status = self.control.read();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            interrupt::pause();
+        }
+        Err(Error::new(EIO))
+    }
+
+    /// Wait for the drive, on its interrupt if `irq` is expected, and check that it reported
+    /// no error and, if `data`, that a sector can move
+    fn wait(&mut self, irq: bool, data: bool) -> Result<()> {
+        if irq && interrupt::are_enabled() {
+            let flag = &IRQS[self.index];
+            let mut yields = 0;
+            while !flag.swap(false, Ordering::SeqCst) && yields < IRQ_LIMIT {
+                unsafe { context::switch(); }
+                yields += 1;
+            }
+        }
+
+        let status = self.poll()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            println!("ATA: channel {}: status {:#X}, error {:#X}", self.index, status, self.error.read());
+            return Err(Error::new(EIO));
+        }
+        if data && status & STATUS_DRQ == 0 {
+            return Err(Error::new(EIO));
+        }
+        Ok(())
+    }
+
+    /// The IDENTIFY data of a drive, if it is an ATA disk
+    fn identify(&mut self, slave: bool) -> Option<[u16; 256]> {
+        self.select(0xA0 | (slave as u8) << 4);
+        self.count.write(0);
+        for lba in self.lba.iter_mut() {
+            lba.write(0);
+        }
+        self.start(CMD_IDENTIFY);
+
+        // A floating bus reads as all ones, an absent drive as zero
+        let status = self.command.read();
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+        // ATAPI and SATA devices abort, leaving a signature in the LBA registers
+        self.poll().ok()?;
+        if self.lba[1].read() != 0 || self.lba[2].read() != 0 {
+            return None;
+        }
+        self.wait(false, true).ok()?;
+
+        let mut words = [0; 256];
+        for word in words.iter_mut() {
+            *word = self.data.read();
+        }
+        Some(words)
+    }
+}
+
+/// An IDENTIFY string, stored with the bytes of each word swapped
+fn identify_string(words: &[u16]) -> String {
+    let mut string = String::new();
+    for word in words {
+        string.push((word >> 8) as u8 as char);
+        string.push(*word as u8 as char);
+    }
+    String::from(string.trim())
+}
+
+/// A disk on one of the channels, shared by its clones
+#[derive(Clone)]
+pub struct Disk {
+    channel: Arc<Mutex<Channel>>,
+    slave: bool,
+    name: &'static str,
+    model: String,
+    sectors: u64,
+    lba48: bool,
+}
+
+impl Disk {
+    fn new(channel: Arc<Mutex<Channel>>, slave: bool, name: &'static str, words: &[u16; 256]) -> Option<Disk> {
+        // Disks without LBA addressing predate anything worth supporting
+        if words[49] & 1 << 9 == 0 {
+            return None;
+        }
+
+        let lba48 = words[83] & 1 << 10 != 0;
+        let sectors = if lba48 {
+            words[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | u64::from(word))
+        } else {
+            u64::from(words[60]) | u64::from(words[61]) << 16
+        };
+
+        Some(Disk {
+            channel,
+            slave,
+            name,
+            model: identify_string(&words[27..47]),
+            sectors,
+            lba48,
+        })
+    }
+
+    /// `hda` to `hdd`, by channel and drive
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Check that `len` bytes from `sector` are whole sectors on the disk
+    fn check(&self, sector: u64, len: usize) -> Result<()> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(Error::new(EIO)),
+        }
+    }
+
+    fn command(&self, command: u8, ext: u8) -> u8 {
+        if self.lba48 { ext } else { command }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.check(sector, buf.len())?;
+        let command = self.command(CMD_READ_PIO, CMD_READ_PIO_EXT);
+        let mut channel = self.channel.lock();
+
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * MAX_SECTORS) as u64;
+            channel.transfer(self.slave, self.lba48, lba, chunk.len() / SECTOR_SIZE, command);
+            // Each sector interrupts once it can be read
+            for data in chunk.chunks_mut(SECTOR_SIZE) {
+                channel.wait(true, true)?;
+                for bytes in data.chunks_mut(2) {
+                    let word = channel.data.read();
+                    bytes[0] = word as u8;
+                    bytes[1] = (word >> 8) as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.check(sector, buf.len())?;
+        let command = self.command(CMD_WRITE_PIO, CMD_WRITE_PIO_EXT);
+        let mut channel = self.channel.lock();
+
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * MAX_SECTORS) as u64;
+            channel.transfer(self.slave, self.lba48, lba, chunk.len() / SECTOR_SIZE, command);
+            // The first sector is asked for without an interrupt, the others after one each
+            for (j, data) in chunk.chunks(SECTOR_SIZE).enumerate() {
+                channel.wait(j > 0, true)?;
+                for bytes in data.chunks(2) {
+                    channel.data.write(u16::from(bytes[0]) | u16::from(bytes[1]) << 8);
+                }
+            }
+            channel.wait(true, false)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let command = self.command(CMD_CACHE_FLUSH, CMD_CACHE_FLUSH_EXT);
+        let mut channel = self.channel.lock();
+        channel.select(0xE0 | (self.slave as u8) << 4);
+        channel.start(command);
+        channel.wait(true, false)
+    }
+}
+
+static DISKS: Once<Vec<Disk>> = Once::new();
+
+/// Find the disks on both channels, and unmask the interrupts of channels that have some
+pub fn init() {
+    let mut disks = Vec::new();
+    for (index, &(base, control)) in [(0x1F0, 0x3F6), (0x170, 0x376)].iter().enumerate() {
+        let channel = Arc::new(Mutex::new(Channel::new(index, base, control)));
+        let mut found = false;
+        for &slave in &[false, true] {
+            let name = NAMES[index * 2 + slave as usize];
+            let words = channel.lock().identify(slave);
+            if let Some(disk) = words.and_then(|words| Disk::new(channel.clone(), slave, name, &words)) {
+                println!("ATA: {}: {}, {} MiB", name, disk.model, disk.sectors / (1024 * 1024 / SECTOR_SIZE as u64));
+                disks.push(disk);
+                found = true;
+            }
+        }
+        if found {
+            unsafe {
+                pic::set_mask(2, false);
+                pic::set_mask(14 + index as u8, false);
+            }
+        }
+    }
+    DISKS.call_once(|| disks);
+}
+
+/// The disks found by `init`
+pub fn disks() -> &'static [Disk] {
+    DISKS.r#try().map_or(&[][..], |disks| &disks[..])
+}
+
+/// Acknowledge an interrupt of `channel` by reading its status, and wake up its waiter
+pub fn irq(channel: usize) {
+    let status = Pio::<u8>::new(if channel == 0 { 0x1F7 } else { 0x177 });
+    status.read();
+    IRQS[channel].store(true, Ordering::SeqCst);
+}
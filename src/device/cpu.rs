@@ -1,9 +1,65 @@
 extern crate raw_cpuid;
 
 use core::fmt::{Result, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
 
 use self::raw_cpuid::CpuId;
 
+/// CR4 bits of the supervisor mode execution and access protections
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// The page attribute table MSR
+const IA32_PAT: u32 = 0x277;
+
+/// The default page attribute table, except that entry 1 is write-combining instead of
+/// write-through. Pages select it with `WRITE_THROUGH` alone, and with `NO_CACHE` as well they
+/// stay uncached. The PAT bit of page entries is left clear, so entries 4 to 7 are not used.
+const PAT: u64 = 0x0007_0406_0007_0106;
+
+/// Enable the protections the CPU supports: no-execute pages, write protection of read-only
+/// pages in the kernel, and SMEP and SMAP, which fault on kernel execution of and access to user
+/// pages. With SMAP, user memory is only reachable between `stac` and `clac`.
+pub unsafe fn init() {
+    let cpuid = CpuId::new();
+
+    if cpuid.get_extended_function_info().map_or(false, |info| info.has_execute_disable()) {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
+    if cpuid.get_feature_info().map_or(false, |info| info.has_pat()) {
+        Msr::new(IA32_PAT).write(PAT);
+    }
+
+    let mut cr4_bits = 0;
+    if let Some(info) = cpuid.get_extended_feature_info() {
+        if info.has_smep() {
+            cr4_bits |= CR4_SMEP;
+        }
+        if info.has_smap() {
+            cr4_bits |= CR4_SMAP;
+        }
+    }
+    if cr4_bits != 0 {
+        asm!("mov rax, cr4
+              or rax, $0
+              mov cr4, rax"
+              : : "r"(cr4_bits) : "rax" : "intel", "volatile");
+    }
+    SMAP.store(cr4_bits & CR4_SMAP != 0, Ordering::SeqCst);
+}
+
+/// Whether SMAP is enabled, so that user memory accesses need `stac` and `clac`
+pub fn has_smap() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
 pub fn cpu_info<W: Write>(w: &mut W) -> Result {
     let cpuid = CpuId::new();
 
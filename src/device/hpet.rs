@@ -0,0 +1,127 @@
+//! # High Precision Event Timer
+//!
+//! The main counter is used as a clock source if it has 64 bits, and timer 0 in legacy replacement mode for one-shot
+//! interrupts on IRQ 0 while idle. See the
+//! [specification](https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf)
+use core::intrinsics::{volatile_load, volatile_store};
+use x86_64::PhysAddr;
+
+use crate::acpi::gas;
+use crate::acpi::hpet::Hpet as HpetTable;
+use crate::memory::{self, ActivePageTable};
+
+const CAPABILITY_OFFSET: usize = 0x00;
+const GENERAL_CONFIG_OFFSET: usize = 0x10;
+const MAIN_COUNTER_OFFSET: usize = 0xF0;
+const T0_CONFIG_OFFSET: usize = 0x100;
+const T0_COMPARATOR_OFFSET: usize = 0x108;
+
+const ENABLE_CNF: u64 = 1;
+const LEG_RT_CNF: u64 = 2;
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const LEG_RT_CAP: u64 = 1 << 15;
+
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+
+/// The counter period may not be larger than 100ns, in femtoseconds
+const MAX_PERIOD: u64 = 100_000_000;
+
+pub static mut HPET: Hpet = Hpet {
+    address: 0,
+    period: 0,
+};
+
+/// Map the HPET described by the ACPI table and start its main counter
+pub unsafe fn init(table: &HpetTable, active_table: &mut ActivePageTable) -> bool {
+    HPET.init(table, active_table)
+}
+
+pub struct Hpet {
+    pub address: usize,
+    /// Period of the main counter, in femtoseconds
+    pub period: u64,
+}
+
+impl Hpet {
+    unsafe fn init(&mut self, table: &HpetTable, active_table: &mut ActivePageTable) -> bool {
+        let base_address = table.base_address;
+        if base_address.address_space != gas::ADDRESS_SPACE_SYSTEM_MEMORY || !base_address.is_valid() {
+            return false;
+        }
+
+        self.address = memory::map_device(active_table, PhysAddr::new(base_address.address), 1024).as_u64() as usize;
+
+        // A 32 bit counter wraps within minutes, which `nanoseconds` would not notice
+        let capabilities = self.read(CAPABILITY_OFFSET);
+        let period = capabilities >> 32;
+        if period == 0 || period > MAX_PERIOD || capabilities & COUNT_SIZE_CAP != COUNT_SIZE_CAP {
+            self.address = 0;
+            return false;
+        }
+        self.period = period;
+
+        // Reset and start the main counter. Legacy routing is turned off, so that IRQ 0 stays
+        // with the PIT until a one-shot takes it.
+        let config = self.read(GENERAL_CONFIG_OFFSET) & !(ENABLE_CNF | LEG_RT_CNF);
+        self.write(GENERAL_CONFIG_OFFSET, config);
+        self.write(MAIN_COUNTER_OFFSET, 0);
+        self.write(GENERAL_CONFIG_OFFSET, config | ENABLE_CNF);
+
+        true
+    }
+
+    /// Whether the main counter is running
+    pub fn is_ready(&self) -> bool {
+        self.address != 0
+    }
+
+    unsafe fn read(&self, offset: usize) -> u64 {
+        volatile_load((self.address + offset) as *const u64)
+    }
+
+    unsafe fn write(&mut self, offset: usize, value: u64) {
+        volatile_store((self.address + offset) as *mut u64, value);
+    }
+
+    /// Value of the main counter
+    pub fn counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER_OFFSET) }
+    }
+
+    /// Whether timer 0 can replace the PIT on IRQ 0
+    pub fn has_legacy_replacement(&self) -> bool {
+        self.is_ready() && unsafe { self.read(CAPABILITY_OFFSET) } & LEG_RT_CAP == LEG_RT_CAP
+    }
+
+    /// Route timer 0 to IRQ 0 in place of the PIT, and fire it once after `nanoseconds`.
+    /// This also takes IRQ 8 from the RTC until `stop_oneshot`.
+    pub fn set_oneshot(&mut self, nanoseconds: u64) {
+        let ticks = (nanoseconds as u128 * 1_000_000 / self.period as u128).max(1) as u64;
+        unsafe {
+            let config = self.read(T0_CONFIG_OFFSET) & !TN_TYPE_CNF;
+            self.write(T0_CONFIG_OFFSET, config | TN_INT_ENB_CNF);
+            let counter = self.counter();
+            self.write(T0_COMPARATOR_OFFSET, counter.wrapping_add(ticks));
+
+            let general = self.read(GENERAL_CONFIG_OFFSET);
+            self.write(GENERAL_CONFIG_OFFSET, general | LEG_RT_CNF);
+        }
+    }
+
+    /// Disable timer 0 and give IRQ 0 back to the PIT
+    pub fn stop_oneshot(&mut self) {
+        unsafe {
+            let config = self.read(T0_CONFIG_OFFSET);
+            self.write(T0_CONFIG_OFFSET, config & !TN_INT_ENB_CNF);
+
+            let general = self.read(GENERAL_CONFIG_OFFSET);
+            self.write(GENERAL_CONFIG_OFFSET, general & !LEG_RT_CNF);
+        }
+    }
+
+    /// Nanoseconds since the main counter was started
+    pub fn nanoseconds(&self) -> u64 {
+        (self.counter() as u128 * self.period as u128 / 1_000_000) as u64
+    }
+}
@@ -0,0 +1,208 @@
+use core::intrinsics::{volatile_load, volatile_store};
+use raw_cpuid::CpuId;
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+
+use crate::device::pit;
+use crate::memory::{self, ActivePageTable};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_X2APIC_ICR: u32 = 0x830;
+const IA32_X2APIC_SIVR: u32 = 0x80F;
+const IA32_X2APIC_EOI: u32 = 0x80B;
+const IA32_X2APIC_APICID: u32 = 0x802;
+const IA32_X2APIC_VERSION: u32 = 0x803;
+const IA32_X2APIC_LVT_TIMER: u32 = 0x832;
+const IA32_X2APIC_INIT_COUNT: u32 = 0x838;
+const IA32_X2APIC_CUR_COUNT: u32 = 0x839;
+const IA32_X2APIC_DIV_CONF: u32 = 0x83E;
+
+/// Vector of the local APIC timer, right after the chained PICs
+pub const TIMER_VECTOR: u8 = 0x30;
+
+/// Divide configuration value for a divisor of 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+/// LVT mask bit
+const LVT_MASKED: u32 = 1 << 16;
+
+pub static mut LOCAL_APIC: LocalApic = LocalApic {
+    address: 0,
+    x2: false,
+    timer_frequency: 0,
+};
+
+pub unsafe fn init(active_table: &mut ActivePageTable) {
+    LOCAL_APIC.init(active_table);
+}
+
+pub unsafe fn init_ap() {
+    LOCAL_APIC.init_ap();
+}
+
+/// Local APIC
+pub struct LocalApic {
+    pub address: usize,
+    pub x2: bool,
+    /// Timer frequency in Hz after the divider, zero if it could not be calibrated
+    pub timer_frequency: u64,
+}
+
+impl LocalApic {
+    unsafe fn init(&mut self, active_table: &mut ActivePageTable) {
+        let physical_address = Msr::new(IA32_APIC_BASE).read() & 0xFFFF_0000;
+        self.address = memory::map_device(active_table, PhysAddr::new(physical_address), 4096).as_u64() as usize;
+        self.x2 = CpuId::new().get_feature_info().unwrap().has_x2apic();
+
+        self.init_ap();
+        self.calibrate_timer();
+    }
+
+    unsafe fn init_ap(&mut self) {
+        if self.x2 {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | 1 << 10);
+            Msr::new(IA32_X2APIC_SIVR).write(0x100);
+        } else {
+            self.write(0xF0, 0x100);
+        }
+    }
+
+    /// Whether the local APIC has been mapped and enabled on this CPU
+    pub fn is_ready(&self) -> bool {
+        self.x2 || self.address != 0
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        volatile_load((self.address + reg as usize) as *const u32)
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        volatile_store((self.address + reg as usize) as *mut u32, value);
+    }
+
+    pub fn id(&self) -> u32 {
+        if self.x2 {
+            unsafe { Msr::new(IA32_X2APIC_APICID).read() as u32 }
+        } else {
+            unsafe { self.read(0x20) }
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        if self.x2 {
+            unsafe { Msr::new(IA32_X2APIC_VERSION).read() as u32 }
+        } else {
+            unsafe { self.read(0x30) }
+        }
+    }
+
+    pub fn icr(&self) -> u64 {
+        if self.x2 {
+            unsafe { Msr::new(IA32_X2APIC_ICR).read() }
+        } else {
+            unsafe { (self.read(0x310) as u64) << 32 | self.read(0x300) as u64 }
+        }
+    }
+
+    pub fn set_icr(&mut self, value: u64) {
+        if self.x2 {
+            unsafe { Msr::new(IA32_X2APIC_ICR).write(value); }
+        } else {
+            unsafe {
+                // Wait for the previous IPI to be delivered
+                while self.read(0x300) & 1 << 12 == 1 << 12 {}
+                self.write(0x310, (value >> 32) as u32);
+                self.write(0x300, value as u32);
+                while self.read(0x300) & 1 << 12 == 1 << 12 {}
+            }
+        }
+    }
+
+    /// Send a fixed interrupt `vector` to the CPU with the given APIC ID
+    pub fn ipi(&mut self, apic_id: usize, vector: u8) {
+        let mut icr = 0x4000 | vector as u64;
+        if self.x2 {
+            icr |= (apic_id as u64) << 32;
+        } else {
+            icr |= (apic_id as u64) << 56;
+        }
+        self.set_icr(icr);
+    }
+
+    unsafe fn timer_read(&self, reg: u32, msr: u32) -> u32 {
+        if self.x2 {
+            Msr::new(msr).read() as u32
+        } else {
+            self.read(reg)
+        }
+    }
+
+    unsafe fn timer_write(&mut self, reg: u32, msr: u32, value: u32) {
+        if self.x2 {
+            Msr::new(msr).write(value as u64);
+        } else {
+            self.write(reg, value);
+        }
+    }
+
+    /// Measure the timer frequency over 10ms of the PIT
+    unsafe fn calibrate_timer(&mut self) {
+        const TICKS: u16 = (pit::FREQUENCY / 100) as u16;
+
+        self.timer_write(0x3E0, IA32_X2APIC_DIV_CONF, TIMER_DIVIDE_16);
+        self.timer_write(0x320, IA32_X2APIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+
+        let mut started = false;
+        let mut remaining = 0;
+        crate::interrupt::without_interrupts(|| {
+            pit::measure(TICKS, || {
+                if !started {
+                    self.timer_write(0x380, IA32_X2APIC_INIT_COUNT, u32::max_value());
+                    started = true;
+                } else {
+                    remaining = self.timer_read(0x390, IA32_X2APIC_CUR_COUNT);
+                }
+            });
+        });
+        self.timer_write(0x380, IA32_X2APIC_INIT_COUNT, 0);
+
+        let elapsed = (u32::max_value() - remaining) as u64;
+        self.timer_frequency = elapsed * pit::FREQUENCY / TICKS as u64;
+    }
+
+    /// Fire `TIMER_VECTOR` once, after `nanoseconds`
+    pub fn set_oneshot(&mut self, nanoseconds: u64) {
+        let count = (nanoseconds as u128 * self.timer_frequency as u128 / 1_000_000_000)
+            .max(1)
+            .min(u32::max_value() as u128) as u32;
+        unsafe {
+            self.timer_write(0x3E0, IA32_X2APIC_DIV_CONF, TIMER_DIVIDE_16);
+            self.timer_write(0x320, IA32_X2APIC_LVT_TIMER, TIMER_VECTOR as u32);
+            self.timer_write(0x380, IA32_X2APIC_INIT_COUNT, count);
+        }
+    }
+
+    /// Stop the timer, returning the nanoseconds elapsed since `set_oneshot`
+    pub fn stop_oneshot(&mut self) -> u64 {
+        unsafe {
+            let initial = self.timer_read(0x380, IA32_X2APIC_INIT_COUNT);
+            let current = self.timer_read(0x390, IA32_X2APIC_CUR_COUNT);
+            self.timer_write(0x320, IA32_X2APIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+            self.timer_write(0x380, IA32_X2APIC_INIT_COUNT, 0);
+
+            if self.timer_frequency == 0 {
+                return 0;
+            }
+            ((initial - current.min(initial)) as u128 * 1_000_000_000 / self.timer_frequency as u128) as u64
+        }
+    }
+
+    pub unsafe fn eoi(&mut self) {
+        if self.x2 {
+            Msr::new(IA32_X2APIC_EOI).write(0);
+        } else {
+            self.write(0xB0, 0);
+        }
+    }
+}
@@ -1,11 +1,44 @@
+use crate::acpi::{self, ACPI_TABLE};
+use crate::memory::ActivePageTable;
+use crate::idle;
+use crate::time;
+
+pub mod ata;
 pub mod rtc;
+pub mod pci;
 pub mod pic;
 pub mod cpu;
+pub mod local_apic;
+pub mod pit;
+pub mod hpet;
+pub mod tsc;
+pub mod virtio;
 
 pub unsafe fn init() {
+    cpu::init();
     pic::PICS.lock().initialize();
+    pit::init();
 }
 
 pub unsafe fn init_noncore() {
+    let mut active_table = ActivePageTable::new();
+    acpi::init(&mut active_table);
+    local_apic::init(&mut active_table);
+    pci::init(&mut active_table);
     rtc::init();
+
+    let hpet = ACPI_TABLE.lock().hpet;
+    if let Some(hpet) = hpet {
+        hpet::init(&hpet, &mut active_table);
+    }
+    tsc::init();
+    time::init();
+    ata::init();
+    pci::register(&virtio::blk::DRIVER);
+    pci::register(&virtio::net::DRIVER);
+
+    if cfg!(feature = "rtc_tick") {
+        rtc::init_tick();
+    }
+    idle::init();
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+/// A base address register, with the size of the range it decodes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bar {
+    /// Not implemented, or the upper half of a 64-bit BAR
+    None,
+    Memory { address: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u16 },
+}
+
+impl Bar {
+    /// Whether a BAR with value `low` is a 64-bit memory BAR, taking the next register too
+    pub fn is_64bit(low: u32) -> bool {
+        low & 1 == 0 && (low >> 1) & 3 == 2
+    }
+
+    /// Decode a BAR from its value and the value read back after writing all ones. A 64-bit
+    /// memory BAR has its upper half in `high` and `high_mask`, which are ignored otherwise.
+    pub fn decode(low: u32, low_mask: u32, high: u32, high_mask: u32) -> Bar {
+        if low & 1 == 1 {
+            // I/O ports only decode 16 bits, the upper half may read back as anything
+            let mask = low_mask & 0xFFFC;
+            if mask == 0 {
+                return Bar::None;
+            }
+            return Bar::Io {
+                port: (low & 0xFFFC) as u16,
+                size: (!mask as u16).wrapping_add(1),
+            };
+        }
+
+        let low_mask = u64::from(low_mask & !0xF);
+        let (address, mask) = if Bar::is_64bit(low) {
+            (u64::from(high) << 32, u64::from(high_mask) << 32 | low_mask)
+        } else if low_mask != 0 {
+            (0, 0xFFFF_FFFF << 32 | low_mask)
+        } else {
+            (0, 0)
+        };
+        if mask == 0 {
+            return Bar::None;
+        }
+        Bar::Memory {
+            address: address | u64::from(low & !0xF),
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & 0x8 == 0x8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(Bar::decode(0xC001, 0xFFFF_FFE1, 0, 0), Bar::Io { port: 0xC000, size: 32 });
+        assert_eq!(Bar::decode(0xC041, 0x0000_FFC1, 0, 0), Bar::Io { port: 0xC040, size: 64 });
+        assert_eq!(
+            Bar::decode(0xFEBC_0000, 0xFFFF_F000, 0, 0),
+            Bar::Memory { address: 0xFEBC_0000, size: 4096, prefetchable: false }
+        );
+
+        assert!(Bar::is_64bit(0x0000_000C));
+        assert_eq!(
+            Bar::decode(0x0000_000C, 0xFFFF_C00C, 0x8, 0xFFFF_FFFF),
+            Bar::Memory { address: 0x8_0000_0000, size: 0x4000, prefetchable: true }
+        );
+        // Larger than 4 GiB
+        assert_eq!(
+            Bar::decode(0x0000_000C, 0x0000_000C, 0x10, 0xFFFF_FFF0),
+            Bar::Memory { address: 0x10_0000_0000, size: 0x10_0000_0000, prefetchable: true }
+        );
+
+        assert_eq!(
+            Bar::decode(0x0000_0004, 0x0000_0004, 0x1, 0xFFFF_FFFF),
+            Bar::Memory { address: 0x1_0000_0000, size: 0x1_0000_0000, prefetchable: false }
+        );
+
+        assert_eq!(Bar::decode(0, 0, 0, 0), Bar::None);
+    }
+}
@@ -0,0 +1,86 @@
+use core::intrinsics::{volatile_load, volatile_store};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::acpi::mcfg::Mcfg;
+use crate::memory::{self, ActivePageTable, PAGE_SIZE};
+use crate::syscall::io::{Io, Pio};
+
+use super::PciAddress;
+
+/// The address and data ports of configuration mechanism 1, used as a pair
+static PORTS: Mutex<(Pio<u32>, Pio<u32>)> = Mutex::new((Pio::new(0xCF8), Pio::new(0xCFC)));
+
+/// Configuration space, memory mapped (ECAM) on the buses the ACPI MCFG table lists, and reached
+/// through ports 0xCF8 and 0xCFC elsewhere, where only the first 256 bytes of a function are
+pub struct ConfigSpace {
+    mcfg: Option<Mcfg>,
+}
+
+impl ConfigSpace {
+    pub fn new(mcfg: Option<Mcfg>) -> ConfigSpace {
+        ConfigSpace { mcfg }
+    }
+
+    /// Whether any configuration space is memory mapped
+    pub fn is_ecam(&self) -> bool {
+        self.mcfg.as_ref().map_or(false, |mcfg| !mcfg.entries.is_empty())
+    }
+
+    /// Physical address of the memory mapped configuration space of a function
+    fn ecam_address(&self, address: PciAddress) -> Option<u64> {
+        let entry = self.mcfg.as_ref()?.find(address.bus)?;
+        let base = entry.base_address;
+        Some(base
+            + (u64::from(address.bus - entry.start_bus) << 20)
+            + (u64::from(address.device) << 15)
+            + (u64::from(address.function) << 12))
+    }
+
+    /// Map the configuration space of a function, before it is first read or written
+    pub unsafe fn map(&self, active_table: &mut ActivePageTable, address: PciAddress) {
+        if let Some(phys) = self.ecam_address(address) {
+            memory::map_device(active_table, PhysAddr::new(phys), PAGE_SIZE);
+        }
+    }
+
+    fn pio_address(address: PciAddress, offset: u16) -> u32 {
+        1 << 31
+            | u32::from(address.bus) << 16
+            | u32::from(address.device) << 11
+            | u32::from(address.function) << 8
+            | u32::from(offset & 0xFC)
+    }
+
+    /// Read the register at `offset`, a multiple of 4
+    pub fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        match self.ecam_address(address) {
+            Some(phys) => unsafe {
+                let virt = memory::phys_to_virt_addr(PhysAddr::new(phys + u64::from(offset & 0xFFC)));
+                volatile_load(virt.as_u64() as *const u32)
+            },
+            None if offset < 256 => {
+                let mut ports = PORTS.lock();
+                ports.0.write(ConfigSpace::pio_address(address, offset));
+                ports.1.read()
+            },
+            None => 0xFFFF_FFFF,
+        }
+    }
+
+    /// Write the register at `offset`, a multiple of 4
+    pub fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        match self.ecam_address(address) {
+            Some(phys) => unsafe {
+                let virt = memory::phys_to_virt_addr(PhysAddr::new(phys + u64::from(offset & 0xFFC)));
+                volatile_store(virt.as_u64() as *mut u32, value);
+            },
+            None if offset < 256 => {
+                let mut ports = PORTS.lock();
+                ports.0.write(ConfigSpace::pio_address(address, offset));
+                ports.1.write(value);
+            },
+            None => (),
+        }
+    }
+}
@@ -0,0 +1,383 @@
+//! # PCI
+//!
+//! Finds the functions on the PCI buses, starting from the host bridges and following PCI-to-PCI
+//! bridges, and decodes their headers and BARs. Drivers implement `PciDriver` and are offered
+//! each device matching one of their IDs once registered; the first to probe a device keeps it.
+//! See [osdev](https://wiki.osdev.org/PCI)
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+
+use crate::acpi::ACPI_TABLE;
+use crate::memory::ActivePageTable;
+use crate::println;
+use crate::syscall::error::Result;
+
+pub use self::bar::Bar;
+pub use self::config::ConfigSpace;
+
+/// Base address registers
+pub mod bar;
+
+/// Configuration space access
+pub mod config;
+
+/// Message signaled interrupts
+pub mod msix;
+
+/// Command register: respond to I/O port accesses
+pub const COMMAND_IO: u32 = 1 << 0;
+/// Command register: respond to memory accesses
+pub const COMMAND_MEMORY: u32 = 1 << 1;
+/// Command register: allow the device to access memory, for DMA
+pub const COMMAND_BUS_MASTER: u32 = 1 << 2;
+/// Command register: mask legacy INTx interrupts
+pub const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+/// Status register, in the upper half of the command register: there is a capability list
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+
+/// Capability IDs
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// The location of a function, on segment 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress { bus, device, function }
+    }
+
+    /// Parse the `bus:device.function` form, in hexadecimal
+    pub fn parse(text: &[u8]) -> Option<PciAddress> {
+        let text = core::str::from_utf8(text).ok()?;
+        let mut parts = text.splitn(2, ':');
+        let bus = parts.next()?;
+        let mut parts = parts.next()?.splitn(2, '.');
+        let device = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let function = u8::from_str_radix(parts.next()?, 16).ok()?;
+        if device >= 32 || function >= 8 {
+            return None;
+        }
+        Some(PciAddress::new(u8::from_str_radix(bus, 16).ok()?, device, function))
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{:x}", self.bus, self.device, self.function)
+    }
+}
+
+/// A function, as its configuration header described it at boot
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Six on ordinary devices, two on bridges
+    pub bars: Vec<Bar>,
+    /// The PIC IRQ firmware routed INTx to
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the function does not interrupt
+    pub interrupt_pin: u8,
+}
+
+impl PciDevice {
+    /// Read the configuration register at `offset`, a multiple of 4
+    pub fn read(&self, offset: u16) -> u32 {
+        config().read(self.address, offset)
+    }
+
+    /// Write the configuration register at `offset`, a multiple of 4
+    pub fn write(&self, offset: u16, value: u32) {
+        config().write(self.address, offset, value)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Set bits of the command register. The status bits above it are written as zero, which
+    /// leaves them alone.
+    pub fn set_command(&self, bits: u32) {
+        let command = self.read(0x04) & 0xFFFF;
+        self.write(0x04, command | bits);
+    }
+
+    /// Let the function decode its BARs and access memory
+    pub fn enable(&self) {
+        self.set_command(COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    /// The capability list, as IDs and the offsets of their registers
+    pub fn capabilities(&self) -> Vec<(u8, u16)> {
+        let mut capabilities = Vec::new();
+        if self.read(0x04) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+
+        let mut offset = u16::from(self.read_u8(0x34)) & 0xFC;
+        // A broken list could loop, there is only room for 48 capabilities
+        while offset >= 0x40 && capabilities.len() < 48 {
+            let header = self.read(offset);
+            capabilities.push((header as u8, offset));
+            offset = (header >> 8) as u16 & 0xFC;
+        }
+        capabilities
+    }
+
+    /// The offset of the first capability with `id`
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().into_iter().find(|&(cap, _)| cap == id).map(|(_, offset)| offset)
+    }
+
+    fn is_bridge(&self) -> bool {
+        self.header_type & 0x7F == 1 && self.class == CLASS_BRIDGE && self.subclass == SUBCLASS_PCI_BRIDGE
+    }
+}
+
+/// Devices a driver handles, fields that are `None` matching anything
+#[derive(Clone, Copy, Debug)]
+pub struct PciId {
+    pub vendor: Option<u16>,
+    pub device: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+impl PciId {
+    /// One device of a vendor
+    pub const fn device(vendor: u16, device: u16) -> PciId {
+        PciId { vendor: Some(vendor), device: Some(device), class: None, subclass: None }
+    }
+
+    /// Any device of a class and subclass
+    pub const fn class(class: u8, subclass: u8) -> PciId {
+        PciId { vendor: None, device: None, class: Some(class), subclass: Some(subclass) }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor.map_or(true, |vendor| vendor == device.vendor)
+            && self.device.map_or(true, |id| id == device.device)
+            && self.class.map_or(true, |class| class == device.class)
+            && self.subclass.map_or(true, |subclass| subclass == device.subclass)
+    }
+}
+
+/// A driver for PCI devices
+pub trait PciDriver: Sync {
+    /// Shown next to the devices it drives in `pci:`
+    fn name(&self) -> &'static str;
+
+    /// The devices it may drive
+    fn ids(&self) -> &'static [PciId];
+
+    /// Take a matching device, which fails if it turns out not to be supported
+    fn probe(&self, device: &PciDevice) -> Result<()>;
+}
+
+static CONFIG: Once<ConfigSpace> = Once::new();
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+/// The devices drivers took, and the names of the drivers
+static BOUND: Mutex<Vec<(PciAddress, &'static str)>> = Mutex::new(Vec::new());
+
+fn config() -> &'static ConfigSpace {
+    CONFIG.r#try().expect("pci: not initialized")
+}
+
+/// Size the BARs of a function, with decoding off so the probed ranges are not decoded
+fn read_bars(config: &ConfigSpace, address: PciAddress, count: u16) -> Vec<Bar> {
+    let command = config.read(address, 0x04) & 0xFFFF;
+    config.write(address, 0x04, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let probe = |offset: u16| {
+        let value = config.read(address, offset);
+        config.write(address, offset, 0xFFFF_FFFF);
+        let mask = config.read(address, offset);
+        config.write(address, offset, value);
+        (value, mask)
+    };
+
+    let mut bars = Vec::new();
+    let mut i = 0;
+    while i < count {
+        let (low, low_mask) = probe(0x10 + i * 4);
+        if Bar::is_64bit(low) && i + 1 < count {
+            let (high, high_mask) = probe(0x10 + (i + 1) * 4);
+            bars.push(Bar::decode(low, low_mask, high, high_mask));
+            bars.push(Bar::None);
+            i += 2;
+        } else {
+            bars.push(Bar::decode(low, low_mask, 0, 0));
+            i += 1;
+        }
+    }
+
+    config.write(address, 0x04, command);
+    bars
+}
+
+/// Read the header of a function, if there is one
+fn read_function(config: &ConfigSpace, address: PciAddress) -> Option<PciDevice> {
+    let id = config.read(address, 0x00);
+    if id as u16 == 0xFFFF {
+        return None;
+    }
+
+    let class = config.read(address, 0x08);
+    let header_type = (config.read(address, 0x0C) >> 16) as u8;
+    let bar_count = match header_type & 0x7F {
+        0 => 6,
+        1 => 2,
+        _ => 0,
+    };
+    let interrupt = config.read(address, 0x3C);
+
+    Some(PciDevice {
+        address,
+        vendor: id as u16,
+        device: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        bars: read_bars(config, address, bar_count),
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    })
+}
+
+/// Scan `bus`, and the buses behind its bridges
+unsafe fn scan_bus(
+    config: &ConfigSpace,
+    active_table: &mut ActivePageTable,
+    bus: u8,
+    scanned: &mut [bool; 256],
+    devices: &mut Vec<PciDevice>,
+) {
+    if scanned[bus as usize] {
+        return;
+    }
+    scanned[bus as usize] = true;
+
+    for device in 0..32 {
+        let address = PciAddress::new(bus, device, 0);
+        config.map(active_table, address);
+        let first = match read_function(config, address) {
+            Some(first) => first,
+            None => continue,
+        };
+
+        let functions = if first.header_type & 0x80 != 0 { 8 } else { 1 };
+        let mut found = vec![first];
+        for function in 1..functions {
+            let address = PciAddress::new(bus, device, function);
+            config.map(active_table, address);
+            found.extend(read_function(config, address));
+        }
+
+        for function in found {
+            // Bus numbers of a bridge: primary, secondary and subordinate
+            let secondary = if function.is_bridge() {
+                Some((config.read(function.address, 0x18) >> 8) as u8)
+            } else {
+                None
+            };
+            devices.push(function);
+            if let Some(secondary) = secondary {
+                scan_bus(config, active_table, secondary, scanned, devices);
+            }
+        }
+    }
+}
+
+/// Find the PCI functions
+pub unsafe fn init(active_table: &mut ActivePageTable) {
+    let config = CONFIG.call_once(|| ConfigSpace::new(ACPI_TABLE.lock().mcfg.clone()));
+
+    let mut devices = Vec::new();
+    let mut scanned = [false; 256];
+    // A multi-function host bridge has a function per root bus
+    let host = PciAddress::new(0, 0, 0);
+    config.map(active_table, host);
+    match read_function(config, host) {
+        Some(ref bridge) if bridge.header_type & 0x80 != 0 => {
+            for function in 0..8 {
+                let address = PciAddress::new(0, 0, function);
+                config.map(active_table, address);
+                if config.read(address, 0x00) as u16 != 0xFFFF {
+                    scan_bus(config, active_table, function, &mut scanned, &mut devices);
+                }
+            }
+        },
+        Some(_) => scan_bus(config, active_table, 0, &mut scanned, &mut devices),
+        None => (),
+    }
+    devices.sort_by_key(|device| device.address);
+
+    println!("PCI: {} functions, configuration through {}", devices.len(), if config.is_ecam() { "ECAM" } else { "ports" });
+    for device in devices.iter() {
+        println!(
+            "PCI: {} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            device.address, device.vendor, device.device, device.class, device.subclass, device.prog_if
+        );
+    }
+    DEVICES.call_once(|| devices);
+}
+
+/// The functions found by `init`
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.r#try().map_or(&[][..], |devices| &devices[..])
+}
+
+/// The driver that took the function at `address`
+pub fn driver(address: PciAddress) -> Option<&'static str> {
+    BOUND.lock().iter().find(|&&(bound, _)| bound == address).map(|&(_, name)| name)
+}
+
+/// Offer `driver` the functions it matches that no driver has yet
+pub fn register(driver: &'static dyn PciDriver) {
+    for device in devices() {
+        if self::driver(device.address).is_some() || !driver.ids().iter().any(|id| id.matches(device)) {
+            continue;
+        }
+        match driver.probe(device) {
+            Ok(()) => BOUND.lock().push((device.address, driver.name())),
+            Err(err) => println!("PCI: {}: {}: {}", device.address, driver.name(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn address() {
+        let address = PciAddress::new(0, 0x1f, 3);
+        assert_eq!(format!("{}", address), "00:1f.3");
+        assert_eq!(PciAddress::parse(b"00:1f.3"), Some(address));
+        assert_eq!(PciAddress::parse(b"00:20.0"), None);
+        assert_eq!(PciAddress::parse(b"00:1f"), None);
+    }
+}
@@ -0,0 +1,50 @@
+use x86_64::PhysAddr;
+
+use crate::device::local_apic::LOCAL_APIC;
+use crate::memory::{self, ActivePageTable};
+use crate::syscall::io::{Io, Mmio};
+
+use super::{Bar, PciDevice, CAP_MSIX};
+
+/// Message control, in the upper half of the capability header: MSI-X is on
+const CONTROL_ENABLE: u32 = 1 << 31;
+/// Message control: all vectors are masked
+const CONTROL_FUNCTION_MASK: u32 = 1 << 30;
+
+/// Where messages to the local APICs are written, with the destination APIC ID from bit 12
+const MESSAGE_ADDRESS: u32 = 0xFEE0_0000;
+
+/// Size of an MSI-X table entry: address, upper address, data and vector control
+const ENTRY_SIZE: usize = 16;
+
+/// Point entry 0 of the MSI-X table of `device` at `vector` on this CPU, and turn MSI-X on, which
+/// turns INTx off. Fails if the device has no MSI-X or the local APIC is not in use.
+pub unsafe fn enable(device: &PciDevice, active_table: &mut ActivePageTable, vector: u8) -> bool {
+    if !LOCAL_APIC.is_ready() {
+        return false;
+    }
+    let capability = match device.capability(CAP_MSIX) {
+        Some(capability) => capability,
+        None => return false,
+    };
+
+    // The table is in a BAR, at an offset with the BAR index in the low bits
+    let table = device.read(capability + 4);
+    let address = match device.bars.get((table & 7) as usize) {
+        Some(&Bar::Memory { address, .. }) => address + u64::from(table & !7),
+        _ => return false,
+    };
+    let entry = memory::map_device(active_table, PhysAddr::new(address), ENTRY_SIZE).as_u64() as usize;
+
+    // The xAPIC keeps its ID in the top byte of the register
+    let apic_id = if LOCAL_APIC.x2 { LOCAL_APIC.id() } else { LOCAL_APIC.id() >> 24 };
+    let register = |offset: usize| &mut *((entry + offset) as *mut Mmio<u32>);
+    register(0).write(MESSAGE_ADDRESS | (apic_id & 0xFF) << 12);
+    register(4).write(0);
+    register(8).write(u32::from(vector));
+    register(12).write(0);
+
+    let header = device.read(capability);
+    device.write(capability, (header & !CONTROL_FUNCTION_MASK) | CONTROL_ENABLE);
+    true
+}
@@ -1,4 +1,5 @@
 use pic8259_simple::ChainedPics;
+use x86_64::instructions::port::Port;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -11,6 +12,8 @@ pub enum InterruptIndex {
     RTC = PIC_2_OFFSET,
     ACPI,
     Mouse = PIC_2_OFFSET + 4,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
 }
 
 impl InterruptIndex {
@@ -26,3 +29,20 @@ impl InterruptIndex {
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Mask or unmask an IRQ line on the chained PICs
+pub unsafe fn set_mask(irq: u8, masked: bool) {
+    let mut data = if irq < 8 { Port::<u8>::new(0x21) } else { Port::<u8>::new(0xA1) };
+    let bit = 1 << (irq % 8);
+    let mask = data.read();
+    data.write(if masked { mask | bit } else { mask & !bit });
+}
+
+/// The interrupt request registers of both PICs, the slave in the high byte
+pub unsafe fn irr() -> u16 {
+    let mut master = Port::<u8>::new(0x20);
+    let mut slave = Port::<u8>::new(0xA0);
+    master.write(0x0A);
+    slave.write(0x0A);
+    (slave.read() as u16) << 8 | master.read() as u16
+}
@@ -0,0 +1,77 @@
+//! # Programmable Interval Timer
+//!
+//! Channel 0 drives the timer interrupt, channel 2 is used as a one-shot reference for calibrating
+//! other clocks. See [osdev](https://wiki.osdev.org/Programmable_Interval_Timer)
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT input clock, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Reload value of channel 0
+pub const CHAN0_DIVISOR: u16 = 2685;
+
+/// Nanoseconds between two channel 0 interrupts
+pub const RATE: u64 = CHAN0_DIVISOR as u64 * 1_000_000_000 / FREQUENCY;
+
+const CHAN0: u16 = 0x40;
+const CHAN2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Gate and speaker control of channel 2, on the keyboard controller
+const GATE: u16 = 0x61;
+
+const SELECT_CHAN0: u8 = 0b00 << 6;
+const SELECT_CHAN2: u8 = 0b10 << 6;
+const ACCESS_LATCH: u8 = 0b00 << 4;
+const ACCESS_LOHI: u8 = 0b11 << 4;
+const MODE_ONESHOT: u8 = 0b000 << 1;
+const MODE_RATE: u8 = 0b010 << 1;
+
+/// Program channel 0 as a rate generator firing every `RATE` nanoseconds
+pub unsafe fn init() {
+    Port::<u8>::new(COMMAND).write(SELECT_CHAN0 | ACCESS_LOHI | MODE_RATE);
+    let mut chan0 = Port::<u8>::new(CHAN0);
+    chan0.write(CHAN0_DIVISOR as u8);
+    chan0.write((CHAN0_DIVISOR >> 8) as u8);
+}
+
+/// Current count of channel 0, counting down from `CHAN0_DIVISOR`
+pub unsafe fn read_chan0() -> u16 {
+    Port::<u8>::new(COMMAND).write(SELECT_CHAN0 | ACCESS_LATCH);
+    let mut chan0 = Port::<u8>::new(CHAN0);
+    let low = chan0.read() as u16;
+    let high = chan0.read() as u16;
+    high << 8 | low
+}
+
+/// Nanoseconds elapsed since the last channel 0 interrupt
+pub unsafe fn elapsed_in_tick() -> u64 {
+    let count = read_chan0().min(CHAN0_DIVISOR) as u64;
+    (CHAN0_DIVISOR as u64 - count) * 1_000_000_000 / FREQUENCY
+}
+
+/// Busy wait for `ticks` periods of the input clock on channel 2, calling `f` right after the
+/// countdown starts and right after it ends. Interrupts should be disabled by the caller.
+pub unsafe fn measure<F: FnMut()>(ticks: u16, mut f: F) {
+    let mut gate = Port::<u8>::new(GATE);
+
+    // Disable the speaker output and the gate while programming
+    let value = gate.read();
+    gate.write(value & !0b11);
+
+    Port::<u8>::new(COMMAND).write(SELECT_CHAN2 | ACCESS_LOHI | MODE_ONESHOT);
+    let mut chan2 = Port::<u8>::new(CHAN2);
+    chan2.write(ticks as u8);
+    chan2.write((ticks >> 8) as u8);
+
+    // Raising the gate starts the countdown
+    let value = gate.read();
+    gate.write(value & !0b10 | 0b01);
+    f();
+
+    // OUT2 goes high when the count reaches zero
+    while gate.read() & 0x20 == 0 {}
+    f();
+
+    let value = gate.read();
+    gate.write(value & !0b01);
+}
@@ -1,16 +1,48 @@
 use x86_64::instructions::port::Port;
-use crate::time;
+use crate::println;
+use crate::acpi;
+use crate::common::calendar::{self, DateTime};
+use crate::device::pic;
+use crate::time::{self, TickSource};
 use crate::syscall::data::RtcDateTime;
 
+/// Rate selector of the periodic interrupt, the frequency is `32768 >> (rate - 1)`, here 512 Hz
+const PERIODIC_RATE: u8 = 7;
+/// Nanoseconds between two periodic interrupts
+pub const PERIODIC_PERIOD: u64 = 1_000_000_000 / (32768 >> (PERIODIC_RATE - 1));
+
+/// Register B: the clock is stopped for setting
+const REG_B_SET: u8 = 0x80;
+/// Register B: periodic interrupt enable
+const REG_B_PIE: u8 = 0x40;
+/// Register B: values are binary rather than BCD
+const REG_B_BINARY: u8 = 0x04;
+/// Register B: hours are 24-hour rather than 12-hour with the PM flag in the top bit
+const REG_B_24_HOUR: u8 = 0x02;
+
 pub fn init() {
     let mut rtc = Rtc::new();
     time::START.lock().0 = rtc.time();
+    println!("RTC: {}", time::local_time());
+}
+
+/// Use the periodic interrupt as the tick source, in place of PIT channel 0
+pub unsafe fn init_tick() {
+    Rtc::new().enable_periodic();
+    time::set_tick_source(TickSource::Rtc);
+    pic::set_mask(0, true);
+    pic::set_mask(2, false);
+    pic::set_mask(8, false);
 }
 
 fn cvt_bcd(value: usize) -> usize {
     (value & 0xF) + ((value / 16) * 10)
 }
 
+fn to_bcd(value: usize) -> usize {
+    ((value / 10) << 4) | (value % 10)
+}
+
 /// RTC
 pub struct Rtc {
     addr: Port<u8>,
@@ -32,6 +64,12 @@ impl Rtc {
         self.data.read()
     }
 
+    /// Write
+    unsafe fn write(&mut self, reg: u8, value: u8) {
+        self.addr.write(reg);
+        self.data.write(value);
+    }
+
     /// Wait
     unsafe fn wait(&mut self) {
         while self.read(0xA) & 0x80 != 0x80 {}
@@ -47,35 +85,8 @@ impl Rtc {
         } = self.date_time();
 
         // Unix time from clock
-        let mut secs: u64 = (year as u64 - 1970) * 31_536_000;
-
-        let mut leap_days = (year as u64 - 1972) / 4 + 1;
-        if year % 4 == 0 && month <= 2 {
-            leap_days -= 1;
-        }
-        secs += leap_days * 86_400;
-
-        match month {
-            2 => secs += 2_678_400,
-            3 => secs += 5_097_600,
-            4 => secs += 7_776_000,
-            5 => secs += 10_368_000,
-            6 => secs += 13_046_400,
-            7 => secs += 15_638_400,
-            8 => secs += 18_316_800,
-            9 => secs += 20_995_200,
-            10 => secs += 23_587_200,
-            11 => secs += 26_265_600,
-            12 => secs += 28_857_600,
-            _ => (),
-        }
-
-        secs += (day as u64 - 1) * 86_400;
-        secs += hour as u64 * 3600;
-        secs += minute as u64 * 60;
-        secs += second as u64;
-
-        secs
+        let days = calendar::days_from_civil(year as i64, month as u8, day as u8);
+        (days * calendar::SECONDS_PER_DAY + (hour * 3600 + minute * 60 + second) as i64) as u64
     }
 
     pub fn date_time(&mut self) -> RtcDateTime {
@@ -88,11 +99,7 @@ impl Rtc {
         let mut century;
         let register_b;
 
-        /*let century_register = if let Some(ref fadt) = acpi::ACPI_TABLE.lock().fadt {
-            Some(fadt.century)
-        } else {
-            None
-        };*/
+        let century_register = acpi::ACPI_TABLE.lock().fadt.and_then(|fadt| fadt.century_register());
 
         unsafe {
             self.wait();
@@ -102,32 +109,37 @@ impl Rtc {
             day = self.read(7) as usize;
             month = self.read(8) as usize;
             year = self.read(9) as usize;
-            century = /* TODO: Fix invalid value from VirtualBox
-            if let Some(century_reg) = century_register {
+            century = if let Some(century_reg) = century_register {
                 self.read(century_reg) as usize
-            } else */ {
+            } else {
                 20
             };
             register_b = self.read(0xB);
         }
 
-        if register_b & 4 != 4 {
+        if register_b & REG_B_BINARY != REG_B_BINARY {
             second = cvt_bcd(second);
             minute = cvt_bcd(minute);
             hour = cvt_bcd(hour & 0x7F) | (hour & 0x80);
             day = cvt_bcd(day);
             month = cvt_bcd(month);
             year = cvt_bcd(year);
-            century = /* TODO: Fix invalid value from VirtualBox
-            if century_register.is_some() {
+            century = if century_register.is_some() {
                 cvt_bcd(century)
-            } else */ {
+            } else {
                 century
             };
         }
 
-        if register_b & 2 != 2 || hour & 0x80 == 0x80 {
-            hour = ((hour & 0x7F) + 12) % 24;
+        // Some firmware, such as VirtualBox, reports a century register holding garbage
+        if century < 19 || century > 21 {
+            century = 20;
+        }
+
+        // 12-hour mode counts 12, 1, ..., 11 with the top bit set after noon
+        if register_b & REG_B_24_HOUR != REG_B_24_HOUR {
+            let pm = hour & 0x80 == 0x80;
+            hour = (hour & 0x7F) % 12 + if pm { 12 } else { 0 };
         }
 
         year += century * 100;
@@ -142,4 +154,72 @@ impl Rtc {
             century,
         }
     }
+
+    /// Set time, as seconds since the Unix epoch
+    pub fn set_time(&mut self, secs: u64) {
+        let date_time = DateTime::from_unix(secs as i64, 0);
+        self.set_date_time(&RtcDateTime {
+            second: date_time.second as usize,
+            minute: date_time.minute as usize,
+            hour: date_time.hour as usize,
+            day: date_time.day as usize,
+            month: date_time.month as usize,
+            year: date_time.year as usize,
+            century: date_time.year as usize / 100,
+        });
+    }
+
+    /// Write the date and time, in the encoding the clock is configured for
+    pub fn set_date_time(&mut self, date_time: &RtcDateTime) {
+        let century_register = acpi::ACPI_TABLE.lock().fadt.and_then(|fadt| fadt.century_register());
+
+        unsafe {
+            let register_b = self.read(0xB);
+            let binary = register_b & REG_B_BINARY == REG_B_BINARY;
+            let encode = |value: usize| (if binary { value } else { to_bcd(value) }) as u8;
+
+            let mut hour = date_time.hour;
+            let mut pm = 0;
+            if register_b & REG_B_24_HOUR != REG_B_24_HOUR {
+                if hour >= 12 {
+                    pm = 0x80;
+                }
+                hour %= 12;
+                if hour == 0 {
+                    hour = 12;
+                }
+            }
+
+            // Stop the update cycle while the registers are inconsistent
+            self.write(0xB, register_b | REG_B_SET);
+
+            self.write(0, encode(date_time.second));
+            self.write(2, encode(date_time.minute));
+            self.write(4, encode(hour) | pm);
+            self.write(7, encode(date_time.day));
+            self.write(8, encode(date_time.month));
+            self.write(9, encode(date_time.year % 100));
+            if let Some(century_reg) = century_register {
+                self.write(century_reg, encode(date_time.year / 100));
+            }
+
+            self.write(0xB, register_b & !REG_B_SET);
+        }
+    }
+
+    /// Start the periodic interrupt at `PERIODIC_PERIOD`
+    pub unsafe fn enable_periodic(&mut self) {
+        crate::interrupt::without_interrupts(|| {
+            let register_a = self.read(0xA);
+            self.write(0xA, (register_a & 0xF0) | PERIODIC_RATE);
+            let register_b = self.read(0xB);
+            self.write(0xB, register_b | REG_B_PIE);
+            self.ack();
+        });
+    }
+
+    /// Acknowledge an interrupt by reading register C, otherwise no further one is raised
+    pub unsafe fn ack(&mut self) -> u8 {
+        self.read(0xC)
+    }
 }
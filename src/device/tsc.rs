@@ -0,0 +1,89 @@
+//! # Time Stamp Counter
+//!
+//! The TSC is only used as a clock source when it is invariant, so that it ticks at a constant
+//! rate in all power states. Its frequency comes from CPUID leaf 0x15 when the processor reports
+//! it, and is otherwise measured against the PIT.
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::pit;
+
+/// TSC frequency in kHz, zero until calibrated
+static FREQUENCY_KHZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value when `init` calibrated it
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Calibrate the TSC, returning whether it can be used as a clock source
+pub unsafe fn init() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    if max_extended < 0x8000_0007 || __cpuid(0x8000_0007).edx & 1 << 8 == 0 {
+        return false;
+    }
+
+    let khz = match cpuid_frequency() {
+        Some(khz) => khz,
+        None => pit_frequency(),
+    };
+    if khz == 0 {
+        return false;
+    }
+
+    BASE.store(read(), Ordering::SeqCst);
+    FREQUENCY_KHZ.store(khz, Ordering::SeqCst);
+    true
+}
+
+/// Frequency from the crystal clock ratio in CPUID leaf 0x15, in kHz
+unsafe fn cpuid_frequency() -> Option<u64> {
+    if __cpuid(0).eax < 0x15 {
+        return None;
+    }
+
+    let leaf = __cpuid(0x15);
+    let (denominator, numerator, crystal_hz) = (leaf.eax as u64, leaf.ebx as u64, leaf.ecx as u64);
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;
+    }
+
+    Some(crystal_hz * numerator / denominator / 1000)
+}
+
+/// Frequency measured over 10ms of PIT channel 2, in kHz
+unsafe fn pit_frequency() -> u64 {
+    const TICKS: u16 = (pit::FREQUENCY / 100) as u16;
+
+    let mut start = 0;
+    let mut end = 0;
+    crate::interrupt::without_interrupts(|| {
+        pit::measure(TICKS, || {
+            if start == 0 {
+                start = read();
+            } else {
+                end = read();
+            }
+        });
+    });
+
+    (end - start) * pit::FREQUENCY / TICKS as u64 / 1000
+}
+
+/// Current value of the TSC
+#[inline(always)]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() as u64 }
+}
+
+/// TSC frequency in kHz, or zero if it is not usable
+pub fn frequency_khz() -> u64 {
+    FREQUENCY_KHZ.load(Ordering::SeqCst)
+}
+
+/// Nanoseconds since the TSC was calibrated
+pub fn nanoseconds() -> u64 {
+    let khz = frequency_khz();
+    if khz == 0 {
+        return 0;
+    }
+    let ticks = read().wrapping_sub(BASE.load(Ordering::SeqCst));
+    (ticks as u128 * 1_000_000 / khz as u128) as u64
+}
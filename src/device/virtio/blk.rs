@@ -0,0 +1,203 @@
+//! # Virtio block devices
+//!
+//! Disks QEMU attaches with `-drive if=virtio`. Each request is a chain of a header, the data and
+//! a status byte the device writes, all in a bounce buffer of the disk, so one request is in
+//! flight at a time.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::device::pci::{PciDevice, PciDriver, PciId};
+use crate::memory::dma::Dma;
+use crate::memory::ActivePageTable;
+use crate::println;
+use crate::syscall::error::*;
+
+use super::queue::{Buffer, Virtqueue};
+use super::transport::Transport;
+use super::VENDOR;
+
+/// The disk cannot be written
+const F_RO: u64 = 1 << 5;
+/// The disk has a write cache, flushed with `T_FLUSH`
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// Offsets in the bounce buffer of the request header, the status and the data
+const HEADER: usize = 0;
+const STATUS: usize = 16;
+const DATA: usize = 512;
+
+/// Sectors moved by one request
+const MAX_SECTORS: usize = 128;
+
+/// Names of the disks, in the order they are found
+const NAMES: [&str; 8] = ["vda", "vdb", "vdc", "vdd", "vde", "vdf", "vdg", "vdh"];
+
+/// Legacy and modern device IDs
+static IDS: [PciId; 2] = [PciId::device(VENDOR, 0x1001), PciId::device(VENDOR, 0x1042)];
+
+struct Inner {
+    transport: Transport,
+    queue: Virtqueue,
+    dma: Dma,
+}
+
+impl Inner {
+    /// Send a request of `kind` for `len` bytes of data from `sector`, which are in the bounce
+    /// buffer for writes and are left there for reads
+    fn request(&mut self, kind: u32, sector: u64, len: usize) -> Result<()> {
+        let phys = self.dma.phys();
+        self.dma.write(HEADER, kind);
+        self.dma.write(HEADER + 4, 0u32);
+        self.dma.write(HEADER + 8, sector);
+        self.dma.write(STATUS, 0xFFu8);
+
+        let header = Buffer { address: phys + HEADER as u64, len: 16, writable: false };
+        let data = Buffer { address: phys + DATA as u64, len: len as u32, writable: kind == T_IN };
+        let status = Buffer { address: phys + STATUS as u64, len: 1, writable: true };
+        if len == 0 {
+            self.queue.push(&[header, status])?;
+        } else {
+            self.queue.push(&[header, data, status])?;
+        }
+        self.transport.notify(self.queue.index());
+        super::wait(&mut self.queue);
+
+        match self.dma.read::<u8>(STATUS) {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(Error::new(ENOSYS)),
+            _ => Err(Error::new(EIO)),
+        }
+    }
+}
+
+/// A virtio disk, shared by its clones
+#[derive(Clone)]
+pub struct Disk {
+    inner: Arc<Mutex<Inner>>,
+    name: &'static str,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+}
+
+impl Disk {
+    /// `vda` and up, in the order the disks are found
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Check that `len` bytes from `sector` are whole sectors on the disk
+    fn check(&self, sector: u64, len: usize) -> Result<()> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(Error::new(EIO)),
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.check(sector, buf.len())?;
+        let mut inner = self.inner.lock();
+
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            inner.request(T_IN, sector + (i * MAX_SECTORS) as u64, chunk.len())?;
+            chunk.copy_from_slice(&inner.dma.as_slice()[DATA..DATA + chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        self.check(sector, buf.len())?;
+        let mut inner = self.inner.lock();
+
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            inner.dma.as_mut_slice()[DATA..DATA + chunk.len()].copy_from_slice(chunk);
+            inner.request(T_OUT, sector + (i * MAX_SECTORS) as u64, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.flush {
+            self.inner.lock().request(T_FLUSH, 0, 0)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+
+unsafe fn probe(device: &PciDevice) -> Result<()> {
+    let index = DISKS.lock().len();
+    let name = *NAMES.get(index).ok_or(Error::new(ENOSPC))?;
+
+    let mut active_table = ActivePageTable::new();
+    let (mut transport, features) = super::start(device, F_RO | F_FLUSH, &mut active_table)?;
+    let interrupts = super::setup_interrupts(device, &mut transport, &mut active_table);
+    let queue = super::setup_queue(&mut transport, 0)?;
+    let dma = Dma::new(DATA + MAX_SECTORS * SECTOR_SIZE)?;
+    super::finish(&mut transport);
+
+    // The capacity is in sectors of 512 bytes, whatever the block size
+    let sectors = u64::from(transport.config_u32(0)) | u64::from(transport.config_u32(4)) << 32;
+    let read_only = features & F_RO != 0;
+    println!(
+        "virtio: {}: {}: {} MiB{}, {}",
+        device.address, name, sectors / (1024 * 1024 / SECTOR_SIZE as u64),
+        if read_only { " read-only" } else { "" }, interrupts
+    );
+
+    DISKS.lock().push(Disk {
+        inner: Arc::new(Mutex::new(Inner { transport, queue, dma })),
+        name,
+        sectors,
+        read_only,
+        flush: features & F_FLUSH != 0,
+    });
+    Ok(())
+}
+
+/// The virtio-blk driver
+pub struct Driver;
+
+pub static DRIVER: Driver = Driver;
+
+impl PciDriver for Driver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn ids(&self) -> &'static [PciId] {
+        &IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        unsafe { probe(device) }
+    }
+}
+
+/// The disks found so far
+pub fn disks() -> Vec<Disk> {
+    DISKS.lock().clone()
+}
@@ -0,0 +1,130 @@
+//! # Virtio
+//!
+//! The paravirtual devices of QEMU and other hypervisors, on PCI with vendor 0x1AF4. Modern
+//! devices describe their register blocks with vendor capabilities, and transitional ones also
+//! keep the legacy registers in I/O space; both are driven through `Transport`. Requests go
+//! through virtqueues in DMA memory. A device signals completions with MSI-X when there is a
+//! local APIC, or with its INTx line otherwise; the driver waits for them by yielding, and polls
+//! the used ring when interrupts are disabled, as during boot.
+use alloc::string::String;
+use core::cmp;
+
+use crate::context;
+use crate::device::local_apic::LOCAL_APIC;
+use crate::device::pci::{msix, PciDevice, CAP_MSIX};
+use crate::interrupt::{self, irq};
+use crate::memory::ActivePageTable;
+use crate::syscall::error::*;
+
+use self::queue::Virtqueue;
+use self::transport::Transport;
+
+/// `vda:` and up - block devices
+pub mod blk;
+
+/// Ethernet cards
+pub mod net;
+
+mod queue;
+mod transport;
+
+pub const VENDOR: u16 = 0x1AF4;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The device follows version 1.0 of the specification, not the legacy interface
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Entries of a queue, modern devices allowing fewer than their maximum
+const QUEUE_SIZE: u16 = 256;
+
+/// Reset `device` and agree on the features in `supported` it offers, returning its transport
+/// and those features
+unsafe fn start(device: &PciDevice, supported: u64, active_table: &mut ActivePageTable) -> Result<(Transport, u64)> {
+    device.enable();
+    let mut transport = Transport::new(device, active_table)?;
+    transport.reset();
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let supported = if transport.is_modern() { supported | F_VERSION_1 } else { supported };
+    let features = transport.device_features() & supported;
+    transport.set_driver_features(features);
+    if transport.is_modern() {
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.set_status(STATUS_FAILED);
+            return Err(Error::new(ENODEV));
+        }
+    }
+    Ok((transport, features))
+}
+
+/// The MSI-X handler, the waiters noticing the completions themselves
+fn wake(_: usize) {}
+
+/// Have `device` interrupt with MSI-X, or with its INTx line, before its queues are set.
+/// Returns how it interrupts.
+unsafe fn setup_interrupts(device: &PciDevice, transport: &mut Transport, active_table: &mut ActivePageTable) -> String {
+    if device.capability(CAP_MSIX).is_some() && LOCAL_APIC.is_ready() {
+        if let Ok(vector) = irq::register_msi((wake, 0)) {
+            if msix::enable(device, active_table, vector) {
+                transport.set_msix();
+                return format!("MSI-X vector {:#x}", vector);
+            }
+        }
+    }
+
+    if device.interrupt_pin != 0 && irq::register_irq(device.interrupt_line, transport.isr_handler()).is_ok() {
+        format!("IRQ {}", device.interrupt_line)
+    } else {
+        String::from("polling")
+    }
+}
+
+/// Allocate queue `index` and hand it to the device
+fn setup_queue(transport: &mut Transport, index: u16) -> Result<Virtqueue> {
+    let max = transport.queue_size(index);
+    if max == 0 {
+        return Err(Error::new(ENODEV));
+    }
+    // Legacy devices only take queues of their own size
+    let size = if transport.is_modern() { cmp::min(max, QUEUE_SIZE) } else { max };
+    let queue = Virtqueue::new(index, size)?;
+    transport.set_queue(&queue)?;
+    Ok(queue)
+}
+
+/// Let the device use its queues
+fn finish(transport: &mut Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_DRIVER_OK);
+}
+
+/// Let other contexts run, or wait for the next interrupt if there are none. Interrupts are
+/// enabled.
+pub fn idle() {
+    unsafe {
+        if !context::switch() {
+            interrupt::enable_and_halt();
+        }
+    }
+}
+
+/// Wait for the device to be done with a chain of `queue`
+fn wait(queue: &mut Virtqueue) -> (u16, u32) {
+    loop {
+        if let Some(used) = queue.pop() {
+            return used;
+        }
+        if interrupt::are_enabled() {
+            idle();
+        } else {
+            interrupt::pause();
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! # Virtio network cards
+//!
+//! Ethernet cards QEMU attaches with `-device virtio-net-pci`. Frames are received into slots of
+//! a buffer kept available on the receive queue, each slot holding the header the device writes
+//! and the frame, and are sent one at a time from a buffer of their own. No offloads are
+//! negotiated, so the headers are left zeroed.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp;
+use spin::Mutex;
+
+use crate::device::pci::{PciDevice, PciDriver, PciId};
+use crate::memory::dma::Dma;
+use crate::memory::ActivePageTable;
+use crate::println;
+use crate::syscall::error::*;
+
+use super::queue::{Buffer, Virtqueue};
+use super::transport::Transport;
+use super::VENDOR;
+
+/// The configuration holds the MAC address
+const F_MAC: u64 = 1 << 5;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Size of a receive slot and of the send buffer, enough for a header and a whole frame
+const SLOT_SIZE: usize = 2048;
+const RX_SLOTS: usize = 16;
+
+/// The largest frame without its checksum: the header and 1500 bytes of payload
+pub const MAX_FRAME: usize = 1514;
+
+/// Legacy and modern device IDs
+static IDS: [PciId; 2] = [PciId::device(VENDOR, 0x1000), PciId::device(VENDOR, 0x1041)];
+
+struct Inner {
+    transport: Transport,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_dma: Dma,
+    tx_dma: Dma,
+    /// The slot of each receive chain, by the descriptor at its head
+    slots: Vec<usize>,
+    /// Modern devices add the number of merged buffers to the header
+    header_len: usize,
+}
+
+impl Inner {
+    /// Make receive slot `slot` available again, as the header and the frame
+    fn give(&mut self, slot: usize) -> Result<()> {
+        let address = self.rx_dma.phys() + (slot * SLOT_SIZE) as u64;
+        let header_len = self.header_len;
+        let head = self.rx.push(&[
+            Buffer { address, len: header_len as u32, writable: true },
+            Buffer { address: address + header_len as u64, len: (SLOT_SIZE - header_len) as u32, writable: true },
+        ])?;
+        self.slots[head as usize] = slot;
+        Ok(())
+    }
+}
+
+/// A virtio network card, shared by its clones
+#[derive(Clone)]
+pub struct Nic {
+    inner: Arc<Mutex<Inner>>,
+    mac: [u8; 6],
+}
+
+impl Nic {
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Send the Ethernet frame in `frame`, without its checksum, waiting until the device took it
+    pub fn send(&self, frame: &[u8]) -> Result<usize> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::new(EMSGSIZE));
+        }
+
+        let mut inner = self.inner.lock();
+        let header_len = inner.header_len;
+        inner.tx_dma.as_mut_slice()[header_len..header_len + frame.len()].copy_from_slice(frame);
+
+        let address = inner.tx_dma.phys();
+        inner.tx.push(&[
+            Buffer { address, len: header_len as u32, writable: false },
+            Buffer { address: address + header_len as u64, len: frame.len() as u32, writable: false },
+        ])?;
+        inner.transport.notify(TX_QUEUE);
+        super::wait(&mut inner.tx);
+        Ok(frame.len())
+    }
+
+    /// The next frame received, if there is one
+    pub fn receive(&self) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock();
+        let (head, len) = inner.rx.pop()?;
+        let slot = inner.slots[head as usize];
+
+        let start = slot * SLOT_SIZE + inner.header_len;
+        let len = cmp::min(len as usize, SLOT_SIZE).saturating_sub(inner.header_len);
+        let frame = inner.rx_dma.as_slice()[start..start + len].to_vec();
+
+        // The slot was just freed, so it fits
+        let _ = inner.give(slot);
+        inner.transport.notify(RX_QUEUE);
+        Some(frame)
+    }
+}
+
+static NICS: Mutex<Vec<Nic>> = Mutex::new(Vec::new());
+
+unsafe fn probe(device: &PciDevice) -> Result<()> {
+    let mut active_table = ActivePageTable::new();
+    let (mut transport, features) = super::start(device, F_MAC, &mut active_table)?;
+    let interrupts = super::setup_interrupts(device, &mut transport, &mut active_table);
+    let rx = super::setup_queue(&mut transport, RX_QUEUE)?;
+    let tx = super::setup_queue(&mut transport, TX_QUEUE)?;
+
+    let mut inner = Inner {
+        header_len: if transport.is_modern() { 12 } else { 10 },
+        slots: vec![0; rx.size() as usize],
+        rx_dma: Dma::new(RX_SLOTS * SLOT_SIZE)?,
+        tx_dma: Dma::new(SLOT_SIZE)?,
+        transport,
+        rx,
+        tx,
+    };
+    // Each slot takes two descriptors
+    for slot in 0..cmp::min(RX_SLOTS, inner.rx.size() as usize / 2) {
+        inner.give(slot)?;
+    }
+    super::finish(&mut inner.transport);
+    inner.transport.notify(RX_QUEUE);
+
+    let mut mac = [0; 6];
+    if features & F_MAC != 0 {
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = inner.transport.config_u8(i);
+        }
+    }
+    println!(
+        "virtio: {}: network {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, {}",
+        device.address, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], interrupts
+    );
+
+    NICS.lock().push(Nic { inner: Arc::new(Mutex::new(inner)), mac });
+    Ok(())
+}
+
+/// The virtio-net driver
+pub struct Driver;
+
+pub static DRIVER: Driver = Driver;
+
+impl PciDriver for Driver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn ids(&self) -> &'static [PciId] {
+        &IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<()> {
+        unsafe { probe(device) }
+    }
+}
+
+/// The network cards found so far
+pub fn nics() -> Vec<Nic> {
+    NICS.lock().clone()
+}
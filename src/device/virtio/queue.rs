@@ -0,0 +1,152 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::memory::dma::Dma;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::error::*;
+
+/// Descriptor flags: the buffer continues in the `next` descriptor
+const DESC_NEXT: u16 = 1;
+/// Descriptor flags: the device writes the buffer
+const DESC_WRITE: u16 = 2;
+
+const DESC_SIZE: usize = 16;
+
+/// A physically contiguous buffer for the device
+#[derive(Clone, Copy)]
+pub struct Buffer {
+    pub address: u64,
+    pub len: u32,
+    pub writable: bool,
+}
+
+/// Offsets of the available and used rings after the descriptor table, and the total size. The
+/// used ring is on its own page, as the legacy interface expects.
+fn layout(size: usize) -> (usize, usize, usize) {
+    let avail = size * DESC_SIZE;
+    let avail_end = avail + 6 + 2 * size;
+    let used = (avail_end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    (avail, used, used + 6 + 8 * size)
+}
+
+/// A split virtqueue: descriptors the driver fills, a ring of the chains it makes available and
+/// a ring of those the device is done with
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    dma: Dma,
+    avail: usize,
+    used: usize,
+    /// Descriptors not in a chain
+    free: Vec<u16>,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocate queue `index` with `size` entries, a power of two
+    pub fn new(index: u16, size: u16) -> Result<Virtqueue> {
+        let (avail, used, len) = layout(size as usize);
+        Ok(Virtqueue {
+            index,
+            size,
+            dma: Dma::new(len)?,
+            avail,
+            used,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_address(&self) -> u64 {
+        self.dma.phys()
+    }
+
+    pub fn avail_address(&self) -> u64 {
+        self.dma.phys() + self.avail as u64
+    }
+
+    pub fn used_address(&self) -> u64 {
+        self.dma.phys() + self.used as u64
+    }
+
+    /// Make `buffers` available as one chain, returning the descriptor at its head. The device
+    /// still has to be notified.
+    pub fn push(&mut self, buffers: &[Buffer]) -> Result<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return Err(Error::new(EAGAIN));
+        }
+
+        let descriptors: Vec<u16> = (0..buffers.len()).filter_map(|_| self.free.pop()).collect();
+        for (i, (buffer, &descriptor)) in buffers.iter().zip(&descriptors).enumerate() {
+            let offset = descriptor as usize * DESC_SIZE;
+            let mut flags = if buffer.writable { DESC_WRITE } else { 0 };
+            let next = match descriptors.get(i + 1) {
+                Some(&next) => {
+                    flags |= DESC_NEXT;
+                    next
+                },
+                None => 0,
+            };
+            self.dma.write(offset, buffer.address);
+            self.dma.write(offset + 8, buffer.len);
+            self.dma.write(offset + 12, flags);
+            self.dma.write(offset + 14, next);
+        }
+
+        let head = descriptors[0];
+        let slot = (self.avail_idx % self.size) as usize;
+        self.dma.write(self.avail + 4 + 2 * slot, head);
+        // The device may look at the ring as soon as the index moves
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.dma.write(self.avail + 2, self.avail_idx);
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Take a chain the device is done with, as its head and the bytes the device wrote
+    pub fn pop(&mut self) -> Option<(u16, u32)> {
+        let used_idx: u16 = self.dma.read(self.used + 2);
+        if used_idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = (self.last_used % self.size) as usize;
+        let head = self.dma.read::<u32>(self.used + 4 + 8 * slot) as u16;
+        let len = self.dma.read::<u32>(self.used + 8 + 8 * slot);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut descriptor = head;
+        loop {
+            self.free.push(descriptor);
+            let offset = descriptor as usize * DESC_SIZE;
+            if self.dma.read::<u16>(offset + 12) & DESC_NEXT == 0 {
+                break;
+            }
+            descriptor = self.dma.read(offset + 14);
+        }
+        Some((head, len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring_layout() {
+        assert_eq!(layout(256), (4096, 8192, 8192 + 6 + 8 * 256));
+        assert_eq!(layout(16), (256, 4096, 4096 + 6 + 8 * 16));
+    }
+}
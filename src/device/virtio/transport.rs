@@ -0,0 +1,292 @@
+use x86_64::PhysAddr;
+
+use crate::device::pci::{Bar, PciDevice, CAP_VENDOR};
+use crate::interrupt::irq::DeviceHandler;
+use crate::memory::{self, ActivePageTable};
+use crate::syscall::error::*;
+use crate::syscall::io::{Io, Mmio, Pio};
+
+use super::queue::Virtqueue;
+
+/// Vendor capabilities of modern devices, by the register block they point to
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+/// Legacy registers, from BAR 0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// Only there with MSI-X on, moving the device configuration after them
+const LEGACY_CONFIG_VECTOR: u16 = 0x14;
+const LEGACY_QUEUE_VECTOR: u16 = 0x16;
+
+/// Common configuration registers of modern devices
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_CONFIG_VECTOR: usize = 0x10;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// A queue or the configuration has no MSI-X vector
+const NO_VECTOR: u16 = 0xFFFF;
+
+fn mmio<T>(address: usize) -> &'static mut Mmio<T> {
+    unsafe { &mut *(address as *mut Mmio<T>) }
+}
+
+/// Acknowledge an INTx interrupt by reading the ISR register of a legacy device
+fn isr_port(port: usize) {
+    Pio::<u8>::new(port as u16).read();
+}
+
+/// Acknowledge an INTx interrupt by reading the ISR register of a modern device
+fn isr_memory(address: usize) {
+    mmio::<u8>(address).read();
+}
+
+/// How the registers of a device are reached
+pub enum Transport {
+    /// Registers in I/O space at BAR 0, followed by the device configuration
+    Legacy { base: u16, msix: bool },
+    /// Register blocks in memory BARs, found through vendor capabilities
+    Modern { common: usize, notify: usize, notify_multiplier: u32, isr: usize, device: usize, msix: bool },
+}
+
+impl Transport {
+    /// Find the registers of `device`, preferring the modern interface of transitional devices
+    pub unsafe fn new(device: &PciDevice, active_table: &mut ActivePageTable) -> Result<Transport> {
+        if let Some(transport) = Transport::modern(device, active_table) {
+            return Ok(transport);
+        }
+        // Only transitional devices have the legacy interface
+        match device.bars.get(0) {
+            Some(&Bar::Io { port, .. }) if device.device < 0x1040 => Ok(Transport::Legacy { base: port, msix: false }),
+            _ => Err(Error::new(ENODEV)),
+        }
+    }
+
+    unsafe fn modern(device: &PciDevice, active_table: &mut ActivePageTable) -> Option<Transport> {
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        for (id, offset) in device.capabilities() {
+            if id != CAP_VENDOR {
+                continue;
+            }
+            let kind = device.read_u8(offset + 3);
+            let bar = device.read_u8(offset + 4) as usize;
+            let address = match device.bars.get(bar) {
+                Some(&Bar::Memory { address, .. }) => address + u64::from(device.read(offset + 8)),
+                _ => continue,
+            };
+            let length = device.read(offset + 12) as usize;
+            let mut map = || memory::map_device(active_table, PhysAddr::new(address), length.max(1)).as_u64() as usize;
+
+            // The first capability of each kind is the preferred one
+            match kind {
+                CAP_COMMON_CFG if common.is_none() => common = Some(map()),
+                CAP_NOTIFY_CFG if notify.is_none() => notify = Some((map(), device.read(offset + 16))),
+                CAP_ISR_CFG if isr.is_none() => isr = Some(map()),
+                CAP_DEVICE_CFG if config.is_none() => config = Some(map()),
+                _ => (),
+            }
+        }
+
+        let (notify, notify_multiplier) = notify?;
+        Some(Transport::Modern {
+            common: common?,
+            notify,
+            notify_multiplier,
+            isr: isr?,
+            device: config?,
+            msix: false,
+        })
+    }
+
+    pub fn is_modern(&self) -> bool {
+        match *self {
+            Transport::Legacy { .. } => false,
+            Transport::Modern { .. } => true,
+        }
+    }
+
+    fn legacy<T>(base: u16, register: u16) -> Pio<T> {
+        Pio::new(base + register)
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { base, .. } => Transport::legacy::<u8>(base, LEGACY_STATUS).read(),
+            Transport::Modern { common, .. } => mmio::<u8>(common + COMMON_STATUS).read(),
+        }
+    }
+
+    pub fn set_status(&mut self, status: u8) {
+        match *self {
+            Transport::Legacy { base, .. } => Transport::legacy::<u8>(base, LEGACY_STATUS).write(status),
+            Transport::Modern { common, .. } => mmio::<u8>(common + COMMON_STATUS).write(status),
+        }
+    }
+
+    /// Reset the device, which modern devices may take a while to finish
+    pub fn reset(&mut self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            crate::interrupt::pause();
+        }
+    }
+
+    pub fn device_features(&mut self) -> u64 {
+        match *self {
+            Transport::Legacy { base, .. } => u64::from(Transport::legacy::<u32>(base, LEGACY_DEVICE_FEATURES).read()),
+            Transport::Modern { common, .. } => {
+                let mut features = 0;
+                for half in 0..2 {
+                    mmio::<u32>(common + COMMON_DEVICE_FEATURE_SELECT).write(half);
+                    features |= u64::from(mmio::<u32>(common + COMMON_DEVICE_FEATURE).read()) << (half * 32);
+                }
+                features
+            }
+        }
+    }
+
+    pub fn set_driver_features(&mut self, features: u64) {
+        match *self {
+            Transport::Legacy { base, .. } => {
+                Transport::legacy::<u32>(base, LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Transport::Modern { common, .. } => {
+                for half in 0..2 {
+                    mmio::<u32>(common + COMMON_DRIVER_FEATURE_SELECT).write(half);
+                    mmio::<u32>(common + COMMON_DRIVER_FEATURE).write((features >> (half * 32)) as u32);
+                }
+            }
+        }
+    }
+
+    /// Route the queue interrupts through MSI-X vector 0, which must be done before the queues are
+    /// set. The configuration does not change, so it gets no vector.
+    pub fn set_msix(&mut self) {
+        match *self {
+            Transport::Legacy { base, ref mut msix } => {
+                *msix = true;
+                Transport::legacy::<u16>(base, LEGACY_CONFIG_VECTOR).write(NO_VECTOR);
+            },
+            Transport::Modern { common, ref mut msix, .. } => {
+                *msix = true;
+                mmio::<u16>(common + COMMON_CONFIG_VECTOR).write(NO_VECTOR);
+            }
+        }
+    }
+
+    /// The largest size of queue `index`, 0 if there is no such queue
+    pub fn queue_size(&mut self, index: u16) -> u16 {
+        match *self {
+            Transport::Legacy { base, .. } => {
+                Transport::legacy::<u16>(base, LEGACY_QUEUE_SELECT).write(index);
+                Transport::legacy::<u16>(base, LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => {
+                mmio::<u16>(common + COMMON_QUEUE_SELECT).write(index);
+                mmio::<u16>(common + COMMON_QUEUE_SIZE).read()
+            }
+        }
+    }
+
+    /// Hand `queue` to the device, failing if it cannot interrupt for it with MSI-X on
+    pub fn set_queue(&mut self, queue: &Virtqueue) -> Result<()> {
+        let vector_ok = match *self {
+            Transport::Legacy { base, msix } => {
+                Transport::legacy::<u16>(base, LEGACY_QUEUE_SELECT).write(queue.index());
+                let mut ok = true;
+                if msix {
+                    let mut vector = Transport::legacy::<u16>(base, LEGACY_QUEUE_VECTOR);
+                    vector.write(0);
+                    ok = vector.read() == 0;
+                }
+                // The legacy interface takes the page of contiguous rings
+                let page = queue.desc_address() / memory::PAGE_SIZE as u64;
+                Transport::legacy::<u32>(base, LEGACY_QUEUE_ADDRESS).write(page as u32);
+                ok
+            },
+            Transport::Modern { common, msix, .. } => {
+                mmio::<u16>(common + COMMON_QUEUE_SELECT).write(queue.index());
+                mmio::<u16>(common + COMMON_QUEUE_SIZE).write(queue.size());
+                let mut ok = true;
+                if msix {
+                    mmio::<u16>(common + COMMON_QUEUE_VECTOR).write(0);
+                    ok = mmio::<u16>(common + COMMON_QUEUE_VECTOR).read() == 0;
+                }
+                for &(register, address) in &[
+                    (COMMON_QUEUE_DESC, queue.desc_address()),
+                    (COMMON_QUEUE_DRIVER, queue.avail_address()),
+                    (COMMON_QUEUE_DEVICE, queue.used_address()),
+                ] {
+                    mmio::<u32>(common + register).write(address as u32);
+                    mmio::<u32>(common + register + 4).write((address >> 32) as u32);
+                }
+                mmio::<u16>(common + COMMON_QUEUE_ENABLE).write(1);
+                ok
+            }
+        };
+
+        if vector_ok {
+            Ok(())
+        } else {
+            Err(Error::new(EBUSY))
+        }
+    }
+
+    /// Tell the device there are new buffers in queue `index`
+    pub fn notify(&mut self, index: u16) {
+        match *self {
+            Transport::Legacy { base, .. } => Transport::legacy::<u16>(base, LEGACY_QUEUE_NOTIFY).write(index),
+            Transport::Modern { common, notify, notify_multiplier, .. } => {
+                mmio::<u16>(common + COMMON_QUEUE_SELECT).write(index);
+                let offset = mmio::<u16>(common + COMMON_QUEUE_NOTIFY_OFF).read() as usize;
+                mmio::<u16>(notify + offset * notify_multiplier as usize).write(index);
+            }
+        }
+    }
+
+    /// The handler that acknowledges INTx interrupts of the device
+    pub fn isr_handler(&self) -> DeviceHandler {
+        match *self {
+            Transport::Legacy { base, .. } => (isr_port, (base + LEGACY_ISR) as usize),
+            Transport::Modern { isr, .. } => (isr_memory, isr),
+        }
+    }
+
+    /// Read the device specific configuration at `offset`
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match *self {
+            Transport::Legacy { base, msix } => Transport::legacy::<u8>(base, Transport::config_base(msix) + offset as u16).read(),
+            Transport::Modern { device, .. } => mmio::<u8>(device + offset).read(),
+        }
+    }
+
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match *self {
+            Transport::Legacy { base, msix } => Transport::legacy::<u32>(base, Transport::config_base(msix) + offset as u16).read(),
+            Transport::Modern { device, .. } => mmio::<u32>(device + offset).read(),
+        }
+    }
+
+    fn config_base(msix: bool) -> u16 {
+        if msix { LEGACY_QUEUE_VECTOR + 2 } else { LEGACY_CONFIG_VECTOR }
+    }
+}
@@ -0,0 +1,240 @@
+//! Inodes as ext2 stores them. Fields this driver does not know about are kept as they were read.
+use alloc::vec::Vec;
+
+use crate::fs::perm::Permissions;
+use crate::fs::{read_u16, read_u32, write_u16, write_u32};
+use crate::syscall::flag::{MODE_DIR, MODE_FILE, MODE_SYMLINK, MODE_TYPE};
+
+/// Block pointers in an inode: direct ones, then a single, double and triple indirect one
+pub const DIRECT_BLOCKS: usize = 12;
+pub const INDIRECT: usize = 12;
+pub const DOUBLE_INDIRECT: usize = 13;
+pub const TRIPLE_INDIRECT: usize = 14;
+pub const BLOCK_POINTERS: usize = 15;
+
+/// Size of the block pointers, which hold the target of a fast symbolic link instead
+pub const FAST_SYMLINK_SIZE: usize = BLOCK_POINTERS * 4;
+
+/// Size of the inodes of revision 0 filesystems, and of the fields every inode has
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Flag of directories indexed by a hash tree, which this driver does not keep up to date
+pub const INDEX_FL: u32 = 0x1000;
+
+const MODE: usize = 0;
+const UID: usize = 2;
+const SIZE: usize = 4;
+const ATIME: usize = 8;
+const CTIME: usize = 12;
+const MTIME: usize = 16;
+const DTIME: usize = 20;
+const GID: usize = 24;
+const LINKS: usize = 26;
+const BLOCKS: usize = 28;
+const FLAGS: usize = 32;
+const BLOCK: usize = 40;
+const FILE_ACL: usize = 104;
+const SIZE_HIGH: usize = 108;
+const UID_HIGH: usize = 120;
+const GID_HIGH: usize = 122;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Inode {
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    /// An inode of `size` bytes with no blocks and one link
+    pub fn new(size: usize, mode: u16, uid: u32, gid: u32, time: u32) -> Inode {
+        let mut inode = Inode { raw: vec![0; size] };
+        inode.set_mode(mode);
+        inode.set_uid(uid);
+        inode.set_gid(gid);
+        inode.set_atime(time);
+        inode.set_ctime(time);
+        inode.set_mtime(time);
+        inode.set_links(1);
+        inode
+    }
+
+    pub fn parse(raw: Vec<u8>) -> Inode {
+        Inode { raw }
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn mode(&self) -> u16 {
+        read_u16(&self.raw, MODE)
+    }
+
+    pub fn set_mode(&mut self, mode: u16) {
+        write_u16(&mut self.raw, MODE, mode);
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_FILE
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_SYMLINK
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from(read_u16(&self.raw, UID)) | u32::from(read_u16(&self.raw, UID_HIGH)) << 16
+    }
+
+    pub fn set_uid(&mut self, uid: u32) {
+        write_u16(&mut self.raw, UID, uid as u16);
+        write_u16(&mut self.raw, UID_HIGH, (uid >> 16) as u16);
+    }
+
+    pub fn gid(&self) -> u32 {
+        u32::from(read_u16(&self.raw, GID)) | u32::from(read_u16(&self.raw, GID_HIGH)) << 16
+    }
+
+    pub fn set_gid(&mut self, gid: u32) {
+        write_u16(&mut self.raw, GID, gid as u16);
+        write_u16(&mut self.raw, GID_HIGH, (gid >> 16) as u16);
+    }
+
+    /// Size in bytes. The high half is only stored for regular files, directories use its field
+    /// for something else.
+    pub fn size(&self) -> u64 {
+        let low = u64::from(read_u32(&self.raw, SIZE));
+        if self.is_file() {
+            low | u64::from(read_u32(&self.raw, SIZE_HIGH)) << 32
+        } else {
+            low
+        }
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        write_u32(&mut self.raw, SIZE, size as u32);
+        if self.is_file() {
+            write_u32(&mut self.raw, SIZE_HIGH, (size >> 32) as u32);
+        }
+    }
+
+    pub fn atime(&self) -> u32 {
+        read_u32(&self.raw, ATIME)
+    }
+
+    pub fn set_atime(&mut self, time: u32) {
+        write_u32(&mut self.raw, ATIME, time);
+    }
+
+    pub fn ctime(&self) -> u32 {
+        read_u32(&self.raw, CTIME)
+    }
+
+    pub fn set_ctime(&mut self, time: u32) {
+        write_u32(&mut self.raw, CTIME, time);
+    }
+
+    pub fn mtime(&self) -> u32 {
+        read_u32(&self.raw, MTIME)
+    }
+
+    pub fn set_mtime(&mut self, time: u32) {
+        write_u32(&mut self.raw, MTIME, time);
+    }
+
+    /// Set the deletion time, which marks the inode unused for `e2fsck`
+    pub fn set_dtime(&mut self, time: u32) {
+        write_u32(&mut self.raw, DTIME, time);
+    }
+
+    pub fn links(&self) -> u16 {
+        read_u16(&self.raw, LINKS)
+    }
+
+    pub fn set_links(&mut self, links: u16) {
+        write_u16(&mut self.raw, LINKS, links);
+    }
+
+    /// Space used by the inode in 512 byte sectors, including indirect blocks
+    pub fn sectors(&self) -> u32 {
+        read_u32(&self.raw, BLOCKS)
+    }
+
+    pub fn set_sectors(&mut self, sectors: u32) {
+        write_u32(&mut self.raw, BLOCKS, sectors);
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(&self.raw, FLAGS)
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        write_u32(&mut self.raw, FLAGS, flags);
+    }
+
+    /// Block pointer `index`, from 0 to `BLOCK_POINTERS`
+    pub fn block(&self, index: usize) -> u32 {
+        read_u32(&self.raw, BLOCK + index * 4)
+    }
+
+    pub fn set_block(&mut self, index: usize, block: u32) {
+        write_u32(&mut self.raw, BLOCK + index * 4, block);
+    }
+
+    /// Whether this is a symbolic link with its target in place of the block pointers. Such
+    /// links use no blocks but for extended attributes.
+    pub fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let acl_sectors = if read_u32(&self.raw, FILE_ACL) != 0 { block_size / 512 } else { 0 };
+        self.is_symlink() && u64::from(self.sectors()) == acl_sectors
+    }
+
+    /// The block pointer area, holding the target of a fast symbolic link
+    pub fn inline_data(&self) -> &[u8] {
+        &self.raw[BLOCK..BLOCK + FAST_SYMLINK_SIZE]
+    }
+
+    pub fn inline_data_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[BLOCK..BLOCK + FAST_SYMLINK_SIZE]
+    }
+}
+
+impl Permissions for Inode {
+    fn mode(&self) -> u16 {
+        Inode::mode(self)
+    }
+
+    fn uid(&self) -> u32 {
+        Inode::uid(self)
+    }
+
+    fn gid(&self) -> u32 {
+        Inode::gid(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let mut inode = Inode::new(256, MODE_FILE | 0o644, 70_000, 100, 1_560_601_842);
+        inode.set_size(5 << 32 | 17);
+        assert_eq!((inode.uid(), inode.gid()), (70_000, 100));
+        assert_eq!(inode.size(), 5 << 32 | 17);
+        assert_eq!(read_u16(inode.raw(), UID_HIGH), 1);
+
+        // Directories keep something else in the high half of the size
+        let mut dir = Inode::new(128, MODE_DIR | 0o755, 0, 0, 0);
+        write_u32(&mut dir.raw, SIZE_HIGH, 7);
+        dir.set_size(1024);
+        assert_eq!(dir.size(), 1024);
+        assert_eq!(read_u32(dir.raw(), SIZE_HIGH), 7);
+
+        let link = Inode::new(128, MODE_SYMLINK | 0o777, 0, 0, 0);
+        assert!(link.is_fast_symlink(1024));
+    }
+}
@@ -0,0 +1,1220 @@
+//! # ext2
+//!
+//! The second extended filesystem, as Linux and `mke2fs` write it. Volumes whose only
+//! incompatible feature is the file type in directory entries are supported; those with
+//! read-only compatible features unknown here are served read-only. Everything goes through a
+//! `BlockCache`, so changes reach the device on `sync`.
+use alloc::vec::Vec;
+use core::cmp;
+
+use crate::block::{BlockCache, BlockDevice};
+use crate::fs::{read_u16, read_u32, write_u16, write_u32};
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::time;
+
+use self::inode::*;
+
+pub mod inode;
+
+/// Inode of the root directory
+pub const ROOT: u32 = 2;
+
+/// Longest name of a directory entry
+pub const MAX_NAME: usize = 255;
+
+/// Sectors kept in the cache of a volume
+const CACHE_SECTORS: usize = 1024;
+
+/// Byte offset and size of the superblock, whatever the block size
+const SUPERBLOCK: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+/// Offsets of superblock fields written back
+const SB_FREE_BLOCKS: u64 = 12;
+const SB_RO_COMPAT: u64 = 100;
+
+/// Directory entries hold the type of their file
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Backups of the superblock are only in some groups
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Regular files may be 2 GiB or larger
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+/// Size of a block group descriptor
+const GROUP_DESC_SIZE: u64 = 32;
+
+/// Size of the fixed part of a directory entry
+const ENTRY_HEADER: usize = 8;
+
+/// File types of directory entries
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_FIFO: u8 = 5;
+const FT_SYMLINK: u8 = 7;
+
+fn now() -> u32 {
+    time::realtime().0 as u32
+}
+
+/// Space taken by a directory entry with a name of `len` bytes
+fn entry_len(len: usize) -> usize {
+    (ENTRY_HEADER + len + 3) & !3
+}
+
+/// The directory entry file type of a file with `mode`
+fn file_type(mode: u16) -> u8 {
+    match mode & MODE_TYPE {
+        MODE_FILE => FT_REG_FILE,
+        MODE_DIR => FT_DIR,
+        MODE_CHR => FT_CHRDEV,
+        MODE_FIFO => FT_FIFO,
+        MODE_SYMLINK => FT_SYMLINK,
+        _ => 0,
+    }
+}
+
+/// Whether `name` can be the name of a new entry
+fn check_name(name: &[u8]) -> Result<()> {
+    if name.len() > MAX_NAME {
+        return Err(Error::new(ENAMETOOLONG));
+    }
+    if name.is_empty() || name == b"." || name == b".." || name.iter().any(|&b| b == b'/' || b == 0) {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(())
+}
+
+/// A block group: its bitmaps, inode table and counters
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    dirs: u16,
+}
+
+/// An entry of a directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+    pub name: Vec<u8>,
+    pub inode: u32,
+}
+
+pub struct FileSystem<D> {
+    cache: BlockCache<D>,
+    block_size: u64,
+    blocks: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    revision: u32,
+    /// Whether directory entries hold file types
+    filetype: bool,
+    ro_compat: u32,
+    read_only: bool,
+    reserved_blocks: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    groups: Vec<Group>,
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Open the volume on `device`, failing with `EINVAL` if it does not hold one this driver
+    /// can use
+    pub fn new(device: D) -> Result<FileSystem<D>> {
+        let mut cache = BlockCache::new(device, CACHE_SECTORS);
+        if cache.size() < SUPERBLOCK + SUPERBLOCK_SIZE as u64 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        cache.read_at(SUPERBLOCK, &mut sb)?;
+        if read_u16(&sb, 56) != MAGIC {
+            return Err(Error::new(EINVAL));
+        }
+
+        let inodes = read_u32(&sb, 0);
+        let blocks = read_u32(&sb, 4);
+        let first_data_block = read_u32(&sb, 20);
+        let log_block_size = read_u32(&sb, 24);
+        let blocks_per_group = read_u32(&sb, 32);
+        let inodes_per_group = read_u32(&sb, 40);
+        let revision = read_u32(&sb, 76);
+        let (inode_size, incompat, ro_compat) = if revision == 0 {
+            (GOOD_OLD_INODE_SIZE, 0, 0)
+        } else {
+            (usize::from(read_u16(&sb, 88)), read_u32(&sb, 96), read_u32(&sb, 100))
+        };
+
+        // Larger blocks encode directory entry lengths differently
+        if log_block_size > 2 {
+            return Err(Error::new(EINVAL));
+        }
+        let block_size = 1024 << log_block_size;
+
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || u64::from(blocks_per_group) > block_size * 8
+            || u64::from(inodes_per_group) > block_size * 8
+            || blocks <= first_data_block
+            || u64::from(blocks) * block_size > cache.size()
+            || !inode_size.is_power_of_two()
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || inode_size as u64 > block_size
+        {
+            return Err(Error::new(EINVAL));
+        }
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let count = (blocks - first_data_block + blocks_per_group - 1) / blocks_per_group;
+        if u64::from(count) * u64::from(inodes_per_group) < u64::from(inodes) {
+            return Err(Error::new(EINVAL));
+        }
+
+        // The descriptors follow the block holding the superblock
+        let table = u64::from(first_data_block + 1) * block_size;
+        let mut raw = vec![0; count as usize * GROUP_DESC_SIZE as usize];
+        cache.read_at(table, &mut raw)?;
+        let mut groups = Vec::new();
+        for desc in raw.chunks(GROUP_DESC_SIZE as usize) {
+            let group = Group {
+                block_bitmap: read_u32(desc, 0),
+                inode_bitmap: read_u32(desc, 4),
+                inode_table: read_u32(desc, 8),
+                free_blocks: read_u16(desc, 12),
+                free_inodes: read_u16(desc, 14),
+                dirs: read_u16(desc, 16),
+            };
+            let table_blocks = (u64::from(inodes_per_group) * inode_size as u64 + block_size - 1) / block_size;
+            if group.block_bitmap >= blocks
+                || group.inode_bitmap >= blocks
+                || u64::from(group.inode_table) + table_blocks > u64::from(blocks)
+            {
+                return Err(Error::new(EINVAL));
+            }
+            groups.push(group);
+        }
+
+        Ok(FileSystem {
+            cache,
+            block_size,
+            blocks,
+            first_data_block,
+            blocks_per_group,
+            inodes,
+            inodes_per_group,
+            inode_size,
+            revision,
+            filetype: incompat & INCOMPAT_FILETYPE == INCOMPAT_FILETYPE,
+            ro_compat,
+            read_only: ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0,
+            reserved_blocks: read_u32(&sb, 8),
+            free_blocks: read_u32(&sb, 12),
+            free_inodes: read_u32(&sb, 16),
+            groups,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Number of blocks, including those holding metadata
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        self.free_blocks
+    }
+
+    /// Blocks only root may use. This driver does not keep them from others.
+    pub fn reserved_blocks(&self) -> u32 {
+        self.reserved_blocks
+    }
+
+    pub fn free_inodes(&self) -> u32 {
+        self.free_inodes
+    }
+
+    /// Whether the volume has features that keep it from being written
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Write all changes to the device
+    pub fn sync(&mut self) -> Result<()> {
+        self.cache.sync()
+    }
+
+    /// Write all changes and return the device
+    pub fn into_inner(self) -> Result<D> {
+        self.cache.into_inner()
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(Error::new(EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    /* Blocks and groups */
+
+    fn is_block(&self, block: u32) -> bool {
+        block >= self.first_data_block && block < self.blocks
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        u64::from(block) * self.block_size
+    }
+
+    fn read_block(&mut self, block: u32) -> Result<Vec<u8>> {
+        if !self.is_block(block) {
+            return Err(Error::new(EIO));
+        }
+        let mut raw = vec![0; self.block_size as usize];
+        self.cache.read_at(self.block_offset(block), &mut raw)?;
+        Ok(raw)
+    }
+
+    fn write_block(&mut self, block: u32, raw: &[u8]) -> Result<()> {
+        if !self.is_block(block) {
+            return Err(Error::new(EIO));
+        }
+        self.cache.write_at(self.block_offset(block), raw)
+    }
+
+    /// The group holding inode `ino`, where its blocks are best allocated
+    fn group_of(&self, ino: u32) -> usize {
+        (ino.saturating_sub(1) / self.inodes_per_group) as usize
+    }
+
+    /// Write the counters of `group`, and those of the superblock
+    fn put_group(&mut self, group: usize) -> Result<()> {
+        let mut raw = [0; 6];
+        write_u16(&mut raw, 0, self.groups[group].free_blocks);
+        write_u16(&mut raw, 2, self.groups[group].free_inodes);
+        write_u16(&mut raw, 4, self.groups[group].dirs);
+        let table = self.block_offset(self.first_data_block + 1);
+        self.cache.write_at(table + group as u64 * GROUP_DESC_SIZE + 12, &raw)?;
+
+        let mut counts = [0; 8];
+        write_u32(&mut counts, 0, self.free_blocks);
+        write_u32(&mut counts, 4, self.free_inodes);
+        self.cache.write_at(SUPERBLOCK + SB_FREE_BLOCKS, &counts)
+    }
+
+    /// Set the first clear bit below `count` in the bitmap at `block`, returning its index
+    fn take_bit(&mut self, block: u32, count: u32) -> Result<Option<u32>> {
+        let bitmap = self.read_block(block)?;
+        for i in 0..count {
+            let byte = bitmap[i as usize / 8];
+            let bit = 1 << (i % 8);
+            if byte & bit == 0 {
+                self.cache.write_at(self.block_offset(block) + u64::from(i / 8), &[byte | bit])?;
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Clear bit `index` of the bitmap at `block`, failing if it was clear already
+    fn clear_bit(&mut self, block: u32, index: u32) -> Result<()> {
+        if !self.is_block(block) {
+            return Err(Error::new(EIO));
+        }
+        let offset = self.block_offset(block) + u64::from(index / 8);
+        let mut byte = [0];
+        self.cache.read_at(offset, &mut byte)?;
+        let bit = 1 << (index % 8);
+        if byte[0] & bit == 0 {
+            return Err(Error::new(EIO));
+        }
+        self.cache.write_at(offset, &[byte[0] & !bit])
+    }
+
+    /// Take a free block, preferably in group `goal`, and fill it with zeros
+    fn alloc_block(&mut self, goal: usize) -> Result<u32> {
+        self.writable()?;
+        for n in 0..self.groups.len() {
+            let group = (goal + n) % self.groups.len();
+            if self.groups[group].free_blocks == 0 {
+                continue;
+            }
+
+            let first = self.first_data_block + group as u32 * self.blocks_per_group;
+            let count = cmp::min(self.blocks_per_group, self.blocks - first);
+            if let Some(index) = self.take_bit(self.groups[group].block_bitmap, count)? {
+                self.groups[group].free_blocks -= 1;
+                self.free_blocks = self.free_blocks.saturating_sub(1);
+                self.put_group(group)?;
+
+                let block = first + index;
+                self.cache.zero_at(self.block_offset(block), self.block_size)?;
+                return Ok(block);
+            }
+        }
+        Err(Error::new(ENOSPC))
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<()> {
+        if !self.is_block(block) {
+            return Err(Error::new(EIO));
+        }
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let index = (block - self.first_data_block) % self.blocks_per_group;
+        self.clear_bit(self.groups[group].block_bitmap, index)?;
+
+        self.groups[group].free_blocks += 1;
+        self.free_blocks += 1;
+        self.put_group(group)
+    }
+
+    /// Take a free inode, preferably in group `goal`
+    fn alloc_inode(&mut self, goal: usize, dir: bool) -> Result<u32> {
+        self.writable()?;
+        for n in 0..self.groups.len() {
+            let group = (goal + n) % self.groups.len();
+            if self.groups[group].free_inodes == 0 {
+                continue;
+            }
+
+            if let Some(index) = self.take_bit(self.groups[group].inode_bitmap, self.inodes_per_group)? {
+                let ino = group as u32 * self.inodes_per_group + index + 1;
+                if ino > self.inodes {
+                    return Err(Error::new(EIO));
+                }
+                self.groups[group].free_inodes -= 1;
+                if dir {
+                    self.groups[group].dirs += 1;
+                }
+                self.free_inodes = self.free_inodes.saturating_sub(1);
+                self.put_group(group)?;
+                return Ok(ino);
+            }
+        }
+        Err(Error::new(ENOSPC))
+    }
+
+    fn free_inode(&mut self, ino: u32, dir: bool) -> Result<()> {
+        let group = self.group_of(ino);
+        let index = (ino - 1) % self.inodes_per_group;
+        self.clear_bit(self.groups[group].inode_bitmap, index)?;
+
+        self.groups[group].free_inodes += 1;
+        if dir {
+            self.groups[group].dirs = self.groups[group].dirs.saturating_sub(1);
+        }
+        self.free_inodes += 1;
+        self.put_group(group)
+    }
+
+    /* Inodes */
+
+    fn inode_offset(&self, ino: u32) -> Result<u64> {
+        if ino == 0 || ino > self.inodes {
+            return Err(Error::new(EIO));
+        }
+        let table = self.groups[self.group_of(ino)].inode_table;
+        let index = (ino - 1) % self.inodes_per_group;
+        Ok(self.block_offset(table) + u64::from(index) * self.inode_size as u64)
+    }
+
+    pub fn inode(&mut self, ino: u32) -> Result<Inode> {
+        let offset = self.inode_offset(ino)?;
+        let mut raw = vec![0; self.inode_size];
+        self.cache.read_at(offset, &mut raw)?;
+        Ok(Inode::parse(raw))
+    }
+
+    fn put_inode(&mut self, ino: u32, inode: &Inode) -> Result<()> {
+        let offset = self.inode_offset(ino)?;
+        self.cache.write_at(offset, inode.raw())
+    }
+
+    /// Change inode `ino` with `f`
+    pub fn update<F: FnOnce(&mut Inode)>(&mut self, ino: u32, f: F) -> Result<()> {
+        self.writable()?;
+        let mut inode = self.inode(ino)?;
+        f(&mut inode);
+        self.put_inode(ino, &inode)
+    }
+
+    fn add_links(&mut self, ino: u32, delta: i32) -> Result<()> {
+        let mut inode = self.inode(ino)?;
+        let links = cmp::max(i32::from(inode.links()) + delta, 0);
+        inode.set_links(links as u16);
+        self.put_inode(ino, &inode)
+    }
+
+    /// Free inode `ino` and its blocks if no directory entry refers to it anymore
+    pub fn release(&mut self, ino: u32) -> Result<()> {
+        let mut inode = self.inode(ino)?;
+        if inode.links() != 0 {
+            return Ok(());
+        }
+        self.writable()?;
+
+        if !inode.is_fast_symlink(self.block_size) {
+            self.free_blocks_from(&mut inode, 0)?;
+        }
+        inode.set_size(0);
+        inode.set_dtime(now());
+        self.put_inode(ino, &inode)?;
+        self.free_inode(ino, inode.is_dir())
+    }
+
+    /* Block pointers */
+
+    /// Block pointers in an indirect block
+    fn pointers(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    fn add_sectors(&self, inode: &mut Inode, blocks: i64) {
+        let sectors = i64::from(inode.sectors()) + blocks * (self.block_size / 512) as i64;
+        inode.set_sectors(cmp::max(sectors, 0) as u32);
+    }
+
+    /// The block pointer of the inode leading to data block `index` of a file, and the index
+    /// to follow in each indirect block after it
+    fn block_path(&self, index: u64) -> Result<(usize, Vec<u64>)> {
+        let p = self.pointers();
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, Vec::new()));
+        }
+        let index = index - DIRECT_BLOCKS as u64;
+        if index < p {
+            return Ok((INDIRECT, vec![index]));
+        }
+        let index = index - p;
+        if index < p * p {
+            return Ok((DOUBLE_INDIRECT, vec![index / p, index % p]));
+        }
+        let index = index - p * p;
+        if index < p * p * p {
+            return Ok((TRIPLE_INDIRECT, vec![index / (p * p), index / p % p, index % p]));
+        }
+        Err(Error::new(EFBIG))
+    }
+
+    /// The device block holding data block `index` of `inode`, or zero for a hole. With a
+    /// `goal` group, missing blocks are allocated instead, and `inode` has to be written back
+    /// even if that fails.
+    fn map(&mut self, inode: &mut Inode, index: u64, goal: Option<usize>) -> Result<u32> {
+        let (slot, path) = self.block_path(index)?;
+
+        let mut block = inode.block(slot);
+        if block == 0 {
+            match goal {
+                Some(goal) => {
+                    block = self.alloc_block(goal)?;
+                    inode.set_block(slot, block);
+                    self.add_sectors(inode, 1);
+                },
+                None => return Ok(0),
+            }
+        }
+
+        for i in path {
+            if !self.is_block(block) {
+                return Err(Error::new(EIO));
+            }
+            let offset = self.block_offset(block) + i * 4;
+            let mut raw = [0; 4];
+            self.cache.read_at(offset, &mut raw)?;
+
+            block = read_u32(&raw, 0);
+            if block == 0 {
+                match goal {
+                    Some(goal) => {
+                        block = self.alloc_block(goal)?;
+                        self.cache.write_at(offset, &block.to_le_bytes())?;
+                        self.add_sectors(inode, 1);
+                    },
+                    None => return Ok(0),
+                }
+            }
+        }
+
+        if !self.is_block(block) {
+            return Err(Error::new(EIO));
+        }
+        Ok(block)
+    }
+
+    /// Free the data blocks from index `keep` on, and the indirect blocks left empty
+    fn free_blocks_from(&mut self, inode: &mut Inode, keep: u64) -> Result<()> {
+        for slot in keep as usize..DIRECT_BLOCKS {
+            let block = inode.block(slot);
+            if block != 0 {
+                self.free_block(block)?;
+                self.add_sectors(inode, -1);
+                inode.set_block(slot, 0);
+            }
+        }
+
+        let mut start = DIRECT_BLOCKS as u64;
+        let mut span = self.pointers();
+        for &(slot, depth) in &[(INDIRECT, 1), (DOUBLE_INDIRECT, 2), (TRIPLE_INDIRECT, 3)] {
+            let block = inode.block(slot);
+            if block != 0 && self.free_tree(inode, block, depth, keep.saturating_sub(start))? {
+                inode.set_block(slot, 0);
+            }
+            start += span;
+            span *= self.pointers();
+        }
+        Ok(())
+    }
+
+    /// Free the data blocks from index `keep` on below the indirect block `block`, which has
+    /// `depth` levels of blocks below it. Returns whether `block` itself was freed.
+    fn free_tree(&mut self, inode: &mut Inode, block: u32, depth: u32, keep: u64) -> Result<bool> {
+        let raw = self.read_block(block)?;
+        let span = self.pointers().pow(depth - 1);
+
+        let mut empty = true;
+        for i in 0..self.pointers() {
+            let child = read_u32(&raw, i as usize * 4);
+            if child == 0 {
+                continue;
+            }
+
+            let start = i * span;
+            let freed = if start + span <= keep {
+                false
+            } else if depth == 1 {
+                self.free_block(child)?;
+                self.add_sectors(inode, -1);
+                true
+            } else {
+                self.free_tree(inode, child, depth - 1, keep.saturating_sub(start))?
+            };
+
+            if freed {
+                self.cache.write_at(self.block_offset(block) + i * 4, &[0; 4])?;
+            } else {
+                empty = false;
+            }
+        }
+
+        if empty {
+            self.free_block(block)?;
+            self.add_sectors(inode, -1);
+        }
+        Ok(empty)
+    }
+
+    /* File contents */
+
+    /// Fail if a file cannot be `size` bytes long, and mark the volume as having large files
+    /// if it becomes one
+    fn check_size(&mut self, inode: &Inode, size: u64) -> Result<()> {
+        if size <= 0x7FFF_FFFF {
+            return Ok(());
+        }
+        if !inode.is_file() || self.revision == 0 || size > u64::from(u32::max_value()) * self.block_size {
+            return Err(Error::new(EFBIG));
+        }
+        if self.ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+            self.ro_compat |= RO_COMPAT_LARGE_FILE;
+            self.cache.write_at(SUPERBLOCK + SB_RO_COMPAT, &self.ro_compat.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Read from byte `offset` of the file `inode` into `buf`
+    pub fn read(&mut self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = cmp::min(buf.len() as u64, size - offset) as usize;
+
+        if inode.is_fast_symlink(self.block_size) {
+            let data = inode.inline_data();
+            let end = cmp::min(offset as usize + count, data.len());
+            let count = end.saturating_sub(offset as usize);
+            buf[..count].copy_from_slice(&data[offset as usize..end]);
+            return Ok(count);
+        }
+
+        let mut inode = inode.clone();
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let part = cmp::min((self.block_size - within) as usize, count - done);
+
+            match self.map(&mut inode, position / self.block_size, None)? {
+                0 => {
+                    for b in &mut buf[done..done + part] {
+                        *b = 0;
+                    }
+                },
+                block => self.cache.read_at(self.block_offset(block) + within, &mut buf[done..done + part])?,
+            }
+            done += part;
+        }
+        Ok(done)
+    }
+
+    /// Write `buf` at byte `offset` of the data blocks of `inode`, allocating them as needed
+    fn write_blocks(&mut self, goal: usize, inode: &mut Inode, offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position % self.block_size;
+            let part = cmp::min((self.block_size - within) as usize, buf.len() - done);
+
+            let block = match self.map(inode, position / self.block_size, Some(goal)) {
+                Ok(block) => block,
+                Err(err) => {
+                    if done == 0 {
+                        return Err(err);
+                    }
+                    break;
+                }
+            };
+            self.cache.write_at(self.block_offset(block) + within, &buf[done..done + part])?;
+            done += part;
+        }
+
+        let end = offset + done as u64;
+        if end > inode.size() {
+            inode.set_size(end);
+        }
+        Ok(done)
+    }
+
+    /// Write `buf` at byte `offset` of the file or symbolic link `ino`
+    pub fn write(&mut self, ino: u32, offset: u64, buf: &[u8]) -> Result<usize> {
+        self.writable()?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::new(EFBIG))?;
+        self.check_size(&inode, end)?;
+
+        let goal = self.group_of(ino);
+        let result = if inode.is_fast_symlink(self.block_size) {
+            let len = cmp::min(inode.size(), FAST_SYMLINK_SIZE as u64) as usize;
+            let mut data = inode.inline_data()[..len].to_vec();
+            if data.len() < end as usize {
+                data.resize(end as usize, 0);
+            }
+            data[offset as usize..end as usize].copy_from_slice(buf);
+
+            if data.len() <= FAST_SYMLINK_SIZE {
+                inode.inline_data_mut()[..data.len()].copy_from_slice(&data);
+                inode.set_size(data.len() as u64);
+                Ok(buf.len())
+            } else {
+                // Too long to stay in the inode, the whole target moves to a block
+                for b in inode.inline_data_mut() {
+                    *b = 0;
+                }
+                inode.set_size(0);
+                self.write_blocks(goal, &mut inode, 0, &data).map(|_| buf.len())
+            }
+        } else {
+            self.write_blocks(goal, &mut inode, offset, buf)
+        };
+
+        let time = now();
+        inode.set_mtime(time);
+        inode.set_ctime(time);
+        self.put_inode(ino, &inode)?;
+        result
+    }
+
+    /// Truncate or extend the file `ino` to `len` bytes. Bytes added at the end read as zeros.
+    pub fn truncate(&mut self, ino: u32, len: u64) -> Result<()> {
+        self.writable()?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        self.check_size(&inode, len)?;
+
+        let size = inode.size();
+        if inode.is_fast_symlink(self.block_size) {
+            if len > FAST_SYMLINK_SIZE as u64 {
+                return Err(Error::new(EINVAL));
+            }
+            for b in &mut inode.inline_data_mut()[len as usize..] {
+                *b = 0;
+            }
+        } else if len < size {
+            // The rest of the last block has to read as zeros if the file grows again
+            let within = len % self.block_size;
+            if within != 0 {
+                let block = self.map(&mut inode, len / self.block_size, None)?;
+                if block != 0 {
+                    self.cache.zero_at(self.block_offset(block) + within, self.block_size - within)?;
+                }
+            }
+            let keep = (len + self.block_size - 1) / self.block_size;
+            self.free_blocks_from(&mut inode, keep)?;
+        }
+
+        let time = now();
+        inode.set_size(len);
+        inode.set_mtime(time);
+        inode.set_ctime(time);
+        self.put_inode(ino, &inode)
+    }
+
+    /* Directories */
+
+    /// The blocks of directory `inode`, in order
+    fn dir_blocks(&mut self, inode: &Inode) -> Result<Vec<u32>> {
+        if !inode.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        let mut inode = inode.clone();
+        let mut blocks = Vec::new();
+        for index in 0..inode.size() / self.block_size {
+            match self.map(&mut inode, index, None)? {
+                0 => return Err(Error::new(EIO)),
+                block => blocks.push(block),
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// The inode, length and name of the directory entry at `pos` of a directory block
+    fn parse_entry<'a>(&self, raw: &'a [u8], pos: usize) -> Result<(u32, usize, &'a [u8])> {
+        if pos + ENTRY_HEADER > raw.len() {
+            return Err(Error::new(EIO));
+        }
+        let ino = read_u32(raw, pos);
+        let rec_len = usize::from(read_u16(raw, pos + 4));
+        let name_len = if self.filetype {
+            usize::from(raw[pos + 6])
+        } else {
+            usize::from(read_u16(raw, pos + 6))
+        };
+        if rec_len < ENTRY_HEADER || rec_len % 4 != 0 || pos + rec_len > raw.len() || ENTRY_HEADER + name_len > rec_len {
+            return Err(Error::new(EIO));
+        }
+        Ok((ino, rec_len, &raw[pos + ENTRY_HEADER..pos + ENTRY_HEADER + name_len]))
+    }
+
+    fn put_entry(&self, raw: &mut [u8], pos: usize, ino: u32, rec_len: usize, name: &[u8], mode: u16) {
+        write_u32(raw, pos, ino);
+        write_u16(raw, pos + 4, rec_len as u16);
+        if self.filetype {
+            raw[pos + 6] = name.len() as u8;
+            raw[pos + 7] = file_type(mode);
+        } else {
+            write_u16(raw, pos + 6, name.len() as u16);
+        }
+        raw[pos + ENTRY_HEADER..pos + ENTRY_HEADER + name.len()].copy_from_slice(name);
+        for b in &mut raw[pos + ENTRY_HEADER + name.len()..pos + entry_len(name.len())] {
+            *b = 0;
+        }
+    }
+
+    /// All entries of directory `dir`, with `.` and `..`
+    fn read_dir(&mut self, dir: u32) -> Result<Vec<DirEntry>> {
+        let inode = self.inode(dir)?;
+        let mut entries = Vec::new();
+        for block in self.dir_blocks(&inode)? {
+            let raw = self.read_block(block)?;
+            let mut pos = 0;
+            while pos < raw.len() {
+                let (ino, rec_len, name) = self.parse_entry(&raw, pos)?;
+                if ino != 0 {
+                    entries.push(DirEntry {
+                        name: name.to_vec(),
+                        inode: ino,
+                    });
+                }
+                pos += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The entries of directory `dir`, without `.` and `..`
+    pub fn list(&mut self, dir: u32) -> Result<Vec<DirEntry>> {
+        let mut entries = self.read_dir(dir)?;
+        entries.retain(|entry| entry.name != b"." && entry.name != b"..");
+        Ok(entries)
+    }
+
+    /// The inode of the entry named `name` in directory `dir`
+    pub fn find(&mut self, dir: u32, name: &[u8]) -> Result<Option<u32>> {
+        Ok(self.read_dir(dir)?.into_iter().find(|entry| entry.name == name).map(|entry| entry.inode))
+    }
+
+    /// Mark directory `dir` changed. Its hash tree index, if any, no longer matches the entries.
+    fn touch_dir(&mut self, dir: u32, inode: &mut Inode) -> Result<()> {
+        let time = now();
+        inode.set_mtime(time);
+        inode.set_ctime(time);
+        inode.set_flags(inode.flags() & !INDEX_FL);
+        self.put_inode(dir, inode)
+    }
+
+    /// Add an entry for inode `ino` with `mode` to directory `dir`
+    fn add_entry(&mut self, dir: u32, name: &[u8], ino: u32, mode: u16) -> Result<()> {
+        let mut inode = self.inode(dir)?;
+        let needed = entry_len(name.len());
+
+        for block in self.dir_blocks(&inode)? {
+            let mut raw = self.read_block(block)?;
+            let mut pos = 0;
+            while pos < raw.len() {
+                let (other, rec_len, other_name) = self.parse_entry(&raw, pos)?;
+                // An entry may have room after its name, or be unused altogether
+                let used = if other == 0 { 0 } else { entry_len(other_name.len()) };
+                if rec_len - used >= needed {
+                    if used != 0 {
+                        write_u16(&mut raw, pos + 4, used as u16);
+                    }
+                    self.put_entry(&mut raw, pos + used, ino, rec_len - used, name, mode);
+                    self.write_block(block, &raw)?;
+                    return self.touch_dir(dir, &mut inode);
+                }
+                pos += rec_len;
+            }
+        }
+
+        // No room left, so the entry gets a new block of its own
+        let index = inode.size() / self.block_size;
+        let goal = self.group_of(dir);
+        let block = match self.map(&mut inode, index, Some(goal)) {
+            Ok(block) => block,
+            Err(err) => {
+                self.put_inode(dir, &inode)?;
+                return Err(err);
+            }
+        };
+        let mut raw = vec![0; self.block_size as usize];
+        self.put_entry(&mut raw, 0, ino, self.block_size as usize, name, mode);
+        self.write_block(block, &raw)?;
+        inode.set_size((index + 1) * self.block_size);
+        self.touch_dir(dir, &mut inode)
+    }
+
+    /// Remove the entry named `name` from directory `dir`, returning its inode
+    fn remove_entry(&mut self, dir: u32, name: &[u8]) -> Result<u32> {
+        let mut inode = self.inode(dir)?;
+        for block in self.dir_blocks(&inode)? {
+            let mut raw = self.read_block(block)?;
+            let mut prev = None;
+            let mut pos = 0;
+            while pos < raw.len() {
+                let (ino, rec_len, other_name) = self.parse_entry(&raw, pos)?;
+                if ino != 0 && other_name == name {
+                    // The previous entry takes over the space, the first one of a block is
+                    // marked unused instead
+                    match prev {
+                        Some(prev) => {
+                            let prev_len = usize::from(read_u16(&raw, prev + 4));
+                            write_u16(&mut raw, prev + 4, (prev_len + rec_len) as u16);
+                        },
+                        None => write_u32(&mut raw, pos, 0),
+                    }
+                    self.write_block(block, &raw)?;
+                    self.touch_dir(dir, &mut inode)?;
+                    return Ok(ino);
+                }
+                prev = Some(pos);
+                pos += rec_len;
+            }
+        }
+        Err(Error::new(ENOENT))
+    }
+
+    /// Point the entry named `name` in directory `dir` at inode `ino` with `mode`
+    fn retarget(&mut self, dir: u32, name: &[u8], ino: u32, mode: u16) -> Result<()> {
+        let inode = self.inode(dir)?;
+        for block in self.dir_blocks(&inode)? {
+            let mut raw = self.read_block(block)?;
+            let mut pos = 0;
+            while pos < raw.len() {
+                let (other, rec_len, other_name) = self.parse_entry(&raw, pos)?;
+                if other != 0 && other_name == name {
+                    write_u32(&mut raw, pos, ino);
+                    if self.filetype {
+                        raw[pos + 7] = file_type(mode);
+                    }
+                    return self.write_block(block, &raw);
+                }
+                pos += rec_len;
+            }
+        }
+        Err(Error::new(ENOENT))
+    }
+
+    /// Create an inode with `mode` named `name` in directory `dir`. Directories get `.` and
+    /// `..`, other files are empty.
+    pub fn create(&mut self, dir: u32, name: &[u8], mode: u16, uid: u32, gid: u32) -> Result<u32> {
+        self.writable()?;
+        check_name(name)?;
+        if self.find(dir, name)?.is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let is_dir = mode & MODE_TYPE == MODE_DIR;
+        let ino = self.alloc_inode(self.group_of(dir), is_dir)?;
+        let mut inode = Inode::new(self.inode_size, mode, uid, gid, now());
+
+        if is_dir {
+            let block = match self.map(&mut inode, 0, Some(self.group_of(ino))) {
+                Ok(block) => block,
+                Err(err) => {
+                    self.free_inode(ino, true)?;
+                    return Err(err);
+                }
+            };
+            let mut raw = vec![0; self.block_size as usize];
+            self.put_entry(&mut raw, 0, ino, entry_len(1), b".", MODE_DIR);
+            self.put_entry(&mut raw, entry_len(1), dir, self.block_size as usize - entry_len(1), b"..", MODE_DIR);
+            self.write_block(block, &raw)?;
+            inode.set_size(self.block_size);
+            inode.set_links(2);
+        }
+        self.put_inode(ino, &inode)?;
+
+        if let Err(err) = self.add_entry(dir, name, ino, mode) {
+            inode.set_links(0);
+            self.put_inode(ino, &inode)?;
+            self.release(ino)?;
+            return Err(err);
+        }
+        if is_dir {
+            self.add_links(dir, 1)?;
+        }
+        Ok(ino)
+    }
+
+    /// Remove the entry `name` of directory `dir`, a file or an empty directory, and return its
+    /// inode. The inode stays until `release` once no entry refers to it.
+    pub fn unlink(&mut self, dir: u32, name: &[u8]) -> Result<u32> {
+        self.writable()?;
+        let ino = self.find(dir, name)?.ok_or(Error::new(ENOENT))?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() && !self.list(ino)?.is_empty() {
+            return Err(Error::new(ENOTEMPTY));
+        }
+
+        self.remove_entry(dir, name)?;
+        if inode.is_dir() {
+            inode.set_links(0);
+            self.add_links(dir, -1)?;
+        } else {
+            inode.set_links(inode.links().saturating_sub(1));
+        }
+        inode.set_ctime(now());
+        self.put_inode(ino, &inode)?;
+        Ok(ino)
+    }
+
+    /// Move the entry `from_name` of directory `from` to `to_name` in `to`, replacing a file or
+    /// an empty directory of the same kind there. Returns the inode of the replaced entry,
+    /// which stays until `release`.
+    pub fn rename(&mut self, from: u32, from_name: &[u8], to: u32, to_name: &[u8]) -> Result<Option<u32>> {
+        self.writable()?;
+        check_name(to_name)?;
+        let ino = self.find(from, from_name)?.ok_or(Error::new(ENOENT))?;
+        let inode = self.inode(ino)?;
+
+        let existing = self.find(to, to_name)?;
+        if existing == Some(ino) {
+            return Ok(None);
+        }
+
+        // A directory cannot move below itself
+        if inode.is_dir() {
+            let mut dir = to;
+            while dir != ROOT {
+                if dir == ino {
+                    return Err(Error::new(EINVAL));
+                }
+                dir = self.find(dir, b"..")?.ok_or(Error::new(EIO))?;
+            }
+        }
+
+        let replaced = match existing {
+            Some(other) => {
+                let mut target = self.inode(other)?;
+                match (inode.is_dir(), target.is_dir()) {
+                    (true, false) => return Err(Error::new(ENOTDIR)),
+                    (false, true) => return Err(Error::new(EISDIR)),
+                    _ => (),
+                }
+                if target.is_dir() && !self.list(other)?.is_empty() {
+                    return Err(Error::new(ENOTEMPTY));
+                }
+
+                self.retarget(to, to_name, ino, inode.mode())?;
+                if target.is_dir() {
+                    target.set_links(0);
+                    self.add_links(to, -1)?;
+                } else {
+                    target.set_links(target.links().saturating_sub(1));
+                }
+                target.set_ctime(now());
+                self.put_inode(other, &target)?;
+                Some(other)
+            },
+            None => {
+                self.add_entry(to, to_name, ino, inode.mode())?;
+                None
+            }
+        };
+        self.remove_entry(from, from_name)?;
+
+        // A directory that changed parent has to point `..` at the new one
+        if inode.is_dir() && from != to {
+            self.retarget(ino, b"..", to, MODE_DIR)?;
+            self.add_links(from, -1)?;
+            self.add_links(to, 1)?;
+        }
+
+        let mut inode = self.inode(ino)?;
+        inode.set_ctime(now());
+        self.put_inode(ino, &inode)?;
+        Ok(replaced)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    /// A 256 KiB volume with 1 KiB blocks made by `mke2fs`, holding `hello.txt`, `docs/big.bin`
+    /// of 20000 bytes, the symbolic link `link` to it and `private`, owned by user 1000
+    fn volume() -> FileSystem<RamDisk> {
+        let image = include_bytes!("../../../tests/images/ext2.img");
+        FileSystem::new(RamDisk::new(image.to_vec())).unwrap()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_image() {
+        let mut fs = volume();
+        assert_eq!(fs.block_size(), 1024);
+        assert!(!fs.read_only());
+
+        let names: Vec<Vec<u8>> = fs.list(ROOT).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, vec![
+            b"lost+found".to_vec(), b"docs".to_vec(), b"hello.txt".to_vec(), b"link".to_vec(), b"private".to_vec(),
+        ]);
+
+        let hello = fs.find(ROOT, b"hello.txt").unwrap().unwrap();
+        let inode = fs.inode(hello).unwrap();
+        let mut buf = vec![0; 32];
+        let count = fs.read(&inode, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"Hello from ext2!\n");
+
+        // Past the direct blocks, through the single indirect one
+        let docs = fs.find(ROOT, b"docs").unwrap().unwrap();
+        let big = fs.find(docs, b"big.bin").unwrap().unwrap();
+        let inode = fs.inode(big).unwrap();
+        let mut buf = vec![0; 20_000];
+        assert_eq!(fs.read(&inode, 0, &mut buf), Ok(20_000));
+        assert_eq!(buf, pattern(20_000));
+
+        let link = fs.find(ROOT, b"link").unwrap().unwrap();
+        let link = fs.inode(link).unwrap();
+        assert!(link.is_fast_symlink(fs.block_size()));
+        let count = fs.read(&link, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"docs/big.bin");
+
+        let private = fs.find(ROOT, b"private").unwrap().unwrap();
+        let private = fs.inode(private).unwrap();
+        assert_eq!((private.mode(), private.uid(), private.gid()), (MODE_FILE | 0o600, 1000, 100));
+    }
+
+    #[test]
+    fn grow_and_truncate() {
+        let mut fs = volume();
+        let free = fs.free_blocks();
+
+        let ino = fs.create(ROOT, b"sparse", MODE_FILE | 0o644, 0, 0).unwrap();
+        // Data block 300 is behind the double indirect block
+        assert_eq!(fs.write(ino, 300 * 1024 + 1000, b"far away"), Ok(8));
+        assert_eq!(fs.free_blocks(), free - 3);
+        assert_eq!(fs.write(ino, 0, &pattern(3000)), Ok(3000));
+        assert_eq!(fs.free_blocks(), free - 6);
+
+        let inode = fs.inode(ino).unwrap();
+        assert_eq!(inode.size(), 300 * 1024 + 1008);
+        assert_eq!(inode.sectors(), 6 * 2);
+        let mut buf = vec![0xFF; 4000];
+        fs.read(&inode, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..3000], &pattern(3000)[..]);
+        assert!(buf[3000..].iter().all(|&b| b == 0));
+
+        fs.truncate(ino, 10).unwrap();
+        assert_eq!(fs.free_blocks(), free - 1);
+        fs.truncate(ino, 2000).unwrap();
+        let inode = fs.inode(ino).unwrap();
+        fs.read(&inode, 0, &mut buf[..2000]).unwrap();
+        assert!(buf[10..2000].iter().all(|&b| b == 0));
+
+        // Everything is still there after going through the device
+        let mut fs = FileSystem::new(fs.into_inner().unwrap()).unwrap();
+        assert_eq!(fs.inode(ino).unwrap().size(), 2000);
+        let free_inodes = fs.free_inodes();
+        assert_eq!(fs.unlink(ROOT, b"sparse"), Ok(ino));
+        fs.release(ino).unwrap();
+        assert_eq!((fs.free_blocks(), fs.free_inodes()), (free, free_inodes + 1));
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = volume();
+        let links = fs.inode(ROOT).unwrap().links();
+
+        let sub = fs.create(ROOT, b"sub", MODE_DIR | 0o755, 0, 0).unwrap();
+        assert_eq!(fs.inode(ROOT).unwrap().links(), links + 1);
+        // Names long enough to need a second block
+        let name = |i| format!("{} {}", "a file with a long name".repeat(4), i).into_bytes();
+        for i in 0..12 {
+            fs.create(sub, &name(i), MODE_FILE | 0o644, 0, 0).unwrap();
+        }
+        assert_eq!(fs.list(sub).unwrap().len(), 12);
+        assert_eq!(fs.inode(sub).unwrap().size(), 2048);
+        assert_eq!(fs.create(sub, &name(3), MODE_FILE, 0, 0), Err(Error::new(EEXIST)));
+        assert_eq!(fs.unlink(ROOT, b"sub"), Err(Error::new(ENOTEMPTY)));
+
+        assert_eq!(fs.rename(sub, &name(7), ROOT, b"moved"), Ok(None));
+        assert_eq!(fs.find(sub, &name(7)), Ok(None));
+
+        let inner = fs.create(sub, b"inner", MODE_DIR | 0o755, 0, 0).unwrap();
+        assert_eq!(fs.rename(ROOT, b"sub", inner, b"loop"), Err(Error::new(EINVAL)));
+        fs.rename(sub, b"inner", ROOT, b"outer").unwrap();
+        assert_eq!(fs.find(inner, b".."), Ok(Some(ROOT)));
+        assert_eq!(fs.inode(ROOT).unwrap().links(), links + 2);
+        assert_eq!(fs.inode(sub).unwrap().links(), 2);
+
+        for entry in fs.list(sub).unwrap() {
+            fs.unlink(sub, &entry.name).unwrap();
+            fs.release(entry.inode).unwrap();
+        }
+        fs.unlink(ROOT, b"sub").unwrap();
+        fs.release(sub).unwrap();
+        assert_eq!(fs.inode(ROOT).unwrap().links(), links + 1);
+    }
+}
@@ -0,0 +1,368 @@
+//! Directory entries, long file names and timestamps as FAT stores them
+use alloc::vec::Vec;
+use core::char;
+
+use crate::common::calendar::DateTime;
+use crate::fs::{read_u16, read_u32, write_u16, write_u32};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes marking a long name entry
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// Size of a directory entry in bytes
+pub const ENTRY_SIZE: usize = 32;
+
+/// First byte of a deleted entry
+pub const DELETED: u8 = 0xE5;
+/// Flag in the order byte of the long name entry holding the end of the name
+pub const LAST_LONG: u8 = 0x40;
+/// UCS-2 characters in one long name entry
+pub const LONG_CHARS: usize = 13;
+/// Longest file name in UCS-2 characters
+pub const MAX_NAME: usize = 255;
+
+/// Flags telling that the short name is shown in lower case, as Windows NT writes them
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// Offsets of the name characters within a long name entry
+const LONG_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// A short directory entry, which holds everything about a file but its long name
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Name and extension, padded with spaces
+    pub short: [u8; 11],
+    pub attr: u8,
+    /// The `LOWER_*` flags
+    pub case: u8,
+    /// First cluster, zero for an empty file
+    pub cluster: u32,
+    pub size: u32,
+    /// Creation date and time
+    pub created: (u16, u16),
+    pub accessed: u16,
+    /// Modification date and time
+    pub modified: (u16, u16),
+}
+
+impl Entry {
+    /// A new entry, created and modified at `secs` since the Unix epoch
+    pub fn new(short: [u8; 11], case: u8, attr: u8, cluster: u32, secs: u64) -> Entry {
+        let (date, time) = fat_time(secs);
+        Entry {
+            short,
+            attr,
+            case,
+            cluster,
+            size: 0,
+            created: (date, time),
+            accessed: date,
+            modified: (date, time),
+        }
+    }
+
+    pub fn parse(raw: &[u8]) -> Entry {
+        let mut short = [0; 11];
+        short.copy_from_slice(&raw[..11]);
+        Entry {
+            short,
+            attr: raw[11],
+            case: raw[12],
+            cluster: u32::from(read_u16(raw, 20)) << 16 | u32::from(read_u16(raw, 26)),
+            size: read_u32(raw, 28),
+            created: (read_u16(raw, 16), read_u16(raw, 14)),
+            accessed: read_u16(raw, 18),
+            modified: (read_u16(raw, 24), read_u16(raw, 22)),
+        }
+    }
+
+    pub fn serialize(&self) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0; ENTRY_SIZE];
+        raw[..11].copy_from_slice(&self.short);
+        raw[11] = self.attr;
+        raw[12] = self.case;
+        write_u16(&mut raw, 14, self.created.1);
+        write_u16(&mut raw, 16, self.created.0);
+        write_u16(&mut raw, 18, self.accessed);
+        write_u16(&mut raw, 20, (self.cluster >> 16) as u16);
+        write_u16(&mut raw, 22, self.modified.1);
+        write_u16(&mut raw, 24, self.modified.0);
+        write_u16(&mut raw, 26, self.cluster as u16);
+        write_u32(&mut raw, 28, self.size);
+        raw
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY == ATTR_DIRECTORY
+    }
+
+    /// The short name as `NAME.EXT`, in lower case where the case flags ask for it
+    pub fn short_name(&self) -> Vec<u8> {
+        let mut name: Vec<u8> = self.short[..8].to_vec();
+        while name.last() == Some(&b' ') {
+            name.pop();
+        }
+        // 0x05 stands for a name starting with 0xE5
+        if name.first() == Some(&0x05) {
+            name[0] = DELETED;
+        }
+        if self.case & LOWER_BASE == LOWER_BASE {
+            name.make_ascii_lowercase();
+        }
+
+        let mut ext: Vec<u8> = self.short[8..].to_vec();
+        while ext.last() == Some(&b' ') {
+            ext.pop();
+        }
+        if self.case & LOWER_EXT == LOWER_EXT {
+            ext.make_ascii_lowercase();
+        }
+
+        if !ext.is_empty() {
+            name.push(b'.');
+            name.extend_from_slice(&ext);
+        }
+        name
+    }
+}
+
+/// Checksum of a short name, stored in its long name entries
+pub fn checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// The order byte, checksum and characters of a long name entry
+pub fn parse_long(raw: &[u8]) -> (u8, u8, [u16; LONG_CHARS]) {
+    let mut chars = [0; LONG_CHARS];
+    for (i, &offset) in LONG_OFFSETS.iter().enumerate() {
+        chars[i] = read_u16(raw, offset);
+    }
+    (raw[0], raw[13], chars)
+}
+
+/// The long name entries of `name`, in the order they are stored before its short entry
+pub fn long_entries(name: &[u16], checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let count = (name.len() + LONG_CHARS - 1) / LONG_CHARS;
+    let mut entries = Vec::with_capacity(count);
+    for ord in (1..=count).rev() {
+        let mut raw = [0; ENTRY_SIZE];
+        raw[0] = ord as u8 | if ord == count { LAST_LONG } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        for (i, &offset) in LONG_OFFSETS.iter().enumerate() {
+            let index = (ord - 1) * LONG_CHARS + i;
+            // The name ends with a zero if there is room for it, the rest is padding
+            let c = if index < name.len() {
+                name[index]
+            } else if index == name.len() {
+                0
+            } else {
+                0xFFFF
+            };
+            write_u16(&mut raw, offset, c);
+        }
+        entries.push(raw);
+    }
+    entries
+}
+
+/// The UTF-8 name in the characters of long name entries
+pub fn decode_long(chars: &[u16]) -> Vec<u8> {
+    let end = chars.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(chars.len());
+    let mut name = Vec::new();
+    for c in char::decode_utf16(chars[..end].iter().cloned()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let mut buf = [0; 4];
+        name.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    name
+}
+
+/// The UTF-16 characters of a name, if it is valid for a long name
+pub fn encode_long(name: &[u8]) -> Option<Vec<u16>> {
+    let name = core::str::from_utf8(name).ok()?;
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    if name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return None;
+    }
+    // Trailing dots and spaces are dropped by other systems, which would make the name differ
+    if name.ends_with('.') || name.ends_with(' ') {
+        return None;
+    }
+    let chars: Vec<u16> = name.encode_utf16().collect();
+    if chars.len() > MAX_NAME {
+        return None;
+    }
+    Some(chars)
+}
+
+fn short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c) || c >= 0x80
+}
+
+/// The short name and case flags of `name` if it can be stored without a long name: a base of up
+/// to eight and an extension of up to three characters, each all upper or all lower case
+pub fn short_exact(name: &[u8]) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.iter().position(|&b| b == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &name[name.len()..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (ext.is_empty() && base.len() < name.len()) {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for &(part, start, flag) in &[(base, 0, LOWER_BASE), (ext, 8, LOWER_EXT)] {
+        let lower = part.iter().any(u8::is_ascii_lowercase);
+        if lower && part.iter().any(u8::is_ascii_uppercase) {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+        for (i, &b) in part.iter().enumerate() {
+            let b = b.to_ascii_uppercase();
+            if b >= 0x80 || !short_char(b) {
+                return None;
+            }
+            short[start + i] = b;
+        }
+    }
+    Some((short, case))
+}
+
+/// The `n`th numbered short name for `name`, like `LONGFI~1.TXT`
+pub fn short_alias(name: &[u8], n: u32) -> [u8; 11] {
+    let clean = |part: &[u8]| -> Vec<u8> {
+        part.iter()
+            .filter(|&&b| b != b' ' && b != b'.')
+            .map(|&b| {
+                let b = b.to_ascii_uppercase();
+                if b < 0x80 && short_char(b) { b } else { b'_' }
+            })
+            .collect()
+    };
+
+    let name = {
+        let start = name.iter().position(|&b| b != b'.').unwrap_or(name.len());
+        &name[start..]
+    };
+    let (base, ext) = match name.iter().rposition(|&b| b == b'.') {
+        Some(dot) => (clean(&name[..dot]), clean(&name[dot + 1..])),
+        None => (clean(name), Vec::new()),
+    };
+
+    let mut tail = vec![b'~'];
+    let mut digits = Vec::new();
+    let mut rest = n;
+    loop {
+        digits.push(b'0' + (rest % 10) as u8);
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    tail.extend(digits.iter().rev());
+
+    let mut short = [b' '; 11];
+    let base_len = core::cmp::min(base.len(), 8 - tail.len());
+    short[..base_len].copy_from_slice(&base[..base_len]);
+    short[base_len..base_len + tail.len()].copy_from_slice(&tail);
+    let ext_len = core::cmp::min(ext.len(), 3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    short
+}
+
+/// Whether two names are the same, as FAT ignores case
+pub fn names_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.eq_ignore_ascii_case(b) {
+        return true;
+    }
+    match (core::str::from_utf8(a), core::str::from_utf8(b)) {
+        (Ok(a), Ok(b)) => a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase)),
+        _ => false,
+    }
+}
+
+/// The FAT date and time of `secs` since the Unix epoch, clamped to the years FAT can store
+pub fn fat_time(secs: u64) -> (u16, u16) {
+    let time = DateTime::from_unix(secs as i64, 0);
+    if time.year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    if time.year > 2107 {
+        return (127 << 9 | 12 << 5 | 31, 23 << 11 | 59 << 5 | 29);
+    }
+    let date = ((time.year - 1980) as u16) << 9 | u16::from(time.month) << 5 | u16::from(time.day);
+    let time = u16::from(time.hour) << 11 | u16::from(time.minute) << 5 | u16::from(time.second / 2);
+    (date, time)
+}
+
+/// Seconds since the Unix epoch of a FAT date and time
+pub fn unix_time(date: u16, time: u16) -> u64 {
+    let year = 1980 + i64::from(date >> 9);
+    let month = ((date >> 5) & 0xF) as u8;
+    let day = (date & 0x1F) as u8;
+    let hour = (time >> 11) as u8;
+    let minute = ((time >> 5) & 0x3F) as u8;
+    let second = ((time & 0x1F) * 2) as u8;
+    DateTime::new(year, month, day, hour, minute, second)
+        .or_else(|| DateTime::new(year, 1, 1, 0, 0, 0))
+        .map_or(0, |time| time.to_unix() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_exact(b"README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(short_exact(b"readme.txt"), Some((*b"README  TXT", LOWER_BASE | LOWER_EXT)));
+        assert_eq!(short_exact(b"ReadMe.txt"), None);
+        assert_eq!(short_exact(b"a.b.c"), None);
+        assert_eq!(short_exact(b"toolongname"), None);
+        assert_eq!(&short_alias(b"Long File Name.text", 1), b"LONGFI~1TEX");
+        assert_eq!(&short_alias(b".profile", 12), b"PROFI~12   ");
+
+        let entry = Entry::new(*b"README  TXT", LOWER_BASE | LOWER_EXT, 0, 0, 0);
+        assert_eq!(entry.short_name(), b"readme.txt".to_vec());
+    }
+
+    #[test]
+    fn long_names() {
+        let name = encode_long("Ünïcode file name.txt".as_bytes()).unwrap();
+        let sum = checksum(b"NCODEF~1TXT");
+        let entries = long_entries(&name, sum);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0][0], LAST_LONG | 2);
+
+        let mut chars = Vec::new();
+        for raw in entries.iter().rev() {
+            let (_, entry_sum, part) = parse_long(raw);
+            assert_eq!(entry_sum, sum);
+            chars.extend_from_slice(&part);
+        }
+        assert_eq!(decode_long(&chars), "Ünïcode file name.txt".as_bytes().to_vec());
+        assert!(encode_long(b"a/b").is_none());
+        assert!(names_equal("ünï".as_bytes(), "ÜNÏ".as_bytes()));
+    }
+
+    #[test]
+    fn timestamps() {
+        // 2019-06-15 12:30:42 UTC
+        let (date, time) = fat_time(1_560_601_842);
+        assert_eq!(date, 39 << 9 | 6 << 5 | 15);
+        assert_eq!(unix_time(date, time), 1_560_601_842);
+        assert_eq!(unix_time(fat_time(0).0, 0), 315_532_800);
+    }
+}
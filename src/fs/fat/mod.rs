@@ -0,0 +1,876 @@
+//! # FAT
+//!
+//! FAT12, FAT16 and FAT32 volumes with long file names. The kind of a volume follows from its
+//! number of clusters, as the specification asks. Everything goes through a `BlockCache`, so
+//! changes reach the device on `sync`.
+use alloc::vec::Vec;
+use core::cmp;
+
+use crate::block::{BlockCache, BlockDevice, SECTOR_SIZE};
+use crate::fs::{read_u16, read_u32};
+use crate::syscall::error::*;
+use crate::time;
+
+use self::dir::*;
+
+pub mod dir;
+
+/// Sectors kept in the cache of a volume
+const CACHE_SECTORS: usize = 1024;
+
+/// Signatures of the FAT32 FSInfo sector
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatKind {
+    /// Smallest FAT value that ends a cluster chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatKind::Fat12 => 0xFF8,
+            FatKind::Fat16 => 0xFFF8,
+            FatKind::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+/// A directory: the fixed root directory of FAT12 and FAT16, or a cluster chain
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dir {
+    Root,
+    Cluster(u32),
+}
+
+/// A file or directory, found through a directory
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// The long name, or the short name if there is none
+    pub name: Vec<u8>,
+    pub entry: Entry,
+    /// Byte offset of the short entry on the device, which identifies the file
+    pub offset: u64,
+    /// Offsets of the long name entries and the short entry
+    slots: Vec<u64>,
+}
+
+fn now() -> u64 {
+    time::realtime().0
+}
+
+pub struct FileSystem<D> {
+    cache: BlockCache<D>,
+    kind: FatKind,
+    cluster_size: u64,
+    /// Byte offset and size of the first FAT
+    fat_start: u64,
+    fat_size: u64,
+    fats: u8,
+    /// Byte offset and number of entries of the fixed root directory
+    root_start: u64,
+    root_entries: u64,
+    /// First cluster of the root directory on FAT32
+    root_cluster: u32,
+    data_start: u64,
+    /// Number of data clusters, numbered from 2
+    clusters: u32,
+    /// Byte offset of the FAT32 FSInfo sector
+    fsinfo: Option<u64>,
+    /// Where to look for a free cluster first
+    next_free: u32,
+    /// Whether the FAT changed since the FSInfo sector was written
+    fat_changed: bool,
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Open the volume on `device`, failing with `EINVAL` if it does not hold one
+    pub fn new(device: D) -> Result<FileSystem<D>> {
+        let mut cache = BlockCache::new(device, CACHE_SECTORS);
+
+        let mut boot = [0; SECTOR_SIZE];
+        cache.read_at(0, &mut boot)?;
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(Error::new(EINVAL));
+        }
+
+        let bytes_per_sector = u64::from(read_u16(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved = u64::from(read_u16(&boot, 14));
+        let fats = boot[16];
+        let root_entries = u64::from(read_u16(&boot, 17));
+        let total = match read_u16(&boot, 19) {
+            0 => u64::from(read_u32(&boot, 32)),
+            total => u64::from(total),
+        };
+        let fat_sectors = match read_u16(&boot, 22) {
+            0 => u64::from(read_u32(&boot, 36)),
+            size => u64::from(size),
+        };
+
+        if ![512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err(Error::new(EINVAL));
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64 + bytes_per_sector - 1) / bytes_per_sector;
+        let meta_sectors = reserved + u64::from(fats) * fat_sectors + root_sectors;
+        if total <= meta_sectors || total * bytes_per_sector > cache.size() {
+            return Err(Error::new(EINVAL));
+        }
+        let clusters = (total - meta_sectors) / sectors_per_cluster;
+
+        let kind = if clusters < 4085 {
+            FatKind::Fat12
+        } else if clusters < 65525 {
+            FatKind::Fat16
+        } else {
+            FatKind::Fat32
+        };
+        if (kind == FatKind::Fat32) != (root_entries == 0) {
+            return Err(Error::new(EINVAL));
+        }
+
+        // The FAT has to have an entry for every cluster
+        let entry_bits = match kind {
+            FatKind::Fat12 => 12,
+            FatKind::Fat16 => 16,
+            FatKind::Fat32 => 32,
+        };
+        if (clusters + 2) * entry_bits > fat_sectors * bytes_per_sector * 8 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut fs = FileSystem {
+            cache,
+            kind,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_start: reserved * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            fats,
+            root_start: (reserved + u64::from(fats) * fat_sectors) * bytes_per_sector,
+            root_entries,
+            root_cluster: 0,
+            data_start: meta_sectors * bytes_per_sector,
+            clusters: clusters as u32,
+            fsinfo: None,
+            next_free: 2,
+            fat_changed: false,
+        };
+
+        if kind == FatKind::Fat32 {
+            fs.root_cluster = read_u32(&boot, 44);
+            if !fs.is_cluster(fs.root_cluster) {
+                return Err(Error::new(EINVAL));
+            }
+
+            let sector = u64::from(read_u16(&boot, 48));
+            if sector != 0 && sector < reserved {
+                let offset = sector * bytes_per_sector;
+                let mut info = [0; SECTOR_SIZE];
+                fs.cache.read_at(offset, &mut info)?;
+                if read_u32(&info, 0) == FSINFO_LEAD && read_u32(&info, 484) == FSINFO_STRUCT {
+                    fs.fsinfo = Some(offset);
+                    let next_free = read_u32(&info, 492);
+                    if fs.is_cluster(next_free) {
+                        fs.next_free = next_free;
+                    }
+                }
+            }
+        }
+
+        Ok(fs)
+    }
+
+    pub fn kind(&self) -> FatKind {
+        self.kind
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// Number of data clusters
+    pub fn clusters(&self) -> u32 {
+        self.clusters
+    }
+
+    pub fn root(&self) -> Dir {
+        match self.kind {
+            FatKind::Fat32 => Dir::Cluster(self.root_cluster),
+            _ => Dir::Root,
+        }
+    }
+
+    /// The directory held by `entry`
+    pub fn dir(&self, entry: &Entry) -> Result<Dir> {
+        if !entry.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        // Entries for the root, like `..` in its subdirectories, have no cluster
+        if entry.cluster == 0 {
+            return Ok(self.root());
+        }
+        Ok(Dir::Cluster(entry.cluster))
+    }
+
+    /// Write all changes to the device
+    pub fn sync(&mut self) -> Result<()> {
+        if self.fat_changed {
+            if let Some(offset) = self.fsinfo {
+                let free = self.free_clusters()?;
+                self.cache.write_at(offset + 488, &free.to_le_bytes())?;
+                self.cache.write_at(offset + 492, &self.next_free.to_le_bytes())?;
+            }
+            self.fat_changed = false;
+        }
+        self.cache.sync()
+    }
+
+    /// Write all changes and return the device
+    pub fn into_inner(mut self) -> Result<D> {
+        self.sync()?;
+        self.cache.into_inner()
+    }
+
+    /* The file allocation table */
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.clusters
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.cluster_size
+    }
+
+    /// The FAT entry of `cluster`
+    fn fat_get(&mut self, cluster: u32) -> Result<u32> {
+        let mut raw = [0; 4];
+        match self.kind {
+            FatKind::Fat12 => {
+                let offset = u64::from(cluster) * 3 / 2;
+                self.cache.read_at(self.fat_start + offset, &mut raw[..2])?;
+                let value = u32::from(read_u16(&raw, 0));
+                Ok(if cluster & 1 == 1 { value >> 4 } else { value & 0xFFF })
+            },
+            FatKind::Fat16 => {
+                self.cache.read_at(self.fat_start + u64::from(cluster) * 2, &mut raw[..2])?;
+                Ok(u32::from(read_u16(&raw, 0)))
+            },
+            FatKind::Fat32 => {
+                self.cache.read_at(self.fat_start + u64::from(cluster) * 4, &mut raw)?;
+                Ok(read_u32(&raw, 0) & 0x0FFF_FFFF)
+            },
+        }
+    }
+
+    /// Set the FAT entry of `cluster` in every copy of the FAT
+    fn fat_set(&mut self, cluster: u32, value: u32) -> Result<()> {
+        self.fat_changed = true;
+        for i in 0..u64::from(self.fats) {
+            let fat = self.fat_start + i * self.fat_size;
+            let mut raw = [0; 4];
+            match self.kind {
+                FatKind::Fat12 => {
+                    let offset = fat + u64::from(cluster) * 3 / 2;
+                    self.cache.read_at(offset, &mut raw[..2])?;
+                    let old = read_u16(&raw, 0);
+                    let value = value as u16 & 0xFFF;
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000F) | value << 4
+                    } else {
+                        (old & 0xF000) | value
+                    };
+                    self.cache.write_at(offset, &new.to_le_bytes())?;
+                },
+                FatKind::Fat16 => {
+                    self.cache.write_at(fat + u64::from(cluster) * 2, &(value as u16).to_le_bytes())?;
+                },
+                FatKind::Fat32 => {
+                    // The top four bits are reserved and kept
+                    let offset = fat + u64::from(cluster) * 4;
+                    self.cache.read_at(offset, &mut raw)?;
+                    let new = (read_u32(&raw, 0) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.cache.write_at(offset, &new.to_le_bytes())?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `start`, none if it is zero
+    fn chain(&mut self, start: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = start;
+        while cluster != 0 {
+            if !self.is_cluster(cluster) || chain.len() >= self.clusters as usize {
+                // Points outside the volume, or loops
+                return Err(Error::new(EIO));
+            }
+            chain.push(cluster);
+
+            let next = self.fat_get(cluster)?;
+            cluster = if next >= self.kind.end_of_chain() { 0 } else { next };
+            if next == 0 {
+                return Err(Error::new(EIO));
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Take a free cluster, fill it with zeros and end a chain with it
+    fn alloc_cluster(&mut self) -> Result<u32> {
+        for i in 0..self.clusters {
+            let cluster = 2 + (self.next_free - 2 + i) % self.clusters;
+            if self.fat_get(cluster)? == 0 {
+                self.fat_set(cluster, 0x0FFF_FFFF)?;
+                self.cache.zero_at(self.cluster_offset(cluster), self.cluster_size)?;
+                self.next_free = 2 + (cluster - 2 + 1) % self.clusters;
+                return Ok(cluster);
+            }
+        }
+        Err(Error::new(ENOSPC))
+    }
+
+    /// Mark the clusters of the chain starting at `start` free
+    fn free_chain(&mut self, start: u32) -> Result<()> {
+        for cluster in self.chain(start)? {
+            self.fat_set(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Number of free clusters
+    pub fn free_clusters(&mut self) -> Result<u32> {
+        let mut free = 0;
+        for cluster in 2..self.clusters + 2 {
+            if self.fat_get(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /* Directories */
+
+    /// Byte offsets of the entry slots of a directory
+    fn slots(&mut self, dir: Dir) -> Result<Vec<u64>> {
+        let per_cluster = self.cluster_size / ENTRY_SIZE as u64;
+        match dir {
+            Dir::Root => Ok((0..self.root_entries).map(|i| self.root_start + i * ENTRY_SIZE as u64).collect()),
+            Dir::Cluster(start) => {
+                let mut slots = Vec::new();
+                for cluster in self.chain(start)? {
+                    let offset = self.cluster_offset(cluster);
+                    slots.extend((0..per_cluster).map(|i| offset + i * ENTRY_SIZE as u64));
+                }
+                Ok(slots)
+            }
+        }
+    }
+
+    /// All entries of a directory, with `.` and `..` but without volume labels
+    fn read_dir(&mut self, dir: Dir) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+
+        // The long name being collected, from its last entry down to its first
+        let mut long: Vec<[u16; LONG_CHARS]> = Vec::new();
+        let mut long_slots = Vec::new();
+        let mut long_sum = 0;
+
+        for offset in self.slots(dir)? {
+            let mut raw = [0; ENTRY_SIZE];
+            self.cache.read_at(offset, &mut raw)?;
+
+            if raw[0] == 0 {
+                break;
+            }
+            if raw[0] == DELETED {
+                long.clear();
+                long_slots.clear();
+                continue;
+            }
+
+            if raw[11] & 0x3F == ATTR_LONG_NAME {
+                let (ord, sum, chars) = parse_long(&raw);
+                let index = (ord & !LAST_LONG) as usize;
+                if ord & LAST_LONG == LAST_LONG {
+                    long = vec![[0; LONG_CHARS]; index];
+                    long_slots.clear();
+                    long_sum = sum;
+                }
+                if index == 0 || index > long.len() || sum != long_sum || index != long.len() - long_slots.len() {
+                    long.clear();
+                    long_slots.clear();
+                    continue;
+                }
+                long[index - 1] = chars;
+                long_slots.push(offset);
+                continue;
+            }
+
+            let entry = Entry::parse(&raw);
+            if entry.attr & ATTR_VOLUME_ID == ATTR_VOLUME_ID {
+                long.clear();
+                long_slots.clear();
+                continue;
+            }
+
+            let complete = !long.is_empty() && long_slots.len() == long.len() && long_sum == checksum(&entry.short);
+            let name = if complete {
+                decode_long(&long.concat())
+            } else {
+                long_slots.clear();
+                entry.short_name()
+            };
+
+            let mut slots = core::mem::replace(&mut long_slots, Vec::new());
+            slots.push(offset);
+            long.clear();
+
+            entries.push(DirEntry {
+                name,
+                entry,
+                offset,
+                slots,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// The entries of a directory, without `.` and `..`
+    pub fn list(&mut self, dir: Dir) -> Result<Vec<DirEntry>> {
+        let mut entries = self.read_dir(dir)?;
+        entries.retain(|entry| entry.name != b"." && entry.name != b"..");
+        Ok(entries)
+    }
+
+    /// The entry named `name` in a directory
+    pub fn find(&mut self, dir: Dir, name: &[u8]) -> Result<Option<DirEntry>> {
+        Ok(self.list(dir)?.into_iter().find(|entry| {
+            names_equal(&entry.name, name) || names_equal(&entry.entry.short_name(), name)
+        }))
+    }
+
+    /// The entry `offset` identifies in a directory
+    pub fn find_offset(&mut self, dir: Dir, offset: u64) -> Result<DirEntry> {
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|entry| entry.offset == offset)
+            .ok_or(Error::new(ENOENT))
+    }
+
+    /// The short entry at byte `offset`
+    pub fn entry(&mut self, offset: u64) -> Result<Entry> {
+        let mut raw = [0; ENTRY_SIZE];
+        self.cache.read_at(offset, &mut raw)?;
+        if raw[0] == 0 || raw[0] == DELETED {
+            return Err(Error::new(ENOENT));
+        }
+        Ok(Entry::parse(&raw))
+    }
+
+    fn put_entry(&mut self, offset: u64, entry: &Entry) -> Result<()> {
+        self.cache.write_at(offset, &entry.serialize())
+    }
+
+    /// Offsets of `count` consecutive free slots in a directory, growing it if needed
+    fn free_slots(&mut self, dir: Dir, count: usize) -> Result<Vec<u64>> {
+        loop {
+            let slots = self.slots(dir)?;
+
+            let mut run = 0;
+            let mut end = false;
+            for (i, &offset) in slots.iter().enumerate() {
+                if !end {
+                    let mut first = [0];
+                    self.cache.read_at(offset, &mut first)?;
+                    // Everything after an entry starting with zero is free
+                    end = first[0] == 0;
+                    if !end && first[0] != DELETED {
+                        run = 0;
+                        continue;
+                    }
+                }
+                run += 1;
+                if run == count {
+                    return Ok(slots[i + 1 - count..=i].to_vec());
+                }
+            }
+
+            match dir {
+                Dir::Root => return Err(Error::new(ENOSPC)),
+                Dir::Cluster(start) => {
+                    let last = *self.chain(start)?.last().ok_or(Error::new(EIO))?;
+                    let cluster = self.alloc_cluster()?;
+                    self.fat_set(last, cluster)?;
+                }
+            }
+        }
+    }
+
+    /// Add `entry` to a directory under `name`, with a short name made up for it if needed
+    fn add_entry(&mut self, dir: Dir, name: &[u8], mut entry: Entry) -> Result<DirEntry> {
+        let long = encode_long(name).ok_or_else(|| {
+            Error::new(if name.len() > MAX_NAME { ENAMETOOLONG } else { EINVAL })
+        })?;
+
+        let existing = self.read_dir(dir)?;
+        if existing.iter().any(|other| names_equal(&other.name, name) || names_equal(&other.entry.short_name(), name)) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let long_entries = match short_exact(name) {
+            Some((short, case)) if !existing.iter().any(|other| other.entry.short == short) => {
+                entry.short = short;
+                entry.case = case;
+                Vec::new()
+            },
+            _ => {
+                let short = (1..1_000_000)
+                    .map(|n| short_alias(name, n))
+                    .find(|short| !existing.iter().any(|other| &other.entry.short == short))
+                    .ok_or(Error::new(EEXIST))?;
+                entry.short = short;
+                entry.case = 0;
+                long_entries(&long, checksum(&short))
+            }
+        };
+
+        let slots = self.free_slots(dir, long_entries.len() + 1)?;
+        for (raw, &offset) in long_entries.iter().zip(slots.iter()) {
+            self.cache.write_at(offset, raw)?;
+        }
+        let offset = *slots.last().unwrap();
+        self.put_entry(offset, &entry)?;
+
+        Ok(DirEntry {
+            name: name.to_vec(),
+            entry,
+            offset,
+            slots,
+        })
+    }
+
+    /// Mark the slots of `entry` free, leaving its clusters alone
+    fn remove_entry(&mut self, entry: &DirEntry) -> Result<()> {
+        for &offset in &entry.slots {
+            self.cache.write_at(offset, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Create an empty file, or a directory holding only `.` and `..`
+    pub fn create(&mut self, dir: Dir, name: &[u8], directory: bool) -> Result<DirEntry> {
+        let time = now();
+        if !directory {
+            return self.add_entry(dir, name, Entry::new([b' '; 11], 0, ATTR_ARCHIVE, 0, time));
+        }
+
+        let cluster = self.alloc_cluster()?;
+        let parent = match dir {
+            Dir::Cluster(parent) if dir != self.root() => parent,
+            _ => 0,
+        };
+
+        let offset = self.cluster_offset(cluster);
+        self.put_entry(offset, &Entry::new(*b".          ", 0, ATTR_DIRECTORY, cluster, time))?;
+        self.put_entry(offset + ENTRY_SIZE as u64, &Entry::new(*b"..         ", 0, ATTR_DIRECTORY, parent, time))?;
+
+        match self.add_entry(dir, name, Entry::new([b' '; 11], 0, ATTR_DIRECTORY, cluster, time)) {
+            Ok(entry) => Ok(entry),
+            Err(err) => {
+                self.free_chain(cluster)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Remove a file, or a directory if it is empty
+    pub fn remove(&mut self, entry: &DirEntry) -> Result<()> {
+        if entry.entry.is_dir() && !self.list(Dir::Cluster(entry.entry.cluster))?.is_empty() {
+            return Err(Error::new(ENOTEMPTY));
+        }
+        self.remove_entry(entry)?;
+        if entry.entry.cluster != 0 {
+            self.free_chain(entry.entry.cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Move `entry` from the directory `from` to `name` in `to`, replacing a file or an empty
+    /// directory of the same kind there. Returns the entry at its new place.
+    pub fn rename(&mut self, from: Dir, entry: &DirEntry, to: Dir, name: &[u8]) -> Result<DirEntry> {
+        if let Some(existing) = self.find(to, name)? {
+            if existing.offset == entry.offset {
+                if existing.name == name {
+                    return Ok(existing);
+                }
+                // Only the case changes, so the old entry goes first to make room for the name
+                self.remove_entry(entry)?;
+                return self.add_entry(to, name, entry.entry.clone());
+            }
+            match (entry.entry.is_dir(), existing.entry.is_dir()) {
+                (true, false) => return Err(Error::new(ENOTDIR)),
+                (false, true) => return Err(Error::new(EISDIR)),
+                _ => self.remove(&existing)?,
+            }
+        }
+
+        let moved = self.add_entry(to, name, entry.entry.clone())?;
+        self.remove_entry(entry)?;
+
+        // A directory that changed parent has to point `..` at the new one
+        if entry.entry.is_dir() && from != to {
+            let parent = match to {
+                Dir::Cluster(parent) if to != self.root() => parent,
+                _ => 0,
+            };
+            let offset = self.cluster_offset(entry.entry.cluster) + ENTRY_SIZE as u64;
+            let mut dotdot = self.entry(offset)?;
+            dotdot.cluster = parent;
+            self.put_entry(offset, &dotdot)?;
+        }
+
+        Ok(moved)
+    }
+
+    /* File contents */
+
+    /// Read from byte `offset` of the file `entry` into `buf`
+    pub fn read(&mut self, entry: &Entry, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let size = u64::from(entry.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = cmp::min(buf.len() as u64, size - offset) as usize;
+
+        let chain = self.chain(entry.cluster)?;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let cluster = *chain.get((position / self.cluster_size) as usize).ok_or(Error::new(EIO))?;
+            let within = position % self.cluster_size;
+            let part = cmp::min((self.cluster_size - within) as usize, count - done);
+
+            self.cache.read_at(self.cluster_offset(cluster) + within, &mut buf[done..done + part])?;
+            done += part;
+        }
+        Ok(done)
+    }
+
+    /// Make the file `entry` `len` bytes long, allocating or freeing clusters. Bytes added at the
+    /// end read as zeros.
+    fn set_len(&mut self, entry: &mut Entry, len: u64) -> Result<()> {
+        if len > u64::from(u32::max_value()) {
+            return Err(Error::new(EFBIG));
+        }
+
+        let needed = ((len + self.cluster_size - 1) / self.cluster_size) as usize;
+        let mut chain = self.chain(entry.cluster)?;
+
+        // New clusters come zeroed, the unused end of the last cluster may not be
+        let size = u64::from(entry.size);
+        if len > size && size % self.cluster_size != 0 {
+            if let Some(&last) = chain.get((size / self.cluster_size) as usize) {
+                let end = cmp::min(len, (size / self.cluster_size + 1) * self.cluster_size);
+                self.cache.zero_at(self.cluster_offset(last) + size % self.cluster_size, end - size)?;
+            }
+        }
+
+        while chain.len() < needed {
+            let cluster = match self.alloc_cluster() {
+                Ok(cluster) => cluster,
+                Err(err) => {
+                    // Keep what was allocated, so the entry still describes all of its clusters
+                    entry.size = cmp::min(len, chain.len() as u64 * self.cluster_size) as u32;
+                    return Err(err);
+                }
+            };
+            match chain.last() {
+                Some(&last) => self.fat_set(last, cluster)?,
+                None => entry.cluster = cluster,
+            }
+            chain.push(cluster);
+        }
+
+        if chain.len() > needed {
+            if needed == 0 {
+                self.free_chain(entry.cluster)?;
+                entry.cluster = 0;
+            } else {
+                self.fat_set(chain[needed - 1], 0x0FFF_FFFF)?;
+                self.free_chain(chain[needed])?;
+            }
+        }
+
+        entry.size = len as u32;
+        Ok(())
+    }
+
+    /// Write `buf` at byte `offset` of the file whose short entry is at `entry_offset`
+    pub fn write(&mut self, entry_offset: u64, offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut entry = self.entry(entry_offset)?;
+        if entry.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::new(EFBIG))?;
+        if end > u64::from(entry.size) {
+            let result = self.set_len(&mut entry, end);
+            if result.is_err() {
+                self.put_entry(entry_offset, &entry)?;
+            }
+            result?;
+        }
+
+        let chain = self.chain(entry.cluster)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let cluster = *chain.get((position / self.cluster_size) as usize).ok_or(Error::new(EIO))?;
+            let within = position % self.cluster_size;
+            let part = cmp::min((self.cluster_size - within) as usize, buf.len() - done);
+
+            self.cache.write_at(self.cluster_offset(cluster) + within, &buf[done..done + part])?;
+            done += part;
+        }
+
+        let (date, time) = fat_time(now());
+        entry.modified = (date, time);
+        entry.accessed = date;
+        entry.attr |= ATTR_ARCHIVE;
+        self.put_entry(entry_offset, &entry)?;
+
+        Ok(done)
+    }
+
+    /// Truncate or extend the file whose short entry is at `entry_offset`
+    pub fn truncate(&mut self, entry_offset: u64, len: u64) -> Result<()> {
+        let mut entry = self.entry(entry_offset)?;
+        if entry.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+
+        let result = self.set_len(&mut entry, len);
+        entry.modified = fat_time(now());
+        entry.attr |= ATTR_ARCHIVE;
+        self.put_entry(entry_offset, &entry)?;
+        result
+    }
+
+    /// Change the entry at `entry_offset` with `f`
+    pub fn update<F: FnOnce(&mut Entry)>(&mut self, entry_offset: u64, f: F) -> Result<()> {
+        let mut entry = self.entry(entry_offset)?;
+        f(&mut entry);
+        self.put_entry(entry_offset, &entry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    /// A 200 KiB FAT12 volume with one sector per cluster, holding `HELLO.TXT` and
+    /// `Long Directory Name/nested file.txt`
+    fn volume() -> FileSystem<RamDisk> {
+        let image = include_bytes!("../../../tests/images/fat12.img");
+        FileSystem::new(RamDisk::new(image.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn read_image() {
+        let mut fs = volume();
+        assert_eq!(fs.kind(), FatKind::Fat12);
+
+        let root = fs.root();
+        let names: Vec<Vec<u8>> = fs.list(root).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, vec![b"HELLO.TXT".to_vec(), b"Long Directory Name".to_vec()]);
+
+        let hello = fs.find(root, b"hello.txt").unwrap().unwrap();
+        let mut buf = [0; 64];
+        let count = fs.read(&hello.entry, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"Hello from FAT!\n");
+
+        let dir = fs.find(root, b"LONGDI~1").unwrap().unwrap();
+        let dir = fs.dir(&dir.entry).unwrap();
+        let nested = fs.find(dir, b"Nested File.TXT").unwrap().unwrap();
+        assert_eq!(nested.name, b"nested file.txt".to_vec());
+        assert_eq!(nested.entry.size, 1500);
+    }
+
+    #[test]
+    fn grow_and_truncate() {
+        let mut fs = volume();
+        let root = fs.root();
+        let free = fs.free_clusters().unwrap();
+
+        let file = fs.create(root, b"a file with a long name.bin", false).unwrap();
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        assert_eq!(fs.write(file.offset, 100, &data), Ok(3000));
+        assert_eq!(fs.free_clusters().unwrap(), free - 7);
+
+        let entry = fs.entry(file.offset).unwrap();
+        assert_eq!(entry.size, 3100);
+        let mut buf = vec![0xFF; 3100];
+        fs.read(&entry, 0, &mut buf).unwrap();
+        assert!(buf[..100].iter().all(|&b| b == 0));
+        assert_eq!(&buf[100..], &data[..]);
+
+        fs.truncate(file.offset, 10).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 1);
+        fs.truncate(file.offset, 600).unwrap();
+        let entry = fs.entry(file.offset).unwrap();
+        fs.read(&entry, 0, &mut buf[..600]).unwrap();
+        assert!(buf[10..600].iter().all(|&b| b == 0));
+
+        // Everything is still there after going through the device
+        let mut fs = FileSystem::new(fs.into_inner().unwrap()).unwrap();
+        let file = fs.find(root, b"A File With A Long Name.bin").unwrap().unwrap();
+        assert_eq!(file.entry.size, 600);
+        fs.remove(&file).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free);
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = volume();
+        let root = fs.root();
+
+        let dir = fs.create(root, b"sub", true).unwrap();
+        let sub = fs.dir(&dir.entry).unwrap();
+        // Enough long names to need more than one cluster
+        for i in 0..20 {
+            fs.create(sub, format!("file number {}", i).as_bytes(), false).unwrap();
+        }
+        assert_eq!(fs.list(sub).unwrap().len(), 20);
+        assert_eq!(fs.create(sub, b"FILE NUMBER 3", false).map(|_| ()), Err(Error::new(EEXIST)));
+        assert_eq!(fs.remove(&dir), Err(Error::new(ENOTEMPTY)));
+
+        let file = fs.find(sub, b"file number 7").unwrap().unwrap();
+        let moved = fs.rename(sub, &file, root, b"moved").unwrap();
+        assert!(fs.find(sub, b"file number 7").unwrap().is_none());
+        assert_eq!(fs.find(root, b"moved").unwrap().unwrap().offset, moved.offset);
+
+        let inner = fs.create(sub, b"inner", true).unwrap();
+        let outer = fs.rename(sub, &inner, root, b"outer").unwrap();
+        let outer = fs.dir(&outer.entry).unwrap();
+        let parent = fs.read_dir(outer).unwrap().into_iter().find(|entry| entry.name == b"..").unwrap();
+        assert_eq!(fs.dir(&parent.entry).unwrap(), root);
+
+        for entry in fs.list(sub).unwrap() {
+            fs.remove(&entry).unwrap();
+        }
+        fs.remove(&dir).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+//! # Filesystems
+//!
+//! On-disk formats, read and written through the block device layer. Schemes in `crate::scheme`
+//! serve them as files.
+
+/// ext2, as Linux writes it
+pub mod ext2;
+/// FAT12, FAT16 and FAT32
+pub mod fat;
+/// Owners and permission bits
+pub mod perm;
+
+/* Little-endian fields of on-disk structures */
+
+pub(crate) fn read_u16(raw: &[u8], offset: usize) -> u16 {
+    u16::from(raw[offset]) | u16::from(raw[offset + 1]) << 8
+}
+
+pub(crate) fn read_u32(raw: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(raw, offset)) | u32::from(read_u16(raw, offset + 2)) << 16
+}
+
+pub(crate) fn write_u16(raw: &mut [u8], offset: usize, value: u16) {
+    raw[offset] = value as u8;
+    raw[offset + 1] = (value >> 8) as u8;
+}
+
+pub(crate) fn write_u32(raw: &mut [u8], offset: usize, value: u32) {
+    write_u16(raw, offset, value as u16);
+    write_u16(raw, offset + 2, (value >> 16) as u16);
+}
@@ -0,0 +1,113 @@
+//! Owners, permission bits and the checks on them that the file system schemes share
+
+use crate::syscall::error::*;
+use crate::syscall::flag::{MODE_DIR, MODE_SYMLINK, MODE_TYPE, O_ACCMODE, O_DIRECTORY, O_RDONLY, O_RDWR, O_STAT, O_SYMLINK, O_TRUNC, O_WRONLY};
+
+/// Symbolic links followed by one lookup before it fails with `ELOOP`
+pub const MAX_LINKS: usize = 32;
+
+/// Permission bits, shifted to the owner, group or other position
+pub const PERM_READ: u16 = 0o4;
+pub const PERM_WRITE: u16 = 0o2;
+pub const PERM_EXEC: u16 = 0o1;
+
+/// A file with an owner and permission bits
+pub trait Permissions {
+    /// Type and permission bits, as in `Stat::st_mode`
+    fn mode(&self) -> u16;
+    fn uid(&self) -> u32;
+    fn gid(&self) -> u32;
+
+    fn is_dir(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_DIR
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode() & MODE_TYPE == MODE_SYMLINK
+    }
+
+    /// Whether `uid` and `gid` have all of the `PERM_*` bits in `perm`
+    fn permission(&self, uid: u32, gid: u32, perm: u16) -> bool {
+        if uid == 0 {
+            // Root may do anything, except execute what nobody may execute
+            return perm & PERM_EXEC == 0 || self.is_dir() || self.mode() & 0o111 != 0;
+        }
+        let bits = if uid == self.uid() {
+            self.mode() >> 6
+        } else if gid == self.gid() {
+            self.mode() >> 3
+        } else {
+            self.mode()
+        };
+        bits & perm == perm
+    }
+
+    /// Whether `uid` may change the owner, permissions and times of the file
+    fn owned_by(&self, uid: u32) -> bool {
+        uid == 0 || uid == self.uid()
+    }
+}
+
+/// Count a symbolic link followed by a lookup, which fails once there were too many
+pub fn follow_link(links: &mut usize) -> Result<()> {
+    *links += 1;
+    if *links > MAX_LINKS {
+        return Err(Error::new(ELOOP));
+    }
+    Ok(())
+}
+
+/// Check that `uid` and `gid` may open `file`, found by a lookup, with `flags`. Returns the flags
+/// of the handle, without the access mode for opens that only stat the file.
+pub fn check_open<F: Permissions>(file: &F, flags: usize, uid: u32, gid: u32) -> Result<usize> {
+    if file.is_symlink() && flags & O_SYMLINK != O_SYMLINK && flags & O_STAT != O_STAT {
+        // Only O_NOFOLLOW gets here with a link
+        return Err(Error::new(ELOOP));
+    }
+    if flags & O_DIRECTORY == O_DIRECTORY && !file.is_dir() {
+        return Err(Error::new(ENOTDIR));
+    }
+
+    let accmode = flags & O_ACCMODE;
+    let writable = accmode == O_WRONLY || accmode == O_RDWR;
+    // Stat needs no permission on the file, but the handle cannot read it then, and writing or
+    // truncating is checked as usual
+    if flags & O_STAT == O_STAT && !writable && flags & O_TRUNC != O_TRUNC {
+        return Ok(flags & !O_ACCMODE);
+    }
+
+    if file.is_dir() && writable {
+        return Err(Error::new(EISDIR));
+    }
+    if (accmode == O_RDONLY || accmode == O_RDWR) && !file.permission(uid, gid, PERM_READ) {
+        return Err(Error::new(EACCES));
+    }
+    if writable && !file.permission(uid, gid, PERM_WRITE) {
+        return Err(Error::new(EACCES));
+    }
+    Ok(flags)
+}
+
+/// Check that `uid` and `gid` may remove or replace `file`, an entry of the directory `dir`
+pub fn check_remove<D: Permissions, F: Permissions>(dir: &D, file: &F, uid: u32, gid: u32) -> Result<()> {
+    if !dir.permission(uid, gid, PERM_WRITE | PERM_EXEC) {
+        return Err(Error::new(EACCES));
+    }
+    // With the sticky bit, only owners may remove entries
+    if dir.mode() & 0o1000 != 0 && !dir.owned_by(uid) && !file.owned_by(uid) {
+        return Err(Error::new(EPERM));
+    }
+    Ok(())
+}
+
+/// Check that `uid`, in the group `gid`, may give `file` to `new_uid` and `new_gid`, where
+/// `u32::max_value()` keeps the current one. Owners may only give the file to their own group,
+/// root may give it to anyone.
+pub fn check_chown<F: Permissions>(file: &F, uid: u32, gid: u32, new_uid: u32, new_gid: u32) -> Result<()> {
+    let keep_uid = new_uid == u32::max_value() || new_uid == file.uid();
+    let own_gid = new_gid == u32::max_value() || new_gid == gid;
+    if uid != 0 && !(uid == file.uid() && keep_uid && own_gid) {
+        return Err(Error::new(EPERM));
+    }
+    Ok(())
+}
@@ -2,11 +2,24 @@ use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
+use core::sync::atomic::Ordering;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
 pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
+const IST_STACK_SIZE: usize = 4096;
+
+/// A stack exceptions arrive on through the IST, page aligned so that mapping it maps nothing else
+#[repr(align(4096))]
+struct Stack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut NMI_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+static mut MACHINE_CHECK_STACK: Stack = Stack([0; IST_STACK_SIZE]);
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
@@ -35,29 +48,44 @@ pub fn init() {
 
     GDT.0.load();
     unsafe {
-        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = VirtAddr::from_ptr(&DOUBLE_FAULT_STACK) + IST_STACK_SIZE;
+        TSS.interrupt_stack_table[NMI_IST_INDEX as usize] = VirtAddr::from_ptr(&NMI_STACK) + IST_STACK_SIZE;
+        TSS.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = VirtAddr::from_ptr(&MACHINE_CHECK_STACK) + IST_STACK_SIZE;
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
 
+/// Address and size of the GDT and the TSS, which the CPU reads when entering the kernel, and of
+/// the IST stacks, which it writes
+#[cfg(feature = "pti")]
+pub fn tables() -> [(usize, usize); 5] {
+    use core::mem;
+    unsafe {
+        [
+            (&GDT.0 as *const GlobalDescriptorTable as usize, mem::size_of::<GlobalDescriptorTable>()),
+            (&TSS as *const TaskStateSegment as usize, mem::size_of::<TaskStateSegment>()),
+            (&DOUBLE_FAULT_STACK as *const Stack as usize, IST_STACK_SIZE),
+            (&NMI_STACK as *const Stack as usize, IST_STACK_SIZE),
+            (&MACHINE_CHECK_STACK as *const Stack as usize, IST_STACK_SIZE),
+        ]
+    }
+}
+
 /// about [pti](https://en.wikipedia.org/wiki/Kernel_page-table_isolation)
+/// Entries from user mode land on the PTI stack of the CPU, which is mapped in user tables, and
+/// move to `stack` once the kernel is mapped
 #[cfg(feature = "pti")]
 pub unsafe fn set_tss_stack(stack: usize) {
-    unimplemented!()
-//    use arch::x86_64::pti::{PTI_CPU_STACK, PTI_CONTEXT_STACK};
-//    TSS.rsp[0] = (PTI_CPU_STACK.as_ptr() as usize + PTI_CPU_STACK.len()) as u64;
-//    PTI_CONTEXT_STACK = stack;
+    use crate::pti;
+    let slot = pti::stack_slot();
+    TSS.privilege_stack_table[0] = VirtAddr::new(slot as u64);
+    pti::set_context_stack(stack);
+    crate::percpu::current().kernel_rsp.store(slot, Ordering::SeqCst);
 }
 
 #[cfg(not(feature = "pti"))]
 pub unsafe fn set_tss_stack(stack: usize) {
     TSS.privilege_stack_table[0] = VirtAddr::new(stack as u64);
+    crate::percpu::current().kernel_rsp.store(stack, Ordering::SeqCst);
 }
\ No newline at end of file
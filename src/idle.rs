@@ -0,0 +1,140 @@
+//! # Tickless idle
+//!
+//! When no context is runnable, the CPU halts until the next deadline rather than taking a timer
+//! interrupt at a fixed rate. The deadline is the earliest of the contexts' wake up times, the
+//! registered timeouts and, if the CPU owns other contexts, the scheduler quantum. A one-shot
+//! interrupt is programmed for it on the local APIC timer, or on the HPET as a fallback.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::context::{self, timeout, Status};
+use crate::device::hpet::HPET;
+use crate::device::local_apic::LOCAL_APIC;
+use crate::device::pic;
+use crate::interrupt;
+use crate::interrupt::irq::QUANTUM_TICKS;
+use crate::time;
+
+/// Shortest sleep worth stopping the tick for, in nanoseconds
+const MIN_SLEEP: u64 = 50_000;
+/// Longest sleep, so that a lost interrupt can not stall the CPU for long
+const MAX_SLEEP: u64 = 1_000_000_000;
+
+/// Devices that can deliver the wake up interrupt
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum OneShot {
+    /// Keep the periodic tick
+    None = 0,
+    /// Local APIC timer, on `local_apic::TIMER_VECTOR`
+    LocalApic = 1,
+    /// HPET timer 0 in legacy replacement mode, on IRQ 0
+    Hpet = 2,
+}
+
+static ONESHOT: AtomicU8 = AtomicU8::new(OneShot::None as u8);
+
+/// Pick the one-shot device, once the timers have been initialized
+pub fn init() {
+    let oneshot = if unsafe { LOCAL_APIC.is_ready() && LOCAL_APIC.timer_frequency != 0 } {
+        OneShot::LocalApic
+    } else if unsafe { HPET.has_legacy_replacement() } {
+        OneShot::Hpet
+    } else {
+        OneShot::None
+    };
+    ONESHOT.store(oneshot as u8, Ordering::SeqCst);
+}
+
+/// The device used by `idle`
+pub fn oneshot() -> OneShot {
+    match ONESHOT.load(Ordering::SeqCst) {
+        1 => OneShot::LocalApic,
+        2 => OneShot::Hpet,
+        _ => OneShot::None,
+    }
+}
+
+/// Run other contexts when they are runnable, and sleep otherwise
+pub fn idle() -> ! {
+    loop {
+        interrupt::disable();
+        if unsafe { context::switch() } {
+            interrupt::enable();
+        } else {
+            unsafe { sleep(); }
+        }
+    }
+}
+
+/// The earliest deadline after `now`, in nanoseconds of monotonic time
+fn next_deadline(now: u64) -> u64 {
+    let cpu_id = crate::cpu_id();
+    let mut deadline = now + MAX_SLEEP;
+
+    if let Some(timeout) = timeout::next_deadline() {
+        deadline = deadline.min(timeout);
+    }
+
+    let contexts = context::contexts();
+    let current = context::context_id();
+    for (id, context_lock) in contexts.iter() {
+        let context = context_lock.read();
+        if context.cpu_id.is_some() && context.cpu_id != Some(cpu_id) {
+            continue;
+        }
+
+        if context.status == Status::Blocked {
+            if let Some(wake) = context.wake {
+                deadline = deadline.min(time::to_nanos(wake));
+            }
+        }
+
+        // Something may make another context runnable without interrupting this CPU
+        if *id != current {
+            deadline = deadline.min(now + QUANTUM_TICKS as u64 * time::tick_source().rate());
+        }
+    }
+
+    deadline
+}
+
+/// Halt until the next deadline or another interrupt, with interrupts disabled on entry and exit
+unsafe fn sleep() {
+    let oneshot = oneshot();
+    let now = time::to_nanos(time::monotonic());
+    let delay = next_deadline(now).saturating_sub(now);
+
+    if oneshot == OneShot::None || delay < MIN_SLEEP {
+        interrupt::enable_and_halt();
+        interrupt::disable();
+        return;
+    }
+
+    let tick_irq = time::tick_source().irq();
+    let slept = match oneshot {
+        OneShot::LocalApic => {
+            pic::set_mask(tick_irq, true);
+            LOCAL_APIC.set_oneshot(delay);
+            interrupt::enable_and_halt();
+            interrupt::disable();
+            LOCAL_APIC.stop_oneshot()
+        }
+        OneShot::Hpet => {
+            // The PIT and the RTC are disconnected from their IRQs while timer 0 replaces them
+            HPET.set_oneshot(delay);
+            interrupt::enable_and_halt();
+            interrupt::disable();
+            HPET.stop_oneshot();
+            0
+        }
+        OneShot::None => unreachable!(),
+    };
+
+    let stale_tick = oneshot == OneShot::LocalApic && pic::irr() & 1 << tick_irq != 0;
+    time::resume(now, slept, stale_tick);
+    if oneshot == OneShot::LocalApic {
+        pic::set_mask(tick_irq, false);
+    }
+
+    timeout::trigger();
+}
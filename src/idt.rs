@@ -1,26 +1,57 @@
 use crate::interrupt::exception::*;
 use crate::interrupt::irq::*;
+use crate::interrupt::ipi::*;
 use crate::device::pic::*;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptDescriptorTable;
+use crate::device::local_apic;
 use crate::gdt;
+use crate::ipi::IpiKind;
+use crate::interrupt::entry;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.breakpoint.set_handler_fn(entry::trampoline(3, breakpoint_handler));
+        idt.page_fault.set_handler_fn(entry::trampoline(14, page_fault_handler));
         unsafe {
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_fn(entry::double_fault_trampoline(double_fault_handler))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(entry::ist_trampoline(2, non_maskable_handler))
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(entry::ist_trampoline(18, machine_check_handler))
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(entry::trampoline(InterruptIndex::Timer.as_usize() as u8, timer_interrupt_handler));
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(entry::trampoline(InterruptIndex::Keyboard.as_usize() as u8, keyboard_interrupt_handler));
+        idt[InterruptIndex::RTC.as_usize()].set_handler_fn(entry::trampoline(InterruptIndex::RTC.as_usize() as u8, rtc_interrupt_handler));
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(entry::trampoline(InterruptIndex::PrimaryAta.as_usize() as u8, ata_primary_interrupt_handler));
+        idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(entry::trampoline(InterruptIndex::SecondaryAta.as_usize() as u8, ata_secondary_interrupt_handler));
+        idt[local_apic::TIMER_VECTOR as usize].set_handler_fn(entry::trampoline(local_apic::TIMER_VECTOR as u8, local_apic_timer_handler));
+        idt[IpiKind::Tlb.as_usize()].set_handler_fn(entry::trampoline(IpiKind::Tlb.as_usize() as u8, tlb_handler));
+        idt[IpiKind::Switch.as_usize()].set_handler_fn(entry::trampoline(IpiKind::Switch.as_usize() as u8, switch_handler));
+        idt[IpiKind::Halt.as_usize()].set_handler_fn(entry::trampoline(IpiKind::Halt.as_usize() as u8, halt_handler));
+        for (&irq, &handler) in PCI_IRQS.iter().zip(PCI_IRQ_HANDLERS.iter()) {
+            let vector = PIC_1_OFFSET + irq;
+            idt[vector as usize].set_handler_fn(entry::trampoline(vector, handler));
+        }
+        for (i, &handler) in MSI_HANDLER_FUNCS.iter().enumerate() {
+            let vector = MSI_VECTOR_BASE + i as u8;
+            idt[vector as usize].set_handler_fn(entry::trampoline(vector, handler));
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt
     };
 }
 
 pub fn init() {
     IDT.load();
+}
+
+/// Address and size of the IDT
+#[cfg(feature = "pti")]
+pub fn table() -> (usize, usize) {
+    (&*IDT as *const InterruptDescriptorTable as usize, core::mem::size_of::<InterruptDescriptorTable>())
 }
\ No newline at end of file
@@ -0,0 +1,248 @@
+//! # Interrupt entry trampolines
+//!
+//! In the kernel, GS base holds the per-CPU block, and in user mode the value of the user, so
+//! every entry from and exit to user mode runs `swapgs`. Handlers in the IDT are therefore
+//! reached through a trampoline: a stub per vector jumps to a common entry that swaps GS for
+//! entries from user mode, then calls the handler with a frame that returns to a common exit,
+//! which swaps GS back before `iretq` to user mode.
+//!
+//! The double fault has a trampoline of its own, as it arrives on its own stack and never
+//! returns. NMIs and machine checks arrive on stacks of their own too, and can interrupt the
+//! kernel between `swapgs` and `iretq`, where the CPL no longer tells which GS base is loaded.
+//! Their trampoline reads GS base instead, which only holds a kernel address while it holds the
+//! per-CPU block, and restores the state it found on the way out. There is no entry for the
+//! `syscall` instruction.
+//!
+//! The `pti` module adds its table and stack switches to the trampolines, which is why they are
+//! in a section of their own that it maps into user tables.
+
+use core::mem;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+
+/// The handler behind the trampoline of each vector, used by the trampolines
+#[no_mangle]
+pub static mut ENTRY_HANDLERS: [usize; 256] = [0; 256];
+
+extern "C" {
+    static __entry_text_start: u8;
+    static __entry_text_end: u8;
+    static __entry_vectors: u8;
+    static __entry_double_fault: u8;
+    static __entry_ist_vectors: u8;
+}
+
+/// Vectors that enter through the IST trampoline: the NMI and the machine check
+const IST_VECTORS: [u8; 2] = [2, 18];
+
+// One 16 byte stub per vector, which pushes a zero error code if the CPU does not, then the
+// vector with bit 8 set if it has an error code. In `__entry_common` the stack then always holds
+// the vector, the error code, and the interrupt frame. `$user_entry` runs after `swapgs` on
+// entries from user mode, `$user_exit` before it on exits to user mode, with the frame at the top
+// of the stack, and `$kernel_table` on double faults, which jump to the handler in
+// `ENTRY_HANDLERS[8]` on the stack they arrived on. The IST trampoline runs `$ist_entry` with
+// the vector at 24(%rsp) and a free slot above it, and `$ist_exit` with that slot at 16(%rsp).
+// It keeps whether it swapped GS in bit 9 of the vector.
+macro_rules! entry_trampolines {
+    ($user_entry:expr, $user_exit:expr, $kernel_table:expr, $ist_entry:expr, $ist_exit:expr) => {
+        global_asm!(concat!(r#"
+    .section .text.entry, "ax", @progbits
+    .balign 4096
+    .global __entry_text_start
+__entry_text_start:
+
+    .global __entry_vectors
+__entry_vectors:
+    .set vector, 0
+    .rept 256
+    .balign 16
+    .if vector == 8 || vector == 10 || vector == 11 || vector == 12 || vector == 13 || vector == 14 || vector == 17 || vector == 21 || vector == 29 || vector == 30
+    pushq $(vector | 0x100)
+    .else
+    pushq $0
+    pushq $vector
+    .endif
+    jmp __entry_common
+    .set vector, vector + 1
+    .endr
+
+__entry_common:
+    cld
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+"#, $user_entry, r#"
+1:
+    // Below the vector, build the frame the handler returns through, into __entry_exit
+    sub $40, %rsp
+    push %rax
+    movq $0, 40(%rsp)
+    lea 48(%rsp), %rax
+    mov %rax, 32(%rsp)
+    pushfq
+    pop %rax
+    mov %rax, 24(%rsp)
+    mov %cs, %eax
+    mov %rax, 16(%rsp)
+    lea __entry_exit(%rip), %rax
+    mov %rax, 8(%rsp)
+
+    // Jump to the handler with `ret`, restoring the scratch registers, and with the error code on
+    // top of the frame if the vector has one
+    push %rcx
+    mov 56(%rsp), %rcx
+    bt $8, %rcx
+    jc 2f
+    movzbl %cl, %ecx
+    lea ENTRY_HANDLERS(%rip), %rax
+    mov (%rax, %rcx, 8), %rcx
+    mov 8(%rsp), %rax
+    mov %rcx, 8(%rsp)
+    pop %rcx
+    ret
+2:
+    movzbl %cl, %ecx
+    lea ENTRY_HANDLERS(%rip), %rax
+    mov (%rax, %rcx, 8), %rcx
+    mov 64(%rsp), %rax
+    xchg %rax, 8(%rsp)
+    xchg %rcx, (%rsp)
+    ret
+
+__entry_exit:
+    testb $3, 24(%rsp)
+    jnz 3f
+    add $16, %rsp
+    iretq
+
+3:
+"#, $user_exit, r#"
+    swapgs
+    add $16, %rsp
+    iretq
+
+    .global __entry_double_fault
+__entry_double_fault:
+    testb $3, 16(%rsp)
+    jz 1f
+    swapgs
+1:
+"#, $kernel_table, r#"
+    jmp *(ENTRY_HANDLERS + 8 * 8)(%rip)
+
+    .balign 16
+    .global __entry_ist_vectors
+__entry_ist_vectors:
+    pushq $0
+    pushq $2
+    jmp __entry_ist
+    .balign 16
+    pushq $0
+    pushq $18
+    jmp __entry_ist
+
+__entry_ist:
+    cld
+    push %rax
+    push %rcx
+    push %rdx
+    // GS base holds the per-CPU block in the kernel, at a kernel address
+    mov $0xc0000101, %ecx
+    rdmsr
+    test %edx, %edx
+    js 1f
+    swapgs
+    orq $0x200, 24(%rsp)
+1:
+"#, $ist_entry, r#"
+    pop %rdx
+    pop %rcx
+    pop %rax
+
+    // As in __entry_common, without error codes
+    sub $40, %rsp
+    push %rax
+    movq $0, 40(%rsp)
+    lea 48(%rsp), %rax
+    mov %rax, 32(%rsp)
+    pushfq
+    pop %rax
+    mov %rax, 24(%rsp)
+    mov %cs, %eax
+    mov %rax, 16(%rsp)
+    lea __entry_ist_exit(%rip), %rax
+    mov %rax, 8(%rsp)
+    push %rcx
+    movzbl 56(%rsp), %ecx
+    lea ENTRY_HANDLERS(%rip), %rax
+    mov (%rax, %rcx, 8), %rcx
+    mov 8(%rsp), %rax
+    mov %rcx, 8(%rsp)
+    pop %rcx
+    ret
+
+__entry_ist_exit:
+    push %rax
+"#, $ist_exit, r#"
+    testq $0x200, 8(%rsp)
+    jz 1f
+    swapgs
+1:
+    pop %rax
+    add $16, %rsp
+    iretq
+
+    .balign 4096
+    .global __entry_text_end
+__entry_text_end:
+    .text
+"#));
+    };
+}
+
+#[cfg(not(feature = "pti"))]
+entry_trampolines!("", "", "", "", "");
+
+/// The entry to install in the IDT for `handler` at `vector`: its trampoline, which calls it
+pub fn trampoline<F: Copy>(vector: u8, handler: F) -> F {
+    assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>(), "entry::trampoline: not a function pointer");
+    unsafe {
+        ENTRY_HANDLERS[vector as usize] = mem::transmute_copy(&handler);
+        let stub = &__entry_vectors as *const u8 as usize + vector as usize * 16;
+        mem::transmute_copy(&stub)
+    }
+}
+
+/// The entry to install in the IDT for the double fault `handler`, which runs on the stack the
+/// fault arrives on
+pub fn double_fault_trampoline<F: Copy>(handler: F) -> F {
+    assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>(), "entry::double_fault_trampoline: not a function pointer");
+    unsafe {
+        ENTRY_HANDLERS[8] = mem::transmute_copy(&handler);
+        let stub = &__entry_double_fault as *const u8 as usize;
+        mem::transmute_copy(&stub)
+    }
+}
+
+/// The entry to install in the IDT for the NMI or machine check `handler` at `vector`, which run
+/// on stacks of their own
+pub fn ist_trampoline<F: Copy>(vector: u8, handler: F) -> F {
+    assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>(), "entry::ist_trampoline: not a function pointer");
+    let index = IST_VECTORS.iter().position(|&ist_vector| ist_vector == vector)
+        .expect("entry::ist_trampoline: not an IST vector");
+    unsafe {
+        ENTRY_HANDLERS[vector as usize] = mem::transmute_copy(&handler);
+        let stub = &__entry_ist_vectors as *const u8 as usize + index * 16;
+        mem::transmute_copy(&stub)
+    }
+}
+
+/// The frame of the interrupted code. Behind a trampoline, handlers get a frame that returns into
+/// it, and the original frame is above, past the vector and error code.
+pub unsafe fn interrupted_frame(frame: &mut InterruptStackFrame) -> &mut InterruptStackFrameValue {
+    &mut *((frame.stack_pointer.as_u64() + 16) as *mut InterruptStackFrameValue)
+}
+
+/// The start and end of the trampolines, which are page aligned
+pub fn text() -> (usize, usize) {
+    unsafe { (&__entry_text_start as *const u8 as usize, &__entry_text_end as *const u8 as usize) }
+}
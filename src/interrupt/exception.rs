@@ -5,10 +5,15 @@
 #![cfg(not(windows))]
 
 use crate::{hlt_loop, println};
+use crate::interrupt::entry;
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", unsafe { entry::interrupted_frame(stack_frame) });
+}
+
+pub extern "x86-interrupt" fn non_maskable_handler(stack_frame: &mut InterruptStackFrame) {
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", unsafe { entry::interrupted_frame(stack_frame) });
 }
 
 pub extern "x86-interrupt" fn page_fault_handler(
@@ -17,9 +22,14 @@ pub extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    // Faults on user memory in a system call fail the call instead
+    if crate::syscall::usercopy::fixup(stack_frame) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
-    println!("{:#?}", stack_frame);
+    println!("{:#?}", unsafe { entry::interrupted_frame(stack_frame) });
     println!("Error code: {:?}", error_code);
     hlt_loop();
 }
@@ -30,4 +40,9 @@ pub extern "x86-interrupt" fn double_fault_handler(
 ) {
     println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
     hlt_loop();
-}
\ No newline at end of file
+}
+
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) {
+    println!("EXCEPTION: MACHINE CHECK\n{:#?}", unsafe { entry::interrupted_frame(stack_frame) });
+    hlt_loop();
+}
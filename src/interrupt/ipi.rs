@@ -0,0 +1,24 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::context;
+use crate::device::local_apic::LOCAL_APIC;
+use crate::ipi;
+
+pub extern "x86-interrupt" fn tlb_handler(_stack_frame: &mut InterruptStackFrame) {
+    ipi::tlb_ack();
+
+    unsafe { LOCAL_APIC.eoi(); }
+}
+
+pub extern "x86-interrupt" fn switch_handler(_stack_frame: &mut InterruptStackFrame) {
+    unsafe {
+        LOCAL_APIC.eoi();
+
+        let _ = context::switch();
+    }
+}
+
+pub extern "x86-interrupt" fn halt_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::interrupt::disable();
+    crate::hlt_loop();
+}
@@ -1,36 +1,169 @@
 use core::sync::atomic::AtomicUsize;
-use spin;
-use x86_64::structures::idt::InterruptStackFrame;
+use spin::{self, Mutex};
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
 use crate::device::pic::*;
 use crate::{print, time};
 use lazy_static::lazy_static;
 use core::sync::atomic::Ordering;
 use crate::context;
+use crate::context::timeout;
+use crate::device::ata;
+use crate::device::local_apic::LOCAL_APIC;
+use crate::device::rtc::Rtc;
+use crate::interrupt;
+use crate::syscall::error::*;
+use crate::time::TickSource;
 
 //resets to 0 in context::switch()
 pub static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
 
+/// Timer interrupts a context runs for before it is preempted
+pub const QUANTUM_TICKS: usize = 10;
+
+/// A device's interrupt handler, and the argument it is called with
+pub type DeviceHandler = (fn(usize), usize);
+
+/// PIC lines left for PCI devices, which firmware routes INTx to
+pub const PCI_IRQS: [u8; 8] = [3, 4, 5, 6, 7, 9, 10, 11];
+
+/// Handlers of the devices sharing each PIC line
+static IRQ_HANDLERS: Mutex<[[Option<DeviceHandler>; 4]; 16]> = Mutex::new([[None; 4]; 16]);
+
+/// First of the vectors given out for MSI and MSI-X, after the IPIs
+pub const MSI_VECTOR_BASE: u8 = 0x50;
+pub const MSI_VECTORS: usize = 8;
+
+static MSI_HANDLERS: Mutex<[Option<DeviceHandler>; MSI_VECTORS]> = Mutex::new([None; MSI_VECTORS]);
+
 unsafe fn irq_trigger(interrupt_id: u8) {
     PICS.lock().notify_end_of_interrupt(interrupt_id);
 }
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    const PIT_RATE: u64 = 2_250_286;
+/// Call `handler` on interrupts of PIC line `irq`, one of `PCI_IRQS`, and unmask it
+pub fn register_irq(irq: u8, handler: DeviceHandler) -> Result<()> {
+    if !PCI_IRQS.contains(&irq) {
+        return Err(Error::new(EINVAL));
+    }
 
-    {
-        let mut offset = time::OFFSET.lock();
-        let sum = offset.1 + PIT_RATE;
-        offset.1 = sum % 1_000_000_000;
-        offset.0 += sum / 1_000_000_000;
+    // Handlers run with the list locked
+    interrupt::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[irq as usize].iter_mut().find(|slot| slot.is_none()).ok_or(Error::new(EBUSY))?;
+        *slot = Some(handler);
+        unsafe {
+            if irq >= 8 {
+                set_mask(2, false);
+            }
+            set_mask(irq, false);
+        }
+        Ok(())
+    })
+}
+
+/// Give a vector to `handler`, for a device to signal with MSI or MSI-X
+pub fn register_msi(handler: DeviceHandler) -> Result<u8> {
+    interrupt::without_interrupts(|| {
+        let mut handlers = MSI_HANDLERS.lock();
+        let index = handlers.iter().position(|slot| slot.is_none()).ok_or(Error::new(EBUSY))?;
+        handlers[index] = Some(handler);
+        Ok(MSI_VECTOR_BASE + index as u8)
+    })
+}
+
+fn dispatch(handlers: &[Option<DeviceHandler>]) {
+    for &(handler, arg) in handlers.iter().flatten() {
+        handler(arg);
     }
+}
+
+macro_rules! pci_irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+            let handlers = IRQ_HANDLERS.lock()[$irq];
+            dispatch(&handlers);
+            unsafe { irq_trigger(PIC_1_OFFSET + $irq); }
+        }
+    };
+}
+
+macro_rules! msi_handler {
+    ($name:ident, $index:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+            let handler = MSI_HANDLERS.lock()[$index];
+            dispatch(&[handler]);
+            unsafe { LOCAL_APIC.eoi(); }
+        }
+    };
+}
+
+pci_irq_handler!(irq3_handler, 3);
+pci_irq_handler!(irq4_handler, 4);
+pci_irq_handler!(irq5_handler, 5);
+pci_irq_handler!(irq6_handler, 6);
+pci_irq_handler!(irq7_handler, 7);
+pci_irq_handler!(irq9_handler, 9);
+pci_irq_handler!(irq10_handler, 10);
+pci_irq_handler!(irq11_handler, 11);
+
+/// The handlers of `PCI_IRQS`, for the IDT
+pub static PCI_IRQ_HANDLERS: [HandlerFunc; 8] = [
+    irq3_handler, irq4_handler, irq5_handler, irq6_handler,
+    irq7_handler, irq9_handler, irq10_handler, irq11_handler,
+];
+
+msi_handler!(msi0_handler, 0);
+msi_handler!(msi1_handler, 1);
+msi_handler!(msi2_handler, 2);
+msi_handler!(msi3_handler, 3);
+msi_handler!(msi4_handler, 4);
+msi_handler!(msi5_handler, 5);
+msi_handler!(msi6_handler, 6);
+msi_handler!(msi7_handler, 7);
 
-//    timeout::trigger();
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 10 {
+/// The handlers of the MSI vectors from `MSI_VECTOR_BASE`, for the IDT
+pub static MSI_HANDLER_FUNCS: [HandlerFunc; MSI_VECTORS] = [
+    msi0_handler, msi1_handler, msi2_handler, msi3_handler,
+    msi4_handler, msi5_handler, msi6_handler, msi7_handler,
+];
+
+/// Time keeping, timeouts and preemption, on every interrupt of the tick source
+fn tick() {
+    time::tick();
+
+    timeout::trigger();
+    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= QUANTUM_TICKS {
         let _ = unsafe { context::switch() };
     }
+}
+
+pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    tick();
     unsafe { irq_trigger(InterruptIndex::Timer.as_u8()); }
 }
 
+pub extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    unsafe { Rtc::new().ack(); }
+    if time::tick_source() == TickSource::Rtc {
+        tick();
+    }
+    unsafe { irq_trigger(InterruptIndex::RTC.as_u8()); }
+}
+
+pub extern "x86-interrupt" fn ata_primary_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    ata::irq(0);
+    unsafe { irq_trigger(InterruptIndex::PrimaryAta.as_u8()); }
+}
+
+pub extern "x86-interrupt" fn ata_secondary_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    ata::irq(1);
+    unsafe { irq_trigger(InterruptIndex::SecondaryAta.as_u8()); }
+}
+
+/// The local APIC timer only wakes up an idle CPU, `idle` does the accounting
+pub extern "x86-interrupt" fn local_apic_timer_handler(_stack_frame: &mut InterruptStackFrame) {
+    unsafe { LOCAL_APIC.eoi(); }
+}
+
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
     use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
     use spin::Mutex;
@@ -1,5 +1,8 @@
+#[macro_use]
+pub mod entry;
 pub mod exception;
 pub mod irq;
+pub mod ipi;
 
 /// Pause instruction
 /// Safe because it is similar to a NOP, and has no memory effects
@@ -8,4 +11,14 @@ pub fn pause() {
     unsafe { asm!("pause" : : : : "intel", "volatile"); }
 }
 
+/// Set interrupts and halt
+/// This will atomically wait for the next interrupt
+/// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
+#[inline(always)]
+pub unsafe fn enable_and_halt() {
+    asm!("sti
+        hlt"
+        : : : : "intel", "volatile");
+}
+
 pub use x86_64::instructions::interrupts::*;
\ No newline at end of file
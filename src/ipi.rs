@@ -0,0 +1,115 @@
+//! # Inter-processor interrupts
+//!
+//! Vectors `0x40` and up are reserved for IPIs, right after the chained PICs.
+//! See [osdev](https://wiki.osdev.org/APIC#Interrupt_Command_Register) for the ICR layout.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::tlb;
+
+use crate::device::local_apic::LOCAL_APIC;
+use crate::percpu;
+use crate::CPU_MAX;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum IpiKind {
+    /// Flush the TLB, see `tlb_shootdown`
+    Tlb = 0x40,
+    /// Run the scheduler
+    Switch = 0x41,
+    /// Stop the CPU for good, used on panic
+    Halt = 0x42,
+}
+
+impl IpiKind {
+    pub fn as_usize(self) -> usize {
+        self as usize
+    }
+}
+
+/// Destination shorthands of the ICR
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum IpiTarget {
+    Current = 1,
+    All = 2,
+    Other = 3,
+}
+
+/// Send an IPI to a group of CPUs
+#[inline(always)]
+pub fn ipi(kind: IpiKind, target: IpiTarget) {
+    unsafe {
+        if !LOCAL_APIC.is_ready() {
+            return;
+        }
+
+        let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
+        LOCAL_APIC.set_icr(icr);
+    }
+}
+
+/// Send an IPI to a single CPU
+#[inline(always)]
+pub fn ipi_single(kind: IpiKind, cpu_id: usize) {
+    unsafe {
+        if !LOCAL_APIC.is_ready() {
+            return;
+        }
+
+        // CPU IDs are the APIC IDs of the processors
+        LOCAL_APIC.ipi(cpu_id, kind as u8);
+    }
+}
+
+/// Bitmap of CPUs that have not yet acknowledged the current shootdown
+static TLB_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Set while a CPU is performing a shootdown, only one can be in flight
+static TLB_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Flush the TLB of every other CPU running `table`, and wait until all of them acknowledged it
+pub fn tlb_shootdown(table: usize) {
+    if crate::cpu_count() <= 1 {
+        return;
+    }
+
+    let cpu_id = crate::cpu_id();
+
+    // Another CPU may be waiting on us while we wait for the lock, so keep acknowledging
+    while TLB_LOCK.compare_and_swap(false, true, Ordering::SeqCst) {
+        tlb_ack();
+        crate::interrupt::pause();
+    }
+
+    let mut targets = 0;
+    for id in 0..crate::cpu_count() {
+        if id != cpu_id && percpu::get(id).page_table.load(Ordering::SeqCst) == table {
+            targets |= 1 << id;
+        }
+    }
+
+    if targets != 0 {
+        TLB_PENDING.store(targets, Ordering::SeqCst);
+
+        for id in 0..CPU_MAX {
+            if targets & 1 << id != 0 {
+                ipi_single(IpiKind::Tlb, id);
+            }
+        }
+
+        while TLB_PENDING.load(Ordering::SeqCst) & targets != 0 {
+            crate::interrupt::pause();
+        }
+    }
+
+    TLB_LOCK.store(false, Ordering::SeqCst);
+}
+
+/// Flush the local TLB if a shootdown is waiting on the current CPU
+pub fn tlb_ack() {
+    let bit = 1 << crate::cpu_id();
+    if TLB_PENDING.load(Ordering::SeqCst) & bit == bit {
+        tlb::flush_all();
+        TLB_PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
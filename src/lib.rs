@@ -3,6 +3,7 @@
 #![feature(asm)]
 #![feature(const_fn, core_intrinsics, thread_local, naked_functions)]
 #![feature(alloc_error_handler)]
+#![feature(global_asm)]
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
@@ -14,15 +15,25 @@ extern crate alloc;
 pub mod serial;
 #[macro_use]
 pub mod vga_buffer;
+#[macro_use]
 pub mod interrupt;
+pub mod acpi;
 pub mod gdt;
 pub mod idt;
+pub mod ipi;
+pub mod idle;
+pub mod percpu;
+pub mod power;
+pub mod pti;
 pub mod memory;
 pub mod time;
 pub mod syscall;
 pub mod device;
 pub mod start;
 pub mod context;
+pub mod scheme;
+pub mod block;
+pub mod fs;
 pub mod consts;
 #[macro_use]
 pub mod common;
@@ -31,29 +42,48 @@ pub mod sync;
 
 pub use consts::*;
 pub use self::start::kernel_main;
-use linked_list_allocator::LockedHeap;
+use bootloader::BootInfo;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::panic::PanicInfo;
 
-pub fn init() {
+/// Set up a kernel that runs tests: the descriptor tables, memory, the heap and the per-CPU block
+/// of the BSP, which interrupt handlers rely on, and only then interrupts
+pub fn init(boot_info: &'static BootInfo) {
+    use bootloader::bootinfo::MemoryRegionType;
+
     gdt::init();
     idt::init();
+
+    let kernel_end = boot_info.memory_map
+        .iter()
+        .filter(|area| area.region_type == MemoryRegionType::Kernel)
+        .last().unwrap()
+        .range.end_addr() as usize;
+    memory::init(boot_info, 0, kernel_end);
+    unsafe {
+        let mut active_table = memory::ActivePageTable::new();
+        memory::heap::init(&mut active_table);
+        percpu::init(0, &mut active_table);
+        #[cfg(feature = "pti")]
+        pti::init(&mut active_table);
+    }
+
     x86_64::instructions::interrupts::enable();
 }
 
 // Heap allocator (disabled during testing)
 #[cfg_attr(not(test), global_allocator)]
-pub static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+pub static HEAP_ALLOCATOR: memory::heap::Allocator = memory::heap::Allocator::new();
 
-/// A unique number that identifies the current CPU - used for scheduling
-static CPU_ID: AtomicUsize = AtomicUsize::new(0);
-
-/// Get the current CPU's scheduling ID
+/// Get the current CPU's scheduling ID, a unique number kept in its per-CPU block
 #[inline(always)]
 pub fn cpu_id() -> usize {
-    CPU_ID.load(Ordering::Relaxed)
+    percpu::current().cpu_id
 }
 
+/// Maximum number of CPUs, limited by the width of the IPI bitmaps
+pub const CPU_MAX: usize = 64;
+
 /// The count of all CPUs that can have work scheduled
 static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
 
@@ -101,15 +131,15 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 }
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     hlt_loop();
 }
@@ -16,6 +16,11 @@ entry_point!(kernel_main);
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    use dongos::ipi::{ipi, IpiKind, IpiTarget};
+
+    // Stop the other CPUs before they make things worse
+    ipi(IpiKind::Halt, IpiTarget::Other);
+
     println!("{}", info);
     dongos::hlt_loop();
 }
@@ -0,0 +1,261 @@
+//! # Buddy frame allocator
+//!
+//! Free memory is kept in blocks of `2^order` frames, aligned to their size, with a doubly linked
+//! free list per order. The links live in the free frames themselves, reached through the physical
+//! memory window, and one byte per frame records the order of the free block starting there. That
+//! way a freed block can tell in constant time whether its buddy is free and merge with it.
+//! See [wikipedia](https://en.wikipedia.org/wiki/Buddy_memory_allocation)
+
+use core::slice;
+use x86_64::structures::paging::{PhysFrame, FrameAllocator as SimpleFrameAllocator, FrameDeallocator, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::{FrameAllocator, MemoryAreaIter, PAGE_SIZE, PHYSICAL_MEMORY_OFFSET};
+
+/// Largest block, in frames as a power of two. 1 GiB, the size of the largest pages.
+pub const MAX_ORDER: usize = 18;
+
+/// Marks the first frame of a free block in `orders`, together with its order
+const FREE: u8 = 0x80;
+
+/// End of a free list
+const NONE: usize = usize::max_value();
+
+/// Free list links, stored at the start of the first frame of each free block
+#[repr(C)]
+struct Link {
+    prev: usize,
+    next: usize,
+}
+
+/// The smallest order with at least `count` frames
+pub fn order_of(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
+}
+
+pub struct BuddyAllocator {
+    /// Virtual address of physical address zero
+    window: usize,
+    /// Frame number of the first entry in `orders`
+    base: usize,
+    /// `FREE | order` for the first frame of a free block, zero for every other frame
+    orders: &'static mut [u8],
+    /// First block of the free list of each order
+    heads: [usize; MAX_ORDER + 1],
+    free: usize,
+    total: usize,
+}
+
+impl BuddyAllocator {
+    /// Manage the usable memory areas, except for the frames of the kernel
+    pub fn new(kernel_start: usize, kernel_end: usize, memory_areas: MemoryAreaIter) -> Self {
+        let window = *PHYSICAL_MEMORY_OFFSET.r#try()
+            .expect("PHYSICAL_MEMORY_OFFSET not initialized") as usize;
+        let kernel = (kernel_start / PAGE_SIZE, (kernel_end + PAGE_SIZE - 1) / PAGE_SIZE);
+
+        let mut min = NONE;
+        let mut max = 0;
+        for_each_range(memory_areas.clone(), kernel, |start, end| {
+            min = min.min(start);
+            max = max.max(end);
+        });
+        if min == NONE {
+            panic!("BuddyAllocator::new: no usable memory");
+        }
+
+        // One byte per frame, taken from the first range large enough to hold it
+        let meta_frames = (max - min + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut meta = None;
+        for_each_range(memory_areas.clone(), kernel, |start, end| {
+            if meta.is_none() && end - start >= meta_frames {
+                meta = Some(start);
+            }
+        });
+        let meta = meta.expect("BuddyAllocator::new: no room for the frame table");
+
+        let orders = unsafe {
+            let orders = slice::from_raw_parts_mut((window + meta * PAGE_SIZE) as *mut u8, max - min);
+            for order in orders.iter_mut() {
+                *order = 0;
+            }
+            orders
+        };
+
+        let mut allocator = BuddyAllocator {
+            window,
+            base: min,
+            orders,
+            heads: [NONE; MAX_ORDER + 1],
+            free: 0,
+            total: 0,
+        };
+
+        for_each_range(memory_areas, kernel, |start, end| {
+            let (start, end) = if start == meta { (start + meta_frames, end) } else { (start, end) };
+            if start < end {
+                allocator.total += end - start;
+                allocator.free_range(start, end);
+            }
+        });
+
+        allocator
+    }
+
+    fn link(&self, frame: usize) -> &'static mut Link {
+        unsafe { &mut *((self.window + frame * PAGE_SIZE) as *mut Link) }
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let next = self.heads[order];
+        *self.link(frame) = Link { prev: NONE, next };
+        if next != NONE {
+            self.link(next).prev = frame;
+        }
+        self.heads[order] = frame;
+        self.orders[frame - self.base] = FREE | order as u8;
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        let (prev, next) = {
+            let link = self.link(frame);
+            (link.prev, link.next)
+        };
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            self.link(prev).next = next;
+        }
+        if next != NONE {
+            self.link(next).prev = prev;
+        }
+        self.orders[frame - self.base] = 0;
+    }
+
+    /// Whether `frame` starts a free block of `order`
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        frame >= self.base && frame - self.base < self.orders.len()
+            && self.orders[frame - self.base] == FREE | order as u8
+    }
+
+    /// Free a block, merging it with its buddies while they are free
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        debug_assert!(self.orders[frame - self.base] & FREE == 0, "BuddyAllocator: double free of frame {:#x}", frame);
+
+        self.free += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            frame &= !(1 << order);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Free the frames `start..end`, as the largest aligned blocks that fit
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Allocate `2^order` frames, aligned to their size
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let mut current = order;
+        while self.heads[current] == NONE {
+            current += 1;
+            if current > MAX_ORDER {
+                return None;
+            }
+        }
+
+        let frame = self.heads[current];
+        self.remove(frame, current);
+
+        // Give back the upper halves until the block has the requested size
+        while current > order {
+            current -= 1;
+            self.push(frame + (1 << current), current);
+        }
+
+        self.free -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new((frame * PAGE_SIZE) as u64)))
+    }
+}
+
+/// Call `f` with the frame numbers `start..end` of each usable area, without the kernel frames
+fn for_each_range<F: FnMut(usize, usize)>(areas: MemoryAreaIter, kernel: (usize, usize), mut f: F) {
+    for area in areas {
+        let start = (area.range.start_addr() as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+        let end = area.range.end_addr() as usize / PAGE_SIZE;
+
+        if start >= kernel.1 || end <= kernel.0 {
+            if start < end {
+                f(start, end);
+            }
+        } else {
+            if start < kernel.0 {
+                f(start, kernel.0);
+            }
+            if kernel.1 < end {
+                f(kernel.1, end);
+            }
+        }
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    #[allow(unused)]
+    fn set_noncore(&mut self, noncore: bool) {}
+
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    fn used_frames(&self) -> usize {
+        self.total - self.free
+    }
+
+    /// Allocate `count` contiguous frames, aligned to the next power of two
+    fn allocate_frames(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+
+        let order = order_of(count);
+        let frame = self.allocate_order(order)?;
+
+        // Keep only what was asked for
+        let start = frame.start_address().as_u64() as usize / PAGE_SIZE;
+        self.free_range(start + count, start + (1 << order));
+        Some(frame)
+    }
+
+    fn deallocate_frames(&mut self, frame: PhysFrame, count: usize) {
+        let start = frame.start_address().as_u64() as usize / PAGE_SIZE;
+        self.free_range(start, start + count);
+    }
+}
+
+unsafe impl SimpleFrameAllocator<Size4KiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_order(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_block(frame.start_address().as_u64() as usize / PAGE_SIZE, 0);
+    }
+}
@@ -0,0 +1,66 @@
+//! # DMA buffers
+//!
+//! Physically contiguous memory that devices read and write directly. The kernel reaches it
+//! through the physical memory window, and hands its physical address to the device.
+use core::ptr::{read_volatile, write_volatile};
+use core::{mem, slice};
+use x86_64::structures::paging::PhysFrame;
+
+use crate::memory::{self, PAGE_SIZE};
+use crate::syscall::error::*;
+
+pub struct Dma {
+    frame: PhysFrame,
+    pages: usize,
+}
+
+impl Dma {
+    /// Allocate `size` zeroed bytes, rounded up to whole pages
+    pub fn new(size: usize) -> Result<Dma> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frame = memory::allocate_frames(pages).ok_or(Error::new(ENOMEM))?;
+        let dma = Dma { frame, pages };
+        unsafe { dma.as_ptr().write_bytes(0, dma.len()); }
+        Ok(dma)
+    }
+
+    /// The address the device uses
+    pub fn phys(&self) -> u64 {
+        self.frame.start_address().as_u64()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        memory::phys_to_virt(self.frame).as_u64() as *mut u8
+    }
+
+    /// Read a `T` at `offset`, which the device may have written
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + mem::size_of::<T>() <= self.len());
+        unsafe { read_volatile(self.as_ptr().add(offset) as *const T) }
+    }
+
+    /// Write a `T` at `offset`, for the device to read
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + mem::size_of::<T>() <= self.len());
+        unsafe { write_volatile(self.as_ptr().add(offset) as *mut T, value) }
+    }
+
+    /// The bytes, when the device is not using them
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        memory::deallocate_frames(self.frame, self.pages);
+    }
+}
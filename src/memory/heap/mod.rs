@@ -1,23 +1,174 @@
-use x86_64::structures::paging::{Page, PageTableFlags as EntryFlags};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags as EntryFlags;
 use x86_64::VirtAddr;
 
 use super::table::ActivePageTable;
 use super::mapper::MapperFlushAll;
 use crate::HEAP_ALLOCATOR;
 
+pub use self::slab::{SlabStats, CLASSES, SIZE_CLASSES};
+use self::slab::{size_class, SlabCache};
+
+mod slab;
+
+/// End of the virtual memory the heap may grow into
+const HEAP_LIMIT: usize = crate::KERNEL_HEAP_OFFSET + crate::PML4_SIZE;
+
+/// Heap growth is rounded up to this, so that it can be mapped with huge pages
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
 unsafe fn map_heap(active_table: &mut ActivePageTable, offset: usize, size: usize) {
     let mut flush_all = MapperFlushAll::new();
 
-    let heap_start_page = Page::containing_address(VirtAddr::new(offset as u64));
-    let heap_end_page = Page::containing_address(VirtAddr::new((offset + size - 1) as u64));
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        let result = active_table.map(page, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
-        flush_all.consume(result);
-    }
+    let flags = EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    active_table.map_range(VirtAddr::new(offset as u64), size, flags, true, &mut flush_all);
 
     flush_all.flush(active_table);
 }
 
+/// The kernel heap: a linked list heap that grows on demand within the heap PML4, with slab
+/// caches in front of it for small objects
+pub struct Allocator {
+    heap: Mutex<Heap>,
+    slabs: [Mutex<SlabCache>; CLASSES],
+    /// Bytes handed out by `heap`, including whole slabs
+    used: AtomicUsize,
+}
+
+impl Allocator {
+    pub const fn new() -> Allocator {
+        Allocator {
+            heap: Mutex::new(Heap::empty()),
+            slabs: [
+                Mutex::new(SlabCache::new(SIZE_CLASSES[0])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[1])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[2])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[3])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[4])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[5])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[6])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[7])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[8])),
+            ],
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Hand the mapped memory `offset..offset + size` to the heap
+    pub unsafe fn init(&self, offset: usize, size: usize) {
+        self.heap.lock().init(offset, size);
+    }
+
+    /// Allocate from the linked list heap, growing it if it is full. The heap is unlocked while the
+    /// new memory is mapped, as mapping may allocate too.
+    unsafe fn allocate_heap(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let (top, step) = {
+                let mut heap = self.heap.lock();
+                if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                    self.used.fetch_add(layout.size(), Ordering::SeqCst);
+                    return ptr.as_ptr();
+                }
+                // Before `init`, there is no heap to grow
+                if heap.bottom() == 0 {
+                    return ptr::null_mut();
+                }
+
+                // The new memory is appended to the last hole, which may already hold part of it
+                let needed = layout.size() + layout.align();
+                let top = heap.top();
+                let step = align_up(top + needed.max(crate::KERNEL_HEAP_SIZE), HUGE_PAGE_SIZE) - top;
+                if top + step > HEAP_LIMIT {
+                    return ptr::null_mut();
+                }
+                (top, step)
+            };
+
+            map_heap(&mut ActivePageTable::new(), top, step);
+
+            // Another allocation may have grown the heap meanwhile, mapped pages being skipped
+            let mut heap = self.heap.lock();
+            if top + step > heap.top() {
+                let by = top + step - heap.top();
+                heap.extend(by);
+            }
+        }
+    }
+
+    unsafe fn deallocate_heap(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+        self.used.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+
+    /// Current size and usage of the heap and its caches
+    pub fn stats(&self) -> HeapStats {
+        let mut slabs = [SlabStats::default(); CLASSES];
+        for (stats, cache) in slabs.iter_mut().zip(self.slabs.iter()) {
+            *stats = cache.lock().stats();
+        }
+
+        HeapStats {
+            size: self.heap.lock().size(),
+            used: self.used.load(Ordering::SeqCst),
+            slabs,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(&layout) {
+            Some(class) => {
+                let mut cache = self.slabs[class].lock();
+                if cache.is_empty() {
+                    // Unlocked while the heap grows, which may allocate objects of this class
+                    let slab_layout = cache.slab_layout();
+                    drop(cache);
+                    let slab = self.allocate_heap(slab_layout);
+                    if slab.is_null() {
+                        return slab;
+                    }
+                    cache = self.slabs[class].lock();
+                    cache.refill(slab);
+                }
+                cache.allocate()
+            }
+            None => self.allocate_heap(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(class) => self.slabs[class].lock().deallocate(ptr),
+            None => self.deallocate_heap(ptr, layout),
+        }
+    }
+}
+
+/// Statistics of the kernel heap, see `stats`
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes mapped for the heap
+    pub size: usize,
+    /// Bytes allocated from the heap, including slabs whether their objects are used or not
+    pub used: usize,
+    /// One entry per size class
+    pub slabs: [SlabStats; CLASSES],
+}
+
+/// Current size and usage of the kernel heap
+pub fn stats() -> HeapStats {
+    HEAP_ALLOCATOR.stats()
+}
+
 pub unsafe fn init(active_table: &mut ActivePageTable) {
     let offset = crate::KERNEL_HEAP_OFFSET;
     let size = crate::KERNEL_HEAP_SIZE;
@@ -26,7 +177,21 @@ pub unsafe fn init(active_table: &mut ActivePageTable) {
     map_heap(active_table, offset, size);
 
     // Initialize global heap
-    HEAP_ALLOCATOR.lock().init(offset, size);
+    HEAP_ALLOCATOR.init(offset, size);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uninitialized_heap() {
+        let allocator = Allocator::new();
+        unsafe {
+            assert!(allocator.alloc(Layout::from_size_align(16, 8).unwrap()).is_null());
+            assert!(allocator.alloc(Layout::from_size_align(64 * 1024, 8).unwrap()).is_null());
+        }
+    }
 }
 
 /// Error handler for allocation errors
@@ -40,4 +205,4 @@ mod alloc_error {
         println!("{:#?}", layout);
         panic!();
     }
-}
\ No newline at end of file
+}
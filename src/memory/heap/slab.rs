@@ -0,0 +1,125 @@
+//! # Slab caches
+//!
+//! Small allocations are served from per-size-class caches rather than the linked list heap. Each
+//! cache takes memory from the heap a slab at a time and splits it into objects of its size, kept
+//! in an intrusive free list. Freed objects go back to their cache, so the objects allocated most
+//! often, such as contexts, their locks and page sized buffers, are handed out in constant time
+//! and do not fragment the heap.
+use core::alloc::Layout;
+use core::ptr;
+
+/// Number of size classes
+pub const CLASSES: usize = 9;
+
+/// Object sizes of the caches, each is also the alignment its objects satisfy
+pub const SIZE_CLASSES: [usize; CLASSES] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Smallest slab, larger classes take eight objects at a time
+const MIN_SLAB_SIZE: usize = 16 * 1024;
+
+/// The index of the cache that serves `layout`, if any
+pub fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// Statistics of one cache
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlabStats {
+    /// Size of the objects, in bytes
+    pub object_size: usize,
+    /// Slabs taken from the heap
+    pub slabs: usize,
+    /// Objects in all slabs
+    pub objects: usize,
+    /// Objects currently allocated
+    pub used: usize,
+}
+
+pub struct SlabCache {
+    object_size: usize,
+    /// First free object, each free object starts with the address of the next one
+    free: *mut u8,
+    slabs: usize,
+    objects: usize,
+    used: usize,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            object_size,
+            free: ptr::null_mut(),
+            slabs: 0,
+            objects: 0,
+            used: 0,
+        }
+    }
+
+    /// Layout of the memory `refill` needs
+    pub fn slab_layout(&self) -> Layout {
+        let size = MIN_SLAB_SIZE.max(8 * self.object_size);
+        unsafe { Layout::from_size_align_unchecked(size, self.object_size) }
+    }
+
+    /// Whether `allocate` needs a `refill` first
+    pub fn is_empty(&self) -> bool {
+        self.free.is_null()
+    }
+
+    /// Split a slab allocated with `slab_layout` into free objects
+    pub unsafe fn refill(&mut self, slab: *mut u8) {
+        let count = self.slab_layout().size() / self.object_size;
+        for i in (0..count).rev() {
+            let object = slab.add(i * self.object_size);
+            *(object as *mut *mut u8) = self.free;
+            self.free = object;
+        }
+        self.slabs += 1;
+        self.objects += count;
+    }
+
+    /// Take an object, the cache must not be empty
+    pub unsafe fn allocate(&mut self) -> *mut u8 {
+        let object = self.free;
+        debug_assert!(!object.is_null(), "SlabCache::allocate: empty cache");
+        self.free = *(object as *mut *mut u8);
+        self.used += 1;
+        object
+    }
+
+    /// Give back an object taken from this cache
+    pub unsafe fn deallocate(&mut self, object: *mut u8) {
+        *(object as *mut *mut u8) = self.free;
+        self.free = object;
+        self.used -= 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            object_size: self.object_size,
+            slabs: self.slabs,
+            objects: self.objects,
+            used: self.used,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_classes() {
+        let class = |size, align| size_class(&Layout::from_size_align(size, align).unwrap()).map(|i| SIZE_CLASSES[i]);
+        assert_eq!(class(1, 1), Some(16));
+        assert_eq!(class(17, 8), Some(32));
+        assert_eq!(class(512, 16), Some(512));
+        assert_eq!(class(24, 64), Some(64));
+        assert_eq!(class(4096, 4096), Some(4096));
+        assert_eq!(class(4097, 8), None);
+        assert_eq!(class(8, 8192), None);
+    }
+}
@@ -2,6 +2,7 @@ use core::mem;
 
 pub use x86_64::structures::paging::{
     mapper::MapperFlush,
+    PageSize,
     Size4KiB,
 };
 
@@ -18,13 +19,13 @@ impl MapperFlushAll {
         MapperFlushAll(false)
     }
 
-    /// Consume a single page flush
-    pub fn consume(&mut self, flush: MapperFlush<Size4KiB>) {
+    /// Consume a single page flush, of any page size
+    pub fn consume<S: PageSize>(&mut self, flush: MapperFlush<S>) {
         self.0 = true;
         mem::forget(flush);
     }
 
-    /// Flush the active page table
+    /// Flush the active page table, on every CPU that is running it
     pub fn flush(self, table: &mut ActivePageTable) {
         if self.0 {
             table.flush_all();
@@ -1,26 +1,25 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType, MemoryRegion, BootInfo};
 use x86_64::structures::paging::{
     FrameAllocator as SimpleFrameAllocator, FrameDeallocator, Mapper, Page, PhysFrame, Size4KiB,
+    mapper::MapperAllSizes, PageTableFlags as EntryFlags,
 };
 use x86_64::{PhysAddr, VirtAddr};
 use spin::{Mutex, Once};
 pub use x86_64::{align_down, align_up};
 
+pub mod dma;
 pub mod heap;
 
 pub mod table;
 pub mod temporary_page;
 pub mod mapper;
-pub mod frame_allocator;
-
-pub mod recycle;
+pub mod buddy;
 
 pub use self::table::{ActivePageTable, InactivePageTable};
-use self::frame_allocator::BumpAllocator;
-use self::recycle::RecycleAllocator;
+use self::buddy::BuddyAllocator;
 
 pub static PHYSICAL_MEMORY_OFFSET: Once<u64> = Once::new();
-pub static FRAME_ALLOCATOR: Mutex<Option<RecycleAllocator<BumpAllocator>>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
 static mut MEMORY_MAP: Option<&'static MemoryMap> = None;
 
 /// Number of entries per page table
@@ -34,8 +33,9 @@ pub const PAGE_SIZE: usize = 4096;
 pub fn init(boot_info: &'static BootInfo, kernel_start: usize, kernel_end: usize) {
     PHYSICAL_MEMORY_OFFSET.call_once(|| boot_info.physical_memory_offset);
     unsafe { MEMORY_MAP = Some(&boot_info.memory_map); }
-    let bump = BumpAllocator::new(kernel_start, kernel_end, MemoryAreaIter::new(MemoryRegionType::Usable));
-    *FRAME_ALLOCATOR.lock() = Some(RecycleAllocator::new(bump));
+    let buddy = BuddyAllocator::new(kernel_start, kernel_end, MemoryAreaIter::new(MemoryRegionType::Usable));
+    *FRAME_ALLOCATOR.lock() = Some(buddy);
+    table::init_shared_entries(boot_info);
 }
 
 pub(crate) fn phys_to_virt(frame: PhysFrame) -> VirtAddr {
@@ -45,6 +45,36 @@ pub(crate) fn phys_to_virt(frame: PhysFrame) -> VirtAddr {
     VirtAddr::new(phys + physical_memory_offset)
 }
 
+/// Address of a physical address in the physical memory window
+pub(crate) fn phys_to_virt_addr(phys: PhysAddr) -> VirtAddr {
+    let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.r#try()
+        .expect("PHYSICAL_MEMORY_OFFSET not initialized");
+    VirtAddr::new(phys.as_u64() + physical_memory_offset)
+}
+
+/// Map a range of device memory at its address in the physical memory window.
+/// The bootloader only maps RAM there, so MMIO regions such as the local APIC are mapped on demand.
+pub(crate) unsafe fn map_device(active_table: &mut ActivePageTable, phys: PhysAddr, size: usize) -> VirtAddr {
+    use self::mapper::MapperFlushAll;
+
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end_frame = PhysFrame::containing_address(phys + (size - 1) as u64);
+    let mut flush_all = MapperFlushAll::new();
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(phys_to_virt(frame));
+        if active_table.translate_addr(page.start_address()).is_none() {
+            let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+                | EntryFlags::WRITE_THROUGH | EntryFlags::NO_CACHE;
+            let result = active_table.map_to(page, frame, flags, FRAME_ALLOCATOR.lock().as_mut().unwrap())
+                .expect("failed to map device memory");
+            flush_all.consume(result);
+        }
+    }
+    flush_all.flush(active_table);
+
+    phys_to_virt(start_frame) + (phys.as_u64() - start_frame.start_address().as_u64())
+}
+
 /// Init memory module after core
 /// Must be called once, and only once,
 pub unsafe fn init_noncore() {
@@ -79,6 +109,42 @@ pub fn allocate_frames(count: usize) -> Option<PhysFrame> {
     }
 }
 
+/// Allocate `2^order` frames, aligned to their size
+pub fn allocate_order(order: usize) -> Option<PhysFrame> {
+    if let Some(ref mut allocator) = *FRAME_ALLOCATOR.lock() {
+        allocator.allocate_order(order)
+    } else {
+        panic!("frame allocator not initialized");
+    }
+}
+
+/// Deallocate a range of frames
+pub fn deallocate_frames(frame: PhysFrame, count: usize) {
+    if let Some(ref mut allocator) = *FRAME_ALLOCATOR.lock() {
+        allocator.deallocate_frames(frame, count)
+    } else {
+        panic!("frame allocator not initialized");
+    }
+}
+
+/// Get the number of frames available
+pub fn free_frames() -> usize {
+    if let Some(ref allocator) = *FRAME_ALLOCATOR.lock() {
+        allocator.free_frames()
+    } else {
+        panic!("frame allocator not initialized");
+    }
+}
+
+/// Get the number of frames used
+pub fn used_frames() -> usize {
+    if let Some(ref allocator) = *FRAME_ALLOCATOR.lock() {
+        allocator.used_frames()
+    } else {
+        panic!("frame allocator not initialized");
+    }
+}
+
 pub trait FrameAllocator: SimpleFrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> {
     fn set_noncore(&mut self, noncore: bool);
     fn free_frames(&self) -> usize;
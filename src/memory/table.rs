@@ -1,5 +1,11 @@
+use alloc::vec::Vec;
+use bootloader::BootInfo;
+use core::{cmp, mem};
 use core::ops::{Deref, DerefMut};
-use x86_64::structures::paging::{MappedPageTable, PhysFrame, PageTable, Size4KiB, PageTableFlags as EntryFlags};
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::structures::paging::{MappedPageTable, PhysFrame, PageTable, PageSize, Size4KiB, Size2MiB, Size1GiB, PageTableFlags as EntryFlags};
+use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::registers::control::Cr3;
 use x86_64::instructions::tlb;
@@ -7,10 +13,13 @@ use x86_64::structures::paging::Page;
 
 
 pub use x86_64::structures::paging::{Mapper, FrameAllocator};
-use super::{FRAME_ALLOCATOR, allocate_frames, PHYSICAL_MEMORY_OFFSET, phys_to_virt};
-use super::mapper::MapperFlush;
 
-type MappedTable = MappedPageTable<'static, fn(PhysFrame) -> *mut PageTable>;
+use super::{FRAME_ALLOCATOR, ENTRY_COUNT, PAGE_SIZE, allocate_frames, allocate_order, deallocate_frames, buddy, PHYSICAL_MEMORY_OFFSET, phys_to_virt, phys_to_virt_addr};
+use super::mapper::{MapperFlush, MapperFlushAll};
+use crate::ipi;
+use crate::{PML4_MASK, PML4_SIZE, USER_PML4, USER_TMP_MISC_PML4};
+
+pub type MappedTable = MappedPageTable<'static, fn(PhysFrame) -> *mut PageTable>;
 
 pub struct ActivePageTable {
     mapper: MappedTable,
@@ -60,21 +69,28 @@ impl ActivePageTable {
         }
     }
 
-    pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
+    /// Load `new_table`, which stays owned by the caller, returning a handle to the previous table
+    pub fn switch(&mut self, new_table: &InactivePageTable) -> InactivePageTable {
         let (p4_frame, flags) = Cr3::read();
-        let old_table = InactivePageTable { p4_frame };
+        let old_table = InactivePageTable { p4_frame, owned: false };
         unsafe {
             Cr3::write(new_table.p4_frame, flags);
         }
         old_table
     }
 
+    /// Flush a page from the local TLB, then shoot down the TLBs of other CPUs running this table
     pub fn flush(&mut self, page: Page) {
+        crate::pti::sync(Cr3::read().0);
         tlb::flush(page.start_address());
+        ipi::tlb_shootdown(unsafe { Self::address().as_u64() as usize });
     }
 
+    /// Flush the local TLB, then shoot down the TLBs of other CPUs running this table
     pub fn flush_all(&mut self) {
+        crate::pti::sync(Cr3::read().0);
         tlb::flush_all();
+        ipi::tlb_shootdown(unsafe { Self::address().as_u64() as usize });
     }
 
     pub fn with<F>(&mut self, table: &mut InactivePageTable, f: F)
@@ -85,6 +101,7 @@ impl ActivePageTable {
             let mut new_table = MappedTable::new(level_4_table, |frame| phys_to_virt(frame).as_mut_ptr());
             f(&mut new_table);
         }
+        crate::pti::sync(table.p4_frame);
     }
 
     pub unsafe fn address() -> PhysAddr {
@@ -97,10 +114,316 @@ impl ActivePageTable {
             self.map_to(page, frame, flags, FRAME_ALLOCATOR.lock().as_mut().unwrap()).unwrap()
         }
     }
+
+    /// Map the unmapped pages of `start..start + size` to new frames. With `huge`, aligned 2 MiB
+    /// and 1 GiB ranges use huge pages, falling back to smaller ones when no contiguous frames are free.
+    pub fn map_range(&mut self, start: VirtAddr, size: usize, flags: EntryFlags, huge: bool, flush_all: &mut MapperFlushAll) {
+        let mut addr = start.as_u64() & !(Size4KiB::SIZE - 1);
+        let end = start.as_u64() + size as u64;
+        while addr < end {
+            if let Some(mapped) = self.mapping_size(VirtAddr::new(addr)) {
+                addr = (addr & !(mapped - 1)) + mapped;
+                continue;
+            }
+
+            if huge {
+                if fits::<Size1GiB>(addr, end) && has_1gib_pages() {
+                    if let Some(result) = self.map_sized(Page::<Size1GiB>::containing_address(VirtAddr::new(addr)), flags) {
+                        flush_all.consume(result);
+                        addr += Size1GiB::SIZE;
+                        continue;
+                    }
+                }
+                if fits::<Size2MiB>(addr, end) {
+                    if let Some(result) = self.map_sized(Page::<Size2MiB>::containing_address(VirtAddr::new(addr)), flags) {
+                        flush_all.consume(result);
+                        addr += Size2MiB::SIZE;
+                        continue;
+                    }
+                }
+            }
+
+            let result = self.map(Page::containing_address(VirtAddr::new(addr)), flags);
+            flush_all.consume(result);
+            addr += Size4KiB::SIZE;
+        }
+    }
+
+    /// Map a page of any size to new, size aligned frames, if there are enough contiguous ones and
+    /// no smaller page is mapped in its range
+    fn map_sized<S: PageSize>(&mut self, page: Page<S>, flags: EntryFlags) -> Option<MapperFlush<S>>
+        where MappedTable: Mapper<S>
+    {
+        self.free_empty_table(page.start_address(), S::SIZE);
+
+        let count = S::SIZE as usize / PAGE_SIZE;
+        let frame = allocate_order(buddy::order_of(count))?;
+        let result = unsafe {
+            self.map_to(page, PhysFrame::<S>::containing_address(frame.start_address()), flags, FRAME_ALLOCATOR.lock().as_mut().unwrap())
+        };
+        match result {
+            Ok(result) => Some(result),
+            Err(_) => {
+                deallocate_frames(frame, count);
+                None
+            }
+        }
+    }
+
+    /// Free the table below the entry a page of `size` at `addr` would take if it maps nothing, as
+    /// unmapping the pages of a split huge page leaves its table behind
+    fn free_empty_table(&mut self, addr: VirtAddr, size: u64) {
+        let p4 = unsafe { active_level_4_table() };
+        let p3 = match next_table(&p4[addr.p4_index()]) {
+            Some(p3) => p3,
+            None => return,
+        };
+        let (entry, level) = if size == Size1GiB::SIZE {
+            (&mut p3[addr.p3_index()], 2)
+        } else {
+            match next_table(&p3[addr.p3_index()]) {
+                Some(p2) => (&mut p2[addr.p2_index()], 1),
+                None => return,
+            }
+        };
+
+        if let Some(table) = next_table(entry) {
+            if maps_nothing(table, level) {
+                // Unlink the tables and drop them from every TLB before their frames can be reused
+                let frame = PhysFrame::containing_address(entry.addr());
+                entry.set_unused();
+                self.flush_all();
+                free_tables(table, level);
+                deallocate_frames(frame, 1);
+            }
+        }
+    }
+
+    /// Size of the page mapping `addr`, if it is mapped
+    pub fn mapping_size(&self, addr: VirtAddr) -> Option<u64> {
+        let p4 = unsafe { active_level_4_table() };
+        let p3 = next_table(&p4[addr.p4_index()])?;
+        let entry = &p3[addr.p3_index()];
+        if is_huge(entry) {
+            return Some(Size1GiB::SIZE);
+        }
+        let p2 = next_table(entry)?;
+        let entry = &p2[addr.p2_index()];
+        if is_huge(entry) {
+            return Some(Size2MiB::SIZE);
+        }
+        let p1 = next_table(entry)?;
+        if p1[addr.p1_index()].flags().contains(EntryFlags::PRESENT) {
+            Some(Size4KiB::SIZE)
+        } else {
+            None
+        }
+    }
+
+    /// Split the huge page mapping `addr` into `ENTRY_COUNT` pages of the next smaller size, which
+    /// keep its frames and flags. Does nothing if `addr` is not in a huge page.
+    pub fn split(&mut self, addr: VirtAddr) {
+        let p4 = unsafe { active_level_4_table() };
+        let p3 = match next_table(&p4[addr.p4_index()]) {
+            Some(p3) => p3,
+            None => return,
+        };
+        let entry = &mut p3[addr.p3_index()];
+        if is_huge(entry) {
+            split_entry(entry, Size2MiB::SIZE);
+        } else {
+            let p2 = match next_table(entry) {
+                Some(p2) => p2,
+                None => return,
+            };
+            let entry = &mut p2[addr.p2_index()];
+            if !is_huge(entry) {
+                return;
+            }
+            split_entry(entry, Size4KiB::SIZE);
+        }
+
+        // Invalidating any address of a huge page removes its whole TLB entry
+        self.flush(Page::containing_address(addr));
+    }
+
+    /// The pages mapping `start..end`, as (address, size) pairs. Huge pages crossing either end or
+    /// larger than `max_size` are split first, so that every page lies within the range.
+    pub fn pages_in(&mut self, start: VirtAddr, end: VirtAddr, max_size: u64) -> Vec<(VirtAddr, u64)> {
+        let mut pages = Vec::new();
+        let mut addr = start.as_u64() & !(Size4KiB::SIZE - 1);
+        while addr < end.as_u64() {
+            match self.mapping_size(VirtAddr::new(addr)) {
+                Some(size) if size > max_size || addr & (size - 1) != 0 || addr + size > end.as_u64() => {
+                    self.split(VirtAddr::new(addr));
+                }
+                Some(size) => {
+                    pages.push((VirtAddr::new(addr), size));
+                    addr += size;
+                }
+                None => addr += Size4KiB::SIZE,
+            }
+        }
+        pages
+    }
+
+    /// Unmap a page returned by `pages_in`, returning its first frame
+    pub fn unmap_sized(&mut self, addr: VirtAddr, size: u64, flush_all: &mut MapperFlushAll) -> PhysFrame {
+        let start = match size {
+            Size1GiB::SIZE => {
+                let (frame, result) = Mapper::<Size1GiB>::unmap(&mut **self, Page::containing_address(addr)).unwrap();
+                flush_all.consume(result);
+                frame.start_address()
+            }
+            Size2MiB::SIZE => {
+                let (frame, result) = Mapper::<Size2MiB>::unmap(&mut **self, Page::containing_address(addr)).unwrap();
+                flush_all.consume(result);
+                frame.start_address()
+            }
+            _ => {
+                let (frame, result) = Mapper::<Size4KiB>::unmap(&mut **self, Page::containing_address(addr)).unwrap();
+                flush_all.consume(result);
+                frame.start_address()
+            }
+        };
+        PhysFrame::containing_address(start)
+    }
+
+    /// Change the flags of a page returned by `pages_in`
+    pub fn update_flags_sized(&mut self, addr: VirtAddr, size: u64, flags: EntryFlags, flush_all: &mut MapperFlushAll) {
+        match size {
+            Size1GiB::SIZE => flush_all.consume(Mapper::<Size1GiB>::update_flags(&mut **self, Page::containing_address(addr), flags | EntryFlags::HUGE_PAGE).unwrap()),
+            Size2MiB::SIZE => flush_all.consume(Mapper::<Size2MiB>::update_flags(&mut **self, Page::containing_address(addr), flags | EntryFlags::HUGE_PAGE).unwrap()),
+            _ => flush_all.consume(Mapper::<Size4KiB>::update_flags(&mut **self, Page::containing_address(addr), flags).unwrap()),
+        }
+    }
+}
+
+/// Map a page of `size` at `addr` to the frames starting at `frame` in an inactive table
+pub unsafe fn map_to_sized(mapper: &mut MappedTable, addr: VirtAddr, frame: PhysFrame, size: u64, flags: EntryFlags) {
+    let frame = frame.start_address();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    // This is not the active table, so the flushes can be ignored
+    match size {
+        Size1GiB::SIZE => mapper.map_to(Page::<Size1GiB>::containing_address(addr), PhysFrame::containing_address(frame), flags, allocator).unwrap().ignore(),
+        Size2MiB::SIZE => mapper.map_to(Page::<Size2MiB>::containing_address(addr), PhysFrame::containing_address(frame), flags, allocator).unwrap().ignore(),
+        _ => mapper.map_to(Page::<Size4KiB>::containing_address(addr), PhysFrame::containing_address(frame), flags, allocator).unwrap().ignore(),
+    }
+}
+
+/// Whether a page of `S` starting at `addr` is aligned and ends before `end`
+fn fits<S: PageSize>(addr: u64, end: u64) -> bool {
+    addr & (S::SIZE - 1) == 0 && addr + S::SIZE <= end
+}
+
+fn has_1gib_pages() -> bool {
+    CpuId::new().get_extended_function_info().map_or(false, |info| info.has_1gib_pages())
 }
 
+fn is_huge(entry: &PageTableEntry) -> bool {
+    entry.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE)
+}
+
+/// The table an entry points to, if it is present and not a huge page
+fn next_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    if !entry.flags().contains(EntryFlags::PRESENT) || is_huge(entry) {
+        return None;
+    }
+    Some(unsafe { &mut *phys_to_virt_addr(entry.addr()).as_mut_ptr() })
+}
+
+/// Whether `table`, which is at `level`, and the tables below it map no page
+fn maps_nothing(table: &PageTable, level: usize) -> bool {
+    table.iter().all(|entry| match next_table(entry) {
+        Some(next) if level > 1 => maps_nothing(next, level - 1),
+        _ => !entry.flags().contains(EntryFlags::PRESENT),
+    })
+}
+
+/// Replace a huge page entry with a table of pages of `size` covering the same frames
+fn split_entry(entry: &mut PageTableEntry, size: u64) {
+    let flags = entry.flags();
+    let base = entry.addr();
+    let child_flags = if size == Size4KiB::SIZE { flags & !EntryFlags::HUGE_PAGE } else { flags };
+
+    let frame = allocate_frames(1).expect("failed to allocate a page table to split a huge page");
+    let table = unsafe { &mut *phys_to_virt(frame).as_mut_ptr::<PageTable>() };
+    for i in 0..ENTRY_COUNT {
+        table[i].set_addr(base + i as u64 * size, child_flags);
+    }
+
+    let table_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | (flags & EntryFlags::USER_ACCESSIBLE);
+    entry.set_addr(frame.start_address(), table_flags);
+}
+
+/// The PML4 entries shared by every address space: the whole kernel half, and the entries the
+/// bootloader used in the lower half for the kernel image, its stack, the boot information and the
+/// physical memory window
+static SHARED_ENTRIES: Once<[bool; ENTRY_COUNT]> = Once::new();
+
+/// Record the shared PML4 entries of the boot table, and give every unused kernel half entry a
+/// table. Kernel mappings made later then land in tables all address spaces link to.
+pub fn init_shared_entries(boot_info: &'static BootInfo) {
+    let p4 = unsafe { active_level_4_table() };
+
+    // A local lives on the kernel stack, and this function in the kernel image
+    let stack = 0usize;
+    let memory_end = boot_info.memory_map.iter().map(|region| region.range.end_addr()).max().unwrap_or(0);
+    let boot_ranges = [
+        (init_shared_entries as usize, 1),
+        (&stack as *const usize as usize, 1),
+        (boot_info as *const BootInfo as usize, mem::size_of::<BootInfo>()),
+        (boot_info.physical_memory_offset as usize, cmp::max(memory_end as usize, 1)),
+    ];
+    let mut boot = [false; ENTRY_COUNT];
+    for &(start, size) in boot_ranges.iter() {
+        let first = (start & PML4_MASK) / PML4_SIZE;
+        let last = ((start + size - 1) & PML4_MASK) / PML4_SIZE;
+        for i in first..=last {
+            boot[i] = true;
+        }
+    }
+
+    let mut shared = [false; ENTRY_COUNT];
+    for (i, entry) in p4.iter_mut().enumerate() {
+        if i >= ENTRY_COUNT / 2 {
+            if entry.is_unused() {
+                let frame = allocate_frames(1).expect("failed to allocate a kernel page table");
+                unsafe { &mut *phys_to_virt(frame).as_mut_ptr::<PageTable>() }.zero();
+                entry.set_addr(frame.start_address(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            }
+            shared[i] = true;
+        } else {
+            shared[i] = boot[i] && !entry.is_unused();
+        }
+    }
+    assert!(!shared[USER_PML4..=USER_TMP_MISC_PML4].contains(&true), "the bootloader mapped the kernel in the user range");
+    SHARED_ENTRIES.call_once(|| shared);
+}
+
+pub(crate) fn shared_entries() -> &'static [bool; ENTRY_COUNT] {
+    SHARED_ENTRIES.r#try().expect("shared page table entries not initialized")
+}
+
+/// Frames per table, as a power of two. With `pti`, a table is a pair of frames, the second being
+/// the table loaded in user mode.
+#[cfg(feature = "pti")]
+const TABLE_ORDER: usize = 1;
+#[cfg(not(feature = "pti"))]
+const TABLE_ORDER: usize = 0;
+
+/// Allocate the frames for an `InactivePageTable`
+pub fn allocate_table_frame() -> Option<PhysFrame> {
+    allocate_order(TABLE_ORDER)
+}
+
+/// A page table that is not loaded in CR3. One created by `new` owns its user half tables, and
+/// frees them along with its PML4 frame when dropped. Frames mapped by them belong to whoever
+/// mapped them, such as `context::memory::Memory`, and are not freed.
 pub struct InactivePageTable {
     p4_frame: PhysFrame,
+    owned: bool,
 }
 
 impl InactivePageTable {
@@ -108,25 +431,65 @@ impl InactivePageTable {
     /// That is we map the whole physical memory in out virtual address space with an offset.
     /// When we want to create a new level 4 page table,we need to do that again.
     /// But as an optimization we link the level 3 page table from current address space to the new address space instead of copying them.
+    /// The same goes for the rest of the kernel: every shared entry links to the same level 3 table
+    /// in all address spaces, so the new table starts with the kernel mapped and an empty user half.
     /// Inspired by this [post](https://os.phil-opp.com/paging-implementation/)
+    /// The frame must come from `allocate_table_frame`.
     pub fn new(frame: PhysFrame) -> InactivePageTable {
-        let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.r#try()
-            .expect("PHYSICAL_MEMORY_OFFSET not initialized");
+        let shared = shared_entries();
         let inactive_table = unsafe { get_level_4_table(frame) };
         let active_table = unsafe { active_level_4_table() };
 
-        let phys_mapped_entry_index = VirtAddr::new(physical_memory_offset).p4_index();
-        let old_entry = &active_table[phys_mapped_entry_index];
-        let new_entry = &mut inactive_table[phys_mapped_entry_index];
-        new_entry.set_addr(old_entry.addr(), old_entry.flags());
-        InactivePageTable { p4_frame: frame }
+        inactive_table.zero();
+        for i in 0..ENTRY_COUNT {
+            if shared[i] {
+                let old_entry = &active_table[i];
+                inactive_table[i].set_addr(old_entry.addr(), old_entry.flags());
+            }
+        }
+        crate::pti::init_user_table(frame);
+        InactivePageTable { p4_frame: frame, owned: true }
     }
 
+    /// A table that is not owned, such as the one of another context, and is never freed by this handle
     pub unsafe fn from_address(cr3: u64) -> InactivePageTable {
-        InactivePageTable { p4_frame: PhysFrame::containing_address(PhysAddr::new(cr3)) }
+        InactivePageTable { p4_frame: PhysFrame::containing_address(PhysAddr::new(cr3)), owned: false }
     }
 
     pub unsafe fn address(&self) -> u64 {
         self.p4_frame.start_address().as_u64()
     }
-}
\ No newline at end of file
+}
+
+/// Free the tables below `table`, which is at `level`, without the frames their pages map
+fn free_tables(table: &PageTable, level: usize) {
+    if level > 1 {
+        for entry in table.iter() {
+            if let Some(next) = next_table(entry) {
+                free_tables(next, level - 1);
+                deallocate_frames(PhysFrame::containing_address(entry.addr()), 1);
+            }
+        }
+    }
+}
+
+impl Drop for InactivePageTable {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        assert_ne!(Cr3::read().0, self.p4_frame, "InactivePageTable: dropping the active table");
+
+        let shared = shared_entries();
+        let table = unsafe { get_level_4_table(self.p4_frame) };
+        for (i, entry) in table.iter().enumerate() {
+            if !shared[i] {
+                if let Some(p3) = next_table(entry) {
+                    free_tables(p3, 3);
+                    deallocate_frames(PhysFrame::containing_address(entry.addr()), 1);
+                }
+            }
+        }
+        deallocate_frames(self.p4_frame, 1 << TABLE_ORDER);
+    }
+}
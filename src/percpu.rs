@@ -0,0 +1,91 @@
+//! # Per-CPU data
+//!
+//! Every CPU owns a `KERNEL_PERCPU_SIZE` block at `KERNEL_PERCPU_OFFSET + cpu_id * KERNEL_PERCPU_SIZE`.
+//! While in the kernel, GS base points at the block of the running CPU, and `KERNEL_GS_BASE`
+//! holds the user value so that `swapgs` can exchange them on entry and exit.
+use alloc::collections::VecDeque;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::paging::{Page, PageTableFlags as EntryFlags};
+
+use crate::context::{AtomicContextId, ContextId};
+use crate::memory::ActivePageTable;
+use crate::{CPU_MAX, KERNEL_PERCPU_OFFSET, KERNEL_PERCPU_SIZE};
+
+/// The per-CPU block, the field order of the first members is relied on by assembly
+#[repr(C)]
+pub struct PercpuBlock {
+    /// Address of this block, so that it can be found with a single `gs:[0]` load
+    self_ptr: usize,
+    /// Scratch slot for the user stack pointer on syscall entry, at `gs:[8]`
+    pub user_rsp: usize,
+    /// Kernel stack pointer to load on syscall entry, at `gs:[16]`
+    pub kernel_rsp: AtomicUsize,
+    /// The ID of this CPU
+    pub cpu_id: usize,
+    /// The ID of the context running on this CPU
+    pub context_id: AtomicContextId,
+    /// The page table running on this CPU, used for TLB shootdowns
+    pub page_table: AtomicUsize,
+    /// Contexts owned by this CPU, in round-robin order
+    pub run_queue: Mutex<VecDeque<ContextId>>,
+}
+
+/// Address of the block of the CPU with the given ID
+pub(crate) fn block_address(cpu_id: usize) -> usize {
+    KERNEL_PERCPU_OFFSET + cpu_id * KERNEL_PERCPU_SIZE
+}
+
+/// Map and initialize the block of the current CPU, and point GS at it.
+/// Must be called once on every CPU, before `cpu_id` is used.
+pub unsafe fn init(cpu_id: usize, active_table: &mut ActivePageTable) {
+    assert!(cpu_id < CPU_MAX, "percpu::init: CPU ID {} out of range", cpu_id);
+    assert!(mem::size_of::<PercpuBlock>() <= KERNEL_PERCPU_SIZE);
+
+    let start = block_address(cpu_id);
+    let start_page = Page::containing_address(VirtAddr::new(start as u64));
+    let end_page = Page::containing_address(VirtAddr::new((start + KERNEL_PERCPU_SIZE - 1) as u64));
+    for page in Page::range_inclusive(start_page, end_page) {
+        // The block was not mapped before, so there is nothing to shoot down on other CPUs
+        active_table.map(page, EntryFlags::PRESENT | EntryFlags::GLOBAL | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE).flush();
+    }
+
+    ptr::write(start as *mut PercpuBlock, PercpuBlock {
+        self_ptr: start,
+        user_rsp: 0,
+        kernel_rsp: AtomicUsize::new(0),
+        cpu_id,
+        context_id: AtomicContextId::default(),
+        page_table: AtomicUsize::new(0),
+        run_queue: Mutex::new(VecDeque::new()),
+    });
+
+    GsBase::write(VirtAddr::new(start as u64));
+    KernelGsBase::write(VirtAddr::new(0));
+}
+
+/// The block of the current CPU
+#[inline(always)]
+pub fn current() -> &'static PercpuBlock {
+    let address: usize;
+    unsafe {
+        asm!("mov $0, gs:[0]" : "=r"(address) : : : "intel", "volatile");
+        &*(address as *const PercpuBlock)
+    }
+}
+
+/// The block of another CPU, which must have been initialized with `init`
+pub fn get(cpu_id: usize) -> &'static PercpuBlock {
+    assert!(cpu_id < crate::cpu_count(), "percpu::get: CPU {} is not running", cpu_id);
+    unsafe { &*(block_address(cpu_id) as *const PercpuBlock) }
+}
+
+impl PercpuBlock {
+    /// Record that this CPU switched to `table`
+    pub fn set_page_table(&self, table: usize) {
+        self.page_table.store(table, Ordering::SeqCst);
+    }
+}
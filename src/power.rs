@@ -0,0 +1,251 @@
+//! # Power management
+//!
+//! Shutdown and reboot. ACPI is used when the firmware provides it, with fallbacks for emulators
+//! and legacy hardware. See [osdev](https://wiki.osdev.org/Shutdown) and [osdev](https://wiki.osdev.org/Reboot)
+use x86_64::PhysAddr;
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+
+use crate::acpi::ACPI_TABLE;
+use crate::acpi::fadt::{self, Fadt};
+use crate::acpi::gas::{self, GenericAddressStructure};
+use crate::acpi::sdt::Sdt;
+use crate::interrupt;
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::memory::{self, ActivePageTable};
+use crate::{hlt_loop, println};
+
+/// PM1 control register: SCI interrupts are enabled, the system is in ACPI mode
+const PM1_SCI_EN: u16 = 1;
+/// PM1 control register: enter the sleep state selected by `SLP_TYP`
+const PM1_SLP_EN: u16 = 1 << 13;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+
+/// Stop the other CPUs and turn the machine off
+pub fn shutdown() -> ! {
+    interrupt::disable();
+    ipi(IpiKind::Halt, IpiTarget::Other);
+
+    let fadt = ACPI_TABLE.lock().fadt;
+    let dsdt = ACPI_TABLE.lock().dsdt;
+    if let (Some(fadt), Some(dsdt)) = (fadt, dsdt) {
+        unsafe { acpi_shutdown(&fadt, dsdt); }
+    }
+
+    unsafe {
+        // QEMU with the ACPI PM1a block at its default port
+        Port::<u16>::new(0x604).write(0x2000);
+        // Bochs and older versions of QEMU
+        Port::<u16>::new(0xB004).write(0x2000);
+        // VirtualBox
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+
+    println!("power::shutdown: failed to power off, halting");
+    hlt_loop();
+}
+
+/// Stop the other CPUs and restart the machine
+pub fn reboot() -> ! {
+    interrupt::disable();
+    ipi(IpiKind::Halt, IpiTarget::Other);
+
+    let fadt = ACPI_TABLE.lock().fadt;
+    if let Some(fadt) = fadt {
+        unsafe { acpi_reset(&fadt); }
+    }
+
+    unsafe {
+        keyboard_reset();
+        triple_fault();
+    }
+}
+
+/// Put the system in the S5 soft off state through the PM1 control blocks
+unsafe fn acpi_shutdown(fadt: &Fadt, dsdt: &Sdt) {
+    let (slp_typa, slp_typb) = match s5_sleep_types(dsdt.data()) {
+        Some(types) => types,
+        None => {
+            println!("power::shutdown: no \\_S5 object in the DSDT");
+            return;
+        }
+    };
+
+    let pm1a = match Pm1Control::new(fadt.x_pm1a_control_block, fadt.pm1a_control_block) {
+        Some(pm1a) => pm1a,
+        None => return,
+    };
+    let pm1b = Pm1Control::new(fadt.x_pm1b_control_block, fadt.pm1b_control_block);
+
+    enable_acpi_mode(fadt, pm1a);
+
+    pm1a.write(slp_typa << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+    if let Some(pm1b) = pm1b {
+        pm1b.write(slp_typb << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+    }
+}
+
+/// A PM1 control register, at the extended address of the FADT when it has one, or else at the
+/// legacy port
+#[derive(Clone, Copy)]
+enum Pm1Control {
+    Io(u16),
+    /// The virtual address the register is mapped at
+    Memory(usize),
+}
+
+impl Pm1Control {
+    unsafe fn new(x_block: GenericAddressStructure, block: u32) -> Option<Pm1Control> {
+        if !x_block.is_valid() {
+            return if block != 0 { Some(Pm1Control::Io(block as u16)) } else { None };
+        }
+
+        let address = x_block.address;
+        match x_block.address_space {
+            gas::ADDRESS_SPACE_SYSTEM_IO => Some(Pm1Control::Io(address as u16)),
+            gas::ADDRESS_SPACE_SYSTEM_MEMORY => {
+                let virt = memory::map_device(&mut ActivePageTable::new(), PhysAddr::new(address), 2);
+                Some(Pm1Control::Memory(virt.as_u64() as usize))
+            }
+            _ => None,
+        }
+    }
+
+    unsafe fn read(self) -> u16 {
+        match self {
+            Pm1Control::Io(port) => Port::<u16>::new(port).read(),
+            Pm1Control::Memory(address) => (address as *const u16).read_volatile(),
+        }
+    }
+
+    unsafe fn write(self, value: u16) {
+        match self {
+            Pm1Control::Io(port) => Port::<u16>::new(port).write(value),
+            Pm1Control::Memory(address) => (address as *mut u16).write_volatile(value),
+        }
+    }
+}
+
+/// Hand over power management from the firmware, when the system still is in legacy mode
+unsafe fn enable_acpi_mode(fadt: &Fadt, pm1a: Pm1Control) {
+    if pm1a.read() & PM1_SCI_EN == PM1_SCI_EN {
+        return;
+    }
+
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+
+    Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+
+    // The transition can take a while, but give up eventually
+    for _ in 0..1_000_000 {
+        if pm1a.read() & PM1_SCI_EN == PM1_SCI_EN {
+            break;
+        }
+        interrupt::pause();
+    }
+}
+
+/// Find the `SLP_TYPa` and `SLP_TYPb` values in the `\_S5` package of the DSDT's AML.
+/// This only understands the encodings firmware uses for it in practice, not AML in general.
+pub fn s5_sleep_types(aml: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let i = (0..aml.len().saturating_sub(4)).find(|&i| {
+        &aml[i..i + 4] == b"_S5_"
+            && ((i >= 1 && aml[i - 1] == NAME_OP) || (i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == b'\\'))
+    })?;
+
+    let mut i = i + 4;
+    if *aml.get(i)? != PACKAGE_OP {
+        return None;
+    }
+    i += 1;
+
+    // PkgLength, the top two bits of the lead byte give the count of following bytes
+    i += 1 + (*aml.get(i)? >> 6) as usize;
+    // NumElements
+    i += 1;
+
+    let mut element = || -> Option<u16> {
+        let mut value = *aml.get(i)?;
+        if value == BYTE_PREFIX {
+            i += 1;
+            value = *aml.get(i)?;
+        }
+        i += 1;
+        Some(value as u16)
+    };
+
+    let slp_typa = element()?;
+    let slp_typb = element()?;
+    Some((slp_typa, slp_typb))
+}
+
+/// Write the reset value to the FADT reset register
+unsafe fn acpi_reset(fadt: &Fadt) {
+    let reset_reg: GenericAddressStructure = fadt.reset_reg;
+    if fadt.flags & fadt::FLAG_RESET_REG_SUP != fadt::FLAG_RESET_REG_SUP || !reset_reg.is_valid() {
+        return;
+    }
+
+    let address = reset_reg.address;
+    match reset_reg.address_space {
+        gas::ADDRESS_SPACE_SYSTEM_IO => {
+            Port::<u8>::new(address as u16).write(fadt.reset_value);
+        }
+        gas::ADDRESS_SPACE_SYSTEM_MEMORY => {
+            let virt = memory::map_device(&mut ActivePageTable::new(), PhysAddr::new(address), 1);
+            virt.as_mut_ptr::<u8>().write_volatile(fadt.reset_value);
+        }
+        gas::ADDRESS_SPACE_PCI_CONFIG => {
+            // Bus 0, device and function in the upper words of the address
+            let device = (address >> 32) & 0x1F;
+            let function = (address >> 16) & 0x7;
+            let offset = address & 0xFF;
+            let config_address = 0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xFC);
+            Port::<u32>::new(0xCF8).write(config_address as u32);
+            Port::<u8>::new(0xCFC + (offset & 3) as u16).write(fadt.reset_value);
+        }
+        _ => return,
+    }
+
+    // Give the chipset a moment to act on it
+    for _ in 0..1_000_000 {
+        interrupt::pause();
+    }
+}
+
+/// Pulse the CPU reset line through the 8042 keyboard controller
+unsafe fn keyboard_reset() {
+    let mut status = Port::<u8>::new(0x64);
+
+    // Wait for the input buffer to be empty
+    for _ in 0..1_000_000 {
+        if status.read() & 2 == 0 {
+            break;
+        }
+        interrupt::pause();
+    }
+
+    status.write(0xFE);
+
+    for _ in 0..1_000_000 {
+        interrupt::pause();
+    }
+}
+
+/// Load an empty IDT and raise an exception, which the CPU can not deliver
+unsafe fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: 0,
+    };
+    x86_64::instructions::tables::lidt(&idt);
+    asm!("int3" : : : : "intel", "volatile");
+
+    hlt_loop();
+}
@@ -0,0 +1,239 @@
+//! # Kernel page-table isolation
+//!
+//! With the `pti` feature, every page table is a pair of frames, 8 KiB aligned. The first is the
+//! full table used in the kernel; the second is loaded while in user mode, and maps the same user
+//! half but, of the kernel, only what is needed to get back into it: the entry trampolines, the
+//! descriptor tables, the IST stacks and, for every CPU, the start of its per-CPU block
+//! and a small PTI stack.
+//! Entering the kernel clears bit 12 of CR3 to switch to the full table, leaving sets it again.
+//!
+//! Interrupts from user mode arrive on the PTI stack of the CPU, as `TSS.rsp0` points there. The
+//! entry trampolines of `interrupt::entry`, which swap GS, are built here with table switches
+//! added: they move the interrupt frame to the kernel stack of the running context, and the way
+//! back to user mode goes through the PTI stack again.
+//!
+//! See [wikipedia](https://en.wikipedia.org/wiki/Kernel_page-table_isolation)
+
+#[cfg(feature = "pti")]
+use core::mem;
+#[cfg(feature = "pti")]
+use spin::Once;
+#[cfg(feature = "pti")]
+use x86_64::registers::control::Cr3;
+#[cfg(feature = "pti")]
+use x86_64::structures::paging::{mapper::MapperAllSizes, Mapper, Page, PageTable, PageTableFlags as EntryFlags, PhysFrame};
+#[cfg(feature = "pti")]
+use x86_64::VirtAddr;
+
+#[cfg(feature = "pti")]
+use crate::memory::{self, phys_to_virt, table, ActivePageTable, ENTRY_COUNT, FRAME_ALLOCATOR, PAGE_SIZE};
+#[cfg(feature = "pti")]
+use crate::{interrupt, percpu, KERNEL_PERCPU_SIZE};
+
+/// Offset in the per-CPU block of the top of the PTI stack, which is the last page of the block.
+/// `TSS.rsp0` points here, and the slot itself holds the kernel stack of the running context.
+#[cfg(feature = "pti")]
+pub const PTI_STACK_SLOT: usize = KERNEL_PERCPU_SIZE - 16;
+
+/// Holds the kernel mappings of the user table: `init_user_table` links every shared PML4 entry
+/// of a new user table to the one in the template
+#[cfg(feature = "pti")]
+static TEMPLATE: Once<PhysFrame> = Once::new();
+
+// From user mode: switch to the full table, then move everything pushed so far, and four scratch
+// registers, from the PTI stack to the top of the context stack stored in the slot. To user mode:
+// move the frame and five scratch registers to the PTI stack of this CPU, whose per-CPU block is
+// at GS:0, then switch to the user table. The offset of the slot in the per-CPU block, 0xfff0, is
+// `PTI_STACK_SLOT`. A double fault may come from a trampoline, in kernel mode with the user
+// table, so it always switches to the full table. So do NMIs and machine checks, which keep the
+// table they found in their free slot and load it again on the way out.
+#[cfg(feature = "pti")]
+entry_trampolines!(r#"
+    push %rax
+    push %rcx
+    push %rsi
+    push %rdi
+    mov %cr3, %rax
+    btr $12, %rax
+    mov %rax, %cr3
+    mov 88(%rsp), %rdi
+    sub $88, %rdi
+    mov %rdi, %rax
+    mov %rsp, %rsi
+    mov $11, %ecx
+    rep movsq
+    mov %rax, %rsp
+    pop %rdi
+    pop %rsi
+    pop %rcx
+    pop %rax
+"#, r#"
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    mov %gs:0, %rax
+    lea (0xfff0 - 96)(%rax), %rdi
+    mov %rdi, %rdx
+    mov %rsp, %rsi
+    mov $12, %ecx
+    rep movsq
+    mov %rdx, %rsp
+    mov %cr3, %rax
+    bts $12, %rax
+    mov %rax, %cr3
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+"#, r#"
+    push %rax
+    mov %cr3, %rax
+    btr $12, %rax
+    mov %rax, %cr3
+    pop %rax
+"#, r#"
+    mov %cr3, %rax
+    mov %rax, 32(%rsp)
+    btr $12, %rax
+    mov %rax, %cr3
+"#, r#"
+    mov 16(%rsp), %rax
+    mov %rax, %cr3
+"#);
+
+/// Build the user table template, and give the running table its user half.
+/// Must be called once, after the heap and the per-CPU block of the BSP are set up.
+#[cfg(feature = "pti")]
+pub unsafe fn init(active_table: &mut ActivePageTable) {
+    assert_eq!(PTI_STACK_SLOT, 0xfff0, "pti: the trampolines assume 64 KiB per-CPU blocks");
+
+    // Global pages survive CR3 switches, which would leave the kernel in the TLB in user mode
+    asm!("mov rax, cr4
+          btr rax, 7
+          mov cr4, rax"
+          : : : "rax" : "intel", "volatile");
+
+    let template = memory::allocate_frames(1).expect("pti: failed to allocate the template table");
+    table_at(template).zero();
+    TEMPLATE.call_once(|| template);
+
+    let (text_start, text_end) = interrupt::entry::text();
+    map_template(active_table, text_start, text_end, EntryFlags::PRESENT);
+
+    let data = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    for &(start, size) in crate::gdt::tables().iter() {
+        map_template(active_table, start, start + size, data);
+    }
+    let (start, size) = crate::idt::table();
+    map_template(active_table, start, start + size, data);
+
+    init_cpu(active_table, 0);
+
+    // Every table is a pair from now on, so move the running table into one
+    let (old, flags) = Cr3::read();
+    let frame = memory::allocate_order(1).expect("pti: failed to allocate the kernel table");
+    let (new_table, old_table) = (table_at(frame), table_at(old));
+    for i in 0..ENTRY_COUNT {
+        new_table[i].set_addr(old_table[i].addr(), old_table[i].flags());
+    }
+    init_user_table(frame);
+    Cr3::write(frame, flags);
+}
+
+/// Map the parts of the per-CPU block of `cpu_id` that are used before switching tables
+#[cfg(feature = "pti")]
+pub unsafe fn init_cpu(active_table: &mut ActivePageTable, cpu_id: usize) {
+    assert!(mem::size_of::<percpu::PercpuBlock>() <= KERNEL_PERCPU_SIZE - PAGE_SIZE, "pti: per-CPU block overlaps the PTI stack");
+
+    let block = percpu::block_address(cpu_id);
+    let data = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    map_template(active_table, block, block + PAGE_SIZE, data);
+    map_template(active_table, block + KERNEL_PERCPU_SIZE - PAGE_SIZE, block + KERNEL_PERCPU_SIZE, data);
+}
+
+#[cfg(feature = "pti")]
+fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame).as_mut_ptr::<PageTable>() }
+}
+
+/// Map `start..end` in the template to the same frames as in the kernel
+#[cfg(feature = "pti")]
+unsafe fn map_template(active_table: &mut ActivePageTable, start: usize, end: usize, flags: EntryFlags) {
+    let template = table_at(*TEMPLATE.r#try().expect("pti: template not initialized"));
+    let mut mapper = table::MappedTable::new(template, |frame| phys_to_virt(frame).as_mut_ptr());
+
+    let start_page = Page::containing_address(VirtAddr::new(start as u64));
+    let end_page = Page::containing_address(VirtAddr::new(end as u64 - 1));
+    for page in Page::range_inclusive(start_page, end_page) {
+        let phys = active_table.translate_addr(page.start_address()).expect("pti: mapping an unmapped page");
+        let frame = PhysFrame::containing_address(phys);
+        // Ranges may share pages, which are then already mapped. This is not the active table,
+        // so the flush can be ignored.
+        if let Ok(result) = mapper.map_to(page, frame, flags, FRAME_ALLOCATOR.lock().as_mut().unwrap()) {
+            result.ignore();
+        }
+    }
+}
+
+/// Set up the user table of the pair starting at `frame`
+#[cfg(feature = "pti")]
+pub fn init_user_table(frame: PhysFrame) {
+    let shared = table::shared_entries();
+    let template = table_at(*TEMPLATE.r#try().expect("pti: template not initialized"));
+    let user = table_at(frame + 1);
+
+    user.zero();
+    for i in 0..ENTRY_COUNT {
+        if shared[i] && !template[i].is_unused() {
+            user[i].set_addr(template[i].addr(), template[i].flags());
+        }
+    }
+    sync(frame);
+}
+
+#[cfg(not(feature = "pti"))]
+#[inline(always)]
+pub fn init_user_table(_frame: x86_64::structures::paging::PhysFrame) {}
+
+/// Copy the user half of the table pair starting at `frame` to its user table. Called whenever
+/// a table is flushed, as that follows every change to the user half.
+#[cfg(feature = "pti")]
+pub fn sync(frame: PhysFrame) {
+    // Tables only come in pairs once `init` is done
+    if TEMPLATE.r#try().is_none() {
+        return;
+    }
+
+    let shared = table::shared_entries();
+    let kernel = table_at(frame);
+    let user = table_at(frame + 1);
+
+    for i in 0..ENTRY_COUNT {
+        if !shared[i] {
+            if kernel[i].is_unused() {
+                user[i].set_unused();
+            } else {
+                user[i].set_addr(kernel[i].addr(), kernel[i].flags());
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "pti"))]
+#[inline(always)]
+pub fn sync(_frame: x86_64::structures::paging::PhysFrame) {}
+
+/// Address of the PTI stack slot of the current CPU
+#[cfg(feature = "pti")]
+pub fn stack_slot() -> usize {
+    percpu::current() as *const percpu::PercpuBlock as usize + PTI_STACK_SLOT
+}
+
+/// Record the kernel stack that entries from user mode move to
+#[cfg(feature = "pti")]
+pub unsafe fn set_context_stack(stack: usize) {
+    *(stack_slot() as *mut usize) = stack;
+}
@@ -0,0 +1,73 @@
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::{MODE_CHR, SEEK_CUR};
+use crate::syscall::scheme::Scheme;
+use crate::{print, serial_print};
+
+/// Writes go to the VGA text buffer and the serial port, reads return end of file as there is no
+/// console input
+pub struct DebugScheme;
+
+impl Scheme for DebugScheme {
+    fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        if path.is_empty() {
+            Ok(0)
+        } else {
+            Err(Error::new(ENOENT))
+        }
+    }
+
+    fn dup(&self, _old_id: usize, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            Ok(0)
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    fn read(&self, _id: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, _id: usize, buf: &[u8]) -> Result<usize> {
+        for &b in buf {
+            print!("{}", b as char);
+            serial_print!("{}", b as char);
+        }
+        Ok(buf.len())
+    }
+
+    fn seek(&self, _id: usize, _pos: isize, whence: usize) -> Result<usize> {
+        match whence {
+            SEEK_CUR => Ok(0),
+            _ => Err(Error::new(ESPIPE)),
+        }
+    }
+
+    fn fpath(&self, _id: usize, buf: &mut [u8]) -> Result<usize> {
+        let path = b"debug:";
+        let count = path.len().min(buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn fstat(&self, _id: usize, stat: &mut Stat) -> Result<usize> {
+        *stat = Stat {
+            st_mode: MODE_CHR | 0o666,
+            ..Default::default()
+        };
+        Ok(0)
+    }
+
+    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn fsync(&self, _id: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn close(&self, _id: usize) -> Result<usize> {
+        Ok(0)
+    }
+}
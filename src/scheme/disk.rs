@@ -0,0 +1,337 @@
+//! # Disk scheme
+//!
+//! Serves whole block devices as files, such as the ATA disks as `disk:hda`. Opening `disk:`
+//! lists them. Reads and writes go to the device at any byte offset, the sectors they cover only
+//! partly being read first. Only root may open disks; a volume mounted from one keeps its own
+//! cache, so writing the disk under it is best avoided.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+
+#[derive(Clone)]
+struct Handle {
+    /// The disk, or `None` for the listing
+    disk: Option<usize>,
+    flags: usize,
+    offset: usize,
+}
+
+struct Disk<D> {
+    name: Box<[u8]>,
+    device: Mutex<D>,
+}
+
+/// `disk:` - block devices
+pub struct DiskScheme<D> {
+    disks: Vec<Disk<D>>,
+    listing: Vec<u8>,
+    next_id: AtomicUsize,
+    handles: Mutex<BTreeMap<usize, Handle>>,
+}
+
+impl<D: BlockDevice> DiskScheme<D> {
+    /// Serve each device as its name
+    pub fn new(devices: Vec<(&[u8], D)>) -> DiskScheme<D> {
+        let mut listing = Vec::new();
+        let mut disks = Vec::new();
+        for (name, device) in devices {
+            listing.extend_from_slice(name);
+            listing.push(b'\n');
+            disks.push(Disk {
+                name: name.to_vec().into_boxed_slice(),
+                device: Mutex::new(device),
+            });
+        }
+
+        DiskScheme {
+            disks,
+            listing,
+            next_id: AtomicUsize::new(0),
+            handles: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn handle(&self, id: usize) -> Result<Handle> {
+        self.handles.lock().get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+
+    /// The disk of a handle and its size in bytes
+    fn disk(&self, handle: &Handle) -> Result<(&Disk<D>, usize)> {
+        let disk = &self.disks[handle.disk.ok_or(Error::new(EISDIR))?];
+        let size = disk.device.lock().sectors() as usize * SECTOR_SIZE;
+        Ok((disk, size))
+    }
+
+    fn set_offset(&self, id: usize, offset: usize) {
+        if let Some(handle) = self.handles.lock().get_mut(&id) {
+            handle.offset = offset;
+        }
+    }
+}
+
+impl<D: BlockDevice + Send> Scheme for DiskScheme<D> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let parts: Vec<&[u8]> = path.split(|&b| b == b'/').filter(|part| !part.is_empty()).collect();
+        let disk = match parts[..] {
+            [] => {
+                if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(EISDIR));
+                }
+                None
+            },
+            [name] => {
+                let index = self.disks.iter().position(|disk| &*disk.name == name).ok_or(Error::new(ENOENT))?;
+                if flags & O_DIRECTORY == O_DIRECTORY {
+                    return Err(Error::new(ENOTDIR));
+                }
+                Some(index)
+            },
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, Handle {
+            disk,
+            flags: flags & !O_CREAT & !O_EXCL & !O_TRUNC,
+            offset: 0,
+        });
+        Ok(id)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let handle = self.handle(old_id)?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_ACCMODE == O_WRONLY {
+            return Err(Error::new(EBADF));
+        }
+
+        let (disk, size) = match handle.disk {
+            Some(_) => self.disk(&handle)?,
+            None => {
+                let start = cmp::min(handle.offset, self.listing.len());
+                let count = cmp::min(buf.len(), self.listing.len() - start);
+                buf[..count].copy_from_slice(&self.listing[start..start + count]);
+                self.set_offset(id, handle.offset + count);
+                return Ok(count);
+            }
+        };
+
+        let start = cmp::min(handle.offset, size);
+        let end = start + cmp::min(buf.len(), size - start);
+        if start == end {
+            return Ok(0);
+        }
+
+        let first = start / SECTOR_SIZE;
+        let last = (end + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        let mut device = disk.device.lock();
+        if start % SECTOR_SIZE == 0 && end % SECTOR_SIZE == 0 {
+            device.read(first as u64, &mut buf[..end - start])?;
+        } else {
+            let mut sectors = vec![0; (last - first) * SECTOR_SIZE];
+            device.read(first as u64, &mut sectors)?;
+            let skip = start - first * SECTOR_SIZE;
+            buf[..end - start].copy_from_slice(&sectors[skip..skip + end - start]);
+        }
+
+        self.set_offset(id, end);
+        Ok(end - start)
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_ACCMODE == O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+        let (disk, size) = self.disk(&handle)?;
+
+        let start = handle.offset;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if start >= size {
+            return Err(Error::new(ENOSPC));
+        }
+        let end = start + cmp::min(buf.len(), size - start);
+
+        let first = start / SECTOR_SIZE;
+        let last = (end + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        let mut device = disk.device.lock();
+        if start % SECTOR_SIZE == 0 && end % SECTOR_SIZE == 0 {
+            device.write(first as u64, &buf[..end - start])?;
+        } else {
+            // Keep the rest of the sectors at either end
+            let mut sectors = vec![0; (last - first) * SECTOR_SIZE];
+            if start % SECTOR_SIZE != 0 {
+                device.read(first as u64, &mut sectors[..SECTOR_SIZE])?;
+            }
+            if end % SECTOR_SIZE != 0 {
+                let tail = sectors.len() - SECTOR_SIZE;
+                device.read(last as u64 - 1, &mut sectors[tail..])?;
+            }
+            let skip = start - first * SECTOR_SIZE;
+            sectors[skip..skip + end - start].copy_from_slice(&buf[..end - start]);
+            device.write(first as u64, &sectors)?;
+        }
+
+        self.set_offset(id, end);
+        Ok(end - start)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let handle = self.handle(id)?;
+        let len = match handle.disk {
+            Some(_) => self.disk(&handle)?.1,
+            None => self.listing.len(),
+        };
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => handle.offset as isize,
+            SEEK_END => len as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let new_offset = base.checked_add(pos).filter(|&offset| offset >= 0).ok_or(Error::new(EINVAL))?;
+
+        self.set_offset(id, new_offset as usize);
+        Ok(new_offset as usize)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut handles = self.handles.lock();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(handle.flags),
+            F_SETFL => {
+                handle.flags = (handle.flags & O_ACCMODE) | (arg & !O_ACCMODE);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+
+        let mut path = b"disk:".to_vec();
+        if let Some(index) = handle.disk {
+            path.extend_from_slice(&self.disks[index].name);
+        }
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handle = self.handle(id)?;
+
+        *stat = match handle.disk {
+            Some(index) => {
+                let size = self.disk(&handle)?.1 as u64;
+                Stat {
+                    st_ino: index as u64 + 1,
+                    st_mode: MODE_FILE | 0o600,
+                    st_nlink: 1,
+                    st_size: size,
+                    st_blksize: SECTOR_SIZE as u32,
+                    st_blocks: size / 512,
+                    ..Default::default()
+                }
+            },
+            None => Stat {
+                st_mode: MODE_DIR | 0o500,
+                st_nlink: 1,
+                st_size: self.listing.len() as u64,
+                ..Default::default()
+            },
+        };
+        Ok(0)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if let Some(index) = handle.disk {
+            self.disks[index].device.lock().flush()?;
+        }
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.lock().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    fn scheme() -> DiskScheme<RamDisk> {
+        let data = (0..4 * SECTOR_SIZE).map(|i| i as u8).collect();
+        DiskScheme::new(vec![(&b"hda"[..], RamDisk::new(data)), (&b"hdb"[..], RamDisk::new(vec![0; 512]))])
+    }
+
+    #[test]
+    fn listing_and_access() {
+        let scheme = scheme();
+        assert_eq!(scheme.open(b"hda", O_RDWR, 1000, 100), Err(Error::new(EACCES)));
+        assert_eq!(scheme.open(b"hdc", O_RDWR, 0, 0), Err(Error::new(ENOENT)));
+
+        let root = scheme.open(b"", O_RDONLY, 0, 0).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(scheme.read(root, &mut buf), Ok(8));
+        assert_eq!(&buf[..8], b"hda\nhdb\n");
+
+        let id = scheme.open(b"hda", O_RDONLY, 0, 0).unwrap();
+        let mut stat = Stat::default();
+        scheme.fstat(id, &mut stat).unwrap();
+        assert_eq!(stat.st_size, 4 * SECTOR_SIZE as u64);
+        assert_eq!(scheme.write(id, b"x"), Err(Error::new(EBADF)));
+    }
+
+    #[test]
+    fn partial_sectors() {
+        let scheme = scheme();
+        let id = scheme.open(b"hda", O_RDWR, 0, 0).unwrap();
+
+        // Across a sector boundary, keeping the bytes around it
+        assert_eq!(scheme.seek(id, 510, SEEK_SET), Ok(510));
+        assert_eq!(scheme.write(id, b"abcd"), Ok(4));
+        assert_eq!(scheme.seek(id, 508, SEEK_SET), Ok(508));
+        let mut buf = [0; 8];
+        assert_eq!(scheme.read(id, &mut buf), Ok(8));
+        assert_eq!(buf, [252, 253, b'a', b'b', b'c', b'd', 2, 3]);
+
+        // Reads and writes stop at the end of the disk
+        assert_eq!(scheme.seek(id, -2, SEEK_END), Ok(4 * SECTOR_SIZE - 2));
+        assert_eq!(scheme.write(id, b"xyz"), Ok(2));
+        assert_eq!(scheme.read(id, &mut buf), Ok(0));
+        assert_eq!(scheme.write(id, b"z"), Err(Error::new(ENOSPC)));
+        assert_eq!(scheme.seek(id, -4, SEEK_END), Ok(4 * SECTOR_SIZE - 4));
+        assert_eq!(scheme.read(id, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], &[252, 253, b'x', b'y']);
+    }
+}
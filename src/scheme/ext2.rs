@@ -0,0 +1,711 @@
+//! # ext2 scheme
+//!
+//! Serves an ext2 volume on a block device. Owners and permission bits come from the inodes and
+//! are checked against the user that opened a handle, the way `tmp:` does. Symbolic links are
+//! followed within the volume, and removed files stay readable until their last handle closes.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+use spin::Mutex;
+
+use crate::block::BlockDevice;
+use crate::fs::ext2::inode::Inode;
+use crate::fs::ext2::{FileSystem, ROOT};
+use crate::fs::perm::{self, Permissions, PERM_EXEC, PERM_WRITE};
+use crate::syscall::data::{Stat, StatVfs, TimeSpec};
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+use crate::time;
+
+/// Longest symbolic link target that is followed
+const MAX_TARGET: u64 = 4096;
+
+fn now() -> u32 {
+    time::realtime().0 as u32
+}
+
+/// The components of a path, without empty and `.` ones, and with `..` dropping the one
+/// before it
+fn components(path: &[u8]) -> Vec<Vec<u8>> {
+    let mut parts = Vec::new();
+    for part in path.split(|&b| b == b'/').filter(|part| !part.is_empty() && *part != b".") {
+        if part == b".." {
+            parts.pop();
+        } else {
+            parts.push(part.to_vec());
+        }
+    }
+    parts
+}
+
+fn join(parts: &[Vec<u8>]) -> Vec<u8> {
+    let mut path = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            path.push(b'/');
+        }
+        path.extend_from_slice(part);
+    }
+    path
+}
+
+/// Where a path leads: a name in a directory and its inode if it exists, and the path without
+/// symbolic links. The root has an empty name.
+struct Lookup {
+    dir: u32,
+    name: Vec<u8>,
+    inode: Option<u32>,
+    path: Vec<Vec<u8>>,
+}
+
+/// One walk along a path, which ends or meets a symbolic link to follow
+enum Walk {
+    Found(Lookup),
+    Link(Vec<Vec<u8>>),
+}
+
+#[derive(Clone)]
+struct Handle {
+    inode: u32,
+    /// The directory entry the handle was opened through
+    parent: u32,
+    name: Vec<u8>,
+    /// Path from the root, for `fpath`
+    path: Vec<u8>,
+    flags: usize,
+    offset: usize,
+    uid: u32,
+    gid: u32,
+    /// Entry names of a directory, read when it was opened
+    listing: Option<Vec<u8>>,
+}
+
+struct Volume<D> {
+    fs: FileSystem<D>,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    fn handle(&self, id: usize) -> Result<&Handle> {
+        self.handles.get(&id).ok_or(Error::new(EBADF))
+    }
+
+    fn handle_mut(&mut self, id: usize) -> Result<&mut Handle> {
+        self.handles.get_mut(&id).ok_or(Error::new(EBADF))
+    }
+
+    /// The target of a symbolic link
+    fn read_link(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.size() > MAX_TARGET {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+        let mut target = vec![0; inode.size() as usize];
+        let count = self.fs.read(inode, 0, &mut target)?;
+        target.truncate(count);
+        Ok(target)
+    }
+
+    /// Resolve a path. Symbolic links are followed except for the last component when
+    /// `follow` is false.
+    fn lookup(&mut self, path: &[u8], uid: u32, gid: u32, follow: bool) -> Result<Lookup> {
+        let mut parts = components(path);
+        let mut links = 0;
+        loop {
+            match self.walk(&parts, uid, gid, follow)? {
+                Walk::Found(lookup) => return Ok(lookup),
+                Walk::Link(next) => {
+                    perm::follow_link(&mut links)?;
+                    parts = next;
+                }
+            }
+        }
+    }
+
+    fn walk(&mut self, parts: &[Vec<u8>], uid: u32, gid: u32, follow: bool) -> Result<Walk> {
+        if parts.is_empty() {
+            return Ok(Walk::Found(Lookup {
+                dir: ROOT,
+                name: Vec::new(),
+                inode: Some(ROOT),
+                path: Vec::new(),
+            }));
+        }
+
+        let mut dir = ROOT;
+        for (i, part) in parts.iter().enumerate() {
+            let last = i + 1 == parts.len();
+
+            let inode = self.fs.inode(dir)?;
+            if !inode.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            if !inode.permission(uid, gid, PERM_EXEC) {
+                return Err(Error::new(EACCES));
+            }
+
+            let child = self.fs.find(dir, part)?;
+            if let Some(child) = child {
+                let inode = self.fs.inode(child)?;
+                if inode.is_symlink() && (follow || !last) {
+                    let target = self.read_link(&inode)?;
+                    if target.contains(&b':') {
+                        // Links into other schemes cannot be followed here
+                        return Err(Error::new(EXDEV));
+                    }
+
+                    // The target replaces the link, relative to the directory holding it
+                    let mut next = if target.starts_with(b"/") { Vec::new() } else { join(&parts[..i]) };
+                    next.push(b'/');
+                    next.extend_from_slice(&target);
+                    for rest in &parts[i + 1..] {
+                        next.push(b'/');
+                        next.extend_from_slice(rest);
+                    }
+                    return Ok(Walk::Link(components(&next)));
+                }
+            }
+
+            if last {
+                return Ok(Walk::Found(Lookup {
+                    dir,
+                    name: part.clone(),
+                    inode: child,
+                    path: parts.to_vec(),
+                }));
+            }
+            dir = child.ok_or(Error::new(ENOENT))?;
+        }
+
+        unreachable!()
+    }
+
+    /// Whether a user may remove or replace `inode`, an entry of the directory `dir`
+    fn may_remove(&mut self, dir: u32, inode: &Inode, uid: u32, gid: u32) -> Result<()> {
+        perm::check_remove(&self.fs.inode(dir)?, inode, uid, gid)
+    }
+
+    /// Free an inode without directory entries once no handle refers to it
+    fn release(&mut self, ino: u32) -> Result<()> {
+        if self.handles.values().any(|handle| handle.inode == ino) {
+            return Ok(());
+        }
+        self.fs.release(ino)
+    }
+
+    fn open(&mut self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let follow = flags & (O_NOFOLLOW | O_SYMLINK) == 0;
+        let lookup = self.lookup(path, uid, gid, follow)?;
+
+        let ino = match lookup.inode {
+            Some(ino) => {
+                if flags & O_CREAT == O_CREAT && flags & O_EXCL == O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+                ino
+            },
+            None => {
+                if flags & O_CREAT != O_CREAT {
+                    return Err(Error::new(ENOENT));
+                }
+                if !self.fs.inode(lookup.dir)?.permission(uid, gid, PERM_WRITE | PERM_EXEC) {
+                    return Err(Error::new(EACCES));
+                }
+                let kind = if flags & O_DIRECTORY == O_DIRECTORY {
+                    MODE_DIR
+                } else if flags & O_SYMLINK == O_SYMLINK {
+                    MODE_SYMLINK
+                } else {
+                    MODE_FILE
+                };
+                self.fs.create(lookup.dir, &lookup.name, kind | (flags as u16 & MODE_PERM), uid, gid)?
+            }
+        };
+
+        let inode = self.fs.inode(ino)?;
+        let flags = perm::check_open(&inode, flags, uid, gid)?;
+        if flags & O_WRONLY == O_WRONLY && self.fs.read_only() {
+            return Err(Error::new(EROFS));
+        }
+
+        let listing = if inode.is_dir() {
+            let mut listing = Vec::new();
+            for entry in self.fs.list(ino)? {
+                listing.extend_from_slice(&entry.name);
+                listing.push(b'\n');
+            }
+            Some(listing)
+        } else {
+            None
+        };
+
+        if flags & O_TRUNC == O_TRUNC && flags & O_WRONLY == O_WRONLY && !inode.is_dir() {
+            self.fs.truncate(ino, 0)?;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, Handle {
+            inode: ino,
+            parent: lookup.dir,
+            name: lookup.name,
+            path: join(&lookup.path),
+            flags: flags & !O_CREAT & !O_EXCL & !O_TRUNC,
+            offset: 0,
+            uid,
+            gid,
+            listing,
+        });
+        Ok(id)
+    }
+
+    fn chmod(&mut self, path: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        let lookup = self.lookup(path, uid, gid, true)?;
+        self.set_mode(lookup.inode.ok_or(Error::new(ENOENT))?, mode, uid)
+    }
+
+    fn set_mode(&mut self, ino: u32, mode: u16, uid: u32) -> Result<usize> {
+        if !self.fs.inode(ino)?.owned_by(uid) {
+            return Err(Error::new(EPERM));
+        }
+        self.fs.update(ino, |inode| {
+            inode.set_mode((inode.mode() & MODE_TYPE) | (mode & MODE_PERM));
+            inode.set_ctime(now());
+        })?;
+        Ok(0)
+    }
+
+    fn unlink(&mut self, path: &[u8], uid: u32, gid: u32, dir: bool) -> Result<usize> {
+        let lookup = self.lookup(path, uid, gid, false)?;
+        let ino = lookup.inode.ok_or(Error::new(ENOENT))?;
+        if lookup.name.is_empty() {
+            return Err(Error::new(EBUSY));
+        }
+
+        let inode = self.fs.inode(ino)?;
+        if dir && !inode.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        if !dir && inode.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+
+        self.may_remove(lookup.dir, &inode, uid, gid)?;
+        self.fs.unlink(lookup.dir, &lookup.name)?;
+        self.release(ino)?;
+        Ok(0)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        if handle.flags & O_RDONLY != O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+
+        let count = match handle.listing {
+            Some(ref listing) => {
+                let start = cmp::min(handle.offset, listing.len());
+                let count = cmp::min(buf.len(), listing.len() - start);
+                buf[..count].copy_from_slice(&listing[start..start + count]);
+                count
+            },
+            None => {
+                let inode = self.fs.inode(handle.inode)?;
+                self.fs.read(&inode, handle.offset as u64, buf)?
+            }
+        };
+
+        self.handle_mut(id)?.offset += count;
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_WRONLY != O_WRONLY || handle.listing.is_some() {
+            return Err(Error::new(EBADF));
+        }
+        let (ino, append, offset) = (handle.inode, handle.flags & O_APPEND == O_APPEND, handle.offset);
+
+        let position = if append { self.fs.inode(ino)?.size() } else { offset as u64 };
+        let count = self.fs.write(ino, position, buf)?;
+
+        self.handle_mut(id)?.offset = position as usize + count;
+        Ok(count)
+    }
+
+    fn seek(&mut self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let (ino, offset, listing_len) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.offset, handle.listing.as_ref().map(|listing| listing.len()))
+        };
+        let len = match listing_len {
+            Some(len) => len,
+            None => self.fs.inode(ino)?.size() as usize,
+        };
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => offset as isize,
+            SEEK_END => len as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let new_offset = base.checked_add(pos).filter(|&offset| offset >= 0).ok_or(Error::new(EINVAL))?;
+
+        self.handle_mut(id)?.offset = new_offset as usize;
+        Ok(new_offset as usize)
+    }
+
+    fn fchown(&mut self, id: usize, new_uid: u32, new_gid: u32) -> Result<usize> {
+        let (ino, uid, gid) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.uid, handle.gid)
+        };
+
+        perm::check_chown(&self.fs.inode(ino)?, uid, gid, new_uid, new_gid)?;
+
+        self.fs.update(ino, |inode| {
+            if new_uid != u32::max_value() {
+                inode.set_uid(new_uid);
+            }
+            if new_gid != u32::max_value() {
+                inode.set_gid(new_gid);
+            }
+            inode.set_ctime(now());
+        })?;
+        Ok(0)
+    }
+
+    fn frename(&mut self, id: usize, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let (ino, old_dir, old_name) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.parent, handle.name.clone())
+        };
+        if old_name.is_empty() {
+            return Err(Error::new(EBUSY));
+        }
+        // The entry may have been moved or removed through another handle
+        if self.fs.find(old_dir, &old_name)? != Some(ino) {
+            return Err(Error::new(ENOENT));
+        }
+
+        let lookup = self.lookup(path, uid, gid, false)?;
+        if lookup.name.is_empty() {
+            return Err(Error::new(EBUSY));
+        }
+
+        let inode = self.fs.inode(ino)?;
+        self.may_remove(old_dir, &inode, uid, gid)?;
+        if !self.fs.inode(lookup.dir)?.permission(uid, gid, PERM_WRITE | PERM_EXEC) {
+            return Err(Error::new(EACCES));
+        }
+        if let Some(existing) = lookup.inode.filter(|&existing| existing != ino) {
+            let target = self.fs.inode(existing)?;
+            self.may_remove(lookup.dir, &target, uid, gid)?;
+        }
+
+        if let Some(replaced) = self.fs.rename(old_dir, &old_name, lookup.dir, &lookup.name)? {
+            self.release(replaced)?;
+        }
+
+        let path = join(&lookup.path);
+        for handle in self.handles.values_mut() {
+            if handle.inode == ino && handle.parent == old_dir && handle.name == old_name {
+                handle.parent = lookup.dir;
+                handle.name = lookup.name.clone();
+                handle.path = path.clone();
+            }
+        }
+        Ok(0)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let ino = self.handle(id)?.inode;
+        let inode = self.fs.inode(ino)?;
+
+        *stat = Stat {
+            st_ino: u64::from(ino),
+            st_mode: inode.mode(),
+            st_nlink: u32::from(inode.links()),
+            st_uid: inode.uid(),
+            st_gid: inode.gid(),
+            st_size: inode.size(),
+            st_blksize: self.fs.block_size() as u32,
+            st_blocks: u64::from(inode.sectors()),
+            st_mtime: u64::from(inode.mtime()),
+            st_atime: u64::from(inode.atime()),
+            st_ctime: u64::from(inode.ctime()),
+            ..Default::default()
+        };
+        Ok(0)
+    }
+
+    fn ftruncate(&mut self, id: usize, len: usize) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_WRONLY != O_WRONLY || handle.listing.is_some() {
+            return Err(Error::new(EBADF));
+        }
+        let ino = handle.inode;
+        self.fs.truncate(ino, len as u64)?;
+        Ok(0)
+    }
+
+    fn futimens(&mut self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        let (ino, uid) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.uid)
+        };
+        if !self.fs.inode(ino)?.owned_by(uid) {
+            return Err(Error::new(EPERM));
+        }
+
+        let time = now();
+        let to_secs = |spec: &TimeSpec| -> Result<u32> {
+            if spec.tv_sec < 0 || spec.tv_nsec < 0 || spec.tv_nsec >= 1_000_000_000 {
+                return Err(Error::new(EINVAL));
+            }
+            Ok(spec.tv_sec as u32)
+        };
+        let atime = times.get(0).map_or(Ok(time), &to_secs)?;
+        let mtime = times.get(1).map_or(Ok(time), &to_secs)?;
+
+        self.fs.update(ino, |inode| {
+            inode.set_atime(atime);
+            inode.set_mtime(mtime);
+            inode.set_ctime(time);
+        })?;
+        Ok(0)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        self.release(handle.inode)?;
+        Ok(0)
+    }
+}
+
+/// `name:` - an ext2 volume
+pub struct Ext2Scheme<D> {
+    name: Box<[u8]>,
+    volume: Mutex<Volume<D>>,
+}
+
+impl<D: BlockDevice> Ext2Scheme<D> {
+    /// Serve the volume on `device` as the scheme `name`
+    pub fn new(name: &[u8], device: D) -> Result<Ext2Scheme<D>> {
+        Ok(Ext2Scheme {
+            name: name.to_vec().into_boxed_slice(),
+            volume: Mutex::new(Volume {
+                fs: FileSystem::new(device)?,
+                handles: BTreeMap::new(),
+                next_id: 0,
+            }),
+        })
+    }
+}
+
+impl<D: BlockDevice + Send> Scheme for Ext2Scheme<D> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        self.volume.lock().open(path, flags, uid, gid)
+    }
+
+    fn chmod(&self, path: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        self.volume.lock().chmod(path, mode, uid, gid)
+    }
+
+    fn rmdir(&self, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        self.volume.lock().unlink(path, uid, gid, true)
+    }
+
+    fn unlink(&self, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        self.volume.lock().unlink(path, uid, gid, false)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut volume = self.volume.lock();
+        let handle = volume.handle(old_id)?.clone();
+        let id = volume.next_id;
+        volume.next_id += 1;
+        volume.handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.volume.lock().read(id, buf)
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        self.volume.lock().write(id, buf)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        self.volume.lock().seek(id, pos, whence)
+    }
+
+    fn fchmod(&self, id: usize, mode: u16) -> Result<usize> {
+        let mut volume = self.volume.lock();
+        let (ino, uid) = {
+            let handle = volume.handle(id)?;
+            (handle.inode, handle.uid)
+        };
+        volume.set_mode(ino, mode, uid)
+    }
+
+    fn fchown(&self, id: usize, uid: u32, gid: u32) -> Result<usize> {
+        self.volume.lock().fchown(id, uid, gid)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut volume = self.volume.lock();
+        let handle = volume.handle_mut(id)?;
+        match cmd {
+            F_GETFL => Ok(handle.flags),
+            F_SETFL => {
+                handle.flags = (handle.flags & O_ACCMODE) | (arg & !O_ACCMODE);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let volume = self.volume.lock();
+        let mut path = self.name.to_vec();
+        path.push(b':');
+        path.extend_from_slice(&volume.handle(id)?.path);
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn frename(&self, id: usize, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        self.volume.lock().frename(id, path, uid, gid)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        self.volume.lock().fstat(id, stat)
+    }
+
+    fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
+        let volume = self.volume.lock();
+        volume.handle(id)?;
+
+        let free = u64::from(volume.fs.free_blocks());
+        *stat = StatVfs {
+            f_bsize: volume.fs.block_size() as u32,
+            f_blocks: u64::from(volume.fs.blocks()),
+            f_bfree: free,
+            f_bavail: free.saturating_sub(u64::from(volume.fs.reserved_blocks())),
+        };
+        Ok(0)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        let mut volume = self.volume.lock();
+        volume.handle(id)?;
+        volume.fs.sync()?;
+        Ok(0)
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        self.volume.lock().ftruncate(id, len)
+    }
+
+    fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        self.volume.lock().futimens(id, times)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.volume.lock().close(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    fn scheme() -> Ext2Scheme<RamDisk> {
+        let image = include_bytes!("../../tests/images/ext2.img");
+        Ext2Scheme::new(b"ext2", RamDisk::new(image.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn symlinks() {
+        let scheme = scheme();
+        let id = scheme.open(b"link", O_RDONLY, 0, 0).unwrap();
+        let mut stat = Stat::default();
+        scheme.fstat(id, &mut stat).unwrap();
+        assert_eq!((stat.st_mode, stat.st_size), (MODE_FILE | 0o644, 20_000));
+
+        let link = scheme.open(b"link", O_RDONLY | O_SYMLINK | O_NOFOLLOW, 0, 0).unwrap();
+        let mut buf = [0; 32];
+        assert_eq!(scheme.read(link, &mut buf), Ok(12));
+        assert_eq!(&buf[..12], b"docs/big.bin");
+        assert_eq!(scheme.open(b"link", O_RDONLY | O_NOFOLLOW, 0, 0), Err(Error::new(ELOOP)));
+
+        let up = scheme.open(b"docs/up", O_CREAT | O_SYMLINK | O_WRONLY | 0o777, 0, 0).unwrap();
+        scheme.write(up, b"../hello.txt").unwrap();
+        scheme.close(up).unwrap();
+
+        let id = scheme.open(b"docs/up", O_RDONLY, 0, 0).unwrap();
+        assert_eq!(scheme.read(id, &mut buf), Ok(17));
+        assert_eq!(&buf[..17], b"Hello from ext2!\n");
+        let count = scheme.fpath(id, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"ext2:hello.txt");
+    }
+
+    #[test]
+    fn permissions() {
+        let scheme = scheme();
+        assert_eq!(scheme.open(b"private", O_RDONLY, 1001, 100), Err(Error::new(EACCES)));
+        assert_eq!(scheme.open(b"private", O_STAT | O_RDWR | O_TRUNC, 1001, 100), Err(Error::new(EACCES)));
+        let stat_only = scheme.open(b"private", O_STAT | O_RDONLY, 1001, 100).unwrap();
+        assert_eq!(scheme.read(stat_only, &mut [0; 8]), Err(Error::new(EBADF)));
+        assert_eq!(scheme.write(stat_only, b"x"), Err(Error::new(EBADF)));
+
+        let id = scheme.open(b"private", O_RDWR, 1000, 100).unwrap();
+        assert_eq!(scheme.chmod(b"private", 0o644, 1001, 100), Err(Error::new(EPERM)));
+        assert_eq!(scheme.fchmod(id, 0o640), Ok(0));
+        assert!(scheme.open(b"private", O_RDONLY, 1001, 100).is_ok());
+
+        let mut stat = Stat::default();
+        scheme.fstat(id, &mut stat).unwrap();
+        assert_eq!((stat.st_mode, stat.st_uid, stat.st_gid), (MODE_FILE | 0o640, 1000, 100));
+
+        // The root directory is only writable by root
+        assert_eq!(scheme.open(b"mine", O_CREAT | O_RDWR | 0o600, 1000, 100), Err(Error::new(EACCES)));
+        assert_eq!(scheme.unlink(b"private", 1000, 100), Err(Error::new(EACCES)));
+        assert_eq!(scheme.fchown(id, 1001, u32::max_value()), Err(Error::new(EPERM)));
+    }
+
+    #[test]
+    fn removed_while_open() {
+        let scheme = scheme();
+        let free = scheme.volume.lock().fs.free_inodes();
+
+        let id = scheme.open(b"docs/notes", O_CREAT | O_RDWR | 0o644, 0, 0).unwrap();
+        assert_eq!(scheme.write(id, b"keep me"), Ok(7));
+        assert_eq!(scheme.frename(id, b"hello.txt", 0, 0), Ok(0));
+        let count = scheme.fpath(id, &mut [0; 32]).unwrap();
+        assert_eq!(count, b"ext2:hello.txt".len());
+        // The replaced file is gone, its inode with it
+        assert_eq!(scheme.volume.lock().fs.free_inodes(), free);
+
+        assert_eq!(scheme.unlink(b"hello.txt", 0, 0), Ok(0));
+        assert_eq!(scheme.open(b"hello.txt", O_RDONLY, 0, 0), Err(Error::new(ENOENT)));
+        scheme.seek(id, 0, SEEK_SET).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(scheme.read(id, &mut buf), Ok(7));
+
+        assert_eq!(scheme.volume.lock().fs.free_inodes(), free);
+        scheme.close(id).unwrap();
+        assert_eq!(scheme.volume.lock().fs.free_inodes(), free + 1);
+    }
+}
@@ -0,0 +1,594 @@
+//! # FAT scheme
+//!
+//! Serves a FAT volume on a block device. FAT stores no owners, so files belong to root: everyone
+//! may read them, but only root may create, write, remove, rename or change them. The read-only
+//! attribute, set by `fchmod` without write bits, keeps files from being written even by root.
+//! Open files cannot be removed or replaced, as their directory entry is all that identifies them.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::block::BlockDevice;
+use crate::fs::fat::{Dir, DirEntry, FileSystem};
+use crate::fs::fat::dir::{fat_time, unix_time, ATTR_READ_ONLY};
+use crate::syscall::data::{Stat, StatVfs, TimeSpec};
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+use crate::time;
+
+/// The root directory, or a file or directory found in `parent` with its short entry at `offset`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Node {
+    Root,
+    Entry { parent: Dir, offset: u64 },
+}
+
+/// Where a path leads: the root directory, or a name in a directory and its entry if it exists
+enum Lookup {
+    Root,
+    Entry(Dir, Vec<u8>, Option<DirEntry>),
+}
+
+#[derive(Clone)]
+struct Handle {
+    node: Node,
+    /// Path from the root, for `fpath`
+    path: Vec<u8>,
+    flags: usize,
+    offset: usize,
+    /// The user that opened the handle
+    uid: u32,
+    /// Entry names of a directory, read when it was opened
+    listing: Option<Vec<u8>>,
+}
+
+/// The components of a path, without empty and `.` ones
+fn components(path: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut parts = Vec::new();
+    for part in path.split(|&b| b == b'/').filter(|part| !part.is_empty() && *part != b".") {
+        if part == b".." {
+            parts.pop().ok_or(Error::new(EINVAL))?;
+        } else {
+            parts.push(part);
+        }
+    }
+    Ok(parts)
+}
+
+fn join(parts: &[&[u8]]) -> Vec<u8> {
+    let mut path = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            path.push(b'/');
+        }
+        path.extend_from_slice(part);
+    }
+    path
+}
+
+/// `name:` - a FAT volume
+pub struct FatScheme<D> {
+    name: Box<[u8]>,
+    fs: Mutex<FileSystem<D>>,
+    next_id: AtomicUsize,
+    /// Locked after `fs` when both are needed
+    handles: Mutex<BTreeMap<usize, Handle>>,
+}
+
+impl<D: BlockDevice> FatScheme<D> {
+    /// Serve the volume on `device` as the scheme `name`
+    pub fn new(name: &[u8], device: D) -> Result<FatScheme<D>> {
+        Ok(FatScheme {
+            name: name.to_vec().into_boxed_slice(),
+            fs: Mutex::new(FileSystem::new(device)?),
+            next_id: AtomicUsize::new(0),
+            handles: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn lookup(fs: &mut FileSystem<D>, parts: &[&[u8]]) -> Result<Lookup> {
+        let (last, dirs) = match parts.split_last() {
+            Some(split) => split,
+            None => return Ok(Lookup::Root),
+        };
+
+        let mut dir = fs.root();
+        for part in dirs {
+            let entry = fs.find(dir, part)?.ok_or(Error::new(ENOENT))?;
+            dir = fs.dir(&entry.entry)?;
+        }
+
+        let entry = fs.find(dir, last)?;
+        Ok(Lookup::Entry(dir, last.to_vec(), entry))
+    }
+
+    fn handle(&self, id: usize) -> Result<Handle> {
+        self.handles.lock().get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+
+    /// Whether a handle other than `except` has the entry at `offset` open
+    fn is_open(&self, offset: u64, except: Option<usize>) -> bool {
+        self.handles.lock().iter().any(|(&id, handle)| {
+            Some(id) != except && match handle.node {
+                Node::Entry { offset: other, .. } => other == offset,
+                Node::Root => false,
+            }
+        })
+    }
+
+    fn remove(&self, path: &[u8], dir: bool, uid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let mut fs = self.fs.lock();
+        let parts = components(path)?;
+        let entry = match FatScheme::lookup(&mut fs, &parts)? {
+            Lookup::Root => return Err(Error::new(EBUSY)),
+            Lookup::Entry(_, _, entry) => entry.ok_or(Error::new(ENOENT))?,
+        };
+
+        if dir && !entry.entry.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        if !dir && entry.entry.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        if self.is_open(entry.offset, None) {
+            return Err(Error::new(EBUSY));
+        }
+
+        fs.remove(&entry)?;
+        Ok(0)
+    }
+}
+
+impl<D: BlockDevice + Send> Scheme for FatScheme<D> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let parts = components(path)?;
+        let writable = flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR;
+        if uid != 0 && (writable || flags & O_TRUNC == O_TRUNC) {
+            return Err(Error::new(EACCES));
+        }
+
+        let (node, entry) = match FatScheme::lookup(&mut fs, &parts)? {
+            Lookup::Root => (Node::Root, None),
+            Lookup::Entry(parent, name, entry) => {
+                let entry = match entry {
+                    Some(entry) => {
+                        if flags & O_CREAT == O_CREAT && flags & O_EXCL == O_EXCL {
+                            return Err(Error::new(EEXIST));
+                        }
+                        entry
+                    },
+                    None if flags & O_CREAT == O_CREAT => {
+                        if uid != 0 {
+                            return Err(Error::new(EACCES));
+                        }
+                        fs.create(parent, &name, flags & O_DIRECTORY == O_DIRECTORY)?
+                    },
+                    None => return Err(Error::new(ENOENT)),
+                };
+                (Node::Entry { parent, offset: entry.offset }, Some(entry.entry))
+            }
+        };
+
+        let is_dir = entry.as_ref().map_or(true, |entry| entry.is_dir());
+        if flags & O_DIRECTORY == O_DIRECTORY && !is_dir {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let listing = if is_dir {
+            if flags & O_STAT != O_STAT && writable {
+                return Err(Error::new(EISDIR));
+            }
+            let dir = match entry {
+                Some(ref entry) => fs.dir(entry)?,
+                None => fs.root(),
+            };
+            let mut listing = Vec::new();
+            for child in fs.list(dir)? {
+                listing.extend_from_slice(&child.name);
+                listing.push(b'\n');
+            }
+            Some(listing)
+        } else {
+            None
+        };
+
+        if let (Some(entry), Node::Entry { offset, .. }) = (entry, node) {
+            if writable && entry.attr & ATTR_READ_ONLY == ATTR_READ_ONLY {
+                return Err(Error::new(EACCES));
+            }
+            if writable && flags & O_TRUNC == O_TRUNC && !entry.is_dir() {
+                fs.truncate(offset, 0)?;
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, Handle {
+            node,
+            path: join(&parts),
+            flags: flags & !O_CREAT & !O_EXCL & !O_TRUNC,
+            offset: 0,
+            uid,
+            listing,
+        });
+        Ok(id)
+    }
+
+    fn rmdir(&self, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        self.remove(path, true, uid)
+    }
+
+    fn unlink(&self, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        self.remove(path, false, uid)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let handle = self.handle(old_id)?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        if handle.flags & O_ACCMODE == O_WRONLY {
+            return Err(Error::new(EBADF));
+        }
+
+        let count = match (handle.listing, handle.node) {
+            (Some(listing), _) => {
+                let start = cmp::min(handle.offset, listing.len());
+                let count = cmp::min(buf.len(), listing.len() - start);
+                buf[..count].copy_from_slice(&listing[start..start + count]);
+                count
+            },
+            (None, Node::Entry { offset, .. }) => {
+                let entry = fs.entry(offset)?;
+                fs.read(&entry, handle.offset as u64, buf)?
+            },
+            (None, Node::Root) => return Err(Error::new(EISDIR)),
+        };
+
+        if let Some(handle) = self.handles.lock().get_mut(&id) {
+            handle.offset += count;
+        }
+        Ok(count)
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        if handle.flags & O_ACCMODE == O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+        let offset = match handle.node {
+            Node::Entry { offset, .. } if handle.listing.is_none() => offset,
+            _ => return Err(Error::new(EISDIR)),
+        };
+
+        let position = if handle.flags & O_APPEND == O_APPEND {
+            fs.entry(offset)?.size as usize
+        } else {
+            handle.offset
+        };
+        let count = fs.write(offset, position as u64, buf)?;
+
+        if let Some(handle) = self.handles.lock().get_mut(&id) {
+            handle.offset = position + count;
+        }
+        Ok(count)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+
+        let len = match (handle.listing, handle.node) {
+            (Some(listing), _) => listing.len(),
+            (None, Node::Entry { offset, .. }) => fs.entry(offset)?.size as usize,
+            (None, Node::Root) => 0,
+        };
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => handle.offset as isize,
+            SEEK_END => len as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let new_offset = base.checked_add(pos).filter(|&offset| offset >= 0).ok_or(Error::new(EINVAL))?;
+
+        if let Some(handle) = self.handles.lock().get_mut(&id) {
+            handle.offset = new_offset as usize;
+        }
+        Ok(new_offset as usize)
+    }
+
+    fn fchmod(&self, id: usize, mode: u16) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        if handle.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        match handle.node {
+            Node::Entry { offset, .. } => {
+                fs.update(offset, |entry| {
+                    if mode & 0o222 == 0 {
+                        entry.attr |= ATTR_READ_ONLY;
+                    } else {
+                        entry.attr &= !ATTR_READ_ONLY;
+                    }
+                })?;
+                Ok(0)
+            },
+            Node::Root => Err(Error::new(EPERM)),
+        }
+    }
+
+    fn fchown(&self, id: usize, uid: u32, gid: u32) -> Result<usize> {
+        let handle = self.handle(id)?;
+        // Everything belongs to root, which is all FAT can store
+        let keep = |id: u32| id == 0 || id == u32::max_value();
+        if handle.uid == 0 && keep(uid) && keep(gid) {
+            Ok(0)
+        } else {
+            Err(Error::new(EPERM))
+        }
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut handles = self.handles.lock();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(handle.flags),
+            F_SETFL => {
+                handle.flags = (handle.flags & O_ACCMODE) | (arg & !O_ACCMODE);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+
+        let mut path = self.name.to_vec();
+        path.push(b':');
+        path.extend_from_slice(&handle.path);
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn frename(&self, id: usize, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        let (parent, offset) = match handle.node {
+            Node::Entry { parent, offset } => (parent, offset),
+            Node::Root => return Err(Error::new(EBUSY)),
+        };
+
+        let parts = components(path)?;
+        let new_path = join(&parts);
+        // A directory cannot move below itself
+        if new_path.starts_with(&handle.path) && new_path.get(handle.path.len()) == Some(&b'/') {
+            return Err(Error::new(EINVAL));
+        }
+
+        let (to, name, existing) = match FatScheme::lookup(&mut fs, &parts)? {
+            Lookup::Root => return Err(Error::new(EBUSY)),
+            Lookup::Entry(to, name, existing) => (to, name, existing),
+        };
+        if self.is_open(offset, Some(id)) {
+            return Err(Error::new(EBUSY));
+        }
+        if let Some(existing) = existing {
+            if existing.offset != offset && self.is_open(existing.offset, None) {
+                return Err(Error::new(EBUSY));
+            }
+        }
+
+        let entry = fs.find_offset(parent, offset)?;
+        let moved = fs.rename(parent, &entry, to, &name)?;
+
+        if let Some(handle) = self.handles.lock().get_mut(&id) {
+            handle.node = Node::Entry { parent: to, offset: moved.offset };
+            handle.path = new_path;
+        }
+        Ok(0)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        let cluster_size = fs.cluster_size();
+
+        *stat = match handle.node {
+            Node::Root => Stat {
+                st_ino: 1,
+                st_mode: MODE_DIR | 0o755,
+                st_nlink: 1,
+                st_size: cluster_size,
+                st_blksize: cluster_size as u32,
+                st_blocks: cluster_size / 512,
+                ..Default::default()
+            },
+            Node::Entry { offset, .. } => {
+                let entry = fs.entry(offset)?;
+                // Owned by root, which alone may write
+                let perm = if entry.attr & ATTR_READ_ONLY == ATTR_READ_ONLY { 0o555 } else { 0o755 };
+                let (mode, size) = if entry.is_dir() {
+                    (MODE_DIR | perm, cluster_size)
+                } else {
+                    (MODE_FILE | (perm & 0o644), u64::from(entry.size))
+                };
+                Stat {
+                    st_ino: offset / 32,
+                    st_mode: mode,
+                    st_nlink: 1,
+                    st_size: size,
+                    st_blksize: cluster_size as u32,
+                    st_blocks: (size + cluster_size - 1) / cluster_size * (cluster_size / 512),
+                    st_mtime: unix_time(entry.modified.0, entry.modified.1),
+                    st_atime: unix_time(entry.accessed, 0),
+                    st_ctime: unix_time(entry.created.0, entry.created.1),
+                    ..Default::default()
+                }
+            }
+        };
+        Ok(0)
+    }
+
+    fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        self.handle(id)?;
+
+        let free = u64::from(fs.free_clusters()?);
+        *stat = StatVfs {
+            f_bsize: fs.cluster_size() as u32,
+            f_blocks: u64::from(fs.clusters()),
+            f_bfree: free,
+            f_bavail: free,
+        };
+        Ok(0)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        self.handle(id)?;
+        fs.sync()?;
+        Ok(0)
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        if handle.flags & O_ACCMODE == O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+        match handle.node {
+            Node::Entry { offset, .. } => fs.truncate(offset, len as u64).and(Ok(0)),
+            Node::Root => Err(Error::new(EISDIR)),
+        }
+    }
+
+    fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = self.handle(id)?;
+        if handle.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        let offset = match handle.node {
+            Node::Entry { offset, .. } => offset,
+            Node::Root => return Err(Error::new(EPERM)),
+        };
+
+        let now = time::realtime().0;
+        let to_secs = |spec: &TimeSpec| -> Result<u64> {
+            if spec.tv_sec < 0 || spec.tv_nsec < 0 || spec.tv_nsec >= 1_000_000_000 {
+                return Err(Error::new(EINVAL));
+            }
+            Ok(spec.tv_sec as u64)
+        };
+        let atime = times.get(0).map_or(Ok(now), &to_secs)?;
+        let mtime = times.get(1).map_or(Ok(now), &to_secs)?;
+
+        fs.update(offset, |entry| {
+            entry.accessed = fat_time(atime).0;
+            entry.modified = fat_time(mtime);
+        })?;
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.lock().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    fn scheme() -> FatScheme<RamDisk> {
+        let image = include_bytes!("../../tests/images/fat12.img");
+        FatScheme::new(b"fat", RamDisk::new(image.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn files() {
+        let scheme = scheme();
+        let id = scheme.open(b"docs/notes.txt", O_CREAT | O_RDWR, 0, 0);
+        assert_eq!(id, Err(Error::new(ENOENT)));
+
+        let dir = scheme.open(b"docs", O_CREAT | O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+        let id = scheme.open(b"docs/Meeting Notes.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+        assert_eq!(scheme.write(id, b"agenda"), Ok(6));
+        assert_eq!(scheme.seek(id, 0, SEEK_SET), Ok(0));
+        let mut buf = [0; 16];
+        assert_eq!(scheme.read(id, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"agenda");
+
+        assert_eq!(scheme.rmdir(b"docs", 0, 0), Err(Error::new(EBUSY)));
+        assert_eq!(scheme.unlink(b"docs/meeting notes.txt", 0, 0), Err(Error::new(EBUSY)));
+        assert_eq!(scheme.frename(dir, b"docs/inside", 0, 0), Err(Error::new(EINVAL)));
+
+        assert_eq!(scheme.frename(id, b"minutes.txt", 0, 0), Ok(0));
+        let count = scheme.fpath(id, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"fat:minutes.txt");
+
+        let root = scheme.open(b"", O_RDONLY, 0, 0).unwrap();
+        let mut listing = [0; 64];
+        let count = scheme.read(root, &mut listing).unwrap();
+        assert_eq!(&listing[..count], &b"HELLO.TXT\nLong Directory Name\ndocs\nminutes.txt\n"[..]);
+
+        let mut stat = Stat::default();
+        scheme.fstat(id, &mut stat).unwrap();
+        assert_eq!((stat.st_mode, stat.st_size), (MODE_FILE | 0o644, 6));
+
+        scheme.fchmod(id, 0o444).unwrap();
+        assert_eq!(scheme.open(b"minutes.txt", O_WRONLY, 0, 0), Err(Error::new(EACCES)));
+
+        for id in &[id, dir, root] {
+            scheme.close(*id).unwrap();
+        }
+        assert_eq!(scheme.unlink(b"minutes.txt", 0, 0), Ok(0));
+        assert_eq!(scheme.rmdir(b"docs", 0, 0), Ok(0));
+    }
+
+    #[test]
+    fn root_only_writes() {
+        let scheme = scheme();
+        let file = scheme.open(b"HELLO.TXT", O_RDONLY, 1000, 1000).unwrap();
+        let mut buf = [0; 16];
+        assert!(scheme.read(file, &mut buf).is_ok());
+
+        assert_eq!(scheme.open(b"HELLO.TXT", O_WRONLY, 1000, 1000), Err(Error::new(EACCES)));
+        assert_eq!(scheme.open(b"new.txt", O_CREAT | O_RDONLY, 1000, 1000), Err(Error::new(EACCES)));
+        assert_eq!(scheme.unlink(b"HELLO.TXT", 1000, 1000), Err(Error::new(EACCES)));
+        assert_eq!(scheme.rmdir(b"Long Directory Name", 1000, 1000), Err(Error::new(EACCES)));
+        assert_eq!(scheme.frename(file, b"moved.txt", 1000, 1000), Err(Error::new(EACCES)));
+        assert_eq!(scheme.fchmod(file, 0o444), Err(Error::new(EPERM)));
+        assert_eq!(scheme.fchown(file, 0, 0), Err(Error::new(EPERM)));
+
+        let mut stat = Stat::default();
+        scheme.fstat(file, &mut stat).unwrap();
+        assert_eq!((stat.st_uid, stat.st_gid, stat.st_mode & MODE_PERM), (0, 0, 0o644));
+    }
+}
@@ -0,0 +1,295 @@
+//! # Initial filesystem
+//!
+//! `initfs:` serves the folder `build.rs` packed into the kernel, so the first programs can run
+//! before any disk driver does. It is read only, and each file is page aligned so `fmap` can
+//! share its pages with processes.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+use crate::memory::PAGE_SIZE;
+use crate::syscall::data::{Map, Stat};
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+
+/// Page aligned file contents. The size of the wrapper is rounded up to whole pages, so mapping
+/// a file never exposes the memory after it.
+#[repr(C, align(4096))]
+pub struct Aligned<T>(pub T);
+
+/// A directory or file, with a path relative to the root
+pub struct Node {
+    pub path: &'static [u8],
+    pub mode: u16,
+    pub data: &'static [u8],
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_DIR
+    }
+}
+
+mod gen {
+    #![allow(unused_imports)]
+
+    use super::{Aligned, Node};
+    use crate::syscall::flag::{MODE_DIR, MODE_FILE};
+
+    include!(concat!(env!("OUT_DIR"), "/initfs.rs"));
+}
+
+/// The path without empty and `.` components
+fn normalize(path: &[u8]) -> Result<Vec<u8>> {
+    let mut normal = Vec::new();
+    for part in path.split(|&b| b == b'/').filter(|part| !part.is_empty() && *part != b".") {
+        if part == b".." {
+            // Paths are canonical by the time they get here
+            return Err(Error::new(EINVAL));
+        }
+        if !normal.is_empty() {
+            normal.push(b'/');
+        }
+        normal.extend_from_slice(part);
+    }
+    Ok(normal)
+}
+
+/// The names of the entries of the directory `dir` in `nodes`, each followed by a newline
+fn listing(nodes: &[Node], dir: &[u8]) -> Vec<u8> {
+    let mut listing = Vec::new();
+    for node in nodes {
+        let name = if dir.is_empty() {
+            node.path
+        } else if node.path.len() > dir.len() && node.path.starts_with(dir) && node.path[dir.len()] == b'/' {
+            &node.path[dir.len() + 1..]
+        } else {
+            continue;
+        };
+        if !name.is_empty() && !name.contains(&b'/') {
+            listing.extend_from_slice(name);
+            listing.push(b'\n');
+        }
+    }
+    listing
+}
+
+struct Handle {
+    node: &'static Node,
+    /// The directory listing, or the file contents
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl Handle {
+    fn data(&self) -> &[u8] {
+        if self.node.is_dir() { &self.data[..] } else { self.node.data }
+    }
+}
+
+/// `initfs:` - files packed into the kernel
+pub struct InitFsScheme {
+    nodes: &'static [Node],
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Handle>>,
+}
+
+impl InitFsScheme {
+    pub fn new() -> InitFsScheme {
+        InitFsScheme::with_nodes(gen::NODES)
+    }
+
+    fn with_nodes(nodes: &'static [Node]) -> InitFsScheme {
+        InitFsScheme {
+            nodes,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Scheme for InitFsScheme {
+    fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let path = normalize(path)?;
+
+        let node = match self.nodes.iter().find(|node| node.path == &path[..]) {
+            Some(node) => node,
+            None if flags & O_CREAT == O_CREAT => return Err(Error::new(EROFS)),
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        if flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR || flags & O_TRUNC == O_TRUNC {
+            return Err(Error::new(if node.is_dir() { EISDIR } else { EROFS }));
+        }
+        if flags & O_DIRECTORY == O_DIRECTORY && !node.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let data = if node.is_dir() { listing(self.nodes, node.path) } else { Vec::new() };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Handle {
+            node,
+            data,
+            offset: 0,
+        });
+        Ok(id)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut handles = self.handles.write();
+        let handle = {
+            let handle = handles.get(&old_id).ok_or(Error::new(EBADF))?;
+            Handle {
+                node: handle.node,
+                data: handle.data.clone(),
+                offset: handle.offset,
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let count = {
+            let data = handle.data();
+            let start = cmp::min(handle.offset, data.len());
+            let count = cmp::min(buf.len(), data.len() - start);
+            buf[..count].copy_from_slice(&data[start..start + count]);
+            count
+        };
+        handle.offset += count;
+        Ok(count)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => handle.offset as isize,
+            SEEK_END => handle.data().len() as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let offset = base.checked_add(pos).filter(|&offset| offset >= 0).ok_or(Error::new(EINVAL))?;
+
+        handle.offset = offset as usize;
+        Ok(handle.offset)
+    }
+
+    fn fcntl(&self, id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        self.handles.read().get(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn fmap(&self, id: usize, map: &Map) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        if handle.node.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+        if map.flags & PROT_WRITE == PROT_WRITE {
+            return Err(Error::new(EACCES));
+        }
+
+        // Mappings may reach into the padding of the last page, but not past it
+        let data = handle.node.data;
+        let end = map.offset.checked_add(map.size).ok_or(Error::new(EINVAL))?;
+        if end > (data.len() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+
+        Ok(data.as_ptr() as usize + map.offset)
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let mut path = b"initfs:".to_vec();
+        path.extend_from_slice(handle.node.path);
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        let node = handle.node;
+        let size = handle.data().len() as u64;
+
+        let index = self.nodes.iter().position(|other| other.path == node.path).unwrap_or(0);
+        *stat = Stat {
+            st_ino: index as u64 + 1,
+            st_mode: node.mode,
+            st_nlink: 1,
+            st_size: size,
+            st_blksize: PAGE_SIZE as u32,
+            st_blocks: (size + 511) / 512,
+            ..Default::default()
+        };
+        Ok(0)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.handles.read().get(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.write().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static HELLO: Aligned<[u8; 5]> = Aligned(*b"hello");
+
+    static NODES: &[Node] = &[
+        Node { path: b"", mode: MODE_DIR | 0o555, data: &[] },
+        Node { path: b"bin", mode: MODE_DIR | 0o555, data: &[] },
+        Node { path: b"bin/init", mode: MODE_FILE | 0o555, data: &HELLO.0 },
+        Node { path: b"etc", mode: MODE_DIR | 0o555, data: &[] },
+    ];
+
+    #[test]
+    fn directories() {
+        assert_eq!(listing(NODES, b""), b"bin\netc\n".to_vec());
+        assert_eq!(listing(NODES, b"bin"), b"init\n".to_vec());
+        assert_eq!(listing(NODES, b"etc"), Vec::new());
+        assert_eq!(normalize(b"/bin//./init/"), Ok(b"bin/init".to_vec()));
+    }
+
+    #[test]
+    fn files() {
+        let scheme = InitFsScheme::with_nodes(NODES);
+        assert_eq!(scheme.open(b"bin/init", O_RDWR, 0, 0), Err(Error::new(EROFS)));
+        assert_eq!(scheme.open(b"bin/init", O_RDONLY | O_DIRECTORY, 0, 0), Err(Error::new(ENOTDIR)));
+
+        let id = scheme.open(b"/bin/init", O_RDONLY, 0, 0).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(scheme.read(id, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+
+        let map = Map { offset: 0, size: PAGE_SIZE, flags: PROT_READ };
+        let address = scheme.fmap(id, &map).unwrap();
+        assert_eq!(address % PAGE_SIZE, 0);
+        let map = Map { offset: 0, size: 2 * PAGE_SIZE, flags: PROT_READ };
+        assert_eq!(scheme.fmap(id, &map), Err(Error::new(EINVAL)));
+    }
+}
@@ -0,0 +1,210 @@
+//! # Schemes
+//! A scheme is a primitive for handling filesystem syscalls in Redox.
+//! Schemes accept paths from the kernel for `open`, and file descriptors that they generate
+//! are then passed for operations like `close`, `read`, `write`, etc.
+//!
+//! The kernel validates paths and file descriptors before they are passed to schemes,
+//! also stripping the scheme identifier of paths if necessary.
+use alloc::sync::Arc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicUsize;
+use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::block::BlockDevice;
+use crate::device::ata;
+use crate::device::virtio::{blk, net};
+use crate::int_like;
+use crate::println;
+use crate::syscall::error::*;
+use crate::syscall::scheme::Scheme;
+
+use self::debug::DebugScheme;
+use self::disk::DiskScheme;
+use self::ext2::Ext2Scheme;
+use self::fat::FatScheme;
+use self::initfs::InitFsScheme;
+use self::network::NetworkScheme;
+use self::pci::PciScheme;
+use self::root::RootScheme;
+use self::tmpfs::TmpScheme;
+
+/// `debug:` - the kernel console
+pub mod debug;
+
+/// `disk:` - whole block devices
+pub mod disk;
+
+/// `name:` - an ext2 volume on a block device
+pub mod ext2;
+
+/// `name:` - a FAT volume on a block device
+pub mod fat;
+
+/// `initfs:` - files packed into the kernel at build time
+pub mod initfs;
+
+/// `network:` - raw Ethernet frames
+pub mod network;
+
+/// `pci:` - the PCI functions
+pub mod pci;
+
+/// `:` - allows the creation of userspace schemes
+pub mod root;
+
+/// `tmp:` - files kept in memory
+pub mod tmpfs;
+
+/// A scheme provided by a user process
+pub mod user;
+
+/// Limit on number of schemes
+pub const SCHEME_MAX_SCHEMES: usize = 65_536;
+
+// Unique identifier for a scheme.
+int_like!(SchemeId, AtomicSchemeId, usize, AtomicUsize);
+
+// Unique identifier for a file descriptor.
+int_like!(FileHandle, AtomicFileHandle, usize, AtomicUsize);
+
+/// Scheme list type
+pub struct SchemeList {
+    map: BTreeMap<SchemeId, Arc<Box<dyn Scheme + Send + Sync>>>,
+    names: BTreeMap<Box<[u8]>, SchemeId>,
+    next_id: usize,
+}
+
+impl SchemeList {
+    /// Create a new scheme list.
+    pub fn new() -> Self {
+        let mut list = SchemeList {
+            map: BTreeMap::new(),
+            names: BTreeMap::new(),
+            next_id: 1,
+        };
+        list.new_root();
+        list
+    }
+
+    /// Register the schemes the kernel provides
+    fn new_root(&mut self) {
+        self.insert(b"", |scheme_id| Arc::new(Box::new(RootScheme::new(scheme_id)))).unwrap();
+        self.insert(b"debug", |_| Arc::new(Box::new(DebugScheme))).unwrap();
+        self.insert(b"initfs", |_| Arc::new(Box::new(InitFsScheme::new()))).unwrap();
+        self.insert(b"pci", |_| Arc::new(Box::new(PciScheme::new()))).unwrap();
+        self.insert(b"tmp", |_| Arc::new(Box::new(TmpScheme::new()))).unwrap();
+
+        let ata_disks = ata::disks();
+        let virtio_disks = blk::disks();
+        self.insert(b"disk", |_| {
+            let mut devices: Vec<(&[u8], Box<dyn BlockDevice + Send>)> = Vec::new();
+            for disk in ata_disks {
+                devices.push((disk.name().as_bytes(), Box::new(disk.clone())));
+            }
+            for disk in &virtio_disks {
+                devices.push((disk.name().as_bytes(), Box::new(disk.clone())));
+            }
+            Arc::new(Box::new(DiskScheme::new(devices)))
+        }).unwrap();
+        for disk in ata_disks {
+            self.mount(disk.name(), disk);
+        }
+        for disk in &virtio_disks {
+            self.mount(disk.name(), disk);
+        }
+
+        if let Some(nic) = net::nics().into_iter().next() {
+            self.insert(b"network", |_| Arc::new(Box::new(NetworkScheme::new(nic.clone())))).unwrap();
+        }
+    }
+
+    /// Serve the ext2 or FAT volume on `disk`, if it holds one, as a scheme named `name`
+    fn mount<D: BlockDevice + Clone + Send + 'static>(&mut self, name: &str, disk: &D) {
+        let (scheme, kind): (Arc<Box<dyn Scheme + Send + Sync>>, &str) = if let Ok(scheme) = Ext2Scheme::new(name.as_bytes(), disk.clone()) {
+            (Arc::new(Box::new(scheme)), "ext2")
+        } else if let Ok(scheme) = FatScheme::new(name.as_bytes(), disk.clone()) {
+            (Arc::new(Box::new(scheme)), "FAT")
+        } else {
+            return;
+        };
+
+        if self.insert(name.as_bytes(), |_| scheme.clone()).is_ok() {
+            println!("{}: {} volume mounted as {}:", name, kind, name);
+        }
+    }
+
+    pub fn iter_name(&self) -> alloc::collections::btree_map::Iter<Box<[u8]>, SchemeId> {
+        self.names.iter()
+    }
+
+    /// Get the nth scheme.
+    pub fn get(&self, id: SchemeId) -> Option<&Arc<Box<dyn Scheme + Send + Sync>>> {
+        self.map.get(&id)
+    }
+
+    /// Get the scheme registered as `name`, without the colon
+    pub fn get_name(&self, name: &[u8]) -> Option<(SchemeId, &Arc<Box<dyn Scheme + Send + Sync>>)> {
+        if let Some(&id) = self.names.get(name) {
+            self.get(id).map(|scheme| (id, scheme))
+        } else {
+            None
+        }
+    }
+
+    /// Create a new scheme.
+    pub fn insert<F>(&mut self, name: &[u8], scheme_fn: F) -> Result<SchemeId>
+        where F: Fn(SchemeId) -> Arc<Box<dyn Scheme + Send + Sync>>
+    {
+        if self.names.contains_key(name) {
+            return Err(Error::new(EEXIST));
+        }
+
+        if self.next_id >= SCHEME_MAX_SCHEMES {
+            self.next_id = 1;
+        }
+
+        while self.map.contains_key(&SchemeId(self.next_id)) {
+            self.next_id += 1;
+        }
+
+        if self.next_id >= SCHEME_MAX_SCHEMES {
+            return Err(Error::new(EAGAIN));
+        }
+
+        let id = SchemeId(self.next_id);
+        self.next_id += 1;
+
+        let scheme = scheme_fn(id);
+
+        assert!(self.map.insert(id, scheme).is_none());
+        assert!(self.names.insert(name.to_vec().into_boxed_slice(), id).is_none());
+
+        Ok(id)
+    }
+
+    /// Remove a scheme, its open files fail from then on
+    pub fn remove(&mut self, id: SchemeId) {
+        assert!(self.map.remove(&id).is_some());
+        self.names.retain(|_, &mut name_id| name_id != id);
+    }
+}
+
+/// Schemes list
+static SCHEMES: Once<RwLock<SchemeList>> = Once::new();
+
+/// Initialize schemes, called if needed
+fn init_schemes() -> RwLock<SchemeList> {
+    RwLock::new(SchemeList::new())
+}
+
+/// Get the global schemes list, const
+pub fn schemes() -> RwLockReadGuard<'static, SchemeList> {
+    SCHEMES.call_once(init_schemes).read()
+}
+
+/// Get the global schemes list, mutable
+pub fn schemes_mut() -> RwLockWriteGuard<'static, SchemeList> {
+    SCHEMES.call_once(init_schemes).write()
+}
@@ -0,0 +1,178 @@
+//! # Network scheme
+//!
+//! Raw Ethernet frames on the virtio network card. Each read of `network:` takes one received
+//! frame, truncated to the buffer, waiting for one unless the file is non-blocking; each write
+//! sends one frame, without its checksum. Reading `network:mac` gives the MAC address of the card.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::device::virtio::{self, net::Nic};
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+
+#[derive(Clone)]
+struct Handle {
+    /// The text of `mac`, or `None` for the frames
+    mac: Option<Vec<u8>>,
+    flags: usize,
+    offset: usize,
+}
+
+/// `network:` - raw Ethernet frames
+pub struct NetworkScheme {
+    nic: Nic,
+    next_id: AtomicUsize,
+    handles: Mutex<BTreeMap<usize, Handle>>,
+}
+
+impl NetworkScheme {
+    pub fn new(nic: Nic) -> NetworkScheme {
+        NetworkScheme {
+            nic,
+            next_id: AtomicUsize::new(0),
+            handles: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn handle(&self, id: usize) -> Result<Handle> {
+        self.handles.lock().get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+}
+
+impl Scheme for NetworkScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        if flags & O_DIRECTORY == O_DIRECTORY {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let path = &path[path.iter().take_while(|&&b| b == b'/').count()..];
+        let mac = match path {
+            b"" => None,
+            b"mac" => {
+                if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(EACCES));
+                }
+                let mac = self.nic.mac();
+                let text = format!(
+                    "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\n",
+                    mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+                );
+                Some(text.into_bytes())
+            },
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, Handle {
+            mac,
+            flags: flags & !O_CREAT & !O_EXCL & !O_TRUNC,
+            offset: 0,
+        });
+        Ok(id)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let handle = self.handle(old_id)?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_ACCMODE == O_WRONLY {
+            return Err(Error::new(EBADF));
+        }
+
+        if let Some(ref text) = handle.mac {
+            let start = cmp::min(handle.offset, text.len());
+            let count = cmp::min(buf.len(), text.len() - start);
+            buf[..count].copy_from_slice(&text[start..start + count]);
+            if let Some(handle) = self.handles.lock().get_mut(&id) {
+                handle.offset = start + count;
+            }
+            return Ok(count);
+        }
+
+        loop {
+            if let Some(frame) = self.nic.receive() {
+                let count = cmp::min(buf.len(), frame.len());
+                buf[..count].copy_from_slice(&frame[..count]);
+                return Ok(count);
+            }
+            if handle.flags & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            }
+            virtio::idle();
+        }
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.mac.is_some() || handle.flags & O_ACCMODE == O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+        self.nic.send(buf)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut handles = self.handles.lock();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        match cmd {
+            F_GETFL => Ok(handle.flags),
+            F_SETFL => {
+                handle.flags = (handle.flags & O_ACCMODE) | (arg & !O_ACCMODE);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        let mut path = String::from("network:");
+        if handle.mac.is_some() {
+            path.push_str("mac");
+        }
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path.as_bytes()[..count]);
+        Ok(count)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handle = self.handle(id)?;
+        *stat = match handle.mac {
+            Some(ref text) => Stat {
+                st_mode: MODE_FILE | 0o444,
+                st_nlink: 1,
+                st_size: text.len() as u64,
+                ..Default::default()
+            },
+            None => Stat {
+                st_mode: MODE_CHR | 0o600,
+                st_nlink: 1,
+                ..Default::default()
+            },
+        };
+        Ok(0)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.lock().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -0,0 +1,185 @@
+//! # PCI scheme
+//!
+//! Describes the PCI functions found at boot. Reading `pci:` lists them with their IDs, class and
+//! driver, and reading `pci:bus:device.function` gives the details of one, including its BARs and
+//! capabilities. The text is taken when the file is opened.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::device::pci::{self, Bar, PciAddress, PciDevice};
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+
+#[derive(Clone)]
+struct Handle {
+    /// The function, or `None` for the list
+    address: Option<PciAddress>,
+    data: Vec<u8>,
+    offset: usize,
+}
+
+fn driver_name(device: &PciDevice) -> &'static str {
+    pci::driver(device.address).unwrap_or("-")
+}
+
+fn list() -> Vec<u8> {
+    let mut text = String::new();
+    for device in pci::devices() {
+        let _ = writeln!(
+            text, "{} {:04x}:{:04x} {:02x}.{:02x}.{:02x} {}",
+            device.address, device.vendor, device.device, device.class, device.subclass, device.prog_if,
+            driver_name(device)
+        );
+    }
+    text.into_bytes()
+}
+
+fn describe(device: &PciDevice) -> Vec<u8> {
+    let mut text = String::new();
+    let _ = writeln!(text, "address {}", device.address);
+    let _ = writeln!(text, "id {:04x}:{:04x}", device.vendor, device.device);
+    let _ = writeln!(text, "class {:02x}.{:02x}.{:02x}", device.class, device.subclass, device.prog_if);
+    let _ = writeln!(text, "revision {:02x}", device.revision);
+    let _ = writeln!(text, "header {:02x}", device.header_type);
+    if device.interrupt_pin != 0 {
+        let pin = (b'A' + device.interrupt_pin - 1) as char;
+        let _ = writeln!(text, "interrupt INT{} irq {}", pin, device.interrupt_line);
+    }
+    for (i, bar) in device.bars.iter().enumerate() {
+        match *bar {
+            Bar::Memory { address, size, prefetchable } => {
+                let _ = writeln!(
+                    text, "bar {} memory {:#x} {:#x}{}",
+                    i, address, size, if prefetchable { " prefetchable" } else { "" }
+                );
+            },
+            Bar::Io { port, size } => {
+                let _ = writeln!(text, "bar {} io {:#x} {:#x}", i, port, size);
+            },
+            Bar::None => (),
+        }
+    }
+    for (id, offset) in device.capabilities() {
+        let _ = writeln!(text, "capability {:02x} at {:#x}", id, offset);
+    }
+    let _ = writeln!(text, "driver {}", driver_name(device));
+    text.into_bytes()
+}
+
+/// `pci:` - the PCI functions
+pub struct PciScheme {
+    next_id: AtomicUsize,
+    handles: Mutex<BTreeMap<usize, Handle>>,
+}
+
+impl PciScheme {
+    pub fn new() -> PciScheme {
+        PciScheme {
+            next_id: AtomicUsize::new(0),
+            handles: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Scheme for PciScheme {
+    fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT {
+            return Err(Error::new(EACCES));
+        }
+
+        let path = &path[path.iter().take_while(|&&b| b == b'/').count()..];
+        let (address, data) = if path.is_empty() {
+            (None, list())
+        } else {
+            let address = PciAddress::parse(path).ok_or(Error::new(ENOENT))?;
+            let device = pci::devices().iter().find(|device| device.address == address).ok_or(Error::new(ENOENT))?;
+            if flags & O_DIRECTORY == O_DIRECTORY {
+                return Err(Error::new(ENOTDIR));
+            }
+            (Some(address), describe(device))
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, Handle { address, data, offset: 0 });
+        Ok(id)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let handle = self.handles.lock().get(&old_id).cloned().ok_or(Error::new(EBADF))?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.lock().insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut handles = self.handles.lock();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let start = cmp::min(handle.offset, handle.data.len());
+        let count = cmp::min(buf.len(), handle.data.len() - start);
+        buf[..count].copy_from_slice(&handle.data[start..start + count]);
+        handle.offset = start + count;
+        Ok(count)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let mut handles = self.handles.lock();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => handle.offset as isize,
+            SEEK_END => handle.data.len() as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let new_offset = base.checked_add(pos).filter(|&offset| offset >= 0).ok_or(Error::new(EINVAL))?;
+        handle.offset = new_offset as usize;
+        Ok(handle.offset)
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handles = self.handles.lock();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let mut path = String::from("pci:");
+        if let Some(address) = handle.address {
+            let _ = write!(path, "{}", address);
+        }
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path.as_bytes()[..count]);
+        Ok(count)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handles = self.handles.lock();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        *stat = Stat {
+            st_mode: if handle.address.is_some() { MODE_FILE | 0o444 } else { MODE_DIR | 0o555 },
+            st_nlink: 1,
+            st_size: handle.data.len() as u64,
+            ..Default::default()
+        };
+        Ok(0)
+    }
+
+    fn fcntl(&self, id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+        self.handles.lock().get(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.handles.lock().remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
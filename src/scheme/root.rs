@@ -0,0 +1,154 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+use crate::context;
+use crate::scheme::{self, SchemeId};
+use crate::scheme::user::{UserInner, UserScheme};
+use crate::syscall::data::Stat;
+use crate::syscall::error::*;
+use crate::syscall::flag::{F_GETFL, F_SETFL, MODE_CHR, O_ACCMODE, O_CREAT};
+use crate::syscall::scheme::Scheme;
+
+/// `:` - opening `:name` with `O_CREAT` registers the scheme `name:`, provided by the caller
+/// through the returned handle. Closing the handle unregisters it.
+pub struct RootScheme {
+    scheme_id: SchemeId,
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Arc<UserInner>>>,
+}
+
+impl RootScheme {
+    pub fn new(scheme_id: SchemeId) -> RootScheme {
+        RootScheme {
+            scheme_id,
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn handle(&self, id: usize) -> Result<Arc<UserInner>> {
+        let handles = self.handles.read();
+        handles.get(&id).map(Arc::clone).ok_or(Error::new(EBADF))
+    }
+}
+
+impl Scheme for RootScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        if flags & O_CREAT != O_CREAT {
+            return Err(Error::new(ENOENT));
+        }
+        if path.is_empty() || path.contains(&b'/') {
+            return Err(Error::new(EINVAL));
+        }
+        if scheme::schemes().get_name(path).is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let context = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            Arc::downgrade(context_lock)
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        let inner = {
+            let path_box = path.to_vec().into_boxed_slice();
+            let inner = Arc::new(UserInner::new(self.scheme_id, id, path_box, flags, context));
+
+            let mut schemes = scheme::schemes_mut();
+            schemes.insert(path, |scheme_id| {
+                inner.scheme_id.store(scheme_id, Ordering::SeqCst);
+                Arc::new(Box::new(UserScheme::new(Arc::downgrade(&inner))))
+            })?;
+
+            inner
+        };
+
+        self.handles.write().insert(id, inner);
+
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.handle(id)?.read(buf)
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        self.handle(id)?.write(buf)
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.handle(id)?.fpath(buf)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        self.handle(id)?;
+        *stat = Stat {
+            st_mode: MODE_CHR | 0o600,
+            ..Default::default()
+        };
+        Ok(0)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let inner = self.handle(id)?;
+        match cmd {
+            F_GETFL => Ok(inner.flags.load(Ordering::SeqCst)),
+            F_SETFL => {
+                let flags = inner.flags.load(Ordering::SeqCst);
+                inner.flags.store((flags & O_ACCMODE) | (arg & !O_ACCMODE), Ordering::SeqCst);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.handle(id).and(Ok(0))
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        let inner = self.handles.write().remove(&id).ok_or(Error::new(EBADF))?;
+
+        scheme::schemes_mut().remove(inner.scheme_id.load(Ordering::SeqCst));
+        inner.unmount();
+
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn open_checked() {
+        let root = RootScheme::new(SchemeId::from(0));
+        assert_eq!(root.open(b"mine", O_CREAT, 1000, 1000), Err(Error::new(EACCES)));
+        assert_eq!(root.open(b"mine", 0, 0, 0), Err(Error::new(ENOENT)));
+        assert_eq!(root.open(b"mine/file", O_CREAT, 0, 0), Err(Error::new(EINVAL)));
+        // `debug:` is provided by the kernel
+        assert_eq!(root.open(b"debug", O_CREAT, 0, 0), Err(Error::new(EEXIST)));
+    }
+
+    #[test]
+    fn status_flags_kept() {
+        use alloc::sync::Weak;
+        use crate::syscall::flag::{O_NONBLOCK, O_RDWR};
+
+        let root = RootScheme::new(SchemeId::from(0));
+        let inner = UserInner::new(SchemeId::from(0), 0, b"mine".to_vec().into_boxed_slice(), O_RDWR, Weak::new());
+        root.handles.write().insert(0, Arc::new(inner));
+
+        assert_eq!(root.fcntl(0, F_SETFL, O_NONBLOCK), Ok(0));
+        assert_eq!(root.fcntl(0, F_GETFL, 0), Ok(O_RDWR | O_NONBLOCK));
+        assert_eq!(root.fcntl(1, F_GETFL, 0), Err(Error::new(EBADF)));
+    }
+}
@@ -0,0 +1,885 @@
+//! # Temporary filesystem
+//!
+//! `tmp:` keeps files, directories and symbolic links in the kernel heap, until they are
+//! removed or the system stops. Nodes have an owner and permissions, checked against the user
+//! that opened a handle, and directories read as their entry names separated by newlines.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp;
+use spin::Mutex;
+
+use crate::fs::perm::{self, Permissions, PERM_EXEC, PERM_WRITE};
+use crate::memory::{self, PAGE_SIZE};
+use crate::syscall::data::{Stat, StatVfs, TimeSpec};
+use crate::syscall::error::*;
+use crate::syscall::flag::*;
+use crate::syscall::scheme::Scheme;
+use crate::time;
+
+/// Inode of the root directory
+const ROOT: usize = 1;
+
+/// Block size reported by `fstat` and `fstatvfs`
+const BLOCK_SIZE: u64 = PAGE_SIZE as u64;
+
+fn now() -> (u64, u32) {
+    let (sec, nsec) = time::realtime();
+    (sec, nsec as u32)
+}
+
+/// Resize `data` to `len` bytes, failing with `ENOSPC` rather than running the heap out of frames
+fn resize(data: &mut Vec<u8>, len: usize) -> Result<()> {
+    if len > data.capacity() {
+        let free = memory::free_frames().saturating_mul(PAGE_SIZE);
+        let needed = len - data.capacity();
+        // The vector doubles its capacity when it grows, so only take what is needed when that
+        // would not fit
+        if cmp::max(data.capacity(), needed) <= free {
+            data.reserve(len - data.len());
+        } else if needed <= free {
+            data.reserve_exact(len - data.len());
+        } else {
+            return Err(Error::new(ENOSPC));
+        }
+    }
+    data.resize(len, 0);
+    Ok(())
+}
+
+struct Node {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    /// Contents of a file, or the target of a symbolic link
+    data: Vec<u8>,
+    /// Entries of a directory
+    children: BTreeMap<Vec<u8>, usize>,
+    /// The directory holding this node and its name there, `None` once removed
+    parent: Option<(usize, Vec<u8>)>,
+    atime: (u64, u32),
+    mtime: (u64, u32),
+    ctime: (u64, u32),
+}
+
+impl Node {
+    fn new(mode: u16, uid: u32, gid: u32, parent: Option<(usize, Vec<u8>)>) -> Node {
+        let time = now();
+        Node {
+            mode,
+            uid,
+            gid,
+            data: Vec::new(),
+            children: BTreeMap::new(),
+            parent,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        }
+    }
+
+    /// Size reported by `fstat`
+    fn size(&self) -> u64 {
+        if self.is_dir() {
+            self.children.keys().map(|name| name.len() as u64 + 1).sum()
+        } else {
+            self.data.len() as u64
+        }
+    }
+}
+
+impl Permissions for Node {
+    fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+}
+
+struct Handle {
+    inode: usize,
+    flags: usize,
+    offset: usize,
+    uid: u32,
+    gid: u32,
+    /// Entry names of a directory, read when it was opened
+    listing: Option<Vec<u8>>,
+}
+
+struct TmpFs {
+    nodes: BTreeMap<usize, Node>,
+    next_inode: usize,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize,
+}
+
+impl TmpFs {
+    fn new() -> TmpFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT, Node::new(MODE_DIR | 0o1777, 0, 0, None));
+        TmpFs {
+            nodes,
+            next_inode: ROOT + 1,
+            handles: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn node(&self, inode: usize) -> Result<&Node> {
+        self.nodes.get(&inode).ok_or(Error::new(ENOENT))
+    }
+
+    fn node_mut(&mut self, inode: usize) -> Result<&mut Node> {
+        self.nodes.get_mut(&inode).ok_or(Error::new(ENOENT))
+    }
+
+    fn handle(&self, id: usize) -> Result<&Handle> {
+        self.handles.get(&id).ok_or(Error::new(EBADF))
+    }
+
+    fn handle_mut(&mut self, id: usize) -> Result<&mut Handle> {
+        self.handles.get_mut(&id).ok_or(Error::new(EBADF))
+    }
+
+    /// The inode of the directory an entry is in and the inode of the entry, if it exists.
+    /// Symbolic links are followed except for the last component when `follow` is false.
+    fn lookup(&self, path: &[u8], uid: u32, gid: u32, follow: bool) -> Result<(usize, Vec<u8>, Option<usize>)> {
+        let mut links = 0;
+        self.lookup_from(ROOT, path, uid, gid, follow, &mut links)
+    }
+
+    fn lookup_from(&self, start: usize, path: &[u8], uid: u32, gid: u32, follow: bool, links: &mut usize)
+        -> Result<(usize, Vec<u8>, Option<usize>)>
+    {
+        let mut dir = if path.starts_with(b"/") { ROOT } else { start };
+        let parts: Vec<&[u8]> = path.split(|&b| b == b'/')
+            .filter(|part| !part.is_empty() && *part != b".")
+            .collect();
+
+        if parts.is_empty() {
+            return Ok((dir, Vec::new(), Some(dir)));
+        }
+
+        for (i, part) in parts.iter().enumerate() {
+            let last = i + 1 == parts.len();
+
+            let node = self.node(dir)?;
+            if !node.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            if !node.permission(uid, gid, PERM_EXEC) {
+                return Err(Error::new(EACCES));
+            }
+
+            let child = if *part == b".." {
+                Some(node.parent.as_ref().map_or(ROOT, |&(parent, _)| parent))
+            } else {
+                node.children.get(*part).cloned()
+            };
+
+            if last {
+                if let Some(child) = child {
+                    if follow && self.node(child)?.is_symlink() {
+                        let (parent, name, target) = self.follow(dir, child, uid, gid, links)?;
+                        return Ok((parent, name, target));
+                    }
+                }
+                return Ok((dir, part.to_vec(), child));
+            }
+
+            let child = child.ok_or(Error::new(ENOENT))?;
+            dir = if self.node(child)?.is_symlink() {
+                let (_, _, target) = self.follow(dir, child, uid, gid, links)?;
+                target.ok_or(Error::new(ENOENT))?
+            } else {
+                child
+            };
+        }
+
+        unreachable!()
+    }
+
+    /// Resolve the symbolic link `link` in the directory `dir`
+    fn follow(&self, dir: usize, link: usize, uid: u32, gid: u32, links: &mut usize) -> Result<(usize, Vec<u8>, Option<usize>)> {
+        perm::follow_link(links)?;
+
+        let target = &self.node(link)?.data;
+        if target.contains(&b':') {
+            // Links into other schemes cannot be followed here
+            return Err(Error::new(EXDEV));
+        }
+        self.lookup_from(dir, target, uid, gid, true, links)
+    }
+
+    /// Add a node named `name` to the directory `dir`
+    fn create(&mut self, dir: usize, name: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        if name.is_empty() || name == b".." {
+            return Err(Error::new(EINVAL));
+        }
+
+        let time = now();
+        {
+            let parent = self.node_mut(dir)?;
+            if !parent.permission(uid, gid, PERM_WRITE | PERM_EXEC) {
+                return Err(Error::new(EACCES));
+            }
+            parent.mtime = time;
+            parent.ctime = time;
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.nodes.insert(inode, Node::new(mode, uid, gid, Some((dir, name.to_vec()))));
+        self.node_mut(dir)?.children.insert(name.to_vec(), inode);
+        Ok(inode)
+    }
+
+    /// Whether a user may remove or replace the entry `inode` of the directory `dir`
+    fn may_remove(&self, dir: usize, inode: usize, uid: u32, gid: u32) -> Result<()> {
+        perm::check_remove(self.node(dir)?, self.node(inode)?, uid, gid)
+    }
+
+    /// Remove the entry of `inode` from its directory, and the node itself unless it is open
+    fn remove(&mut self, inode: usize) -> Result<()> {
+        let time = now();
+        if let Some((dir, name)) = self.node_mut(inode)?.parent.take() {
+            let parent = self.node_mut(dir)?;
+            parent.children.remove(&name);
+            parent.mtime = time;
+            parent.ctime = time;
+        }
+        self.release(inode);
+        Ok(())
+    }
+
+    /// Drop a node that has been removed once no handle refers to it
+    fn release(&mut self, inode: usize) {
+        let removed = self.nodes.get(&inode).map_or(false, |node| node.parent.is_none() && inode != ROOT);
+        if removed && !self.handles.values().any(|handle| handle.inode == inode) {
+            self.nodes.remove(&inode);
+        }
+    }
+
+    fn open(&mut self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let follow = flags & (O_NOFOLLOW | O_SYMLINK) == 0;
+        let (dir, name, inode) = self.lookup(path, uid, gid, follow)?;
+
+        let inode = match inode {
+            Some(inode) => {
+                if flags & O_CREAT == O_CREAT && flags & O_EXCL == O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+                inode
+            },
+            None => {
+                if flags & O_CREAT != O_CREAT {
+                    return Err(Error::new(ENOENT));
+                }
+                let kind = if flags & O_DIRECTORY == O_DIRECTORY {
+                    MODE_DIR
+                } else if flags & O_SYMLINK == O_SYMLINK {
+                    MODE_SYMLINK
+                } else {
+                    MODE_FILE
+                };
+                self.create(dir, &name, kind | (flags as u16 & MODE_PERM), uid, gid)?
+            }
+        };
+
+        let node = self.node(inode)?;
+        let flags = perm::check_open(node, flags, uid, gid)?;
+
+        let listing = if node.is_dir() {
+            let mut listing = Vec::new();
+            for name in node.children.keys() {
+                listing.extend_from_slice(name);
+                listing.push(b'\n');
+            }
+            Some(listing)
+        } else {
+            None
+        };
+
+        if flags & O_TRUNC == O_TRUNC && flags & O_WRONLY == O_WRONLY && !node.is_dir() {
+            let time = now();
+            let node = self.node_mut(inode)?;
+            node.data.clear();
+            node.mtime = time;
+            node.ctime = time;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, Handle {
+            inode,
+            flags: flags & !O_CREAT & !O_EXCL & !O_TRUNC,
+            offset: 0,
+            uid,
+            gid,
+            listing,
+        });
+        Ok(id)
+    }
+
+    fn chmod(&mut self, path: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        let (_, _, inode) = self.lookup(path, uid, gid, true)?;
+        self.set_mode(inode.ok_or(Error::new(ENOENT))?, mode, uid)
+    }
+
+    fn set_mode(&mut self, inode: usize, mode: u16, uid: u32) -> Result<usize> {
+        let time = now();
+        let node = self.node_mut(inode)?;
+        if !node.owned_by(uid) {
+            return Err(Error::new(EPERM));
+        }
+        node.mode = (node.mode & MODE_TYPE) | (mode & MODE_PERM);
+        node.ctime = time;
+        Ok(0)
+    }
+
+    fn unlink(&mut self, path: &[u8], uid: u32, gid: u32, dir: bool) -> Result<usize> {
+        let (parent, _, inode) = self.lookup(path, uid, gid, false)?;
+        let inode = inode.ok_or(Error::new(ENOENT))?;
+        if inode == ROOT {
+            return Err(Error::new(EBUSY));
+        }
+
+        let node = self.node(inode)?;
+        if dir {
+            if !node.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            if !node.children.is_empty() {
+                return Err(Error::new(ENOTEMPTY));
+            }
+        } else if node.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+
+        self.may_remove(parent, inode, uid, gid)?;
+        self.remove(inode)?;
+        Ok(0)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_RDONLY != O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+        let (inode, offset) = (handle.inode, handle.offset);
+
+        let count = {
+            let data = match handle.listing {
+                Some(ref listing) => listing,
+                None => &self.node(inode)?.data,
+            };
+            let start = cmp::min(offset, data.len());
+            let count = cmp::min(buf.len(), data.len() - start);
+            buf[..count].copy_from_slice(&data[start..start + count]);
+            count
+        };
+
+        self.handle_mut(id)?.offset += count;
+        self.node_mut(inode)?.atime = now();
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_WRONLY != O_WRONLY || handle.listing.is_some() {
+            return Err(Error::new(EBADF));
+        }
+        let (inode, append, offset) = (handle.inode, handle.flags & O_APPEND == O_APPEND, handle.offset);
+
+        let time = now();
+        let end = {
+            let node = self.node_mut(inode)?;
+            let start = if append { node.data.len() } else { offset };
+            let end = start.checked_add(buf.len()).ok_or(Error::new(EFBIG))?;
+            if end > node.data.len() {
+                resize(&mut node.data, end)?;
+            }
+            node.data[start..end].copy_from_slice(buf);
+            node.mtime = time;
+            node.ctime = time;
+            end
+        };
+
+        self.handle_mut(id)?.offset = end;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let (inode, offset, listing_len) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.offset, handle.listing.as_ref().map(|listing| listing.len()))
+        };
+        let len = match listing_len {
+            Some(len) => len,
+            None => self.node(inode)?.data.len(),
+        };
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => offset as isize,
+            SEEK_END => len as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let new_offset = base.checked_add(pos).filter(|&offset| offset >= 0).ok_or(Error::new(EINVAL))?;
+
+        self.handle_mut(id)?.offset = new_offset as usize;
+        Ok(new_offset as usize)
+    }
+
+    fn fchown(&mut self, id: usize, new_uid: u32, new_gid: u32) -> Result<usize> {
+        let (inode, uid, gid) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.uid, handle.gid)
+        };
+
+        let time = now();
+        let node = self.node_mut(inode)?;
+        perm::check_chown(node, uid, gid, new_uid, new_gid)?;
+
+        if new_uid != u32::max_value() {
+            node.uid = new_uid;
+        }
+        if new_gid != u32::max_value() {
+            node.gid = new_gid;
+        }
+        node.ctime = time;
+        Ok(0)
+    }
+
+    /// The path of a node from the root, without the scheme
+    fn path(&self, mut inode: usize) -> Result<Vec<u8>> {
+        let mut parts = Vec::new();
+        while let Some((parent, ref name)) = self.node(inode)?.parent {
+            parts.push(name.clone());
+            inode = parent;
+        }
+        if inode != ROOT {
+            return Err(Error::new(ENOENT));
+        }
+
+        let mut path = Vec::new();
+        for (i, part) in parts.iter().rev().enumerate() {
+            if i > 0 {
+                path.push(b'/');
+            }
+            path.extend_from_slice(part);
+        }
+        Ok(path)
+    }
+
+    fn frename(&mut self, id: usize, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let inode = self.handle(id)?.inode;
+        let (old_dir, old_name) = self.node(inode)?.parent.clone().ok_or(Error::new(ENOENT))?;
+        let (new_dir, new_name, existing) = self.lookup(path, uid, gid, false)?;
+        if new_name.is_empty() || new_name == b".." {
+            return Err(Error::new(EINVAL));
+        }
+
+        if existing == Some(inode) {
+            return Ok(0);
+        }
+
+        // A directory cannot move below itself
+        let mut ancestor = Some(new_dir);
+        while let Some(dir) = ancestor {
+            if dir == inode {
+                return Err(Error::new(EINVAL));
+            }
+            ancestor = self.node(dir)?.parent.as_ref().map(|&(parent, _)| parent);
+        }
+
+        self.may_remove(old_dir, inode, uid, gid)?;
+        if !self.node(new_dir)?.permission(uid, gid, PERM_WRITE | PERM_EXEC) {
+            return Err(Error::new(EACCES));
+        }
+
+        if let Some(existing) = existing {
+            let is_dir = self.node(inode)?.is_dir();
+            let target = self.node(existing)?;
+            if is_dir && !target.is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            if !is_dir && target.is_dir() {
+                return Err(Error::new(EISDIR));
+            }
+            if target.is_dir() && !target.children.is_empty() {
+                return Err(Error::new(ENOTEMPTY));
+            }
+            self.may_remove(new_dir, existing, uid, gid)?;
+            self.remove(existing)?;
+        }
+
+        let time = now();
+        {
+            let old_parent = self.node_mut(old_dir)?;
+            old_parent.children.remove(&old_name);
+            old_parent.mtime = time;
+        }
+        {
+            let new_parent = self.node_mut(new_dir)?;
+            new_parent.children.insert(new_name.clone(), inode);
+            new_parent.mtime = time;
+        }
+        let node = self.node_mut(inode)?;
+        node.parent = Some((new_dir, new_name));
+        node.ctime = time;
+        Ok(0)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let inode = self.handle(id)?.inode;
+        let node = self.node(inode)?;
+        let size = node.size();
+
+        let links = if node.is_dir() {
+            2 + node.children.values().filter(|&&child| self.nodes.get(&child).map_or(false, Node::is_dir)).count() as u32
+        } else if node.parent.is_some() {
+            1
+        } else {
+            0
+        };
+
+        *stat = Stat {
+            st_dev: 0,
+            st_ino: inode as u64,
+            st_mode: node.mode,
+            st_nlink: links,
+            st_uid: node.uid,
+            st_gid: node.gid,
+            st_size: size,
+            st_blksize: BLOCK_SIZE as u32,
+            st_blocks: (size + 511) / 512,
+            st_mtime: node.mtime.0,
+            st_mtime_nsec: node.mtime.1,
+            st_atime: node.atime.0,
+            st_atime_nsec: node.atime.1,
+            st_ctime: node.ctime.0,
+            st_ctime_nsec: node.ctime.1,
+        };
+        Ok(0)
+    }
+
+    /// Blocks used by the contents of all nodes
+    fn used_blocks(&self) -> u64 {
+        self.nodes.values()
+            .map(|node| (node.data.capacity() as u64 + BLOCK_SIZE - 1) / BLOCK_SIZE)
+            .sum()
+    }
+
+    fn ftruncate(&mut self, id: usize, len: usize) -> Result<usize> {
+        let handle = self.handle(id)?;
+        if handle.flags & O_WRONLY != O_WRONLY || handle.listing.is_some() {
+            return Err(Error::new(EBADF));
+        }
+        let inode = handle.inode;
+
+        let time = now();
+        let node = self.node_mut(inode)?;
+        resize(&mut node.data, len)?;
+        node.mtime = time;
+        node.ctime = time;
+        Ok(0)
+    }
+
+    fn futimens(&mut self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        let (inode, uid) = {
+            let handle = self.handle(id)?;
+            (handle.inode, handle.uid)
+        };
+
+        let time = now();
+        let node = self.node_mut(inode)?;
+        if !node.owned_by(uid) {
+            return Err(Error::new(EPERM));
+        }
+
+        let to_time = |spec: &TimeSpec| -> Result<(u64, u32)> {
+            if spec.tv_sec < 0 || spec.tv_nsec < 0 || spec.tv_nsec >= 1_000_000_000 {
+                return Err(Error::new(EINVAL));
+            }
+            Ok((spec.tv_sec as u64, spec.tv_nsec as u32))
+        };
+        let atime = times.get(0).map_or(Ok(time), &to_time)?;
+        let mtime = times.get(1).map_or(Ok(time), &to_time)?;
+
+        node.atime = atime;
+        node.mtime = mtime;
+        node.ctime = time;
+        Ok(0)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        self.release(handle.inode);
+        Ok(0)
+    }
+}
+
+/// `tmp:` - files kept in memory
+pub struct TmpScheme {
+    fs: Mutex<TmpFs>,
+}
+
+impl TmpScheme {
+    pub fn new() -> TmpScheme {
+        TmpScheme {
+            fs: Mutex::new(TmpFs::new()),
+        }
+    }
+}
+
+impl Scheme for TmpScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        self.fs.lock().open(path, flags, uid, gid)
+    }
+
+    fn chmod(&self, path: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        self.fs.lock().chmod(path, mode, uid, gid)
+    }
+
+    fn rmdir(&self, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        self.fs.lock().unlink(path, uid, gid, true)
+    }
+
+    fn unlink(&self, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        self.fs.lock().unlink(path, uid, gid, false)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut fs = self.fs.lock();
+        let handle = {
+            let handle = fs.handle(old_id)?;
+            Handle {
+                inode: handle.inode,
+                flags: handle.flags,
+                offset: handle.offset,
+                uid: handle.uid,
+                gid: handle.gid,
+                listing: handle.listing.clone(),
+            }
+        };
+        let id = fs.next_id;
+        fs.next_id += 1;
+        fs.handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.fs.lock().read(id, buf)
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        self.fs.lock().write(id, buf)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        self.fs.lock().seek(id, pos, whence)
+    }
+
+    fn fchmod(&self, id: usize, mode: u16) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let (inode, uid) = {
+            let handle = fs.handle(id)?;
+            (handle.inode, handle.uid)
+        };
+        fs.set_mode(inode, mode, uid)
+    }
+
+    fn fchown(&self, id: usize, uid: u32, gid: u32) -> Result<usize> {
+        self.fs.lock().fchown(id, uid, gid)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let handle = fs.handle_mut(id)?;
+        match cmd {
+            F_GETFL => Ok(handle.flags),
+            F_SETFL => {
+                handle.flags = (handle.flags & O_ACCMODE) | (arg & !O_ACCMODE);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let fs = self.fs.lock();
+        let mut path = b"tmp:".to_vec();
+        path.extend_from_slice(&fs.path(fs.handle(id)?.inode)?);
+
+        let count = cmp::min(path.len(), buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn frename(&self, id: usize, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        self.fs.lock().frename(id, path, uid, gid)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        self.fs.lock().fstat(id, stat)
+    }
+
+    fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
+        let used = {
+            let fs = self.fs.lock();
+            fs.handle(id)?;
+            fs.used_blocks()
+        };
+
+        // Files live in the kernel heap, which can grow into any free frame
+        let free = memory::free_frames() as u64 * PAGE_SIZE as u64 / BLOCK_SIZE;
+        *stat = StatVfs {
+            f_bsize: BLOCK_SIZE as u32,
+            f_blocks: used + free,
+            f_bfree: free,
+            f_bavail: free,
+        };
+        Ok(0)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.fs.lock().handle(id).and(Ok(0))
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        self.fs.lock().ftruncate(id, len)
+    }
+
+    fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        self.fs.lock().futimens(id, times)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.fs.lock().close(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_file(fs: &mut TmpFs, path: &[u8], data: &[u8], uid: u32) -> usize {
+        let id = fs.open(path, O_CREAT | O_RDWR | 0o644, uid, uid).unwrap();
+        assert_eq!(fs.write(id, data), Ok(data.len()));
+        id
+    }
+
+    #[test]
+    fn files_and_directories() {
+        let mut fs = TmpFs::new();
+        let dir = fs.open(b"docs", O_CREAT | O_DIRECTORY | O_STAT | 0o755, 0, 0).unwrap();
+        fs.close(dir).unwrap();
+
+        let id = write_file(&mut fs, b"docs/readme", b"hello", 0);
+        fs.seek(id, 0, SEEK_SET).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(id, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        fs.close(id).unwrap();
+
+        let dir = fs.open(b"docs", O_RDONLY, 0, 0).unwrap();
+        assert_eq!(fs.read(dir, &mut buf), Ok(7));
+        assert_eq!(&buf[..7], b"readme\n");
+
+        assert_eq!(fs.unlink(b"docs", 0, 0, true), Err(Error::new(ENOTEMPTY)));
+        assert_eq!(fs.unlink(b"docs/readme", 0, 0, false), Ok(0));
+        assert_eq!(fs.unlink(b"docs", 0, 0, true), Ok(0));
+        assert_eq!(fs.open(b"docs/readme", O_RDONLY, 0, 0), Err(Error::new(ENOENT)));
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut fs = TmpFs::new();
+        let id = write_file(&mut fs, b"target", b"data", 0);
+        fs.close(id).unwrap();
+
+        let link = fs.open(b"link", O_CREAT | O_SYMLINK | O_WRONLY | 0o777, 0, 0).unwrap();
+        fs.write(link, b"target").unwrap();
+        fs.close(link).unwrap();
+
+        let id = fs.open(b"link", O_RDONLY, 0, 0).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(fs.read(id, &mut buf), Ok(4));
+        assert_eq!(fs.open(b"link", O_RDONLY | O_NOFOLLOW, 0, 0), Err(Error::new(ELOOP)));
+
+        let link = fs.open(b"link", O_RDONLY | O_SYMLINK | O_NOFOLLOW, 0, 0).unwrap();
+        assert_eq!(fs.read(link, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"target");
+    }
+
+    #[test]
+    fn permissions() {
+        let mut fs = TmpFs::new();
+        let id = write_file(&mut fs, b"private", b"secret", 1000);
+        assert_eq!(fs.set_mode(fs.handle(id).unwrap().inode, 0o600, 1000), Ok(0));
+
+        assert_eq!(fs.open(b"private", O_RDONLY, 1001, 1001), Err(Error::new(EACCES)));
+        assert!(fs.open(b"private", O_RDONLY, 0, 0).is_ok());
+        assert_eq!(fs.chmod(b"private", 0o644, 1001, 1001), Err(Error::new(EPERM)));
+
+        // The root directory is sticky, so others cannot remove the file
+        assert_eq!(fs.unlink(b"private", 1001, 1001, false), Err(Error::new(EPERM)));
+    }
+
+    #[test]
+    fn stat_open_grants_no_access() {
+        let mut fs = TmpFs::new();
+        let id = write_file(&mut fs, b"private", b"secret", 1000);
+        assert_eq!(fs.set_mode(fs.handle(id).unwrap().inode, 0o600, 1000), Ok(0));
+
+        let flags = O_STAT | O_RDWR | O_TRUNC;
+        assert_eq!(fs.open(b"private", flags, 1001, 1001), Err(Error::new(EACCES)));
+        assert_eq!(fs.node(fs.handle(id).unwrap().inode).unwrap().data, b"secret");
+
+        // Others may still stat the file, but not read or write through the handle
+        let stat = fs.open(b"private", O_STAT | O_RDONLY, 1001, 1001).unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(fs.read(stat, &mut buf), Err(Error::new(EBADF)));
+        assert_eq!(fs.write(stat, b"x"), Err(Error::new(EBADF)));
+        assert_eq!(fs.ftruncate(stat, 0), Err(Error::new(EBADF)));
+    }
+
+    #[test]
+    fn growth_is_bounded() {
+        let mut fs = TmpFs::new();
+        let id = write_file(&mut fs, b"file", b"data", 0);
+        fs.seek(id, isize::max_value(), SEEK_SET).unwrap();
+        assert_eq!(fs.write(id, b"x"), Err(Error::new(ENOSPC)));
+        assert_eq!(fs.ftruncate(id, usize::max_value()), Err(Error::new(ENOSPC)));
+        assert_eq!(fs.ftruncate(id, 2), Ok(0));
+        assert_eq!(fs.node(fs.handle(id).unwrap().inode).unwrap().data, b"da");
+    }
+
+    #[test]
+    fn rename_keeps_open_files() {
+        let mut fs = TmpFs::new();
+        let id = write_file(&mut fs, b"old", b"data", 0);
+        assert_eq!(fs.frename(id, b"new", 0, 0), Ok(0));
+        assert_eq!(fs.path(fs.handle(id).unwrap().inode), Ok(b"new".to_vec()));
+        assert_eq!(fs.open(b"old", O_RDONLY, 0, 0), Err(Error::new(ENOENT)));
+
+        // Removed files stay readable until closed
+        fs.unlink(b"new", 0, 0, false).unwrap();
+        fs.seek(id, 0, SEEK_SET).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(fs.read(id, &mut buf), Ok(4));
+        let inode = fs.handle(id).unwrap().inode;
+        fs.close(id).unwrap();
+        assert!(fs.nodes.get(&inode).is_none());
+    }
+}
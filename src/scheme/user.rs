@@ -0,0 +1,440 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::{mem, ptr, slice};
+use spin::{Mutex, RwLock};
+use x86_64::structures::paging::{PageTableFlags as EntryFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::context::{self, Context, memory::Grant};
+use crate::memory::{self, phys_to_virt, InactivePageTable, PAGE_SIZE};
+use crate::scheme::{AtomicSchemeId, SchemeId};
+use crate::sync::{WaitMap, WaitQueue};
+use crate::syscall::data::{Packet, Stat, StatVfs, TimeSpec};
+use crate::syscall::driver::find_free;
+use crate::syscall::error::*;
+use crate::syscall::flag::O_NONBLOCK;
+use crate::syscall::number::*;
+use crate::syscall::scheme::Scheme;
+use crate::{PML4_SIZE, USER_TMP_GRANT_OFFSET};
+
+/// A buffer of a call, copied to frames that are mapped into the provider at `address`
+struct Capture {
+    address: usize,
+    frame: Option<PhysFrame>,
+    pages: usize,
+}
+
+/// The count a provider replied for a buffer of `len` bytes, which can not be more than it holds
+fn filled(count: usize, len: usize) -> Result<usize> {
+    if count > len {
+        Err(Error::new(EIO))
+    } else {
+        Ok(count)
+    }
+}
+
+/// The kernel side of a scheme provided by a user process, which reads the packets of calls
+/// from its root handle and writes back their results
+pub struct UserInner {
+    root_id: SchemeId,
+    handle_id: usize,
+    pub name: Box<[u8]>,
+    pub flags: AtomicUsize,
+    pub scheme_id: AtomicSchemeId,
+    next_id: AtomicU64,
+    context: Weak<RwLock<Context>>,
+    /// Call buffers mapped into the provider, sorted by address
+    grants: Mutex<Vec<Grant>>,
+    /// Calls waiting for their result
+    calls: Mutex<BTreeSet<u64>>,
+    todo: WaitQueue<Packet>,
+    done: WaitMap<u64, usize>,
+    unmounting: AtomicBool,
+}
+
+impl UserInner {
+    pub fn new(root_id: SchemeId, handle_id: usize, name: Box<[u8]>, flags: usize, context: Weak<RwLock<Context>>) -> UserInner {
+        UserInner {
+            root_id,
+            handle_id,
+            name,
+            flags: AtomicUsize::new(flags),
+            scheme_id: AtomicSchemeId::default(),
+            next_id: AtomicU64::new(1),
+            context,
+            grants: Mutex::new(Vec::new()),
+            calls: Mutex::new(BTreeSet::new()),
+            todo: WaitQueue::new(),
+            done: WaitMap::new(),
+            unmounting: AtomicBool::new(false),
+        }
+    }
+
+    /// Fail the calls that are waiting, and the ones made from now on
+    pub fn unmount(&self) {
+        self.unmounting.store(true, Ordering::SeqCst);
+
+        let calls = mem::replace(&mut *self.calls.lock(), BTreeSet::new());
+        for id in calls {
+            self.done.send(id, Error::mux(Err(Error::new(ENODEV))));
+        }
+    }
+
+    /// Send a call to the provider and wait for its result
+    pub fn call(&self, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
+        let (pid, uid, gid) = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let context = context_lock.read();
+            (context.id.into(), context.euid, context.egid)
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.calls.lock().insert(id);
+        if self.unmounting.load(Ordering::SeqCst) {
+            self.calls.lock().remove(&id);
+            return Err(Error::new(ENODEV));
+        }
+
+        self.todo.send(Packet { id, pid, uid, gid, a, b, c, d });
+
+        Error::demux(self.done.receive(&id))
+    }
+
+    /// Copy `buf` to new frames and map them into the provider
+    fn capture(&self, buf: &[u8], writable: bool) -> Result<Capture> {
+        if buf.is_empty() {
+            return Ok(Capture { address: 0, frame: None, pages: 0 });
+        }
+
+        let pages = (buf.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frame = memory::allocate_frames(pages).ok_or(Error::new(ENOMEM))?;
+        unsafe {
+            let dst = phys_to_virt(frame).as_mut_ptr::<u8>();
+            ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len());
+            ptr::write_bytes(dst.add(buf.len()), 0, pages * PAGE_SIZE - buf.len());
+        }
+
+        let mut flags = EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE | EntryFlags::NO_EXECUTE;
+        if writable {
+            flags |= EntryFlags::WRITABLE;
+        }
+
+        match self.grant(frame, pages * PAGE_SIZE, flags) {
+            Ok(address) => Ok(Capture { address, frame: Some(frame), pages }),
+            Err(err) => {
+                memory::deallocate_frames(frame, pages);
+                Err(err)
+            }
+        }
+    }
+
+    /// Map `size` bytes of frames from `frame` into the provider, in its temporary grant PML4
+    fn grant(&self, frame: PhysFrame, size: usize, flags: EntryFlags) -> Result<usize> {
+        let context_lock = self.context.upgrade().ok_or(Error::new(ENODEV))?;
+        let context = context_lock.read();
+        let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table() as u64) };
+
+        let mut grants = self.grants.lock();
+        let ranges: Vec<(usize, usize)> = grants.iter()
+            .map(|grant| (grant.start_address().as_u64() as usize, grant.size()))
+            .collect();
+        let (address, index) = find_free(&ranges, USER_TMP_GRANT_OFFSET, size).ok_or(Error::new(ENOMEM))?;
+        if address + size > USER_TMP_GRANT_OFFSET + PML4_SIZE {
+            return Err(Error::new(ENOMEM));
+        }
+
+        grants.insert(index, Grant::map_inactive(
+            frame.start_address(),
+            VirtAddr::new(address as u64),
+            size,
+            flags,
+            &mut new_table,
+        ));
+
+        Ok(address)
+    }
+
+    /// Unmap a captured buffer from the provider, copy it back into `out` and free its frames
+    fn release(&self, capture: Capture, out: Option<&mut [u8]>) {
+        let frame = match capture.frame {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        // Once the provider is gone, so is its table
+        if let Some(context_lock) = self.context.upgrade() {
+            let context = context_lock.read();
+            let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table() as u64) };
+
+            let mut grants = self.grants.lock();
+            if let Some(index) = grants.iter().position(|grant| grant.start_address().as_u64() as usize == capture.address) {
+                grants.remove(index).unmap_inactive(&mut new_table);
+            }
+        }
+
+        if let Some(out) = out {
+            unsafe {
+                let src = phys_to_virt(frame).as_ptr::<u8>();
+                ptr::copy_nonoverlapping(src, out.as_mut_ptr(), out.len());
+            }
+        }
+
+        memory::deallocate_frames(frame, capture.pages);
+    }
+
+    /// A call on a path, whose scheme part was stripped
+    fn call_path(&self, a: usize, path: &[u8], d: usize) -> Result<usize> {
+        let capture = self.capture(path, false)?;
+        let result = self.call(a, capture.address, path.len(), d);
+        self.release(capture, None);
+        result
+    }
+
+    /// A call on a file that passes a buffer to the provider
+    fn call_in(&self, a: usize, id: usize, buf: &[u8]) -> Result<usize> {
+        let capture = self.capture(buf, false)?;
+        let result = self.call(a, id, capture.address, buf.len());
+        self.release(capture, None);
+        result
+    }
+
+    /// A call on a file that the provider fills a buffer for
+    fn call_out(&self, a: usize, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let capture = self.capture(buf, true)?;
+        let result = self.call(a, id, capture.address, buf.len());
+        self.release(capture, Some(buf));
+        result.and_then(|count| filled(count, buf.len()))
+    }
+
+    /// Take waiting calls, as whole packets
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let size = mem::size_of::<Packet>();
+        if buf.len() < size {
+            return Err(Error::new(EINVAL));
+        }
+        let mut packets = vec![Packet::default(); buf.len() / size];
+        let block = self.flags.load(Ordering::SeqCst) & O_NONBLOCK != O_NONBLOCK && !self.unmounting.load(Ordering::SeqCst);
+        let count = self.todo.receive_into(&mut packets, block).ok_or(Error::new(EINTR))?;
+
+        for (chunk, packet) in buf.chunks_mut(size).zip(packets[..count].iter()) {
+            chunk.copy_from_slice(packet);
+        }
+        Ok(count * size)
+    }
+
+    /// Complete calls, with the result in `a` of each packet
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        let size = mem::size_of::<Packet>();
+        let mut count = 0;
+        for chunk in buf.chunks(size) {
+            if chunk.len() < size {
+                break;
+            }
+
+            let mut packet = Packet::default();
+            packet.copy_from_slice(chunk);
+
+            // Results of calls that were not made, or were failed by unmount, are dropped
+            if self.calls.lock().remove(&packet.id) {
+                self.done.send(packet.id, packet.a);
+            }
+            count += size;
+        }
+        Ok(count)
+    }
+
+    pub fn fpath(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut path = Vec::with_capacity(self.name.len() + 1);
+        path.push(b':');
+        path.extend_from_slice(&self.name);
+
+        let count = path.len().min(buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    pub fn root_id(&self) -> SchemeId {
+        self.root_id
+    }
+
+    pub fn handle_id(&self) -> usize {
+        self.handle_id
+    }
+}
+
+/// A scheme provided by a user process, which forwards its calls to the provider
+pub struct UserScheme {
+    inner: Weak<UserInner>,
+}
+
+impl UserScheme {
+    pub fn new(inner: Weak<UserInner>) -> UserScheme {
+        UserScheme { inner }
+    }
+
+    fn inner(&self) -> Result<Arc<UserInner>> {
+        self.inner.upgrade().ok_or(Error::new(ENODEV))
+    }
+}
+
+impl Scheme for UserScheme {
+    fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        self.inner()?.call_path(SYS_OPEN, path, flags)
+    }
+
+    fn chmod(&self, path: &[u8], mode: u16, _uid: u32, _gid: u32) -> Result<usize> {
+        self.inner()?.call_path(SYS_CHMOD, path, mode as usize)
+    }
+
+    fn rmdir(&self, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
+        self.inner()?.call_path(SYS_RMDIR, path, 0)
+    }
+
+    fn unlink(&self, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
+        self.inner()?.call_path(SYS_UNLINK, path, 0)
+    }
+
+    fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
+        self.inner()?.call_in(SYS_DUP, old_id, buf)
+    }
+
+    fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner()?.call_out(SYS_READ, id, buf)
+    }
+
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        self.inner()?.call_in(SYS_WRITE, id, buf)
+    }
+
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        self.inner()?.call(SYS_LSEEK, id, pos as usize, whence)
+    }
+
+    fn fchmod(&self, id: usize, mode: u16) -> Result<usize> {
+        self.inner()?.call(SYS_FCHMOD, id, mode as usize, 0)
+    }
+
+    fn fchown(&self, id: usize, uid: u32, gid: u32) -> Result<usize> {
+        self.inner()?.call(SYS_FCHOWN, id, uid as usize, gid as usize)
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        self.inner()?.call(SYS_FCNTL, id, cmd, arg)
+    }
+
+    fn fevent(&self, id: usize, flags: usize) -> Result<usize> {
+        self.inner()?.call(SYS_FEVENT, id, flags, 0)
+    }
+
+    fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner()?.call_out(SYS_FPATH, id, buf)
+    }
+
+    fn frename(&self, id: usize, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
+        self.inner()?.call_in(SYS_FRENAME, id, path)
+    }
+
+    fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
+        self.inner()?.call_out(SYS_FSTAT, id, stat)
+    }
+
+    fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
+        self.inner()?.call_out(SYS_FSTATVFS, id, stat)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        self.inner()?.call(SYS_FSYNC, id, 0, 0)
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
+        self.inner()?.call(SYS_FTRUNCATE, id, len, 0)
+    }
+
+    fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
+        let buf = unsafe {
+            slice::from_raw_parts(times.as_ptr() as *const u8, times.len() * mem::size_of::<TimeSpec>())
+        };
+        self.inner()?.call_in(SYS_FUTIMENS, id, buf)
+    }
+
+    fn close(&self, id: usize) -> Result<usize> {
+        self.inner()?.call(SYS_CLOSE, id, 0, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn inner() -> UserInner {
+        UserInner::new(SchemeId::from(0), 0, b"test".to_vec().into_boxed_slice(), O_NONBLOCK, Weak::new())
+    }
+
+    fn packet(id: u64, a: usize) -> Packet {
+        Packet { id, a, ..Packet::default() }
+    }
+
+    #[test]
+    fn whole_packets_read() {
+        let size = mem::size_of::<Packet>();
+        let inner = inner();
+        for id in 1..4 {
+            inner.todo.send(packet(id, 0));
+        }
+
+        assert_eq!(inner.read(&mut vec![0; size - 1]), Err(Error::new(EINVAL)));
+
+        let mut buf = vec![0; 2 * size + size / 2];
+        assert_eq!(inner.read(&mut buf), Ok(2 * size));
+        let mut first = Packet::default();
+        first.copy_from_slice(&buf[..size]);
+        let mut second = Packet::default();
+        second.copy_from_slice(&buf[size..2 * size]);
+        assert_eq!((first.id, second.id), (1, 2));
+
+        assert_eq!(inner.read(&mut buf), Ok(size));
+        assert_eq!(inner.read(&mut buf), Ok(0));
+    }
+
+    #[test]
+    fn results_written() {
+        let size = mem::size_of::<Packet>();
+        let inner = inner();
+        inner.calls.lock().insert(5);
+
+        // The trailing partial packet is ignored, the result for a call not made is dropped
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&packet(5, 42));
+        buf.extend_from_slice(&packet(6, 1));
+        buf.extend_from_slice(&[0; 8]);
+        assert_eq!(inner.write(&buf), Ok(2 * size));
+        assert_eq!(inner.done.receive_nonblock(&5), Some(42));
+        assert_eq!(inner.done.receive_nonblock(&6), None);
+    }
+
+    #[test]
+    fn oversized_replies_rejected() {
+        assert_eq!(filled(0, 16), Ok(0));
+        assert_eq!(filled(16, 16), Ok(16));
+        assert_eq!(filled(17, 16), Err(Error::new(EIO)));
+        assert_eq!(filled(usize::max_value(), 0), Err(Error::new(EIO)));
+    }
+
+    #[test]
+    fn unmount_fails_waiting_calls() {
+        let inner = inner();
+        inner.calls.lock().insert(7);
+
+        inner.unmount();
+        assert_eq!(inner.done.receive_nonblock(&7), Some(Error::mux(Err(Error::new(ENODEV)))));
+        assert!(inner.calls.lock().is_empty());
+
+        // A late result is dropped
+        inner.write(&packet(7, 0)).unwrap();
+        assert_eq!(inner.done.receive_nonblock(&7), None);
+    }
+}
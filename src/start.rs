@@ -32,6 +32,9 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut active_page_table = unsafe {
         let mut active_page_table = ActivePageTable::new();
         heap::init(&mut active_page_table);
+        crate::percpu::init(0, &mut active_page_table);
+        #[cfg(feature = "pti")]
+        crate::pti::init(&mut active_page_table);
         active_page_table
     };
 
@@ -59,5 +62,5 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     unsafe { (0xdeadbeaf900 as *mut u64).write_volatile(0xf021f077f065f04e) };
 
     println!("It did not crash!");
-    hlt_loop();
+    crate::idle::idle();
 }
\ No newline at end of file
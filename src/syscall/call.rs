@@ -62,6 +62,16 @@ pub fn clock_gettime(clock: usize, tp: &mut TimeSpec) -> Result<usize> {
     unsafe { syscall2(SYS_CLOCK_GETTIME, clock, tp as *mut TimeSpec as usize) }
 }
 
+/// Set the system time, small corrections are applied gradually
+///
+/// # Errors
+///
+/// * `EPERM` - `euid != 0`
+/// * `EINVAL` - `clock` is not `CLOCK_REALTIME`, or `tp` is out of range
+pub fn clock_settime(clock: usize, tp: &TimeSpec) -> Result<usize> {
+    unsafe { syscall2(SYS_CLOCK_SETTIME, clock, tp as *const TimeSpec as usize) }
+}
+
 /// Copy and transform a file descriptor
 pub fn dup(fd: usize, buf: &[u8]) -> Result<usize> {
     unsafe { syscall3(SYS_DUP, fd, buf.as_ptr() as usize, buf.len()) }
@@ -294,6 +304,16 @@ pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
     unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
 }
 
+/// Power off or restart the machine, this only returns on error
+///
+/// # Errors
+///
+/// * `EPERM` - `euid != 0`
+/// * `EINVAL` - `cmd` is not one of the `REBOOT_CMD_*` values
+pub fn reboot(cmd: usize) -> Result<usize> {
+    unsafe { syscall1(SYS_REBOOT, cmd) }
+}
+
 /// Remove a directory
 pub fn rmdir<T: AsRef<[u8]>>(path: T) -> Result<usize> {
     unsafe { syscall2(SYS_RMDIR, path.as_ref().as_ptr() as usize, path.as_ref().len()) }
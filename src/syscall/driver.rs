@@ -0,0 +1,246 @@
+//! # Driver system calls
+//!
+//! Drivers run in user space, so root may allocate physical memory for DMA, map device memory
+//! into its address space, look up the frames behind its pages and access I/O ports.
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{mapper::MapperAllSizes, PageTableFlags as EntryFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::context::{self, memory::Grant};
+use crate::memory::{self, ActivePageTable, PAGE_SIZE};
+use crate::syscall::error::*;
+use crate::syscall::flag::{PHYSMAP_WRITE, PHYSMAP_WRITE_COMBINE};
+use crate::syscall::usercopy::is_user_range;
+use crate::{PML4_SIZE, USER_GRANT_OFFSET};
+
+/// The I/O privilege level bits of RFLAGS
+const RFLAGS_IOPL: usize = 3 << 12;
+
+/// The frames `physalloc` gave out, as their address and count, which are all `physfree` takes
+static ALLOCATIONS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+/// The number of pages `size` bytes take
+fn pages(size: usize) -> Result<usize> {
+    size.checked_add(PAGE_SIZE - 1).map(|size| size / PAGE_SIZE).ok_or(Error::new(EINVAL))
+}
+
+fn enforce_root() -> Result<()> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    if context.euid == 0 {
+        Ok(())
+    } else {
+        Err(Error::new(EPERM))
+    }
+}
+
+/// Set the I/O privilege level of the current context, 3 gives it access to all ports
+pub fn iopl(level: usize) -> Result<usize> {
+    enforce_root()?;
+
+    if level > 3 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    let rflags = context.user_rflags().ok_or(Error::new(EINVAL))?;
+    *rflags = (*rflags & !RFLAGS_IOPL) | (level << 12);
+
+    Ok(0)
+}
+
+/// Allocate `size` bytes of physically contiguous memory, returning its physical address
+pub fn physalloc(size: usize) -> Result<usize> {
+    enforce_root()?;
+
+    if size == 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let count = pages(size)?;
+    let address = memory::allocate_frames(count)
+        .ok_or(Error::new(ENOMEM))?
+        .start_address().as_u64() as usize;
+    ALLOCATIONS.lock().push((address, count));
+
+    Ok(address)
+}
+
+/// Forget the allocation of `count` frames at `address`, which must be exactly one `physalloc`
+/// made
+fn take_allocation(allocations: &mut Vec<(usize, usize)>, address: usize, count: usize) -> Result<()> {
+    let index = allocations.iter().position(|&allocation| allocation == (address, count))
+        .ok_or(Error::new(EINVAL))?;
+    allocations.swap_remove(index);
+    Ok(())
+}
+
+/// Free memory allocated with `physalloc`, with the address and size it was allocated with
+pub fn physfree(physical_address: usize, size: usize) -> Result<usize> {
+    enforce_root()?;
+
+    if size == 0 || physical_address % PAGE_SIZE != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let count = pages(size)?;
+    take_allocation(&mut ALLOCATIONS.lock(), physical_address, count)?;
+    let frame = PhysFrame::containing_address(PhysAddr::new(physical_address as u64));
+    memory::deallocate_frames(frame, count);
+
+    Ok(0)
+}
+
+/// The page flags of a physical mapping. Device memory is uncached, unless write-combining is
+/// asked for, which selects the PAT entry `device::cpu` set up for it.
+fn physmap_flags(flags: usize) -> EntryFlags {
+    let mut entry_flags = EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE | EntryFlags::NO_EXECUTE;
+    if flags & PHYSMAP_WRITE == PHYSMAP_WRITE {
+        entry_flags |= EntryFlags::WRITABLE;
+    }
+    if flags & PHYSMAP_WRITE_COMBINE == PHYSMAP_WRITE_COMBINE {
+        entry_flags |= EntryFlags::WRITE_THROUGH;
+    } else {
+        entry_flags |= EntryFlags::WRITE_THROUGH | EntryFlags::NO_CACHE;
+    }
+    entry_flags
+}
+
+/// The first address from `base` where `size` bytes fit between the sorted `(start, size)`
+/// ranges, and the index to insert them at, or `None` if the address space runs out first
+pub(crate) fn find_free(ranges: &[(usize, usize)], base: usize, size: usize) -> Option<(usize, usize)> {
+    let mut address = base;
+    for (i, &(start, len)) in ranges.iter().enumerate() {
+        if address.checked_add(size)? <= start {
+            return Some((address, i));
+        }
+        address = start.checked_add(len)?;
+    }
+    address.checked_add(size)?;
+    Some((address, ranges.len()))
+}
+
+/// Map `size` bytes of physical memory into the current context, returning their address
+pub fn physmap(physical_address: usize, size: usize, flags: usize) -> Result<usize> {
+    enforce_root()?;
+
+    if size == 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let mut grants = context.grants.lock();
+
+    let from_address = physical_address / PAGE_SIZE * PAGE_SIZE;
+    let offset = physical_address - from_address;
+    let full_size = pages(offset.checked_add(size).ok_or(Error::new(EINVAL))?)? * PAGE_SIZE;
+
+    let to_address = insert_grant(&mut grants, full_size, |to| {
+        Grant::physmap(PhysAddr::new(from_address as u64), to, full_size, physmap_flags(flags))
+    })?;
+
+    Ok(to_address + offset)
+}
+
+/// Make a grant of `size` bytes with `f` at the first free address of the grant area, and add
+/// it to the sorted `grants`
+pub(crate) fn insert_grant<F>(grants: &mut Vec<Grant>, size: usize, f: F) -> Result<usize>
+    where F: FnOnce(VirtAddr) -> Grant
+{
+    let ranges: Vec<(usize, usize)> = grants.iter()
+        .map(|grant| (grant.start_address().as_u64() as usize, grant.size()))
+        .collect();
+    let (to_address, index) = find_free(&ranges, USER_GRANT_OFFSET, size).ok_or(Error::new(EINVAL))?;
+    if to_address + size > USER_GRANT_OFFSET + PML4_SIZE {
+        return Err(Error::new(ENOMEM));
+    }
+
+    grants.insert(index, f(VirtAddr::new(to_address as u64)));
+
+    Ok(to_address)
+}
+
+/// Unmap a mapping returned by `physmap`
+pub fn physunmap(virtual_address: usize) -> Result<usize> {
+    enforce_root()?;
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let mut grants = context.grants.lock();
+
+    let index = grants.iter().position(|grant| {
+        let start = grant.start_address().as_u64() as usize;
+        virtual_address >= start && virtual_address < start + grant.size()
+    }).ok_or(Error::new(EFAULT))?;
+
+    // Dropping the grant unmaps it
+    grants.remove(index);
+
+    Ok(0)
+}
+
+/// The physical address `virtual_address` of the current context maps to
+pub fn virttophys(virtual_address: usize) -> Result<usize> {
+    enforce_root()?;
+
+    if !is_user_range(virtual_address, 1) {
+        return Err(Error::new(EFAULT));
+    }
+
+    let active_table = unsafe { ActivePageTable::new() };
+    active_table.translate_addr(VirtAddr::new(virtual_address as u64))
+        .map(|address| address.as_u64() as usize)
+        .ok_or(Error::new(EFAULT))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn grant_placement() {
+        let page = PAGE_SIZE;
+        assert_eq!(find_free(&[], USER_GRANT_OFFSET, page), Some((USER_GRANT_OFFSET, 0)));
+
+        let ranges = [(USER_GRANT_OFFSET, page), (USER_GRANT_OFFSET + 3 * page, page)];
+        assert_eq!(find_free(&ranges, USER_GRANT_OFFSET, 2 * page), Some((USER_GRANT_OFFSET + page, 1)));
+        assert_eq!(find_free(&ranges, USER_GRANT_OFFSET, 3 * page), Some((USER_GRANT_OFFSET + 4 * page, 2)));
+        assert_eq!(find_free(&ranges, USER_GRANT_OFFSET, usize::max_value()), None);
+    }
+
+    #[test]
+    fn sizes_overflow() {
+        assert_eq!(pages(1), Ok(1));
+        assert_eq!(pages(PAGE_SIZE + 1), Ok(2));
+        assert_eq!(pages(usize::max_value()), Err(Error::new(EINVAL)));
+    }
+
+    #[test]
+    fn only_allocations_are_freed() {
+        let mut allocations = vec![(0x10_0000, 2), (0x20_0000, 1)];
+        assert_eq!(take_allocation(&mut allocations, 0x10_0000, 1), Err(Error::new(EINVAL)));
+        assert_eq!(take_allocation(&mut allocations, 0x10_1000, 1), Err(Error::new(EINVAL)));
+        assert_eq!(take_allocation(&mut allocations, 0, 1), Err(Error::new(EINVAL)));
+        assert_eq!(take_allocation(&mut allocations, 0x10_0000, 2), Ok(()));
+        assert_eq!(take_allocation(&mut allocations, 0x10_0000, 2), Err(Error::new(EINVAL)));
+        assert_eq!(allocations, [(0x20_0000, 1)]);
+    }
+
+    #[test]
+    fn physmap_caching() {
+        let uncached = physmap_flags(0);
+        assert!(uncached.contains(EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH));
+        assert!(!uncached.contains(EntryFlags::WRITABLE));
+
+        let combined = physmap_flags(PHYSMAP_WRITE | PHYSMAP_WRITE_COMBINE);
+        assert!(combined.contains(EntryFlags::WRITABLE | EntryFlags::WRITE_THROUGH));
+        assert!(!combined.contains(EntryFlags::NO_CACHE));
+    }
+}
@@ -65,6 +65,9 @@ pub const PROT_EXEC: usize = 0x0001_0000;
 pub const PROT_WRITE: usize = 0x0002_0000;
 pub const PROT_READ: usize = 0x0004_0000;
 
+pub const REBOOT_CMD_RESTART: usize = 1;
+pub const REBOOT_CMD_POWER_OFF: usize = 2;
+
 pub const SEEK_SET: usize = 0;
 pub const SEEK_CUR: usize = 1;
 pub const SEEK_END: usize = 2;
@@ -0,0 +1,417 @@
+//! Filesystem syscalls
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp;
+use spin::RwLock;
+use x86_64::structures::paging::{mapper::MapperAllSizes, PageTableFlags as EntryFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::context::{self, file::{FileDescription, FileDescriptor}, memory::Grant};
+use crate::memory::{ActivePageTable, PAGE_SIZE};
+use crate::scheme::{self, FileHandle};
+use crate::syscall::data::{Map, Stat, StatVfs, TimeSpec};
+use crate::syscall::driver::insert_grant;
+use crate::syscall::error::*;
+use crate::syscall::flag::{F_DUPFD, F_GETFD, F_GETFL, F_SETFD, F_SETFL, MODE_PERM, O_ACCMODE, O_CLOEXEC, PROT_EXEC, PROT_WRITE};
+use crate::syscall::scheme::Scheme;
+use crate::syscall::usercopy::{copy_from_user, copy_to_user, UserSlice};
+
+/// Run `f` with the scheme and number of an open file of the current context
+pub fn file_op<F, T>(fd: FileHandle, f: F) -> Result<T>
+    where F: FnOnce(&dyn Scheme, usize) -> Result<T>
+{
+    let file = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.get_file(fd).ok_or(Error::new(EBADF))?
+    };
+
+    let (scheme_id, number) = {
+        let description = file.description.read();
+        (description.scheme, description.number)
+    };
+
+    let scheme = {
+        let schemes = scheme::schemes();
+        let scheme = schemes.get(scheme_id).ok_or(Error::new(EBADF))?;
+        scheme.clone()
+    };
+
+    f(&**scheme, number)
+}
+
+/// Split a canonical path into its scheme and the reference within it, and find the scheme
+fn path_op<F, T>(path: &[u8], f: F) -> Result<T>
+    where F: FnOnce(&dyn Scheme, scheme::SchemeId, &[u8], u32, u32) -> Result<T>
+{
+    let (path_canon, uid, gid) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.canonicalize(path), context.euid, context.egid)
+    };
+
+    let mut parts = path_canon.splitn(2, |&b| b == b':');
+    let scheme_name = parts.next().ok_or(Error::new(ENOENT))?;
+    let reference = parts.next().ok_or(Error::new(ENOENT))?;
+
+    let (scheme_id, scheme) = {
+        let schemes = scheme::schemes();
+        let (scheme_id, scheme) = schemes.get_name(scheme_name).ok_or(Error::new(ENODEV))?;
+        (scheme_id, scheme.clone())
+    };
+
+    f(&**scheme, scheme_id, reference, uid, gid)
+}
+
+/// Open syscall
+pub fn open(path: &[u8], flags: usize) -> Result<FileHandle> {
+    let umask = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.umask
+    };
+
+    // The umask only clears permission bits of the mode
+    let perm = MODE_PERM as usize;
+    let flags = (flags & !perm) | (flags & perm & !umask);
+
+    let (scheme_id, number) = path_op(path, |scheme, scheme_id, reference, uid, gid| {
+        scheme.open(reference, flags, uid, gid).map(|number| (scheme_id, number))
+    })?;
+
+    let file = FileDescriptor {
+        description: Arc::new(RwLock::new(FileDescription {
+            scheme: scheme_id,
+            number,
+            flags: flags & !O_CLOEXEC,
+        })),
+        cloexec: flags & O_CLOEXEC == O_CLOEXEC,
+    };
+
+    let result = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.add_file(file.clone())
+    };
+
+    match result {
+        Some(fd) => Ok(fd),
+        None => {
+            let _ = file.close();
+            Err(Error::new(EMFILE))
+        }
+    }
+}
+
+/// chmod syscall
+pub fn chmod(path: &[u8], mode: u16) -> Result<usize> {
+    path_op(path, |scheme, _, reference, uid, gid| scheme.chmod(reference, mode, uid, gid))
+}
+
+/// rmdir syscall
+pub fn rmdir(path: &[u8]) -> Result<usize> {
+    path_op(path, |scheme, _, reference, uid, gid| scheme.rmdir(reference, uid, gid))
+}
+
+/// Unlink syscall
+pub fn unlink(path: &[u8]) -> Result<usize> {
+    path_op(path, |scheme, _, reference, uid, gid| scheme.unlink(reference, uid, gid))
+}
+
+/// Close syscall
+pub fn close(fd: FileHandle) -> Result<usize> {
+    let file = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.remove_file(fd).ok_or(Error::new(EBADF))?
+    };
+
+    file.close()
+}
+
+/// A new descriptor for the file `fd`. With an empty `buf` it shares the description of `fd`,
+/// otherwise the scheme opens a new file from `fd` and `buf`, such as a path relative to it.
+/// The new descriptor is never close-on-exec.
+fn duplicate_file(fd: FileHandle, buf: &[u8]) -> Result<FileDescriptor> {
+    let file = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.get_file(fd).ok_or(Error::new(EBADF))?
+    };
+
+    if buf.is_empty() {
+        Ok(FileDescriptor {
+            description: Arc::clone(&file.description),
+            cloexec: false,
+        })
+    } else {
+        let (scheme_id, flags) = {
+            let description = file.description.read();
+            (description.scheme, description.flags)
+        };
+
+        let new_number = file_op(fd, |scheme, number| scheme.dup(number, buf))?;
+
+        Ok(FileDescriptor {
+            description: Arc::new(RwLock::new(FileDescription {
+                scheme: scheme_id,
+                number: new_number,
+                flags,
+            })),
+            cloexec: false,
+        })
+    }
+}
+
+/// Duplicate file descriptor
+pub fn dup(fd: FileHandle, buf: &[u8]) -> Result<FileHandle> {
+    let new_file = duplicate_file(fd, buf)?;
+
+    let result = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.add_file(new_file.clone())
+    };
+
+    match result {
+        Some(new_fd) => Ok(new_fd),
+        None => {
+            let _ = new_file.close();
+            Err(Error::new(EMFILE))
+        }
+    }
+}
+
+/// Duplicate file descriptor, replacing another
+pub fn dup2(fd: FileHandle, new_fd: FileHandle, buf: &[u8]) -> Result<FileHandle> {
+    if fd == new_fd && buf.is_empty() {
+        // Still fails if fd is not open
+        file_op(fd, |_, _| Ok(()))?;
+        return Ok(new_fd);
+    }
+
+    if new_fd.into() >= context::CONTEXT_MAX_FILES {
+        return Err(Error::new(EBADF));
+    }
+
+    let new_file = duplicate_file(fd, buf)?;
+    let _ = close(new_fd);
+
+    let result = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.insert_file(new_fd, new_file.clone())
+    };
+
+    // Another thread sharing the file table may have taken the slot meanwhile
+    match result {
+        Some(new_fd) => Ok(new_fd),
+        None => {
+            let _ = new_file.close();
+            Err(Error::new(EBUSY))
+        }
+    }
+}
+
+/// File descriptor controls. `F_DUPFD`, the close-on-exec flag and the status flags are kept by
+/// the kernel, which changes the status flags once the scheme accepts them. The access mode never
+/// changes.
+pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
+    if cmd == F_DUPFD {
+        let new_file = duplicate_file(fd, &[])?;
+
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        return context.add_file_min(new_file, arg)
+            .ok_or(Error::new(EMFILE))
+            .map(FileHandle::into);
+    }
+
+    if cmd == F_SETFL {
+        file_op(fd, |scheme, number| scheme.fcntl(number, cmd, arg))?;
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let mut files = context.files.lock();
+    let file = files.get_mut(fd.into())
+        .and_then(|file_option| file_option.as_mut())
+        .ok_or(Error::new(EBADF))?;
+
+    match cmd {
+        F_GETFD => Ok(if file.cloexec { O_CLOEXEC } else { 0 }),
+        F_SETFD => {
+            file.cloexec = arg & O_CLOEXEC == O_CLOEXEC;
+            Ok(0)
+        },
+        F_GETFL => Ok(file.description.read().flags),
+        F_SETFL => {
+            let mut description = file.description.write();
+            description.flags = (description.flags & O_ACCMODE) | (arg & !O_ACCMODE & !O_CLOEXEC);
+            Ok(0)
+        },
+        _ => Err(Error::new(EINVAL))
+    }
+}
+
+/// Most bytes a read or write moves through its kernel buffer. Larger ones are short: they are
+/// not split into several scheme calls, which would join packets or block once data ran out.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// Read into a kernel buffer, and copy what was read to user memory
+pub fn read(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let mut buf = vec![0; cmp::min(slice.len(), MAX_TRANSFER)];
+    let count = file_op(fd, |scheme, number| scheme.read(number, &mut buf))?;
+    // A scheme that claims more than the buffer holds is broken, not a reason to panic
+    copy_to_user(slice.addr(), buf.get(..count).ok_or(Error::new(EIO))?)?;
+    Ok(count)
+}
+
+pub fn write(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let mut buf = vec![0; cmp::min(slice.len(), MAX_TRANSFER)];
+    copy_from_user(&mut buf, slice.addr())?;
+    file_op(fd, |scheme, number| scheme.write(number, &buf))
+}
+
+pub fn lseek(fd: FileHandle, pos: isize, whence: usize) -> Result<usize> {
+    file_op(fd, |scheme, number| scheme.seek(number, pos, whence))
+}
+
+pub fn fchmod(fd: FileHandle, mode: u16) -> Result<usize> {
+    file_op(fd, |scheme, number| scheme.fchmod(number, mode))
+}
+
+pub fn fchown(fd: FileHandle, uid: u32, gid: u32) -> Result<usize> {
+    file_op(fd, |scheme, number| scheme.fchown(number, uid, gid))
+}
+
+/// Map part of a file into the current context. The scheme gives the kernel memory holding it,
+/// and the frames behind that memory are shared with the context rather than copied.
+pub fn fmap(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let map = slice.read::<Map>()?;
+    if map.size == 0 || map.offset % PAGE_SIZE != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let pages = map.size.checked_add(PAGE_SIZE - 1).ok_or(Error::new(EINVAL))? / PAGE_SIZE;
+
+    let address = file_op(fd, |scheme, number| scheme.fmap(number, &map))?;
+    if address % PAGE_SIZE != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let frames = {
+        let active_table = unsafe { ActivePageTable::new() };
+        let mut frames = Vec::new();
+        for i in 0..pages {
+            let page = VirtAddr::new((address + i * PAGE_SIZE) as u64);
+            let physical = active_table.translate_addr(page).ok_or(Error::new(EFAULT))?;
+            frames.push(PhysFrame::containing_address(physical));
+        }
+        frames
+    };
+
+    let mut flags = EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE;
+    if map.flags & PROT_WRITE == PROT_WRITE {
+        flags |= EntryFlags::WRITABLE;
+    }
+    if map.flags & PROT_EXEC != PROT_EXEC {
+        flags |= EntryFlags::NO_EXECUTE;
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let mut grants = context.grants.lock();
+
+    insert_grant(&mut grants, frames.len() * PAGE_SIZE, |to| Grant::map_frames(&frames, to, flags))
+}
+
+/// Unmap memory mapped with `fmap` at `address`
+pub fn funmap(address: usize) -> Result<usize> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let mut grants = context.grants.lock();
+
+    let index = grants.iter()
+        .position(|grant| grant.start_address().as_u64() as usize == address)
+        .ok_or(Error::new(EINVAL))?;
+
+    // Dropping the grant unmaps it
+    grants.remove(index);
+
+    Ok(0)
+}
+
+/// The path of a file, truncated to the buffer and to a page
+pub fn fpath(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let mut buf = vec![0; cmp::min(slice.len(), PAGE_SIZE)];
+    let count = file_op(fd, |scheme, number| scheme.fpath(number, &mut buf))?;
+    copy_to_user(slice.addr(), buf.get(..count).ok_or(Error::new(EIO))?)?;
+    Ok(count)
+}
+
+pub fn frename(fd: FileHandle, path: &[u8]) -> Result<usize> {
+    let file_scheme = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        let file = context.get_file(fd).ok_or(Error::new(EBADF))?;
+        let description = file.description.read();
+        description.scheme
+    };
+
+    // Renaming only works within a scheme
+    path_op(path, |_, scheme_id, reference, uid, gid| {
+        if scheme_id != file_scheme {
+            return Err(Error::new(EXDEV));
+        }
+        file_op(fd, |scheme, number| scheme.frename(number, reference, uid, gid))
+    })
+}
+
+pub fn fstat(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let mut stat = Stat::default();
+    let result = file_op(fd, |scheme, number| scheme.fstat(number, &mut stat))?;
+    slice.write(&stat)?;
+    Ok(result)
+}
+
+pub fn fstatvfs(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let mut stat = StatVfs::default();
+    let result = file_op(fd, |scheme, number| scheme.fstatvfs(number, &mut stat))?;
+    slice.write(&stat)?;
+    Ok(result)
+}
+
+pub fn fsync(fd: FileHandle) -> Result<usize> {
+    file_op(fd, |scheme, number| scheme.fsync(number))
+}
+
+pub fn ftruncate(fd: FileHandle, len: usize) -> Result<usize> {
+    file_op(fd, |scheme, number| scheme.ftruncate(number, len))
+}
+
+pub fn futimens(fd: FileHandle, slice: UserSlice) -> Result<usize> {
+    let size = core::mem::size_of::<TimeSpec>();
+    if slice.len() % size != 0 || slice.len() / size > 2 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut times = Vec::new();
+    for i in 0..slice.len() / size {
+        times.push(UserSlice::ro(slice.addr() + i * size, size)?.read::<TimeSpec>()?);
+    }
+    file_op(fd, |scheme, number| scheme.futimens(number, &times))
+}
@@ -0,0 +1,24 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::ops::{BitAnd, BitOr, Not};
+
+use super::io::Io;
+
+/// A device register in memory, reached by casting its address to `&mut Mmio<T>`
+#[repr(transparent)]
+pub struct Mmio<T> {
+    value: T,
+}
+
+impl<T> Io for Mmio<T> where T: Copy + PartialEq + BitAnd<Output = T> + BitOr<Output = T> + Not<Output = T> {
+    type Value = T;
+
+    #[inline(always)]
+    fn read(&self) -> T {
+        unsafe { read_volatile(&self.value) }
+    }
+
+    #[inline(always)]
+    fn write(&mut self, value: T) {
+        unsafe { write_volatile(&mut self.value, value) }
+    }
+}
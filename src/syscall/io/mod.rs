@@ -1,3 +1,7 @@
 pub use self::io::*;
+pub use self::mmio::*;
+pub use self::pio::*;
 
-mod io;
\ No newline at end of file
+mod io;
+mod mmio;
+mod pio;
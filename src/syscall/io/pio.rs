@@ -0,0 +1,69 @@
+use core::marker::PhantomData;
+use x86_64::instructions::port::{Port, PortReadWrite};
+
+use super::io::Io;
+
+/// An I/O port, read and written with `in` and `out`
+#[derive(Copy, Clone)]
+pub struct Pio<T> {
+    port: u16,
+    value: PhantomData<T>,
+}
+
+impl<T> Pio<T> {
+    pub const fn new(port: u16) -> Pio<T> {
+        Pio {
+            port,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T: PortReadWrite> Pio<T> {
+    #[inline(always)]
+    fn port(&self) -> Port<T> {
+        Port::new(self.port)
+    }
+}
+
+impl Io for Pio<u8> {
+    type Value = u8;
+
+    #[inline(always)]
+    fn read(&self) -> u8 {
+        unsafe { self.port().read() }
+    }
+
+    #[inline(always)]
+    fn write(&mut self, value: u8) {
+        unsafe { self.port().write(value) }
+    }
+}
+
+impl Io for Pio<u16> {
+    type Value = u16;
+
+    #[inline(always)]
+    fn read(&self) -> u16 {
+        unsafe { self.port().read() }
+    }
+
+    #[inline(always)]
+    fn write(&mut self, value: u16) {
+        unsafe { self.port().write(value) }
+    }
+}
+
+impl Io for Pio<u32> {
+    type Value = u32;
+
+    #[inline(always)]
+    fn read(&self) -> u32 {
+        unsafe { self.port().read() }
+    }
+
+    #[inline(always)]
+    fn write(&mut self, value: u32) {
+        unsafe { self.port().write(value) }
+    }
+}
@@ -10,7 +10,101 @@ pub mod flag;
 pub mod data;
 pub mod io;
 pub mod time;
+pub mod power;
+pub mod driver;
+pub mod fs;
+pub mod scheme;
+pub mod usercopy;
 pub mod error;
 pub mod arch;
 pub mod number;
-pub mod call;
\ No newline at end of file
+pub mod call;
+
+use alloc::vec::Vec;
+use core::mem;
+use crate::memory::PAGE_SIZE;
+use crate::scheme::FileHandle;
+use self::usercopy::UserSlice;
+
+/// Copy a path argument, which can be at most a page long
+fn path_arg(addr: usize, len: usize) -> Result<Vec<u8>> {
+    if len > PAGE_SIZE {
+        return Err(Error::new(ENAMETOOLONG));
+    }
+    UserSlice::ro(addr, len)?.to_vec()
+}
+
+/// Copy the buffer passed to `dup` or `dup2`, which can be at most a page long
+fn dup_arg(addr: usize, len: usize) -> Result<Vec<u8>> {
+    if len > PAGE_SIZE {
+        return Err(Error::new(EINVAL));
+    }
+    UserSlice::ro(addr, len)?.to_vec()
+}
+
+/// Kernel entry point for system calls, `a` is the call number and `b` to `f` are its arguments
+pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> usize {
+    #[inline(always)]
+    fn inner(a: usize, b: usize, c: usize, d: usize, e: usize, _f: usize) -> Result<usize> {
+        match a & SYS_CLASS {
+            SYS_CLASS_FILE => {
+                let fd = FileHandle::from(b);
+                match a {
+                    SYS_READ => fs::read(fd, UserSlice::wo(c, d)?),
+                    SYS_WRITE => fs::write(fd, UserSlice::ro(c, d)?),
+                    SYS_LSEEK => fs::lseek(fd, c as isize, d),
+                    SYS_FCHMOD => fs::fchmod(fd, c as u16),
+                    SYS_FCHOWN => fs::fchown(fd, c as u32, d as u32),
+                    SYS_FMAP => fs::fmap(fd, UserSlice::ro(c, d)?),
+                    // Takes an address rather than a file
+                    SYS_FUNMAP => fs::funmap(b),
+                    SYS_FPATH => fs::fpath(fd, UserSlice::wo(c, d)?),
+                    SYS_FRENAME => fs::frename(fd, &path_arg(c, d)?),
+                    SYS_FSTAT => fs::fstat(fd, UserSlice::wo(c, d)?),
+                    SYS_FSTATVFS => fs::fstatvfs(fd, UserSlice::wo(c, d)?),
+                    SYS_FSYNC => fs::fsync(fd),
+                    SYS_FTRUNCATE => fs::ftruncate(fd, c),
+                    SYS_FUTIMENS => fs::futimens(fd, UserSlice::ro(c, d)?),
+                    SYS_DUP => fs::dup(fd, &dup_arg(c, d)?).map(FileHandle::into),
+                    SYS_DUP2 => fs::dup2(fd, FileHandle::from(c), &dup_arg(d, e)?).map(FileHandle::into),
+                    SYS_FCNTL => fs::fcntl(fd, c, d),
+                    SYS_CLOSE => fs::close(fd),
+                    _ => Err(Error::new(ENOSYS))
+                }
+            },
+            SYS_CLASS_PATH => {
+                let path = path_arg(b, c)?;
+                match a {
+                    SYS_OPEN => fs::open(&path, d).map(FileHandle::into),
+                    SYS_CHMOD => fs::chmod(&path, d as u16),
+                    SYS_RMDIR => fs::rmdir(&path),
+                    SYS_UNLINK => fs::unlink(&path),
+                    _ => Err(Error::new(ENOSYS))
+                }
+            },
+            _ => match a {
+                SYS_CLOCK_GETTIME => {
+                    let slice = UserSlice::wo(c, mem::size_of::<TimeSpec>())?;
+                    let mut time = TimeSpec::default();
+                    let result = time::clock_gettime(b, &mut time)?;
+                    slice.write(&time)?;
+                    Ok(result)
+                },
+                SYS_CLOCK_SETTIME => {
+                    let time = UserSlice::ro(c, mem::size_of::<TimeSpec>())?.read::<TimeSpec>()?;
+                    time::clock_settime(b, &time)
+                },
+                SYS_REBOOT => power::reboot(b),
+                SYS_IOPL => driver::iopl(b),
+                SYS_PHYSALLOC => driver::physalloc(b),
+                SYS_PHYSFREE => driver::physfree(b, c),
+                SYS_PHYSMAP => driver::physmap(b, c, d),
+                SYS_PHYSUNMAP => driver::physunmap(b),
+                SYS_VIRTTOPHYS => driver::virttophys(b),
+                _ => Err(Error::new(ENOSYS))
+            }
+        }
+    }
+
+    Error::mux(inner(a, b, c, d, e, f))
+}
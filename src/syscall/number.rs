@@ -40,6 +40,7 @@ pub const SYS_FUTIMENS: usize = SYS_CLASS_FILE | SYS_ARG_SLICE | 320;
 pub const SYS_BRK: usize = 45;
 pub const SYS_CHDIR: usize = 12;
 pub const SYS_CLOCK_GETTIME: usize = 265;
+pub const SYS_CLOCK_SETTIME: usize = 264;
 pub const SYS_CLONE: usize = 120;
 pub const SYS_EXIT: usize = 1;
 pub const SYS_FUTEX: usize = 240;
@@ -64,6 +65,7 @@ pub const SYS_PHYSMAP: usize = 947;
 pub const SYS_PHYSUNMAP: usize = 948;
 pub const SYS_VIRTTOPHYS: usize = 949;
 pub const SYS_PIPE2: usize = 331;
+pub const SYS_REBOOT: usize = 88;
 pub const SYS_SETPGID: usize = 57;
 pub const SYS_SETREGID: usize = 204;
 pub const SYS_SETRENS: usize = 952;
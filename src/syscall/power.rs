@@ -0,0 +1,23 @@
+use crate::context;
+use crate::power;
+use crate::syscall::error::*;
+use crate::syscall::flag::{REBOOT_CMD_POWER_OFF, REBOOT_CMD_RESTART};
+
+pub fn reboot(cmd: usize) -> Result<usize> {
+    let euid = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.euid
+    };
+
+    if euid != 0 {
+        return Err(Error::new(EPERM));
+    }
+
+    match cmd {
+        REBOOT_CMD_RESTART => power::reboot(),
+        REBOOT_CMD_POWER_OFF => power::shutdown(),
+        _ => Err(Error::new(EINVAL))
+    }
+}
@@ -1,3 +1,5 @@
+use crate::context;
+use crate::device::rtc::Rtc;
 use crate::time;
 use crate::syscall::data::TimeSpec;
 use crate::syscall::error::*;
@@ -13,4 +15,28 @@ pub fn clock_gettime(clock: usize, time: &mut TimeSpec) -> Result<usize> {
     time.tv_sec = arch_time.0 as i64;
     time.tv_nsec = arch_time.1 as i32;
     Ok(0)
-}
\ No newline at end of file
+}
+
+pub fn clock_settime(clock: usize, time: &TimeSpec) -> Result<usize> {
+    let euid = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.euid
+    };
+
+    if euid != 0 {
+        return Err(Error::new(EPERM));
+    }
+
+    if clock != CLOCK_REALTIME || time.tv_nsec < 0 || time.tv_nsec >= 1_000_000_000 {
+        return Err(Error::new(EINVAL));
+    }
+    if time.tv_sec < 0 || time.tv_sec as u64 > time::MAX_REALTIME {
+        return Err(Error::new(EINVAL));
+    }
+
+    time::set_realtime((time.tv_sec as u64, time.tv_nsec as u64));
+    Rtc::new().set_time(time.tv_sec as u64);
+    Ok(0)
+}
@@ -0,0 +1,240 @@
+//! # User memory access
+//!
+//! System calls must not dereference pointers from user space directly: they may point into the
+//! kernel, at unmapped memory, or with SMAP enabled, fault even when valid. A `UserSlice` checks
+//! that a range lies in the user half and in the memory of the current context, and copies go
+//! through `__user_copy`, whose page faults return to the caller as `EFAULT`.
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags as EntryFlags;
+use x86_64::VirtAddr;
+
+use crate::context::{self, memory::Memory};
+use crate::device::cpu;
+use crate::interrupt::entry;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::error::*;
+
+/// End of the lower half, where all user memory lives
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+// `__user_copy(dst, src, len)` returns the number of bytes left uncopied. A page fault in the
+// `rep movsb` resumes at `__user_copy_fault`, with the count left in rcx.
+global_asm!(r#"
+    .text
+    .global __user_copy
+__user_copy:
+    cld
+    mov %rdx, %rcx
+    .global __user_copy_start
+__user_copy_start:
+    rep movsb
+    xor %eax, %eax
+    ret
+    .global __user_copy_fault
+__user_copy_fault:
+    mov %rcx, %rax
+    ret
+"#);
+
+extern "C" {
+    fn __user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static __user_copy_start: u8;
+    static __user_copy_fault: u8;
+}
+
+/// Called by the page fault handler: if the fault happened in `__user_copy`, make it return
+/// instead, and return true
+pub fn fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    unsafe {
+        let frame = entry::interrupted_frame(stack_frame);
+        if frame.instruction_pointer.as_u64() != &__user_copy_start as *const u8 as u64 {
+            return false;
+        }
+
+        frame.instruction_pointer = VirtAddr::new(&__user_copy_fault as *const u8 as u64);
+    }
+    true
+}
+
+/// Copy with user memory accessible, returning `EFAULT` on a fault
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<()> {
+    let smap = cpu::has_smap();
+    if smap {
+        asm!("stac" : : : "memory" : "intel", "volatile");
+    }
+    let left = __user_copy(dst, src, len);
+    if smap {
+        asm!("clac" : : : "memory" : "intel", "volatile");
+    }
+
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(Error::new(EFAULT))
+    }
+}
+
+/// Whether `addr..addr + len` lies in the user half
+pub fn is_user_range(addr: usize, len: usize) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => end <= USER_END,
+        None => false,
+    }
+}
+
+/// A range of user memory of the current context, checked to be mapped
+#[derive(Clone, Copy, Debug)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    /// A range that is read from
+    pub fn ro(addr: usize, len: usize) -> Result<UserSlice> {
+        UserSlice::new(addr, len, false)
+    }
+
+    /// A range that is written to
+    pub fn wo(addr: usize, len: usize) -> Result<UserSlice> {
+        UserSlice::new(addr, len, true)
+    }
+
+    /// A range that is read and written
+    pub fn rw(addr: usize, len: usize) -> Result<UserSlice> {
+        UserSlice::new(addr, len, true)
+    }
+
+    fn new(addr: usize, len: usize, writable: bool) -> Result<UserSlice> {
+        if len == 0 {
+            return Ok(UserSlice { addr, len });
+        }
+        if addr == 0 || !is_user_range(addr, len) {
+            return Err(Error::new(EFAULT));
+        }
+
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        // Every page of the range must be in one of the memories of the context
+        let mut page = addr / PAGE_SIZE * PAGE_SIZE;
+        while page < addr + len {
+            let mut found = false;
+            {
+                let mut check = |memory: &Memory| {
+                    let start = memory.start_address().as_u64() as usize;
+                    if page >= start && page < start + memory.size()
+                        && (!writable || memory.flags().contains(EntryFlags::WRITABLE))
+                        && memory.flags().contains(EntryFlags::USER_ACCESSIBLE) {
+                        found = true;
+                    }
+                };
+
+                for shared in context.image.iter() {
+                    shared.with(|memory| check(memory));
+                }
+                if let Some(ref heap) = context.heap {
+                    heap.with(|memory| check(memory));
+                }
+                if let Some(ref stack) = context.stack {
+                    check(stack);
+                }
+                if let Some(ref sigstack) = context.sigstack {
+                    check(sigstack);
+                }
+                if let Some(ref tls) = context.tls {
+                    check(&tls.mem);
+                }
+                for grant in context.grants.lock().iter() {
+                    let start = grant.start_address().as_u64() as usize;
+                    if page >= start && page < start + grant.size()
+                        && (!writable || grant.flags().contains(EntryFlags::WRITABLE)) {
+                        found = true;
+                    }
+                }
+            }
+            if !found {
+                return Err(Error::new(EFAULT));
+            }
+            page += PAGE_SIZE;
+        }
+
+        Ok(UserSlice { addr, len })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Copy the whole range into `buf`, which must have the same length
+    pub fn copy_to_slice(&self, buf: &mut [u8]) -> Result<()> {
+        if buf.len() != self.len {
+            return Err(Error::new(EINVAL));
+        }
+        unsafe { copy(buf.as_mut_ptr(), self.addr as *const u8, self.len) }
+    }
+
+    /// Copy `buf`, which must have the same length, into the whole range
+    pub fn copy_from_slice(&self, buf: &[u8]) -> Result<()> {
+        if buf.len() != self.len {
+            return Err(Error::new(EINVAL));
+        }
+        unsafe { copy(self.addr as *mut u8, buf.as_ptr(), self.len) }
+    }
+
+    /// Copy the whole range into a new buffer
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0; self.len];
+        self.copy_to_slice(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read a plain value the size of the range
+    pub fn read<T: Copy + Default>(&self) -> Result<T> {
+        let mut value = T::default();
+        if mem::size_of::<T>() != self.len {
+            return Err(Error::new(EINVAL));
+        }
+        unsafe { copy(&mut value as *mut T as *mut u8, self.addr as *const u8, self.len)?; }
+        Ok(value)
+    }
+
+    /// Write a plain value the size of the range
+    pub fn write<T: Copy>(&self, value: &T) -> Result<()> {
+        if mem::size_of::<T>() != self.len {
+            return Err(Error::new(EINVAL));
+        }
+        unsafe { copy(self.addr as *mut u8, value as *const T as *const u8, self.len) }
+    }
+}
+
+/// Copy `dst.len()` bytes from user memory at `src`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<()> {
+    UserSlice::ro(src, dst.len())?.copy_to_slice(dst)
+}
+
+/// Copy `src` to user memory at `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<()> {
+    UserSlice::wo(dst, src.len())?.copy_from_slice(src)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn user_range() {
+        assert!(is_user_range(0x1000, 0x1000));
+        assert!(is_user_range(USER_END - 8, 8));
+        assert!(!is_user_range(USER_END - 8, 9));
+        assert!(!is_user_range(0xffff_8000_0000_0000, 8));
+        assert!(!is_user_range(usize::max_value() - 1, 4));
+    }
+}
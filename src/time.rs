@@ -1,17 +1,284 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use spin::Mutex;
 
+use crate::common::calendar::DateTime;
+use crate::common::tzif::TimeZone;
+use crate::device::hpet::HPET;
+use crate::device::{pit, rtc, tsc};
+
 /// Kernel start time, measured in (seconds, nanoseconds) since Unix epoch
 pub static START: Mutex<(u64, u64)> = Mutex::new((0, 0));
 /// Kernel up time, measured in (seconds, nanoseconds) since `START_TIME`
 pub static OFFSET: Mutex<(u64, u64)> = Mutex::new((0, 0));
 
+/// Time zone used by `local_time`, UTC when unset
+static TIME_ZONE: Mutex<Option<TimeZone>> = Mutex::new(None);
+
+/// Hardware counters `monotonic` can read from, in order of preference
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Timer ticks counted in `OFFSET`, interpolated with the channel 0 count when the PIT ticks
+    Pit = 0,
+    /// Main counter of the HPET
+    Hpet = 1,
+    /// Invariant time stamp counter
+    Tsc = 2,
+}
+
+/// The source in use. These are atomics rather than a lock, as they are read from the timer interrupt.
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+/// Up time in nanoseconds when the source was selected
+static BASE_UPTIME: AtomicU64 = AtomicU64::new(0);
+/// Reading of the source in nanoseconds when it was selected
+static BASE_READING: AtomicU64 = AtomicU64::new(0);
+
+/// Set when a channel 0 interrupt raised while the tick was stopped is still pending
+static SKIP_TICK: AtomicBool = AtomicBool::new(false);
+
+/// Interrupts that advance `OFFSET` and drive preemption
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum TickSource {
+    /// PIT channel 0, on IRQ 0
+    Pit = 0,
+    /// RTC periodic interrupt, on IRQ 8
+    Rtc = 1,
+}
+
+impl TickSource {
+    /// The IRQ line of the source
+    pub fn irq(self) -> u8 {
+        match self {
+            TickSource::Pit => 0,
+            TickSource::Rtc => 8,
+        }
+    }
+
+    /// Nanoseconds between two ticks
+    pub fn rate(self) -> u64 {
+        match self {
+            TickSource::Pit => pit::RATE,
+            TickSource::Rtc => rtc::PERIODIC_PERIOD,
+        }
+    }
+}
+
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+
+/// Corrections smaller than this are slewed rather than stepped, in nanoseconds
+const SLEW_THRESHOLD: u64 = 128_000_000;
+/// Slew rate, in nanoseconds per second of monotonic time
+const SLEW_RATE: u64 = 500_000;
+
+/// Last second `set_realtime` accepts: the end of 2261, before realtime in nanoseconds overflows an
+/// `i64`, and well within the years 0 to 9999 the RTC century register holds
+pub const MAX_REALTIME: u64 = 9_214_646_399;
+
+/// A correction of `START` being applied gradually
+#[derive(Clone, Copy, Debug)]
+struct Slew {
+    /// Monotonic time in nanoseconds when the correction started
+    start: u64,
+    /// The whole correction in nanoseconds
+    delta: i64,
+}
+
+impl Slew {
+    /// The part of the correction applied at monotonic time `now`
+    fn applied(&self, now: u64) -> i64 {
+        let max = (now.saturating_sub(self.start) as u128 * SLEW_RATE as u128 / 1_000_000_000) as i64;
+        if self.delta.abs() <= max {
+            self.delta
+        } else {
+            self.delta.signum() * max
+        }
+    }
+}
+
+static SLEW: Mutex<Option<Slew>> = Mutex::new(None);
+
+/// Largest value returned by `monotonic`, so that readings never go backwards
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds in (seconds, nanoseconds), saturating for times too far away to count
+pub fn to_nanos(time: (u64, u64)) -> u64 {
+    time.0.checked_mul(1_000_000_000)
+        .and_then(|nanos| nanos.checked_add(time.1))
+        .unwrap_or(u64::max_value())
+}
+
+pub fn from_nanos(nanos: u64) -> (u64, u64) {
+    (nanos / 1_000_000_000, nanos % 1_000_000_000)
+}
+
+/// Switch to the best clock source available, once the timers have been initialized
+pub fn init() {
+    let source = if tsc::frequency_khz() != 0 {
+        ClockSource::Tsc
+    } else if unsafe { HPET.is_ready() } {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    };
+
+    BASE_UPTIME.store(monotonic_nanos(), Ordering::SeqCst);
+    BASE_READING.store(read_source(source), Ordering::SeqCst);
+    SOURCE.store(source as u8, Ordering::SeqCst);
+}
+
+/// The clock source `monotonic` reads from
+pub fn source() -> ClockSource {
+    match SOURCE.load(Ordering::SeqCst) {
+        1 => ClockSource::Hpet,
+        2 => ClockSource::Tsc,
+        _ => ClockSource::Pit,
+    }
+}
+
+fn read_source(source: ClockSource) -> u64 {
+    match source {
+        ClockSource::Pit => 0,
+        ClockSource::Hpet => unsafe { HPET.nanoseconds() },
+        ClockSource::Tsc => tsc::nanoseconds(),
+    }
+}
+
+fn monotonic_nanos() -> u64 {
+    let source = source();
+    let nanos = match source {
+        ClockSource::Pit => {
+            let offset = to_nanos(*OFFSET.lock());
+            if tick_source() == TickSource::Pit {
+                offset + unsafe { pit::elapsed_in_tick() }
+            } else {
+                offset
+            }
+        }
+        _ => {
+            let elapsed = read_source(source).saturating_sub(BASE_READING.load(Ordering::SeqCst));
+            BASE_UPTIME.load(Ordering::SeqCst) + elapsed
+        }
+    };
+
+    let mut last = LAST.load(Ordering::SeqCst);
+    loop {
+        if nanos <= last {
+            return last;
+        }
+        match LAST.compare_exchange(last, nanos, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return nanos,
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// Switch the interrupt `tick` is called from, the caller takes care of the IRQ masks
+pub fn set_tick_source(tick_source: TickSource) {
+    TICK_SOURCE.store(tick_source as u8, Ordering::SeqCst);
+}
+
+/// The interrupt `tick` is called from
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::SeqCst) {
+        1 => TickSource::Rtc,
+        _ => TickSource::Pit,
+    }
+}
+
+/// Advance `OFFSET` on an interrupt of the tick source
+pub fn tick() {
+    if SKIP_TICK.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    if source() == ClockSource::Pit {
+        let mut offset = OFFSET.lock();
+        let sum = offset.1 + tick_source().rate();
+        offset.1 = sum % 1_000_000_000;
+        offset.0 += sum / 1_000_000_000;
+    } else {
+        let now = from_nanos(monotonic_nanos());
+        *OFFSET.lock() = now;
+    }
+}
+
+/// Account for `slept` nanoseconds spent with the periodic tick stopped, starting at up time `start`.
+/// `stale_tick` tells whether a tick interrupt is pending from that time, it will then be ignored.
+pub fn resume(start: u64, slept: u64, stale_tick: bool) {
+    if source() == ClockSource::Pit {
+        // The channel 0 count is meaningless now, restart the period from here
+        if tick_source() == TickSource::Pit {
+            unsafe { pit::init(); }
+        }
+        SKIP_TICK.store(stale_tick, Ordering::SeqCst);
+        *OFFSET.lock() = from_nanos(start + slept);
+    } else {
+        *OFFSET.lock() = monotonic();
+    }
+}
+
+/// Kernel up time with nanosecond resolution, as (seconds, nanoseconds)
 pub fn monotonic() -> (u64, u64) {
-    *OFFSET.lock()
+    from_nanos(monotonic_nanos())
+}
+
+/// Current time in nanoseconds since the Unix epoch, folding a finished slew into `START`
+fn realtime_nanos() -> u64 {
+    let now = monotonic_nanos();
+    let mut start = START.lock();
+    let mut slew = SLEW.lock();
+
+    let mut applied = 0;
+    if let Some(correction) = *slew {
+        applied = correction.applied(now);
+        if applied == correction.delta {
+            *start = from_nanos((to_nanos(*start) as i64 + applied) as u64);
+            *slew = None;
+            applied = 0;
+        }
+    }
+
+    (to_nanos(*start) as i64 + applied) as u64 + now
 }
 
 pub fn realtime() -> (u64, u64) {
-    let offset = monotonic();
-    let start = *START.lock();
-    let sum = start.1 + offset.1;
-    (start.0 + offset.0 + sum / 1_000_000_000, sum % 1_000_000_000)
+    from_nanos(realtime_nanos())
+}
+
+/// Set the current time, as (seconds, nanoseconds) since Unix epoch. Small corrections are slewed
+/// at `SLEW_RATE` so that the time does not jump, larger ones are applied at once.
+pub fn set_realtime(time: (u64, u64)) {
+    let now = monotonic_nanos();
+    let target = to_nanos(time) as i64;
+    let current = realtime_nanos() as i64;
+    let delta = target - current;
+
+    let mut start = START.lock();
+    let mut slew = SLEW.lock();
+
+    // Keep the part of a previous correction applied so far
+    if let Some(correction) = slew.take() {
+        *start = from_nanos((to_nanos(*start) as i64 + correction.applied(now)) as u64);
+    }
+
+    if delta.abs() as u64 <= SLEW_THRESHOLD {
+        *slew = Some(Slew { start: now, delta });
+    } else {
+        *start = from_nanos((target - now as i64) as u64);
+    }
+}
+
+/// Set the time zone of `local_time`, parsed from a TZif file
+pub fn set_time_zone(time_zone: TimeZone) {
+    *TIME_ZONE.lock() = Some(time_zone);
+}
+
+/// The current date and time in the local time zone
+pub fn local_time() -> DateTime {
+    let (secs, nanos) = realtime();
+    match *TIME_ZONE.lock() {
+        Some(ref time_zone) => time_zone.local(secs as i64, nanos as u32),
+        None => DateTime::from_unix(secs as i64, nanos as u32),
+    }
 }
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dongos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use dongos::memory::{self, table, ActivePageTable, InactivePageTable};
+use dongos::{serial_print, serial_println};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags as EntryFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    dongos::init(boot_info);
+
+    test_main();
+    dongos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dongos::test_panic_handler(info)
+}
+
+#[test_case]
+fn kernel_mapped() {
+    serial_print!("kernel_mapped... ");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mut new_table = InactivePageTable::new(table::allocate_table_frame().unwrap());
+
+    // The kernel image and the heap translate the same in the new table
+    let code = VirtAddr::new(kernel_mapped as usize as u64);
+    let heap = VirtAddr::new(dongos::KERNEL_HEAP_OFFSET as u64);
+    let expected = (active_table.translate_addr(code), active_table.translate_addr(heap));
+    active_table.with(&mut new_table, |mapper| {
+        assert_eq!((mapper.translate_addr(code), mapper.translate_addr(heap)), expected);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_tables_freed() {
+    serial_print!("user_tables_freed... ");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let free = memory::free_frames();
+
+    let mut new_table = InactivePageTable::new(table::allocate_table_frame().unwrap());
+    let frame = memory::allocate_frames(1).unwrap();
+    active_table.with(&mut new_table, |mapper| {
+        let page = Page::containing_address(VirtAddr::new(0x0000_4000_0000_0000));
+        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+        unsafe {
+            mapper.map_to(page, frame, flags, memory::FRAME_ALLOCATOR.lock().as_mut().unwrap()).unwrap().ignore();
+        }
+    });
+    drop(new_table);
+
+    // Only the mapped frame is left, the tables created to map it are gone
+    assert_eq!(memory::free_frames(), free - 1);
+    memory::deallocate_frames(frame, 1);
+    assert_eq!(memory::free_frames(), free);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_half_private() {
+    serial_print!("user_half_private... ");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mut new_table = InactivePageTable::new(table::allocate_table_frame().unwrap());
+
+    // The user image lies outside of the entries of the kernel, so a new table has nothing there
+    let user = VirtAddr::new(dongos::USER_OFFSET as u64);
+    active_table.with(&mut new_table, |mapper| {
+        assert_eq!(mapper.translate_addr(user), None);
+    });
+    assert_ne!(dongos::USER_PML4, (kernel_mapped as usize & dongos::PML4_MASK) / dongos::PML4_SIZE);
+    serial_println!("[ok]");
+}
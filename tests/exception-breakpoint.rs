@@ -2,15 +2,37 @@
 #![cfg_attr(not(test), no_main)]
 #![allow(dead_code, unused_macros, unused_imports, unused_variables, unused_mut, deprecated)]
 
+use bootloader::{bootinfo::BootInfo, entry_point, bootinfo::MemoryRegionType};
 use dongos::{exit_qemu, serial_println};
 use core::panic::PanicInfo;
-
+entry_point!(kernel_main);
 #[cfg(not(test))]
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    use dongos::memory::{self, ActivePageTable, heap};
+    dongos::gdt::init();
     dongos::idt::init();
     unsafe {
         dongos::device::init();
+    }
+
+    let kernel_end = {
+        let end_area = boot_info.memory_map
+            .iter()
+            .filter(|area| area.region_type == MemoryRegionType::Kernel)
+            .last().unwrap();
+        end_area.range.end_addr() as usize
+    };
+    memory::init(boot_info, 0, kernel_end);
+
+    unsafe {
+        let mut active_page_table = ActivePageTable::new();
+        heap::init(&mut active_page_table);
+        dongos::percpu::init(0, &mut active_page_table);
+    }
+
+    // Devices map their registers, so memory comes first
+    unsafe {
         dongos::device::init_noncore();
     }
     x86_64::instructions::interrupts::int3();
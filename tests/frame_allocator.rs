@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dongos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use dongos::memory::{self, buddy::MAX_ORDER};
+use dongos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    dongos::init(boot_info);
+
+    test_main();
+    dongos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dongos::test_panic_handler(info)
+}
+
+#[test_case]
+fn order_aligned() {
+    serial_print!("order_aligned... ");
+    for order in 0..8 {
+        let frame = memory::allocate_order(order).unwrap();
+        assert_eq!(frame.start_address().as_u64() % (4096 << order), 0);
+        memory::deallocate_frames(frame, 1 << order);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn counts_restored() {
+    serial_print!("counts_restored... ");
+    let free = memory::free_frames();
+    let used = memory::used_frames();
+
+    let a = memory::allocate_frames(3).unwrap();
+    let b = memory::allocate_frames(1).unwrap();
+    let c = memory::allocate_frames(100).unwrap();
+    assert_eq!(memory::free_frames(), free - 104);
+    assert_eq!(memory::used_frames(), used + 104);
+
+    memory::deallocate_frames(b, 1);
+    memory::deallocate_frames(a, 3);
+    memory::deallocate_frames(c, 100);
+    assert_eq!(memory::free_frames(), free);
+    assert_eq!(memory::used_frames(), used);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn buddies_merge() {
+    serial_print!("buddies_merge... ");
+    let order = 6;
+    let block = memory::allocate_order(order).unwrap();
+    memory::deallocate_frames(block, 1 << order);
+    assert_eq!(memory::allocate_order(order), Some(block));
+
+    // Freeing the frames one by one merges them back into the same block
+    for i in 0..1 << order {
+        memory::deallocate_frames(block + i, 1);
+    }
+    assert_eq!(memory::allocate_order(order), Some(block));
+    memory::deallocate_frames(block, 1 << order);
+
+    assert!(memory::allocate_order(MAX_ORDER + 1).is_none());
+    serial_println!("[ok]");
+}
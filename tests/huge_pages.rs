@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dongos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use dongos::memory::{self, mapper::MapperFlushAll, ActivePageTable, PAGE_SIZE};
+use dongos::{serial_print, serial_println};
+use x86_64::structures::paging::{Page, PageTableFlags as EntryFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// An unused, 1 GiB aligned address in the lower half
+const TEST_ADDR: u64 = 0x0000_5000_0000_0000;
+const HUGE: u64 = 2 * 1024 * 1024;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    dongos::init(boot_info);
+
+    test_main();
+    dongos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dongos::test_panic_handler(info)
+}
+
+fn flags() -> EntryFlags {
+    EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+}
+
+#[test_case]
+fn map_huge() {
+    serial_print!("map_huge... ");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let start = VirtAddr::new(TEST_ADDR);
+
+    // An unaligned tail falls back to small pages
+    let mut flush_all = MapperFlushAll::new();
+    active_table.map_range(start, (HUGE as usize) + PAGE_SIZE, flags(), true, &mut flush_all);
+    flush_all.flush(&mut active_table);
+    assert_eq!(active_table.mapping_size(start), Some(HUGE));
+    assert_eq!(active_table.mapping_size(start + HUGE), Some(PAGE_SIZE as u64));
+
+    let pages = active_table.pages_in(start, start + HUGE + PAGE_SIZE as u64, HUGE);
+    assert_eq!(pages.len(), 2);
+
+    let mut flush_all = MapperFlushAll::new();
+    for (addr, size) in pages {
+        let frame = active_table.unmap_sized(addr, size, &mut flush_all);
+        memory::deallocate_frames(frame, size as usize / PAGE_SIZE);
+    }
+    flush_all.flush(&mut active_table);
+    assert_eq!(active_table.mapping_size(start), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn split_keeps_frames() {
+    serial_print!("split_keeps_frames... ");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let start = VirtAddr::new(TEST_ADDR);
+
+    let mut flush_all = MapperFlushAll::new();
+    active_table.map_range(start, HUGE as usize, flags(), true, &mut flush_all);
+    flush_all.flush(&mut active_table);
+    assert_eq!(active_table.mapping_size(start), Some(HUGE));
+
+    let marker = (start + 3 * PAGE_SIZE as u64).as_mut_ptr::<u64>();
+    unsafe { marker.write_volatile(0xdead_beef); }
+
+    // Asking for the second half splits the huge page, without moving its contents
+    let pages = active_table.pages_in(start + HUGE / 2, start + HUGE, HUGE);
+    assert_eq!(pages.len(), 256);
+    assert_eq!(active_table.mapping_size(start), Some(PAGE_SIZE as u64));
+    assert_eq!(unsafe { marker.read_volatile() }, 0xdead_beef);
+
+    let mut flush_all = MapperFlushAll::new();
+    for (addr, size) in active_table.pages_in(start, start + HUGE, HUGE) {
+        let frame = active_table.unmap_sized(addr, size, &mut flush_all);
+        memory::deallocate_frames(frame, size as usize / PAGE_SIZE);
+    }
+    flush_all.flush(&mut active_table);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn remap_after_split() {
+    serial_print!("remap_after_split... ");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let start = VirtAddr::new(TEST_ADDR);
+
+    let mut flush_all = MapperFlushAll::new();
+    active_table.map_range(start, HUGE as usize, flags(), true, &mut flush_all);
+    flush_all.flush(&mut active_table);
+
+    // Unmapping the split pages leaves an empty table where the huge page was
+    let mut flush_all = MapperFlushAll::new();
+    for (addr, size) in active_table.pages_in(start, start + HUGE, PAGE_SIZE as u64) {
+        let frame = active_table.unmap_sized(addr, size, &mut flush_all);
+        memory::deallocate_frames(frame, size as usize / PAGE_SIZE);
+    }
+    flush_all.flush(&mut active_table);
+
+    // That table is freed to map a huge page again, and a small page in the way falls back to
+    // small pages
+    let mut flush_all = MapperFlushAll::new();
+    let result = active_table.map(Page::containing_address(start + HUGE + 5 * PAGE_SIZE as u64), flags());
+    flush_all.consume(result);
+    active_table.map_range(start, 2 * HUGE as usize, flags(), true, &mut flush_all);
+    flush_all.flush(&mut active_table);
+    assert_eq!(active_table.mapping_size(start), Some(HUGE));
+    assert_eq!(active_table.mapping_size(start + HUGE), Some(PAGE_SIZE as u64));
+
+    let mut flush_all = MapperFlushAll::new();
+    for (addr, size) in active_table.pages_in(start, start + 2 * HUGE, HUGE) {
+        let frame = active_table.unmap_sized(addr, size, &mut flush_all);
+        memory::deallocate_frames(frame, size as usize / PAGE_SIZE);
+    }
+    flush_all.flush(&mut active_table);
+    serial_println!("[ok]");
+}
@@ -15,9 +15,7 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     dongos::idt::init();
     unsafe {
         dongos::device::init();
-        dongos::device::init_noncore();
     };
-    x86_64::instructions::interrupts::enable();
 
     let kernel_end = {
         let end_area = boot_info.memory_map
@@ -31,9 +29,16 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut active_page_table = unsafe {
         let mut active_page_table = ActivePageTable::new();
         heap::init(&mut active_page_table);
+        dongos::percpu::init(0, &mut active_page_table);
         active_page_table
     };
 
+    // Devices map their registers, so memory comes first
+    unsafe {
+        dongos::device::init_noncore();
+    };
+    x86_64::instructions::interrupts::enable();
+
     use x86_64::structures::paging::{Page, PhysFrame};
     use x86_64::{VirtAddr, PhysAddr};
     use x86_64::structures::paging::PageTableFlags as Flags;